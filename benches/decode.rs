@@ -0,0 +1,53 @@
+// SPDX-License-Identifier: MIT
+
+//! Compare the futures decoders on frames recorded from the combined market
+//! streams, see `frames.txt`.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+
+use binance::futures::websocket::Event;
+
+const FRAMES: &str = include_str!("frames.txt");
+
+fn decode(c: &mut Criterion) {
+    let frames: Vec<&str> = FRAMES.lines().filter(|line| !line.is_empty()).collect();
+    let bytes = frames.iter().map(|frame| frame.len() as u64).sum();
+
+    let mut group = c.benchmark_group("futures");
+    group.throughput(Throughput::Bytes(bytes));
+    group.bench_function("decode_value", |b| {
+        b.iter(|| {
+            for frame in &frames {
+                let value = serde_json::from_str(black_box(frame)).unwrap();
+                black_box(Event::decode_value(value).unwrap());
+            }
+        })
+    });
+    group.bench_function("decode_text", |b| {
+        b.iter(|| {
+            for frame in &frames {
+                black_box(Event::decode_text(black_box(frame)).unwrap());
+            }
+        })
+    });
+    group.finish();
+
+    for frame in &frames {
+        let name = frame.split('"').nth(3).unwrap_or("frame").replace('@', "_");
+        let mut group = c.benchmark_group(name);
+        group.throughput(Throughput::Bytes(frame.len() as u64));
+        group.bench_function("decode_value", |b| {
+            b.iter(|| {
+                let value = serde_json::from_str(black_box(frame)).unwrap();
+                black_box(Event::decode_value(value).unwrap())
+            })
+        });
+        group.bench_function("decode_text", |b| {
+            b.iter(|| black_box(Event::decode_text(black_box(frame)).unwrap()))
+        });
+        group.finish();
+    }
+}
+
+criterion_group!(benches, decode);
+criterion_main!(benches);
@@ -39,6 +39,7 @@ struct FuturesBuyOptions {}
 async fn main() -> anyhow::Result<()> {
     let opts: Opts = Opts::parse();
     match opts {
+        Opts::Futures(Futures::Buy(opts)) => futures_buy_order(opts).await,
         Opts::Futures(Futures::Cancel(opts)) => futures_cancel_order(opts).await,
         Opts::Futures(Futures::OpenOrders(opts)) => futures_open_orders(opts).await,
         _ => unimplemented!(),
@@ -46,10 +47,10 @@ async fn main() -> anyhow::Result<()> {
 }
 
 fn get_binance_authentication() -> Result<Authentication> {
-    Ok(Authentication {
-        api_key: std::env::var("API_KEY")?,
-        api_secret: std::env::var("API_SECRET")?,
-    })
+    Ok(Authentication::hmac(
+        std::env::var("API_KEY")?,
+        std::env::var("API_SECRET")?,
+    ))
 }
 
 async fn futures_open_orders(opts: OpenOrdersOpts) -> Result<()> {
@@ -77,8 +78,8 @@ async fn futures_cancel_order(opts: CancelOrderOpts) -> Result<()> {
     Ok(())
 }
 
-async fn futures_buy_order(options: FuturesBuyOptions) -> Result<()> {
-    let auth = get_binance_authentication()?;
+async fn futures_buy_order(_options: FuturesBuyOptions) -> Result<()> {
+    let _auth = get_binance_authentication()?;
 
     Ok(())
 }
@@ -1,11 +1,15 @@
-use binance::common::websocket::{stream_name_aggtrade, stream_name_kline};
+use binance::common::stream_name::StreamName;
+use binance::types::Interval;
 
 #[tokio::main]
 pub async fn main() {
     println!("Futures WebSocket Example.");
-    let kline_stream = stream_name_kline("btcusdt", "1m");
-    let aggtrade_stream = stream_name_aggtrade("solusdt");
-    let mut ws = binance::futures::websocket::connect_combined(&[kline_stream, aggtrade_stream])
+    let streams = binance::futures::websocket::stream_names(&[
+        StreamName::kline("btcusdt", Interval::OneMinute),
+        StreamName::agg_trade("solusdt"),
+    ])
+    .unwrap();
+    let mut ws = binance::futures::websocket::connect_combined(&streams)
         .await
         .unwrap();
     loop {
@@ -0,0 +1,440 @@
+// SPDX-License-Identifier: MIT
+
+//! Recording of REST and WebSocket traffic to JSONL cassettes, and replay of
+//! them through the same client APIs.
+//!
+//! Each line of a cassette is one request/response pair or one inbound
+//! WebSocket frame, with the time it was recorded. Signatures and listen keys
+//! are redacted and the API key header is not recorded, so cassettes taken
+//! in production can be checked in as regression tests.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use base64::Engine;
+use futures_util::future::BoxFuture;
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::common::time::now_millis;
+use crate::common::transport::{HttpRequest, HttpResponse, HttpTransport};
+use crate::error::Error;
+
+/// Replaces the value of redacted parameters and fields.
+pub const REDACTED: &str = "REDACTED";
+
+/// Query and form parameters, and JSON fields, that are redacted.
+const REDACTED_KEYS: &[&str] = &["signature", "listenKey"];
+
+/// Parameters that change on every request and are ignored when matching a
+/// request to a recorded one.
+const VOLATILE_KEYS: &[&str] = &["signature", "timestamp", "recvWindow"];
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CassetteEntry {
+    Http(HttpExchange),
+    Frame(Frame),
+}
+
+/// A recorded REST request and its response.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HttpExchange {
+    /// Wall clock time in milliseconds the response was received.
+    pub time: i64,
+    pub method: String,
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_body: Option<String>,
+    pub status: u16,
+    /// Response headers, only the `x-mbx-*` headers are kept.
+    #[serde(default)]
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+/// A recorded inbound WebSocket frame.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Frame {
+    /// Wall clock time in milliseconds the frame was received.
+    pub time: i64,
+    pub kind: FrameKind,
+    /// The text of text frames, base64 for all others.
+    pub data: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FrameKind {
+    Text,
+    Binary,
+    Ping,
+    Pong,
+    Close,
+}
+
+impl Frame {
+    pub fn from_message(message: &Message) -> Self {
+        let encode = |data: &[u8]| base64::engine::general_purpose::STANDARD.encode(data);
+        let (kind, data) = match message {
+            Message::Text(text) => (FrameKind::Text, redact_json(text)),
+            Message::Binary(data) => (FrameKind::Binary, encode(data)),
+            Message::Ping(data) => (FrameKind::Ping, encode(data)),
+            Message::Pong(data) => (FrameKind::Pong, encode(data)),
+            Message::Close(_) => (FrameKind::Close, String::new()),
+        };
+        Self {
+            time: now_millis(),
+            kind,
+            data,
+        }
+    }
+
+    pub fn to_message(&self) -> Message {
+        let decode = || {
+            base64::engine::general_purpose::STANDARD
+                .decode(&self.data)
+                .unwrap_or_default()
+        };
+        match self.kind {
+            FrameKind::Text => Message::Text(self.data.clone()),
+            FrameKind::Binary => Message::Binary(decode()),
+            FrameKind::Ping => Message::Ping(decode()),
+            FrameKind::Pong => Message::Pong(decode()),
+            FrameKind::Close => Message::Close(None),
+        }
+    }
+}
+
+/// A cassette being recorded. Entries are flushed as they are written so a
+/// cassette is complete up to the moment a process dies.
+pub struct Cassette {
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl Cassette {
+    /// Create, or truncate, a cassette file.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Ok(Self::from_writer(BufWriter::new(File::create(path)?)))
+    }
+
+    pub fn from_writer<W: Write + Send + 'static>(writer: W) -> Self {
+        Self {
+            writer: Mutex::new(Box::new(writer)),
+        }
+    }
+
+    pub fn record(&self, entry: &CassetteEntry) -> Result<(), Error> {
+        let line = serde_json::to_string(entry)?;
+        let mut writer = self.writer.lock().unwrap();
+        writeln!(writer, "{}", line)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn record_http(&self, request: &HttpRequest, response: &HttpResponse) -> Result<(), Error> {
+        let mut url = request.url.clone();
+        if let Some(query) = request.url.query() {
+            url.set_query(Some(&redact_form(query)));
+        }
+        let headers = response
+            .headers
+            .iter()
+            .filter(|(name, _)| name.as_str().starts_with("x-mbx-"))
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect();
+        self.record(&CassetteEntry::Http(HttpExchange {
+            time: now_millis(),
+            method: request.method.to_string(),
+            url: url.to_string(),
+            request_body: request.body.as_deref().map(redact_form),
+            status: response.status.as_u16(),
+            headers,
+            body: redact_json(&response.body),
+        }))
+    }
+
+    pub fn record_frame(&self, message: &Message) -> Result<(), Error> {
+        self.record(&CassetteEntry::Frame(Frame::from_message(message)))
+    }
+}
+
+/// Read the entries of a cassette file.
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<CassetteEntry>, Error> {
+    parse(BufReader::new(File::open(path)?))
+}
+
+/// Read cassette entries, one JSON object per line. Blank lines are skipped.
+pub fn parse<R: BufRead>(reader: R) -> Result<Vec<CassetteEntry>, Error> {
+    let mut entries = vec![];
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        entries.push(serde_json::from_str(&line)?);
+    }
+    Ok(entries)
+}
+
+/// The frames of a cassette as WebSocket messages, in order.
+pub fn frames(entries: &[CassetteEntry]) -> VecDeque<Message> {
+    entries
+        .iter()
+        .filter_map(|entry| match entry {
+            CassetteEntry::Frame(frame) => Some(frame.to_message()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Redact the secret parameters of a query string or form.
+pub fn redact_form(form: &str) -> String {
+    form.split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((key, _)) if REDACTED_KEYS.contains(&key) => format!("{}={}", key, REDACTED),
+            _ => pair.to_string(),
+        })
+        .collect::<Vec<String>>()
+        .join("&")
+}
+
+/// Redact the secret fields of a JSON object, other text is returned as is.
+pub fn redact_json(text: &str) -> String {
+    let mut value = match serde_json::from_str::<Value>(text) {
+        Ok(value @ Value::Object(_)) => value,
+        _ => return text.to_string(),
+    };
+    let object = value.as_object_mut().unwrap();
+    let mut redacted = false;
+    for key in REDACTED_KEYS {
+        if let Some(field) = object.get_mut(*key) {
+            *field = Value::String(REDACTED.to_string());
+            redacted = true;
+        }
+    }
+    if redacted {
+        value.to_string()
+    } else {
+        text.to_string()
+    }
+}
+
+/// A transport that records every request and response to a cassette.
+pub struct RecordingTransport {
+    inner: Arc<dyn HttpTransport>,
+    cassette: Arc<Cassette>,
+}
+
+impl RecordingTransport {
+    pub fn new(inner: Arc<dyn HttpTransport>, cassette: Arc<Cassette>) -> Self {
+        Self { inner, cassette }
+    }
+}
+
+impl HttpTransport for RecordingTransport {
+    fn send(&self, request: HttpRequest) -> BoxFuture<'_, Result<HttpResponse, Error>> {
+        Box::pin(async move {
+            let response = self.inner.send(request.clone()).await?;
+            self.cassette.record_http(&request, &response)?;
+            Ok(response)
+        })
+    }
+}
+
+/// A transport that serves the responses of a cassette.
+///
+/// A request is answered with the first unused exchange with the same
+/// method, path and parameters, ignoring the timestamp, recvWindow and
+/// signature. Requests with no such exchange fail.
+pub struct ReplayTransport {
+    exchanges: Mutex<Vec<HttpExchange>>,
+}
+
+impl ReplayTransport {
+    pub fn new(entries: &[CassetteEntry]) -> Self {
+        let exchanges = entries
+            .iter()
+            .filter_map(|entry| match entry {
+                CassetteEntry::Http(exchange) => Some(exchange.clone()),
+                _ => None,
+            })
+            .collect();
+        Self {
+            exchanges: Mutex::new(exchanges),
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Ok(Self::new(&load(path)?))
+    }
+
+    /// Number of recorded exchanges not yet served.
+    pub fn remaining(&self) -> usize {
+        self.exchanges.lock().unwrap().len()
+    }
+}
+
+impl HttpTransport for ReplayTransport {
+    fn send(&self, request: HttpRequest) -> BoxFuture<'_, Result<HttpResponse, Error>> {
+        let key = request_key(
+            request.method.as_str(),
+            request.url.path(),
+            request.url.query(),
+            request.body.as_deref(),
+        );
+        let mut exchanges = self.exchanges.lock().unwrap();
+        let position = exchanges.iter().position(|exchange| {
+            let url = match reqwest::Url::parse(&exchange.url) {
+                Ok(url) => url,
+                Err(_) => return false,
+            };
+            request_key(
+                &exchange.method,
+                url.path(),
+                url.query(),
+                exchange.request_body.as_deref(),
+            ) == key
+        });
+        let result = match position.map(|i| exchanges.remove(i)) {
+            Some(exchange) => replay_response(&exchange),
+            None => Err(Error::Anyhow(anyhow::anyhow!(
+                "no recorded response for {} {}",
+                request.method,
+                request.url
+            ))),
+        };
+        Box::pin(async move { result })
+    }
+}
+
+fn replay_response(exchange: &HttpExchange) -> Result<HttpResponse, Error> {
+    let status = StatusCode::from_u16(exchange.status)
+        .map_err(|err| Error::Anyhow(anyhow::anyhow!("invalid recorded status: {}", err)))?;
+    let mut headers = HeaderMap::new();
+    for (name, value) in &exchange.headers {
+        if let (Ok(name), Ok(value)) = (
+            reqwest::header::HeaderName::from_bytes(name.as_bytes()),
+            value.parse(),
+        ) {
+            headers.insert(name, value);
+        }
+    }
+    Ok(HttpResponse {
+        status,
+        headers,
+        body: exchange.body.clone(),
+    })
+}
+
+fn request_key(method: &str, path: &str, query: Option<&str>, body: Option<&str>) -> String {
+    let params = [query, body]
+        .iter()
+        .flatten()
+        .flat_map(|form| form.split('&'))
+        .filter(|pair| {
+            let key = pair.split('=').next().unwrap_or("");
+            !pair.is_empty() && !VOLATILE_KEYS.contains(&key)
+        })
+        .collect::<Vec<&str>>()
+        .join("&");
+    format!("{} {}?{}", method, path, params)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::client::{Authentication, Client};
+    use crate::common::transport::MockTransport;
+    use reqwest::Method;
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_redact() {
+        assert_eq!(
+            redact_form("symbol=BTCUSDT&timestamp=1&signature=abcd"),
+            "symbol=BTCUSDT&timestamp=1&signature=REDACTED"
+        );
+        assert_eq!(
+            redact_json(
+                r#"{"listenKey":"pqia91ma19a5s61cv6a81va65sdf19v8a65a1a5s61cv6a81va65sdf19v8a65a1"}"#
+            ),
+            r#"{"listenKey":"REDACTED"}"#
+        );
+        assert_eq!(redact_json(r#"{"e":"trade"}"#), r#"{"e":"trade"}"#);
+        assert_eq!(redact_json("not json"), "not json");
+    }
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let buffer = SharedBuffer::default();
+        let cassette = Arc::new(Cassette::from_writer(buffer.clone()));
+        let mock = Arc::new(MockTransport::new());
+        mock.push_response(
+            HttpResponse::new(StatusCode::OK, r#"{"dualSidePosition":true}"#)
+                .header("x-mbx-used-weight-1m", "30"),
+        );
+
+        let auth = Authentication::hmac("key", "secret");
+        let mut client = Client::new("https://fapi.binance.com", Some(auth));
+        client.set_transport(Arc::new(RecordingTransport::new(mock, cassette.clone())));
+        client
+            .send_signed(Method::GET, "/fapi/v1/positionSide/dual", "")
+            .await
+            .unwrap();
+        cassette
+            .record_frame(&Message::Text(r#"{"e":"listenKeyExpired"}"#.to_string()))
+            .unwrap();
+
+        let text = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let entries = parse(text.as_bytes()).unwrap();
+        assert_eq!(entries.len(), 2);
+        match &entries[0] {
+            CassetteEntry::Http(exchange) => {
+                assert!(exchange.url.ends_with("&signature=REDACTED"));
+                assert_eq!(
+                    exchange.headers,
+                    [("x-mbx-used-weight-1m".to_string(), "30".to_string())]
+                );
+            }
+            entry => panic!("unexpected entry {:?}", entry),
+        }
+        assert_eq!(
+            frames(&entries),
+            [Message::Text(r#"{"e":"listenKeyExpired"}"#.to_string())]
+        );
+
+        let replay = Arc::new(ReplayTransport::new(&entries));
+        let mut client = Client::new("https://fapi.binance.com", None);
+        client.set_transport(replay.clone());
+        let response = client
+            .send(Method::GET, "/fapi/v1/positionSide/dual", None)
+            .await
+            .unwrap();
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.body, r#"{"dualSidePosition":true}"#);
+        assert_eq!(replay.remaining(), 0);
+        assert!(client
+            .send(Method::GET, "/fapi/v1/positionSide/dual", None)
+            .await
+            .is_err());
+    }
+}
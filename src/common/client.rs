@@ -1,43 +1,173 @@
 // SPDX-License-Identifier: MIT
 
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::future::Future;
+use std::sync::Arc;
 
 use anyhow::{anyhow, Result};
-use hmac::{Mac, NewMac};
+use reqwest::{Method, StatusCode};
 use serde::Deserialize;
+use tracing::warn;
 
-use crate::error::Error;
+use crate::common::cassette::{Cassette, RecordingTransport};
+use crate::common::ratelimit::{RateLimit, RateLimitState};
+use crate::common::retry::RetryPolicy;
+use crate::common::scheduler::{endpoint_cost, RequestCost, Scheduler};
+use crate::common::signer::{Ed25519Signer, HmacSigner, RsaSigner, Signer};
+use crate::common::time::{now_millis, ServerTimeResponse, TimeSync};
+use crate::common::transport::{HttpRequest, HttpResponse, HttpTransport, ReqwestTransport};
+use crate::error::{ApiError, ApiErrorCode, Error};
+
+/// The recvWindow, in milliseconds, sent with signed requests unless
+/// configured otherwise.
+pub const DEFAULT_RECV_WINDOW: u64 = 1000;
 
 #[derive(Clone)]
 pub struct Authentication {
-    pub api_secret: String,
     pub api_key: String,
+    pub signer: Arc<dyn Signer>,
+}
+
+impl Authentication {
+    /// Authenticate with an API key and HMAC secret.
+    pub fn hmac<K: Into<String>, S: Into<String>>(api_key: K, api_secret: S) -> Self {
+        Self::with_signer(api_key, HmacSigner::new(api_secret))
+    }
+
+    /// Authenticate with an Ed25519 API key and its PKCS#8 PEM private key.
+    pub fn ed25519<K: Into<String>>(api_key: K, private_key_pem: &str) -> Result<Self, Error> {
+        Ok(Self::with_signer(
+            api_key,
+            Ed25519Signer::from_pem(private_key_pem)?,
+        ))
+    }
+
+    /// Authenticate with an RSA API key and its PEM private key.
+    pub fn rsa<K: Into<String>>(api_key: K, private_key_pem: &str) -> Result<Self, Error> {
+        Ok(Self::with_signer(
+            api_key,
+            RsaSigner::from_pem(private_key_pem)?,
+        ))
+    }
+
+    pub fn with_signer<K: Into<String>, S: Signer + 'static>(api_key: K, signer: S) -> Self {
+        Self {
+            api_key: api_key.into(),
+            signer: Arc::new(signer),
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct Client {
     auth: Option<Authentication>,
-    pub client: reqwest::Client,
+    transport: Arc<dyn HttpTransport>,
     base_url: String,
+    time_endpoint: Option<String>,
+    time_sync: Arc<TimeSync>,
+    recv_window: u64,
+    rate_limits: Arc<RateLimitState>,
+    scheduler: Option<Arc<Scheduler>>,
+    retry_policy: RetryPolicy,
 }
 
 impl Client {
     pub fn new<S: Into<String>>(base_url: S, authentication: Option<Authentication>) -> Self {
         Self {
             base_url: base_url.into(),
-            client: reqwest::Client::new(),
+            transport: Arc::new(ReqwestTransport::default()),
             auth: authentication,
+            time_endpoint: None,
+            time_sync: Arc::new(TimeSync::default()),
+            recv_window: DEFAULT_RECV_WINDOW,
+            rate_limits: Arc::new(RateLimitState::default()),
+            scheduler: None,
+            retry_policy: RetryPolicy::none(),
+        }
+    }
+
+    /// Set the endpoint used to query the server time, enabling time
+    /// synchronisation.
+    pub fn with_time_endpoint<S: Into<String>>(mut self, endpoint: S) -> Self {
+        self.time_endpoint = Some(endpoint.into());
+        self
+    }
+
+    /// Send requests through another transport, for example a
+    /// `MockTransport` in tests.
+    pub fn set_transport(&mut self, transport: Arc<dyn HttpTransport>) {
+        self.transport = transport;
+    }
+
+    /// Record all requests and responses to a cassette. Replay them with a
+    /// `ReplayTransport`.
+    pub fn record(&mut self, cassette: Arc<Cassette>) {
+        self.transport = Arc::new(RecordingTransport::new(self.transport.clone(), cassette));
+    }
+
+    pub fn transport(&self) -> &Arc<dyn HttpTransport> {
+        &self.transport
+    }
+
+    /// The clock offset shared with the clones of this client.
+    pub fn time_sync(&self) -> Arc<TimeSync> {
+        self.time_sync.clone()
+    }
+
+    /// Rate limit usage as reported by the responses to this client and its
+    /// clones.
+    pub fn rate_limits(&self) -> Arc<RateLimitState> {
+        self.rate_limits.clone()
+    }
+
+    /// Set the advertised rate limits, usually from the exchange info.
+    pub fn set_rate_limits(&self, limits: Vec<RateLimit>) {
+        if let Some(scheduler) = &self.scheduler {
+            scheduler.set_limits(&limits);
         }
+        self.rate_limits.set_limits(limits);
+    }
+
+    /// Throttle requests to stay under the rate limits. Only 429 and 418
+    /// responses are handled until the limits are known, see
+    /// `set_rate_limits`.
+    pub fn enable_scheduler(&mut self) {
+        if self.scheduler.is_none() {
+            self.scheduler = Some(Arc::new(Scheduler::new(&self.rate_limits.limits())));
+        }
+    }
+
+    pub fn scheduler(&self) -> Option<&Arc<Scheduler>> {
+        self.scheduler.as_ref()
+    }
+
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry_policy
+    }
+
+    /// Set the policy for retrying GET requests that failed for transient
+    /// reasons. Other requests are never retried here.
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.retry_policy = retry_policy;
+    }
+
+    pub fn recv_window(&self) -> u64 {
+        self.recv_window
+    }
+
+    pub fn set_recv_window(&mut self, recv_window: u64) {
+        self.recv_window = recv_window;
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
     }
 
     pub fn compute_signature(&self, request_body: &str) -> Result<String> {
-        let mut macr = hmac::Hmac::<sha2::Sha256>::new_varkey(
-            self.auth.as_ref().unwrap().api_secret.as_bytes(),
-        )
-        .map_err(|err| anyhow!("hmac: {:?}", err))?;
-        macr.update(request_body.as_bytes());
-        let signature = macr.finalize();
-        Ok(format!("{:x}", signature.into_bytes()))
+        let auth = self
+            .auth
+            .as_ref()
+            .ok_or_else(|| anyhow!("signed request without authentication"))?;
+        Ok(auth.signer.sign(request_body)?)
     }
 
     pub fn headers(&self) -> Result<reqwest::header::HeaderMap> {
@@ -62,81 +192,239 @@ impl Client {
         Ok(url)
     }
 
+    /// Add the recvWindow, timestamp and signature to a form. The signature
+    /// is URL encoded as Ed25519 and RSA signatures are base64.
     pub fn sign_form(&self, form: Option<&str>) -> Result<String> {
-        let form = form.unwrap_or("");
         let timestamp = self.get_timestamp();
-        let form = format!("{}&recvWindow=1000&timestamp={}", form, timestamp);
+        let form = match form.filter(|form| !form.is_empty()) {
+            Some(form) => format!(
+                "{}&recvWindow={}&timestamp={}",
+                form, self.recv_window, timestamp
+            ),
+            None => format!("recvWindow={}&timestamp={}", self.recv_window, timestamp),
+        };
         let signature = self.compute_signature(&form)?;
-        let form = format!("{}&signature={}", &form, &signature);
-        Ok(form)
+        let signature = serde_urlencoded::to_string([("signature", signature)])?;
+        Ok(format!("{}&{}", form, signature))
     }
 
-    fn get_timestamp(&self) -> u128 {
-        SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_millis()
+    fn get_timestamp(&self) -> i64 {
+        self.time_sync.timestamp()
     }
 
-    pub fn get<E: AsRef<str>>(
+    /// Query the server time and update the clock offset. Returns the new
+    /// offset in milliseconds.
+    pub async fn sync_time(&self) -> Result<i64, Error> {
+        let endpoint = self
+            .time_endpoint
+            .as_ref()
+            .ok_or_else(|| Error::UrlError("no time endpoint configured".to_string()))?;
+        let sent = now_millis();
+        let response = self.send(Method::GET, endpoint, None).await?;
+        let received = now_millis();
+        let response: ServerTimeResponse = decode_response(&response)?;
+        self.time_sync.update(sent, response.server_time, received);
+        Ok(self.time_sync.offset())
+    }
+
+    /// Send a signed request. The form is signed and sent as the query string
+    /// for GET and DELETE requests, and as the body otherwise.
+    ///
+    /// If the server rejects the timestamp the clock is resynced and the
+    /// request is retried once.
+    pub async fn send_signed(
+        &self,
+        method: Method,
+        endpoint: &str,
+        form: &str,
+    ) -> Result<HttpResponse, Error> {
+        if method == Method::GET {
+            self.with_retry(|| self.send_signed_resync(method.clone(), endpoint, form))
+                .await
+        } else {
+            self.send_signed_resync(method, endpoint, form).await
+        }
+    }
+
+    async fn send_signed_resync(
+        &self,
+        method: Method,
+        endpoint: &str,
+        form: &str,
+    ) -> Result<HttpResponse, Error> {
+        if self.time_endpoint.is_some() && self.time_sync.needs_refresh() {
+            if let Err(err) = self.sync_time().await {
+                warn!("Failed to refresh server time: {}", err);
+            }
+        }
+        let response = self
+            .send_signed_once(method.clone(), endpoint, form)
+            .await?;
+        if self.time_endpoint.is_some() && is_invalid_timestamp(&response) {
+            match self.sync_time().await {
+                Ok(offset) => {
+                    warn!(
+                        "Timestamp rejected, resynced clock with offset {}ms",
+                        offset
+                    );
+                    return self.send_signed_once(method, endpoint, form).await;
+                }
+                // The rejection says more about the request than the
+                // failed resync does.
+                Err(err) => warn!("Timestamp rejected, failed to resync clock: {}", err),
+            }
+        }
+        Ok(response)
+    }
+
+    async fn send_signed_once(
         &self,
-        endpoint: E,
-        query_string: Option<E>,
-    ) -> reqwest::RequestBuilder {
-        let url = if let Some(query_string) = query_string {
-            format!(
-                "{}?{}",
-                self.url(endpoint.as_ref()).unwrap(),
-                query_string.as_ref()
-            )
+        method: Method,
+        endpoint: &str,
+        form: &str,
+    ) -> Result<HttpResponse, Error> {
+        let cost = endpoint_cost(&method, endpoint, form);
+        let form = self.sign_form(Some(form))?;
+        let request = if method == Method::GET || method == Method::DELETE {
+            HttpRequest {
+                url: self.url2(endpoint, Some(&form))?,
+                method,
+                headers: self.headers()?,
+                body: None,
+            }
         } else {
-            self.url(endpoint.as_ref()).unwrap()
+            HttpRequest {
+                url: self.url2(endpoint, None)?,
+                method,
+                headers: self.headers()?,
+                body: Some(form),
+            }
         };
-        self.client.get(&url)
+        self.execute(request, cost).await
     }
 
-    pub fn get2(
+    /// Send an unsigned request with an optional query string.
+    pub async fn send(
         &self,
+        method: Method,
         endpoint: &str,
         query_string: Option<&str>,
-    ) -> anyhow::Result<reqwest::RequestBuilder> {
-        let mut url = reqwest::Url::parse(&format!("{}{}", self.base_url, endpoint))?;
-        url.set_query(query_string);
-        Ok(self.client.get(url))
+    ) -> Result<HttpResponse, Error> {
+        if method == Method::GET {
+            self.with_retry(|| self.send_once(method.clone(), endpoint, query_string))
+                .await
+        } else {
+            self.send_once(method, endpoint, query_string).await
+        }
     }
 
-    pub fn delete(&self, endpoint: &str, query_string: Option<&str>) -> reqwest::RequestBuilder {
-        self.client
-            .delete(self.url2(endpoint, query_string).unwrap())
+    async fn send_once(
+        &self,
+        method: Method,
+        endpoint: &str,
+        query_string: Option<&str>,
+    ) -> Result<HttpResponse, Error> {
+        let query_string = query_string.filter(|q| !q.is_empty());
+        let cost = endpoint_cost(&method, endpoint, query_string.unwrap_or(""));
+        let request = HttpRequest {
+            url: self.url2(endpoint, query_string)?,
+            method,
+            headers: self.headers()?,
+            body: None,
+        };
+        self.execute(request, cost).await
+    }
+
+    /// Run a request under the retry policy.
+    async fn with_retry<F, Fut>(&self, request: F) -> Result<HttpResponse, Error>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<HttpResponse, Error>>,
+    {
+        let mut attempt = 1;
+        loop {
+            let result = request().await;
+            if attempt >= self.retry_policy.max_attempts || !self.retry_policy.should_retry(&result)
+            {
+                return result;
+            }
+            let backoff = self.retry_policy.backoff_for(attempt);
+            warn!(
+                "Request failed with {:?}, retrying in {:?}",
+                RetryPolicy::classify(&result),
+                backoff
+            );
+            tokio::time::sleep(backoff).await;
+            attempt += 1;
+        }
     }
 
+    async fn execute(
+        &self,
+        request: HttpRequest,
+        cost: RequestCost,
+    ) -> Result<HttpResponse, Error> {
+        if let Some(scheduler) = &self.scheduler {
+            scheduler.acquire(cost).await;
+        }
+        let response = self.transport.send(request).await?;
+        self.rate_limits.record_headers(&response.headers);
+        if let Some(scheduler) = &self.scheduler {
+            scheduler.reconcile(&self.rate_limits);
+            scheduler.on_response(response.status, &response.headers, &response.body);
+        }
+        Ok(response)
+    }
+
+    /// Create a user data stream listen key. Listen key endpoints take the
+    /// API key but are not signed.
     pub async fn post_listenkey(&self, endpoint: &str) -> Result<ListenKeyResponse, Error> {
-        let response = self
-            .client
-            .post(&self.url(endpoint)?)
-            .headers(self.headers()?)
-            .send()
-            .await?;
-        let response_body = response.text().await?;
-        Ok(serde_json::from_str(&response_body)?)
+        let response = self.send(Method::POST, endpoint, None).await?;
+        decode_response(&response)
     }
 
-    pub async fn put_listenkey(&self, endpoint: &str) -> Result<ListenKeyResponse, Error> {
-        let timestamp = self.get_timestamp();
-        let form = format!("timestamp={}", timestamp);
-        let form = format!("{}&signature={}", &form, self.compute_signature(&form)?);
-        let response = self
-            .client
-            .post(self.url(endpoint)?)
-            .body(form)
-            .headers(self.headers()?)
-            .send()
-            .await?;
-        let response_body = response.text().await?;
-        Ok(serde_json::from_str(&response_body)?)
+    /// Keep a listen key alive for another 60 minutes.
+    pub async fn put_listenkey(&self, endpoint: &str, listen_key: &str) -> Result<(), Error> {
+        let query = serde_urlencoded::to_string([("listenKey", listen_key)])?;
+        let response = self.send(Method::PUT, endpoint, Some(&query)).await?;
+        decode_response::<serde_json::Value>(&response)?;
+        Ok(())
+    }
+
+    /// Close the user data stream of a listen key.
+    pub async fn delete_listenkey(&self, endpoint: &str, listen_key: &str) -> Result<(), Error> {
+        let query = serde_urlencoded::to_string([("listenKey", listen_key)])?;
+        let response = self.send(Method::DELETE, endpoint, Some(&query)).await?;
+        decode_response::<serde_json::Value>(&response)?;
+        Ok(())
     }
 }
 
+/// Decode a response body, or the API error if the status is not OK.
+pub fn decode_response<T>(response: &HttpResponse) -> Result<T, Error>
+where
+    T: serde::de::DeserializeOwned,
+{
+    match response.status {
+        StatusCode::OK => serde_json::from_str(&response.body).map_err(|error| Error::Decode {
+            error,
+            text: response.body.clone(),
+        }),
+        status => Err(Error::from_response(
+            status,
+            &response.headers,
+            &response.body,
+        )),
+    }
+}
+
+fn is_invalid_timestamp(response: &HttpResponse) -> bool {
+    response.status != StatusCode::OK
+        && serde_json::from_str::<ApiError>(&response.body)
+            .map(|error| error.error_code() == ApiErrorCode::InvalidTimestamp)
+            .unwrap_or(false)
+}
+
 #[derive(Deserialize, Debug)]
 pub struct ListenKeyResponse {
     #[serde(rename = "listenKey")]
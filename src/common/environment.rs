@@ -0,0 +1,120 @@
+// SPDX-License-Identifier: MIT
+
+//! Selection of the Binance deployment (mainnet, testnet, ...) that the REST
+//! clients and WebSocket connections talk to.
+
+use crate::futures;
+use crate::spot;
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Environment {
+    /// The production exchange.
+    #[default]
+    Mainnet,
+
+    /// The spot and futures testnets.
+    Testnet,
+
+    /// Binance.US. Only spot is available.
+    US,
+
+    /// A user provided REST root and WebSocket root, for example a local mock
+    /// server. Used for both spot and futures.
+    Custom { rest: String, ws: String },
+}
+
+impl Environment {
+    pub fn custom<R: Into<String>, W: Into<String>>(rest: R, ws: W) -> Self {
+        Self::Custom {
+            rest: rest.into(),
+            ws: ws.into(),
+        }
+    }
+
+    /// The REST API root for spot.
+    pub fn spot_rest_url(&self) -> &str {
+        match self {
+            Self::Mainnet => spot::client::API_ROOT,
+            Self::Testnet => spot::client::TESTNET_API_ROOT,
+            Self::US => spot::client::US_API_ROOT,
+            Self::Custom { rest, .. } => rest,
+        }
+    }
+
+    /// The WebSocket stream root for spot.
+    pub fn spot_ws_url(&self) -> &str {
+        match self {
+            Self::Mainnet => spot::websocket::BASE_URL,
+            Self::Testnet => spot::websocket::TESTNET_BASE_URL,
+            Self::US => spot::websocket::US_BASE_URL,
+            Self::Custom { ws, .. } => ws,
+        }
+    }
+
+    /// The WebSocket API endpoint for spot.
+    pub fn spot_ws_api_url(&self) -> &str {
+        match self {
+            Self::Mainnet => spot::ws_api::WS_API_URL,
+            Self::Testnet => spot::ws_api::TESTNET_WS_API_URL,
+            Self::US => spot::ws_api::US_WS_API_URL,
+            Self::Custom { ws, .. } => ws,
+        }
+    }
+
+    /// The REST API root for USD-M futures, `None` if the environment has no
+    /// futures market.
+    pub fn futures_rest_url(&self) -> Option<&str> {
+        match self {
+            Self::Mainnet => Some(futures::client::API_ROOT),
+            Self::Testnet => Some(futures::client::TESTNET_API_ROOT),
+            Self::US => None,
+            Self::Custom { rest, .. } => Some(rest),
+        }
+    }
+
+    /// The WebSocket stream root for USD-M futures, `None` if the environment
+    /// has no futures market.
+    pub fn futures_ws_url(&self) -> Option<&str> {
+        match self {
+            Self::Mainnet => Some(futures::websocket::BASE_URL),
+            Self::Testnet => Some(futures::websocket::TESTNET_BASE_URL),
+            Self::US => None,
+            Self::Custom { ws, .. } => Some(ws),
+        }
+    }
+
+    /// The WebSocket API endpoint for USD-M futures, `None` if the
+    /// environment has no futures market.
+    pub fn futures_ws_api_url(&self) -> Option<&str> {
+        match self {
+            Self::Mainnet => Some(futures::ws_api::WS_API_URL),
+            Self::Testnet => Some(futures::ws_api::TESTNET_WS_API_URL),
+            Self::US => None,
+            Self::Custom { ws, .. } => Some(ws),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_environment_urls() {
+        assert_eq!(
+            Environment::Mainnet.spot_rest_url(),
+            "https://api.binance.com"
+        );
+        assert_eq!(
+            Environment::Testnet.futures_rest_url(),
+            Some("https://testnet.binancefuture.com")
+        );
+        assert_eq!(Environment::US.futures_ws_url(), None);
+        assert_eq!(Environment::US.futures_ws_api_url(), None);
+
+        let env = Environment::custom("http://127.0.0.1:8080", "ws://127.0.0.1:8081");
+        assert_eq!(env.spot_rest_url(), "http://127.0.0.1:8080");
+        assert_eq!(env.futures_rest_url(), Some("http://127.0.0.1:8080"));
+        assert_eq!(env.futures_ws_url(), Some("ws://127.0.0.1:8081"));
+    }
+}
@@ -0,0 +1,284 @@
+// SPDX-License-Identifier: MIT
+
+//! Receive timestamps and stream latency.
+//!
+//! Events carry the time Binance generated them, `Received` adds when and
+//! on which connection the frame was read here. `LatencyTracker` keeps the
+//! latency from the event time to the receipt per stream, with the local
+//! clock moved to the server clock by the `TimeSync` offset, so a connection
+//! or host falling behind shows in its percentiles.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::common::time::{now_millis, TimeSync};
+
+/// The number of latencies per stream the percentiles are taken over.
+pub const DEFAULT_WINDOW: usize = 1000;
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A new connection id, unique within the process. 0 is left for frames
+/// replayed from a cassette.
+pub(crate) fn next_connection_id() -> u64 {
+    NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// When and on which connection a frame was read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Receipt {
+    /// Monotonic time, for intervals between frames.
+    pub instant: Instant,
+
+    /// Local wall clock time in milliseconds since the epoch.
+    pub wall_clock: i64,
+
+    /// The connection the frame was read on. A reconnect or rollover gets a
+    /// new id, frames replayed from a cassette have id 0.
+    pub connection_id: u64,
+}
+
+impl Receipt {
+    pub fn now(connection_id: u64) -> Self {
+        Self {
+            instant: Instant::now(),
+            wall_clock: now_millis(),
+            connection_id,
+        }
+    }
+}
+
+/// An event with the receipt of the frame it was decoded from.
+#[derive(Debug, Clone)]
+pub struct Received<T> {
+    pub event: T,
+
+    /// The stream the event was sent on, if known from the combined stream
+    /// envelope or the connection.
+    pub stream: Option<String>,
+
+    pub receipt: Receipt,
+}
+
+impl<T: EventTime> Received<T> {
+    /// Milliseconds from the event time to the receipt, where `offset` is the
+    /// server time minus local time as kept by `TimeSync`.
+    pub fn latency(&self, offset: i64) -> Option<i64> {
+        Some(self.receipt.wall_clock + offset - self.event.event_time()?)
+    }
+}
+
+/// Events with the time Binance generated them.
+pub trait EventTime {
+    /// The event time in milliseconds since the epoch, `None` for events
+    /// without one such as pings and connection state.
+    fn event_time(&self) -> Option<i64>;
+}
+
+/// The stream of a combined stream frame. Binance sends the stream name
+/// first, so it is read without parsing the frame.
+pub(crate) fn combined_stream(text: &str) -> Option<&str> {
+    let rest = text.strip_prefix(r#"{"stream":""#)?;
+    rest.find('"').map(|end| &rest[..end])
+}
+
+/// Latency percentiles of a stream in milliseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencyStats {
+    /// All latencies recorded, the others are over the window.
+    pub count: u64,
+    pub min: i64,
+    pub p50: i64,
+    pub p90: i64,
+    pub p99: i64,
+    pub max: i64,
+}
+
+#[derive(Debug, Default)]
+struct Samples {
+    latencies: VecDeque<i64>,
+    count: u64,
+}
+
+impl Samples {
+    fn sorted(&self) -> Vec<i64> {
+        let mut sorted: Vec<i64> = self.latencies.iter().copied().collect();
+        sorted.sort_unstable();
+        sorted
+    }
+}
+
+/// Latency per stream over the most recent events. Shared between tasks
+/// through an `Arc`.
+#[derive(Debug)]
+pub struct LatencyTracker {
+    time_sync: Arc<TimeSync>,
+    window: usize,
+    streams: Mutex<HashMap<String, Samples>>,
+}
+
+impl Default for LatencyTracker {
+    /// A tracker trusting the local clock.
+    fn default() -> Self {
+        Self::new(Arc::new(TimeSync::default()))
+    }
+}
+
+impl LatencyTracker {
+    /// A tracker using the offset of a client's `TimeSync`, which should be
+    /// synced for the latencies to be meaningful.
+    pub fn new(time_sync: Arc<TimeSync>) -> Self {
+        Self {
+            time_sync,
+            window: DEFAULT_WINDOW,
+            streams: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take the percentiles over the last `window` events of each stream.
+    pub fn with_window(mut self, window: usize) -> Self {
+        self.window = window.max(1);
+        self
+    }
+
+    /// Record the latency of an event, returning it. Events without a
+    /// stream or an event time are not recorded.
+    pub fn record<T: EventTime>(&self, received: &Received<T>) -> Option<i64> {
+        let stream = received.stream.as_deref()?;
+        let latency = received.latency(self.time_sync.offset())?;
+        self.record_latency(stream, latency);
+        Some(latency)
+    }
+
+    pub fn record_latency(&self, stream: &str, latency: i64) {
+        let mut streams = self.streams.lock().unwrap();
+        let samples = match streams.get_mut(stream) {
+            Some(samples) => samples,
+            None => streams.entry(stream.to_string()).or_default(),
+        };
+        if samples.latencies.len() == self.window {
+            samples.latencies.pop_front();
+        }
+        samples.latencies.push_back(latency);
+        samples.count += 1;
+    }
+
+    /// A percentile, between 0 and 100, of the latencies of a stream.
+    pub fn percentile(&self, stream: &str, percentile: f64) -> Option<i64> {
+        let streams = self.streams.lock().unwrap();
+        let sorted = streams.get(stream)?.sorted();
+        Some(nearest_rank(&sorted, percentile))
+    }
+
+    pub fn stats(&self, stream: &str) -> Option<LatencyStats> {
+        self.streams.lock().unwrap().get(stream).map(stats)
+    }
+
+    /// The stats of every stream recorded.
+    pub fn all_stats(&self) -> HashMap<String, LatencyStats> {
+        self.streams
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(stream, samples)| (stream.clone(), stats(samples)))
+            .collect()
+    }
+
+    /// Forget all latencies, for example after a resync of the clock.
+    pub fn reset(&self) {
+        self.streams.lock().unwrap().clear();
+    }
+}
+
+fn stats(samples: &Samples) -> LatencyStats {
+    let sorted = samples.sorted();
+    LatencyStats {
+        count: samples.count,
+        min: sorted[0],
+        p50: nearest_rank(&sorted, 50.0),
+        p90: nearest_rank(&sorted, 90.0),
+        p99: nearest_rank(&sorted, 99.0),
+        max: sorted[sorted.len() - 1],
+    }
+}
+
+/// The smallest value at least `percentile` percent of the values are less
+/// than or equal to. `sorted` is never empty.
+fn nearest_rank(sorted: &[i64], percentile: f64) -> i64 {
+    let rank = (percentile.clamp(0.0, 100.0) / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Timed(Option<i64>);
+
+    impl EventTime for Timed {
+        fn event_time(&self) -> Option<i64> {
+            self.0
+        }
+    }
+
+    fn received(stream: Option<&str>, event_time: Option<i64>, wall_clock: i64) -> Received<Timed> {
+        Received {
+            event: Timed(event_time),
+            stream: stream.map(String::from),
+            receipt: Receipt {
+                wall_clock,
+                ..Receipt::now(1)
+            },
+        }
+    }
+
+    #[test]
+    fn test_combined_stream() {
+        assert_eq!(
+            combined_stream(r#"{"stream":"btcusdt@aggTrade","data":{}}"#),
+            Some("btcusdt@aggTrade")
+        );
+        assert_eq!(combined_stream(r#"{"e":"aggTrade"}"#), None);
+        assert_eq!(combined_stream(r#"{"stream":"btc"#), None);
+    }
+
+    #[test]
+    fn test_tracker() {
+        let time_sync = Arc::new(TimeSync::default());
+        // The server clock is 100ms ahead of the local clock.
+        time_sync.update(1000, 1150, 1100);
+        let tracker = LatencyTracker::new(time_sync).with_window(100);
+
+        assert_eq!(
+            tracker.record(&received(Some("a@trade"), Some(2000), 1950)),
+            Some(50)
+        );
+        assert_eq!(tracker.record(&received(None, Some(2000), 1950)), None);
+        assert_eq!(tracker.record(&received(Some("a@trade"), None, 1950)), None);
+
+        tracker.reset();
+        for latency in 1..=200 {
+            tracker.record_latency("a@trade", latency);
+        }
+        tracker.record_latency("b@trade", -3);
+
+        // Only the last 100 are kept.
+        assert_eq!(
+            tracker.stats("a@trade"),
+            Some(LatencyStats {
+                count: 200,
+                min: 101,
+                p50: 150,
+                p90: 190,
+                p99: 199,
+                max: 200,
+            })
+        );
+        assert_eq!(tracker.percentile("a@trade", 0.0), Some(101));
+        assert_eq!(tracker.percentile("a@trade", 100.0), Some(200));
+        assert_eq!(tracker.percentile("c@trade", 50.0), None);
+        assert_eq!(tracker.all_stats()["b@trade"].p99, -3);
+    }
+}
@@ -0,0 +1,275 @@
+// SPDX-License-Identifier: MIT
+
+//! Management of the listen key of a user data stream.
+//!
+//! A listen key expires 60 minutes after it was created or last kept alive.
+//! `ListenKeyManager` creates the key, keeps it alive, replaces it when it
+//! expires or a keepalive fails, and publishes the current key so the user
+//! data stream can be reconnected with it.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::sync::{watch, Notify};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{info, warn};
+
+use crate::common::client::Client;
+use crate::error::Error;
+use crate::parsers::parse_u64_or_string;
+
+/// How often listen keys are kept alive unless configured otherwise.
+pub const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+const MIN_RENEW_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RENEW_BACKOFF: Duration = Duration::from_secs(60);
+
+pub struct ListenKeyManager {
+    client: Client,
+    endpoint: String,
+    keepalive_interval: Duration,
+    sender: watch::Sender<Option<String>>,
+    expired: Notify,
+    shutdown: Notify,
+}
+
+impl ListenKeyManager {
+    /// Create a manager for the listen key endpoint of a market. See
+    /// `listen_key_manager` on the spot and futures clients.
+    pub fn new<S: Into<String>>(client: Client, endpoint: S) -> Self {
+        Self {
+            client,
+            endpoint: endpoint.into(),
+            keepalive_interval: DEFAULT_KEEPALIVE_INTERVAL,
+            sender: watch::channel(None).0,
+            expired: Notify::new(),
+            shutdown: Notify::new(),
+        }
+    }
+
+    pub fn with_keepalive_interval(mut self, keepalive_interval: Duration) -> Self {
+        self.keepalive_interval = keepalive_interval;
+        self
+    }
+
+    /// The current listen key, `None` before the first is created and after
+    /// the stream is closed.
+    pub fn listen_key(&self) -> Option<String> {
+        self.sender.borrow().clone()
+    }
+
+    /// A receiver that sees each new listen key.
+    pub fn subscribe(&self) -> watch::Receiver<Option<String>> {
+        self.sender.subscribe()
+    }
+
+    /// Create a listen key and publish it.
+    pub async fn create(&self) -> Result<String, Error> {
+        let response = self.client.post_listenkey(&self.endpoint).await?;
+        info!("Created listen key");
+        self.sender.send_replace(Some(response.listen_key.clone()));
+        Ok(response.listen_key)
+    }
+
+    /// Keep the current listen key alive, creating one if there is none.
+    pub async fn keepalive(&self) -> Result<(), Error> {
+        match self.listen_key() {
+            Some(listen_key) => self.client.put_listenkey(&self.endpoint, &listen_key).await,
+            None => self.create().await.map(|_| ()),
+        }
+    }
+
+    /// Replace the listen key, retrying with backoff until a key is created
+    /// or the manager is closed.
+    pub async fn renew(&self) -> Option<String> {
+        let mut backoff = MIN_RENEW_BACKOFF;
+        loop {
+            match self.create().await {
+                Ok(listen_key) => return Some(listen_key),
+                Err(err) => warn!(
+                    "Failed to create listen key, retrying in {:?}: {}",
+                    backoff, err
+                ),
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(backoff) => {}
+                _ = self.shutdown.notified() => return None,
+            }
+            backoff = (backoff * 2).min(MAX_RENEW_BACKOFF);
+        }
+    }
+
+    /// Signal that the server expired the listen key. The running manager
+    /// replaces it.
+    pub fn notify_expired(&self) {
+        self.expired.notify_one();
+    }
+
+    /// Check a message from the user data stream for a `listenKeyExpired`
+    /// event, notifying the running manager if found.
+    pub fn check_message(&self, message: &Message) -> bool {
+        let expired = match message {
+            Message::Text(text) => is_listen_key_expired(text),
+            _ => false,
+        };
+        if expired {
+            self.notify_expired();
+        }
+        expired
+    }
+
+    /// Keep the listen key alive until `close` is called, creating a key
+    /// first if there is none. A key that expires or fails to be kept alive
+    /// is replaced.
+    pub async fn run(&self) {
+        if self.listen_key().is_none() && self.renew().await.is_none() {
+            return;
+        }
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(self.keepalive_interval) => {
+                    if let Err(err) = self.keepalive().await {
+                        warn!("Listen key keepalive failed, creating a new key: {}", err);
+                        if self.renew().await.is_none() {
+                            return;
+                        }
+                    }
+                }
+                _ = self.expired.notified() => {
+                    warn!("Listen key expired, creating a new key");
+                    if self.renew().await.is_none() {
+                        return;
+                    }
+                }
+                _ = self.shutdown.notified() => return,
+            }
+        }
+    }
+
+    /// Run the manager on a new task.
+    pub fn spawn(self: &Arc<Self>) -> JoinHandle<()> {
+        let manager = self.clone();
+        tokio::spawn(async move { manager.run().await })
+    }
+
+    /// Stop the running manager and close the user data stream.
+    pub async fn close(&self) -> Result<(), Error> {
+        self.shutdown.notify_one();
+        match self.sender.send_replace(None) {
+            Some(listen_key) => {
+                self.client
+                    .delete_listenkey(&self.endpoint, &listen_key)
+                    .await
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// The `listenKeyExpired` event of a user data stream.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ListenKeyExpired {
+    #[serde(rename = "e")]
+    pub event_type: String,
+    /// Sent as a string by some streams.
+    #[serde(rename = "E", deserialize_with = "parse_u64_or_string")]
+    pub event_time: u64,
+    #[serde(default, rename = "listenKey")]
+    pub listen_key: String,
+}
+
+/// True if the text is a `listenKeyExpired` event, bare or in a combined
+/// stream envelope.
+pub fn is_listen_key_expired(text: &str) -> bool {
+    match serde_json::from_str::<Value>(text) {
+        Ok(value) => value["e"] == "listenKeyExpired" || value["data"]["e"] == "listenKeyExpired",
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::client::Authentication;
+    use crate::common::transport::MockTransport;
+    use reqwest::{Method, StatusCode};
+
+    const LISTEN_KEY: &str = "pqia91ma19a5s61cv6a81va65sdf19v8a65a1a5s61cv6a81va65sdf19v8a65a1";
+
+    fn mock_manager() -> (Arc<ListenKeyManager>, Arc<MockTransport>) {
+        let transport = Arc::new(MockTransport::new());
+        let mut client = Client::new(
+            "https://api.binance.com",
+            Some(Authentication::hmac("key", "secret")),
+        );
+        client.set_transport(transport.clone());
+        let manager = ListenKeyManager::new(client, "/api/v3/userDataStream");
+        (Arc::new(manager), transport)
+    }
+
+    #[test]
+    fn test_is_listen_key_expired() {
+        assert!(is_listen_key_expired(
+            r#"{"e":"listenKeyExpired","E":1576653824250,"listenKey":"OfYGbUzi3PraNagEkdKuFwUHn48brFsItTdsuiIXrucEvD0rhRXZ7I6URWfE8YE8"}"#
+        ));
+        assert!(is_listen_key_expired(
+            r#"{"stream":"x","data":{"e":"listenKeyExpired","E":1576653824250}}"#
+        ));
+        assert!(!is_listen_key_expired(r#"{"e":"executionReport"}"#));
+        assert!(!is_listen_key_expired("ping"));
+    }
+
+    #[tokio::test]
+    async fn test_keepalive_and_close() {
+        let (manager, transport) = mock_manager();
+        transport.push(
+            StatusCode::OK,
+            format!(r#"{{"listenKey":"{}"}}"#, LISTEN_KEY),
+        );
+        transport.push(StatusCode::OK, "{}");
+        transport.push(StatusCode::OK, "{}");
+
+        manager.keepalive().await.unwrap();
+        assert_eq!(manager.listen_key().as_deref(), Some(LISTEN_KEY));
+        manager.keepalive().await.unwrap();
+        manager.close().await.unwrap();
+        assert_eq!(manager.listen_key(), None);
+
+        let requests = transport.requests();
+        assert_eq!(requests[0].method, Method::POST);
+        assert_eq!(requests[0].url.path(), "/api/v3/userDataStream");
+        assert_eq!(requests[0].headers["X-MBX-APIKEY"], "key");
+        assert_eq!(requests[1].method, Method::PUT);
+        assert_eq!(requests[1].query(), format!("listenKey={}", LISTEN_KEY));
+        assert_eq!(requests[2].method, Method::DELETE);
+        assert_eq!(requests[2].query(), format!("listenKey={}", LISTEN_KEY));
+    }
+
+    #[tokio::test]
+    async fn test_renew_on_expired() {
+        let (manager, transport) = mock_manager();
+        transport.push(StatusCode::OK, r#"{"listenKey":"first"}"#);
+        transport.push(StatusCode::OK, r#"{"listenKey":"second"}"#);
+        transport.push(StatusCode::OK, "{}");
+
+        let mut listen_key = manager.subscribe();
+        let task = manager.spawn();
+        listen_key.changed().await.unwrap();
+        assert_eq!(listen_key.borrow_and_update().as_deref(), Some("first"));
+
+        assert!(manager.check_message(&Message::Text(
+            r#"{"e":"listenKeyExpired","E":1576653824250}"#.to_string()
+        )));
+        listen_key.changed().await.unwrap();
+        assert_eq!(listen_key.borrow_and_update().as_deref(), Some("second"));
+
+        manager.close().await.unwrap();
+        task.await.unwrap();
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[2].query(), "listenKey=second");
+    }
+}
@@ -20,6 +20,21 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
 // DEALINGS IN THE SOFTWARE.
 
+pub mod cassette;
 pub mod client;
+pub mod environment;
+pub mod latency;
+pub mod listenkey;
+pub mod pool;
+pub mod ratelimit;
+pub mod reconnect;
+pub mod retry;
+pub mod scheduler;
+pub mod signer;
 pub mod stream;
+pub mod stream_name;
+pub mod time;
+pub mod transport;
+pub(crate) mod userdata;
 pub mod websocket;
+pub mod ws_api;
@@ -0,0 +1,457 @@
+// SPDX-License-Identifier: MIT
+
+//! Sharding of a large set of streams across connections.
+//!
+//! A connection carries at most 1024 streams and may be sent only a few
+//! control messages per second. `ConnectionPool` assigns streams to
+//! reconnecting connections, opening new ones as they fill, paces the
+//! SUBSCRIBE and UNSUBSCRIBE messages sent to each, and merges the messages
+//! of all connections.
+
+use std::collections::{HashSet, VecDeque};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_util::Stream;
+use tokio::sync::{mpsc, watch, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use crate::common::latency::Receipt;
+use crate::common::reconnect::{ReconnectMessage, ReconnectingConnection};
+use crate::common::retry::RetryPolicy;
+use crate::common::websocket::{Keepalive, Outcome, Writer};
+use crate::error::Error;
+
+/// The most streams Binance allows on one connection.
+pub const MAX_STREAMS_PER_CONNECTION: usize = 1024;
+
+/// Messages buffered from all connections before reading pauses.
+const EVENT_BUFFER: usize = 4096;
+
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    /// Streams per connection.
+    pub max_streams: usize,
+    /// Control messages sent per second on each connection.
+    pub messages_per_second: u32,
+    pub reconnect_policy: Option<RetryPolicy>,
+    pub keepalive: Keepalive,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            max_streams: MAX_STREAMS_PER_CONNECTION,
+            messages_per_second: 5,
+            reconnect_policy: None,
+            keepalive: Keepalive::default(),
+        }
+    }
+}
+
+/// A message from one of the connections of a pool.
+#[derive(Debug)]
+pub struct PoolMessage {
+    /// The index of the connection, in the order they were opened.
+    pub connection: usize,
+    pub message: ReconnectMessage,
+    /// Taken as the message is read, before it waits in the pool's channel.
+    pub receipt: Receipt,
+}
+
+struct Shard {
+    streams: Vec<String>,
+    writer: Writer,
+    /// True while a request waits for its reply, see `forward`.
+    awaiting_reply: watch::Sender<bool>,
+    next_send: Instant,
+    task: JoinHandle<()>,
+}
+
+impl Shard {
+    async fn subscribe(&self, streams: &[String]) -> Result<Outcome, Error> {
+        let _awaiting = AwaitingReply::new(&self.awaiting_reply);
+        self.writer.subscribe(streams).await
+    }
+
+    async fn unsubscribe(&self, streams: &[String]) -> Result<Outcome, Error> {
+        let _awaiting = AwaitingReply::new(&self.awaiting_reply);
+        self.writer.unsubscribe(streams).await
+    }
+}
+
+/// Marks a shard as waiting for a reply until dropped, also when the
+/// request is cancelled.
+struct AwaitingReply<'a>(&'a watch::Sender<bool>);
+
+impl<'a> AwaitingReply<'a> {
+    fn new(awaiting_reply: &'a watch::Sender<bool>) -> Self {
+        let _ = awaiting_reply.send(true);
+        Self(awaiting_reply)
+    }
+}
+
+impl Drop for AwaitingReply<'_> {
+    fn drop(&mut self) {
+        let _ = self.0.send(false);
+    }
+}
+
+struct Shards {
+    base_url: String,
+    config: PoolConfig,
+    shards: Vec<Shard>,
+    sender: mpsc::Sender<PoolMessage>,
+}
+
+impl Drop for Shards {
+    fn drop(&mut self) {
+        for shard in &self.shards {
+            shard.task.abort();
+        }
+    }
+}
+
+pub struct ConnectionPool {
+    writer: PoolWriter,
+    receiver: mpsc::Receiver<PoolMessage>,
+}
+
+impl ConnectionPool {
+    pub fn new<S: Into<String>>(base_url: S, config: PoolConfig) -> Self {
+        let (sender, receiver) = mpsc::channel(EVENT_BUFFER);
+        let shards = Shards {
+            base_url: base_url.into(),
+            config,
+            shards: vec![],
+            sender,
+        };
+        Self {
+            writer: PoolWriter {
+                shards: Arc::new(Mutex::new(shards)),
+            },
+            receiver,
+        }
+    }
+
+    /// All subscribed streams.
+    pub async fn streams(&self) -> Vec<String> {
+        self.writer.streams().await
+    }
+
+    /// The number of connections opened.
+    pub async fn connections(&self) -> usize {
+        self.writer.connections().await
+    }
+
+    /// See `PoolWriter::subscribe`.
+    pub async fn subscribe<T: AsRef<str>>(&mut self, streams: &[T]) -> Result<(), Error> {
+        self.writer.subscribe(streams).await
+    }
+
+    /// See `PoolWriter::unsubscribe`.
+    pub async fn unsubscribe<T: AsRef<str>>(&mut self, streams: &[T]) -> Result<(), Error> {
+        self.writer.unsubscribe(streams).await
+    }
+
+    /// The write half, for subscribing and unsubscribing while another task
+    /// reads the pool.
+    pub fn writer(&self) -> PoolWriter {
+        self.writer.clone()
+    }
+
+    /// The next message from any connection.
+    pub async fn next(&mut self) -> Option<PoolMessage> {
+        self.receiver.recv().await
+    }
+}
+
+impl Stream for ConnectionPool {
+    type Item = PoolMessage;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+/// Subscribes and unsubscribes the streams of a `ConnectionPool`.
+/// Subscribing waits for the replies, which are read even while the pool
+/// is not, so the pool may be read from the same task after.
+#[derive(Clone)]
+pub struct PoolWriter {
+    shards: Arc<Mutex<Shards>>,
+}
+
+impl PoolWriter {
+    pub async fn streams(&self) -> Vec<String> {
+        let shards = self.shards.lock().await;
+        shards
+            .shards
+            .iter()
+            .flat_map(|shard| shard.streams.iter().cloned())
+            .collect()
+    }
+
+    pub async fn connections(&self) -> usize {
+        self.shards.lock().await.shards.len()
+    }
+
+    /// Subscribe to streams, filling the open connections first and opening
+    /// new ones for the rest. Streams already subscribed to are skipped.
+    pub async fn subscribe<T: AsRef<str>>(&self, streams: &[T]) -> Result<(), Error> {
+        let mut shards = self.shards.lock().await;
+        let mut seen = HashSet::new();
+        let mut streams: Vec<String> = streams
+            .iter()
+            .map(|stream| stream.as_ref().to_string())
+            .filter(|stream| {
+                !shards
+                    .shards
+                    .iter()
+                    .any(|shard| shard.streams.contains(stream))
+            })
+            .filter(|stream| seen.insert(stream.clone()))
+            .collect();
+        let max_streams = shards.config.max_streams.max(1);
+
+        for i in 0..shards.shards.len() {
+            let free = max_streams.saturating_sub(shards.shards[i].streams.len());
+            if free == 0 || streams.is_empty() {
+                continue;
+            }
+            let batch: Vec<String> = streams.drain(..free.min(streams.len())).collect();
+            shards.pace(i).await;
+            shards.shards[i].subscribe(&batch).await?;
+            shards.shards[i].streams.extend(batch);
+        }
+
+        while !streams.is_empty() {
+            let batch: Vec<String> = streams.drain(..max_streams.min(streams.len())).collect();
+            shards.open(batch).await?;
+        }
+        Ok(())
+    }
+
+    /// Unsubscribe from streams. Connections left without streams stay
+    /// open for later subscriptions.
+    pub async fn unsubscribe<T: AsRef<str>>(&self, streams: &[T]) -> Result<(), Error> {
+        let mut shards = self.shards.lock().await;
+        for i in 0..shards.shards.len() {
+            let batch: Vec<String> = shards.shards[i]
+                .streams
+                .iter()
+                .filter(|s| streams.iter().any(|stream| stream.as_ref() == s.as_str()))
+                .cloned()
+                .collect();
+            if batch.is_empty() {
+                continue;
+            }
+            shards.pace(i).await;
+            shards.shards[i].unsubscribe(&batch).await?;
+            shards.shards[i].streams.retain(|s| !batch.contains(s));
+        }
+        Ok(())
+    }
+}
+
+impl Shards {
+    /// Wait until a control message may be sent on a connection.
+    async fn pace(&mut self, shard: usize) {
+        let interval = Duration::from_secs(1) / self.config.messages_per_second.max(1);
+        let shard = &mut self.shards[shard];
+        tokio::time::sleep_until(shard.next_send).await;
+        shard.next_send = Instant::now() + interval;
+    }
+
+    async fn open(&mut self, streams: Vec<String>) -> Result<(), Error> {
+        let mut connection =
+            ReconnectingConnection::connect(self.base_url.clone(), streams.clone()).await?;
+        connection.set_keepalive(self.config.keepalive.clone());
+        if let Some(policy) = &self.config.reconnect_policy {
+            connection.set_reconnect_policy(policy.clone());
+        }
+        let writer = connection.writer();
+        let (awaiting_reply, awaiting) = watch::channel(false);
+        let task = tokio::spawn(forward(
+            self.shards.len(),
+            connection,
+            self.sender.clone(),
+            awaiting,
+        ));
+        self.shards.push(Shard {
+            streams,
+            writer,
+            awaiting_reply,
+            next_send: Instant::now(),
+            task,
+        });
+        Ok(())
+    }
+}
+
+/// Read a connection into the merged channel until the pool is dropped.
+///
+/// Replies are read along with the messages, so while a request waits for
+/// its reply the connection is read even if the channel is full, holding
+/// the messages here. Otherwise a subscribe made while the pool is not
+/// being read would never complete.
+async fn forward(
+    index: usize,
+    mut connection: ReconnectingConnection,
+    sender: mpsc::Sender<PoolMessage>,
+    mut awaiting_reply: watch::Receiver<bool>,
+) {
+    let mut held = VecDeque::new();
+    loop {
+        let read = held.is_empty() || *awaiting_reply.borrow();
+        tokio::select! {
+            permit = sender.reserve(), if !held.is_empty() => match permit {
+                Ok(permit) => permit.send(held.pop_front().unwrap()),
+                Err(_) => return,
+            },
+            message = connection.next(), if read => held.push_back(PoolMessage {
+                connection: index,
+                message,
+                receipt: connection.receipt(),
+            }),
+            changed = awaiting_reply.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::Message;
+
+    /// Accept connections, sending each its request URI and `flood`
+    /// numbered messages, and then answering every request, followed by a
+    /// message with the method.
+    #[allow(clippy::result_large_err)]
+    async fn serve(flood: usize) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut uri = String::new();
+                    let mut ws = tokio_tungstenite::accept_hdr_async(
+                        stream,
+                        |request: &tokio_tungstenite::tungstenite::handshake::server::Request,
+                         response| {
+                            uri = request.uri().to_string();
+                            Ok(response)
+                        },
+                    )
+                    .await
+                    .unwrap();
+                    ws.send(Message::Text(uri)).await.unwrap();
+                    for i in 0..flood {
+                        ws.send(Message::Text(i.to_string())).await.unwrap();
+                    }
+                    while let Some(Ok(Message::Text(text))) = ws.next().await {
+                        let request: serde_json::Value = serde_json::from_str(&text).unwrap();
+                        let reply = serde_json::json!({"result": null, "id": request["id"]});
+                        ws.send(Message::Text(reply.to_string())).await.unwrap();
+                        let method = request["method"].as_str().unwrap().to_string();
+                        ws.send(Message::Text(method)).await.unwrap();
+                    }
+                });
+            }
+        });
+        url
+    }
+
+    async fn next_text(pool: &mut ConnectionPool) -> (usize, String) {
+        loop {
+            let message = pool.next().await.unwrap();
+            if let ReconnectMessage::Message(Message::Text(text)) = message.message {
+                return (message.connection, text);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sharding() {
+        let url = serve(0).await;
+        let config = PoolConfig {
+            max_streams: 2,
+            messages_per_second: 100,
+            ..Default::default()
+        };
+        let mut pool = ConnectionPool::new(url, config);
+        pool.subscribe(&["a@trade", "b@trade", "c@trade"])
+            .await
+            .unwrap();
+        assert_eq!(pool.connections().await, 2);
+
+        let mut uris = vec![next_text(&mut pool).await, next_text(&mut pool).await];
+        uris.sort();
+        assert_eq!(
+            uris,
+            [
+                (0, "/stream?streams=a@trade/b@trade".to_string()),
+                (1, "/stream?streams=c@trade".to_string())
+            ]
+        );
+
+        // Duplicates are dropped wherever they are in the list.
+        pool.subscribe(&["a@trade", "d@trade", "e@trade", "d@trade"])
+            .await
+            .unwrap();
+        assert_eq!(pool.connections().await, 3);
+        assert_eq!(
+            pool.streams().await,
+            ["a@trade", "b@trade", "c@trade", "d@trade", "e@trade"]
+        );
+        let mut texts = vec![next_text(&mut pool).await, next_text(&mut pool).await];
+        texts.sort();
+        assert_eq!(
+            texts,
+            [
+                (1, "SUBSCRIBE".to_string()),
+                (2, "/stream?streams=e@trade".to_string())
+            ]
+        );
+
+        pool.unsubscribe(&["b@trade", "e@trade"]).await.unwrap();
+        assert_eq!(pool.streams().await, ["a@trade", "c@trade", "d@trade"]);
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_with_full_channel() {
+        let url = serve(EVENT_BUFFER + 100).await;
+        let config = PoolConfig {
+            messages_per_second: 100,
+            ..Default::default()
+        };
+        let mut pool = ConnectionPool::new(url, config);
+        pool.subscribe(&["a@trade"]).await.unwrap();
+        while pool.writer.shards.lock().await.sender.capacity() > 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // The pool is not read while subscribing.
+        tokio::time::timeout(Duration::from_secs(5), pool.subscribe(&["b@trade"]))
+            .await
+            .unwrap()
+            .unwrap();
+
+        // No message was lost or reordered.
+        assert_eq!(next_text(&mut pool).await.1, "/stream?streams=a@trade");
+        for i in 0..EVENT_BUFFER + 100 {
+            assert_eq!(next_text(&mut pool).await.1, i.to_string());
+        }
+        assert_eq!(next_text(&mut pool).await.1, "SUBSCRIBE");
+    }
+}
@@ -0,0 +1,301 @@
+// SPDX-License-Identifier: MIT
+
+//! Tracking of the request weight and order count rate limits.
+//!
+//! Binance reports the current usage of each limit in the response headers
+//! (`X-MBX-USED-WEIGHT-1M`, `X-MBX-ORDER-COUNT-10S`, ...) and advertises the
+//! limits themselves in the `rateLimits` of the exchange info.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use reqwest::header::HeaderMap;
+use serde::Deserialize;
+
+use crate::common::time::now_millis;
+
+const USED_WEIGHT_PREFIX: &str = "x-mbx-used-weight-";
+const ORDER_COUNT_PREFIX: &str = "x-mbx-order-count-";
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum RateLimitType {
+    RequestWeight,
+    Orders,
+    RawRequests,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum RateLimitInterval {
+    Second,
+    Minute,
+    Hour,
+    Day,
+    /// An interval added by Binance after this crate. Its window length is
+    /// not known so its usage never resets and it is not paced.
+    #[serde(other)]
+    Unknown,
+}
+
+impl RateLimitInterval {
+    /// Parse the single letter interval used in the headers.
+    pub fn from_letter(letter: &str) -> Option<Self> {
+        match letter.to_ascii_lowercase().as_str() {
+            "s" => Some(Self::Second),
+            "m" => Some(Self::Minute),
+            "h" => Some(Self::Hour),
+            "d" => Some(Self::Day),
+            _ => None,
+        }
+    }
+
+    pub fn to_millis(&self) -> u64 {
+        match self {
+            Self::Second => 1000,
+            Self::Minute => 60 * 1000,
+            Self::Hour => 60 * 60 * 1000,
+            Self::Day => 24 * 60 * 60 * 1000,
+            Self::Unknown => 0,
+        }
+    }
+}
+
+/// A rate limit as advertised in the exchange info.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct RateLimit {
+    #[serde(rename = "rateLimitType")]
+    pub rate_limit_type: RateLimitType,
+    pub interval: RateLimitInterval,
+    #[serde(rename = "intervalNum")]
+    pub interval_num: u32,
+    pub limit: u64,
+}
+
+impl RateLimit {
+    pub fn key(&self) -> RateLimitKey {
+        RateLimitKey {
+            rate_limit_type: self.rate_limit_type.clone(),
+            interval: self.interval,
+            interval_num: self.interval_num,
+        }
+    }
+}
+
+/// A rate limit with its current usage, as returned in the `rateLimits` of
+/// WebSocket API responses.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct RateLimitCount {
+    #[serde(flatten)]
+    pub limit: RateLimit,
+    pub count: u64,
+}
+
+/// Identifies a limit window, for example request weight per 1 minute.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RateLimitKey {
+    pub rate_limit_type: RateLimitType,
+    pub interval: RateLimitInterval,
+    pub interval_num: u32,
+}
+
+impl RateLimitKey {
+    /// Parse a header name such as `x-mbx-used-weight-1m`.
+    pub fn from_header(name: &str) -> Option<Self> {
+        let name = name.to_ascii_lowercase();
+        let (rate_limit_type, suffix) = if let Some(suffix) = name.strip_prefix(USED_WEIGHT_PREFIX)
+        {
+            (RateLimitType::RequestWeight, suffix)
+        } else if let Some(suffix) = name.strip_prefix(ORDER_COUNT_PREFIX) {
+            (RateLimitType::Orders, suffix)
+        } else {
+            return None;
+        };
+        if suffix.len() < 2 {
+            return None;
+        }
+        let (num, letter) = suffix.split_at(suffix.len() - 1);
+        Some(Self {
+            rate_limit_type,
+            interval: RateLimitInterval::from_letter(letter)?,
+            interval_num: num.parse().ok()?,
+        })
+    }
+
+    pub fn window_millis(&self) -> u64 {
+        self.interval.to_millis() * self.interval_num as u64
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Used {
+    count: u64,
+    // Wall clock time in milliseconds the count was reported.
+    updated: i64,
+}
+
+/// How much of an advertised limit is currently used.
+#[derive(Debug, Clone)]
+pub struct RateLimitUsage {
+    pub limit: RateLimit,
+    /// The last reported usage in the current window, `None` if no response
+    /// has reported this limit.
+    pub used: Option<u64>,
+}
+
+impl RateLimitUsage {
+    pub fn remaining(&self) -> u64 {
+        self.limit.limit.saturating_sub(self.used.unwrap_or(0))
+    }
+
+    /// Fraction of the limit used, 1.0 or more means further requests will be
+    /// rejected.
+    pub fn ratio(&self) -> f64 {
+        if self.limit.limit == 0 {
+            return 0.0;
+        }
+        self.used.unwrap_or(0) as f64 / self.limit.limit as f64
+    }
+}
+
+/// Rate limit usage shared by all clones of a client.
+#[derive(Debug, Default)]
+pub struct RateLimitState {
+    used: Mutex<HashMap<RateLimitKey, Used>>,
+    limits: Mutex<Vec<RateLimit>>,
+}
+
+impl RateLimitState {
+    /// Record the usage headers from a response.
+    pub fn record_headers(&self, headers: &HeaderMap) {
+        let now = now_millis();
+        let mut used = self.used.lock().unwrap();
+        for (name, value) in headers {
+            if let Some(key) = RateLimitKey::from_header(name.as_str()) {
+                if let Some(count) = value.to_str().ok().and_then(|v| v.parse().ok()) {
+                    used.insert(
+                        key,
+                        Used {
+                            count,
+                            updated: now,
+                        },
+                    );
+                }
+            }
+        }
+    }
+
+    /// Record the usage reported in a WebSocket API response.
+    pub fn record_counts(&self, counts: &[RateLimitCount]) {
+        let now = now_millis();
+        let mut used = self.used.lock().unwrap();
+        for count in counts {
+            used.insert(
+                count.limit.key(),
+                Used {
+                    count: count.count,
+                    updated: now,
+                },
+            );
+        }
+    }
+
+    /// The last reported usage for a limit window. Usage reported in an
+    /// earlier window than the current one has since been reset and is
+    /// returned as 0.
+    pub fn used(&self, key: &RateLimitKey) -> Option<u64> {
+        let used = *self.used.lock().unwrap().get(key)?;
+        let window = key.window_millis() as i64;
+        if window > 0 && used.updated / window != now_millis() / window {
+            return Some(0);
+        }
+        Some(used.count)
+    }
+
+    /// All reported usage, keyed by limit window.
+    pub fn all_used(&self) -> HashMap<RateLimitKey, u64> {
+        let keys: Vec<RateLimitKey> = self.used.lock().unwrap().keys().cloned().collect();
+        keys.into_iter()
+            .filter_map(|key| self.used(&key).map(|used| (key, used)))
+            .collect()
+    }
+
+    /// Set the limits, usually from the `rateLimits` of the exchange info.
+    pub fn set_limits(&self, limits: Vec<RateLimit>) {
+        *self.limits.lock().unwrap() = limits;
+    }
+
+    pub fn limits(&self) -> Vec<RateLimit> {
+        self.limits.lock().unwrap().clone()
+    }
+
+    /// The usage of each known limit.
+    pub fn usage(&self) -> Vec<RateLimitUsage> {
+        self.limits()
+            .into_iter()
+            .map(|limit| RateLimitUsage {
+                used: self.used(&limit.key()),
+                limit,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_key_from_header() {
+        let key = RateLimitKey::from_header("X-MBX-ORDER-COUNT-10S").unwrap();
+        assert_eq!(key.rate_limit_type, RateLimitType::Orders);
+        assert_eq!(key.interval, RateLimitInterval::Second);
+        assert_eq!(key.interval_num, 10);
+
+        let key = RateLimitKey::from_header("x-mbx-used-weight-1m").unwrap();
+        assert_eq!(key.rate_limit_type, RateLimitType::RequestWeight);
+        assert_eq!(key.window_millis(), 60000);
+
+        assert!(RateLimitKey::from_header("x-mbx-used-weight").is_none());
+        assert!(RateLimitKey::from_header("content-type").is_none());
+    }
+
+    #[test]
+    fn test_usage() {
+        let limits: Vec<RateLimit> = serde_json::from_str(
+            r#"[
+                {"rateLimitType":"REQUEST_WEIGHT","interval":"DAY","intervalNum":1,"limit":1200000},
+                {"rateLimitType":"ORDERS","interval":"DAY","intervalNum":1,"limit":200000},
+                {"rateLimitType":"RAW_REQUESTS","interval":"DAY","intervalNum":1,"limit":6100}
+            ]"#,
+        )
+        .unwrap();
+
+        let state = RateLimitState::default();
+        state.set_limits(limits);
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-mbx-used-weight-1d", "300000".parse().unwrap());
+        state.record_headers(&headers);
+
+        let usage = state.usage();
+        assert_eq!(usage.len(), 3);
+        assert_eq!(usage[0].used, Some(300000));
+        assert_eq!(usage[0].remaining(), 900000);
+        assert!((usage[0].ratio() - 0.25).abs() < f64::EPSILON);
+        assert_eq!(usage[1].used, None);
+        assert_eq!(usage[2].limit.rate_limit_type, RateLimitType::RawRequests);
+    }
+
+    #[test]
+    fn test_unknown_interval() {
+        let limit: RateLimit = serde_json::from_str(
+            r#"{"rateLimitType":"ORDERS","interval":"WEEK","intervalNum":1,"limit":100}"#,
+        )
+        .unwrap();
+        assert_eq!(limit.interval, RateLimitInterval::Unknown);
+        assert_eq!(limit.key().window_millis(), 0);
+    }
+}
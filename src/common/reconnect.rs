@@ -0,0 +1,654 @@
+// SPDX-License-Identifier: MIT
+
+//! A combined stream connection that reconnects when it drops.
+//!
+//! Binance closes every connection after 24 hours. Before then a
+//! replacement connection to the same streams is opened and both are read
+//! until the replacement delivers a message the old connection did not, so
+//! the scheduled disconnect loses no messages. Messages delivered by both
+//! connections are passed on once.
+
+use std::collections::{HashSet, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_util::future::{poll_fn, BoxFuture};
+use futures_util::{ready, FutureExt, Stream};
+use serde_json::{json, Value};
+use tokio::sync::oneshot;
+use tokio::time::{Instant, Sleep};
+use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite::{self, Message};
+use tracing::{info, warn};
+
+use crate::common::latency::Receipt;
+use crate::common::retry::RetryPolicy;
+use crate::common::websocket::{
+    closed, decode_subscriptions, stream_params, Keepalive, LiveConnection, Outcome, Request,
+    Requests, WebSocketStream, Writer,
+};
+use crate::error::Error;
+
+/// How long after connecting a replacement connection is opened, ahead of
+/// the server closing the connection at 24 hours.
+pub const DEFAULT_ROLLOVER: Duration = Duration::from_secs(23 * 60 * 60);
+
+/// The most messages of the old connection remembered during a rollover to
+/// drop their copies on the replacement.
+const OVERLAP_LIMIT: usize = 4096;
+
+#[derive(Debug)]
+pub enum ReconnectMessage {
+    Message(Message),
+
+    /// The connection dropped, with the reason. Messages are lost until
+    /// `Reconnected`.
+    Disconnected(String),
+
+    /// A new connection to the same streams was established.
+    Reconnected,
+}
+
+pub struct ReconnectingConnection {
+    base_url: String,
+    streams: Vec<String>,
+    ws: Option<LiveConnection>,
+    keepalive: Keepalive,
+    rollover: Duration,
+    connected_at: Instant,
+    rollover_timer: Pin<Box<Sleep>>,
+    reconnect_policy: RetryPolicy,
+    attempt: u32,
+    backoff: Option<Pin<Box<Sleep>>>,
+    connecting: Option<BoxFuture<'static, Result<WebSocketStream, tungstenite::Error>>>,
+    replacing: Option<BoxFuture<'static, Result<WebSocketStream, tungstenite::Error>>>,
+    /// The connection being replaced, read until the replacement catches up.
+    old: Option<LiveConnection>,
+    /// The texts read on the old connection during a rollover.
+    overlap: HashSet<String>,
+    /// Messages read ahead, with their receipts.
+    pending: VecDeque<(ReconnectMessage, Receipt)>,
+    /// The receipt of the last message returned.
+    receipt: Receipt,
+    requests: Requests,
+    unconfirmed: Vec<Unconfirmed>,
+}
+
+/// A subscribe or unsubscribe sent and waiting for the server to accept it.
+struct Unconfirmed {
+    request: Request,
+    result: oneshot::Receiver<Result<Option<Value>, Error>>,
+}
+
+impl ReconnectingConnection {
+    /// Connect to the combined stream of the streams. An error is returned
+    /// only if the first connection fails.
+    pub async fn connect<S: Into<String>>(
+        base_url: S,
+        streams: Vec<String>,
+    ) -> Result<Self, tungstenite::Error> {
+        let mut connection = Self {
+            base_url: base_url.into(),
+            streams,
+            ws: None,
+            keepalive: Keepalive::default(),
+            rollover: DEFAULT_ROLLOVER,
+            connected_at: Instant::now(),
+            rollover_timer: Box::pin(tokio::time::sleep(DEFAULT_ROLLOVER)),
+            reconnect_policy: RetryPolicy::default()
+                .backoff(Duration::from_millis(500), Duration::from_secs(30)),
+            attempt: 0,
+            backoff: None,
+            connecting: None,
+            replacing: None,
+            old: None,
+            overlap: HashSet::new(),
+            pending: VecDeque::new(),
+            receipt: Receipt::now(0),
+            requests: Requests::new(),
+            unconfirmed: vec![],
+        };
+        let ws = connection.open().await?;
+        connection.ws = Some(connection.connected(ws));
+        Ok(connection)
+    }
+
+    /// Set how long after connecting the connection is replaced.
+    pub fn set_rollover(&mut self, rollover: Duration) {
+        self.rollover = rollover;
+        self.rollover_timer
+            .as_mut()
+            .reset(self.connected_at + rollover);
+    }
+
+    pub fn set_reconnect_policy(&mut self, reconnect_policy: RetryPolicy) {
+        self.reconnect_policy = reconnect_policy;
+    }
+
+    /// Set the keepalive of this and future connections.
+    pub fn set_keepalive(&mut self, keepalive: Keepalive) {
+        if let Some(ws) = self.ws.as_mut() {
+            ws.set_keepalive(keepalive.clone());
+        }
+        self.keepalive = keepalive;
+    }
+
+    /// Subscribe to more streams on the current connection, and on every
+    /// reconnect. While disconnected the streams are only remembered and the
+    /// outcome is `Deferred`.
+    pub async fn subscribe<T: AsRef<str>>(&mut self, streams: &[T]) -> Result<Outcome, Error> {
+        self.request("SUBSCRIBE", stream_params(streams))
+            .await
+            .map(Outcome::of)
+    }
+
+    /// Unsubscribe from streams on the current connection and future ones.
+    pub async fn unsubscribe<T: AsRef<str>>(&mut self, streams: &[T]) -> Result<Outcome, Error> {
+        self.request("UNSUBSCRIBE", stream_params(streams))
+            .await
+            .map(Outcome::of)
+    }
+
+    /// The streams the current connection is subscribed to, as reported by
+    /// the server.
+    pub async fn list_subscriptions(&mut self) -> Result<Vec<String>, Error> {
+        let result = self.request("LIST_SUBSCRIPTIONS", json!([])).await?;
+        decode_subscriptions(result.unwrap_or_default())
+    }
+
+    /// The write half, for subscribing and unsubscribing while another task
+    /// reads. Streams subscribed to through it are also remembered for
+    /// reconnects.
+    pub fn writer(&self) -> Writer {
+        self.requests.writer()
+    }
+
+    /// Send a request and wait for its reply, queueing the messages read in
+    /// the meantime.
+    async fn request(
+        &mut self,
+        method: &'static str,
+        params: Value,
+    ) -> Result<Option<Value>, Error> {
+        let mut result = self.requests.push(method, params);
+        loop {
+            tokio::select! {
+                biased;
+                reply = &mut result => return reply.unwrap_or_else(|_| Err(closed())),
+                message = poll_fn(|cx| self.poll_live(cx)) => self.pending.push_back(message),
+            }
+        }
+    }
+
+    /// Send a request. The streams of a subscribe or unsubscribe are
+    /// remembered once the server accepts it, or at once while
+    /// disconnected, when the reply is `None` for `Outcome::Deferred`.
+    fn handle_request(&mut self, request: Request) {
+        let ws = match self.ws.as_mut() {
+            Some(ws) => ws,
+            None if request.method == "LIST_SUBSCRIPTIONS" => {
+                let _ = request
+                    .reply
+                    .send(Err(tungstenite::Error::AlreadyClosed.into()));
+                return;
+            }
+            None => {
+                self.record_streams(request.method, &request.params);
+                let _ = request.reply.send(Ok(None));
+                return;
+            }
+        };
+        if request.method == "LIST_SUBSCRIPTIONS" {
+            ws.queue_request(request);
+            return;
+        }
+        let (reply, result) = oneshot::channel();
+        ws.queue_request(Request {
+            method: request.method,
+            params: request.params.clone(),
+            reply,
+        });
+        self.unconfirmed.push(Unconfirmed { request, result });
+    }
+
+    /// Record the streams of the subscribes and unsubscribes the server
+    /// replied to, and pass on the replies.
+    fn poll_unconfirmed(&mut self, cx: &mut Context<'_>) {
+        let mut i = 0;
+        while i < self.unconfirmed.len() {
+            let result = match self.unconfirmed[i].result.poll_unpin(cx) {
+                Poll::Ready(result) => result.unwrap_or_else(|_| Err(closed())),
+                Poll::Pending => {
+                    i += 1;
+                    continue;
+                }
+            };
+            let Unconfirmed { request, .. } = self.unconfirmed.remove(i);
+            if result.is_ok() {
+                self.record_streams(request.method, &request.params);
+            }
+            let _ = request.reply.send(result);
+        }
+    }
+
+    fn record_streams(&mut self, method: &str, params: &Value) {
+        let streams: Vec<String> = match params.as_array() {
+            Some(params) => params
+                .iter()
+                .filter_map(|stream| stream.as_str().map(String::from))
+                .collect(),
+            None => vec![],
+        };
+        match method {
+            "SUBSCRIBE" => {
+                for stream in streams {
+                    if !self.streams.contains(&stream) {
+                        self.streams.push(stream);
+                    }
+                }
+            }
+            "UNSUBSCRIBE" => self.streams.retain(|s| !streams.contains(s)),
+            _ => {}
+        }
+    }
+
+    pub fn streams(&self) -> &[String] {
+        &self.streams
+    }
+
+    /// The URL of the combined stream.
+    pub fn url(&self) -> String {
+        format!(
+            "{}/stream?streams={}",
+            self.base_url,
+            self.streams.join("/")
+        )
+    }
+
+    fn open(&self) -> BoxFuture<'static, Result<WebSocketStream, tungstenite::Error>> {
+        let url = self.url();
+        async move { connect_async(url).await.map(|(ws, _response)| ws) }.boxed()
+    }
+
+    /// When and on which connection the last message was read. Connection
+    /// state messages are stamped as they happen.
+    pub fn receipt(&self) -> Receipt {
+        self.receipt
+    }
+
+    /// The id of the connection the last message was read on, see
+    /// `Receipt::connection_id`.
+    pub fn connection_id(&self) -> u64 {
+        self.receipt.connection_id
+    }
+
+    fn connected(&mut self, ws: WebSocketStream) -> LiveConnection {
+        self.connected_at = Instant::now();
+        self.rollover_timer
+            .as_mut()
+            .reset(self.connected_at + self.rollover);
+        LiveConnection::new(ws, self.keepalive.clone())
+    }
+
+    pub async fn next(&mut self) -> ReconnectMessage {
+        poll_fn(|cx| self.poll_message(cx)).await
+    }
+
+    fn poll_message(&mut self, cx: &mut Context<'_>) -> Poll<ReconnectMessage> {
+        let (message, receipt) = match self.pending.pop_front() {
+            Some(pending) => pending,
+            None => ready!(self.poll_live(cx)),
+        };
+        self.receipt = receipt;
+        Poll::Ready(message)
+    }
+
+    /// Send the queued requests and read the current connection,
+    /// reconnecting and replacing it as needed.
+    fn poll_live(&mut self, cx: &mut Context<'_>) -> Poll<(ReconnectMessage, Receipt)> {
+        loop {
+            while let Poll::Ready(Some(request)) = self.requests.poll_next(cx) {
+                self.handle_request(request);
+            }
+            self.poll_unconfirmed(cx);
+            if self.ws.is_none() {
+                if let Some(backoff) = self.backoff.as_mut() {
+                    ready!(backoff.as_mut().poll(cx));
+                    self.backoff = None;
+                }
+                if self.connecting.is_none() {
+                    self.connecting = Some(self.open());
+                }
+                let result = ready!(self.connecting.as_mut().unwrap().poll_unpin(cx));
+                self.connecting = None;
+                match result {
+                    Ok(ws) => {
+                        let ws = self.connected(ws);
+                        let receipt = Receipt::now(ws.id());
+                        self.ws = Some(ws);
+                        self.attempt = 0;
+                        return Poll::Ready((ReconnectMessage::Reconnected, receipt));
+                    }
+                    Err(err) => {
+                        warn!("Failed to reconnect to {}: {}", self.url(), err);
+                        self.attempt += 1;
+                        let backoff = self.reconnect_policy.backoff_for(self.attempt);
+                        self.backoff = Some(Box::pin(tokio::time::sleep(backoff)));
+                        continue;
+                    }
+                }
+            }
+            match self.replacing.as_mut() {
+                Some(replacing) => {
+                    if let Poll::Ready(result) = replacing.poll_unpin(cx) {
+                        self.replacing = None;
+                        self.replace(result);
+                        continue;
+                    }
+                }
+                None => {
+                    if self.rollover_timer.as_mut().poll(cx).is_ready() {
+                        self.replacing = Some(self.open());
+                        continue;
+                    }
+                }
+            }
+            if let Some(old) = self.old.as_mut() {
+                match old.poll_next(cx) {
+                    Poll::Ready(Some(Ok((message, receipt)))) => {
+                        if let Message::Text(text) = &message {
+                            if self.overlap.len() < OVERLAP_LIMIT {
+                                self.overlap.insert(text.clone());
+                            }
+                        }
+                        return Poll::Ready((ReconnectMessage::Message(message), receipt));
+                    }
+                    Poll::Ready(_) => self.close_old(),
+                    Poll::Pending => {}
+                }
+            }
+            let ws = self.ws.as_mut().unwrap();
+            let id = ws.id();
+            return match ready!(ws.poll_next(cx)) {
+                Some(Ok((message, receipt))) => {
+                    if let Message::Text(text) = &message {
+                        if !self.overlap.is_empty() && self.overlap.remove(text) {
+                            // Already delivered by the old connection.
+                            continue;
+                        }
+                        // The replacement caught up. Copies may still follow
+                        // the first message after the old connection closed.
+                        if self.old.is_some() {
+                            self.close_old();
+                        } else {
+                            self.overlap.clear();
+                        }
+                    }
+                    Poll::Ready((ReconnectMessage::Message(message), receipt))
+                }
+                Some(Err(err)) if self.old.is_some() => {
+                    warn!("Replacement connection failed: {}", err);
+                    self.fall_back();
+                    continue;
+                }
+                None if self.old.is_some() => {
+                    warn!("Replacement connection closed");
+                    self.fall_back();
+                    continue;
+                }
+                Some(Err(err)) => {
+                    self.ws = None;
+                    Poll::Ready((
+                        ReconnectMessage::Disconnected(err.to_string()),
+                        Receipt::now(id),
+                    ))
+                }
+                None => {
+                    self.ws = None;
+                    Poll::Ready((
+                        ReconnectMessage::Disconnected("connection closed".to_string()),
+                        Receipt::now(id),
+                    ))
+                }
+            };
+        }
+    }
+
+    /// Switch to a new connection, reading the old one until the new one
+    /// catches up.
+    fn replace(&mut self, result: Result<WebSocketStream, tungstenite::Error>) {
+        let ws = match result {
+            Ok(ws) => self.connected(ws),
+            Err(err) => {
+                warn!("Failed to open replacement connection: {}", err);
+                self.retry_rollover();
+                return;
+            }
+        };
+        self.close_old();
+        self.overlap.clear();
+        self.old = self.ws.replace(ws);
+        info!("Replaced connection to {}", self.url());
+    }
+
+    fn close_old(&mut self) {
+        if let Some(mut old) = self.old.take() {
+            tokio::spawn(async move {
+                let _ = old.close().await;
+            });
+        }
+    }
+
+    /// Go back to the old connection after the replacement failed.
+    fn fall_back(&mut self) {
+        self.ws = self.old.take();
+        self.overlap.clear();
+        self.retry_rollover();
+    }
+
+    fn retry_rollover(&mut self) {
+        self.rollover_timer
+            .as_mut()
+            .reset(Instant::now() + self.reconnect_policy.max_backoff);
+    }
+}
+
+impl Stream for ReconnectingConnection {
+    type Item = ReconnectMessage;
+
+    /// Never ends, the connection reconnects instead.
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.poll_message(cx).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    /// Accept connections, sending each the next text and leaving it open
+    /// if `hold` else closing it.
+    #[allow(clippy::result_large_err)]
+    async fn serve(texts: Vec<&'static str>, hold: bool) -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::channel(16);
+        tokio::spawn(async move {
+            let mut open = vec![];
+            for text in texts {
+                let (stream, _) = listener.accept().await.unwrap();
+                let sender = sender.clone();
+                let mut ws =
+                    tokio_tungstenite::accept_hdr_async(stream, |request: &_, response| {
+                        let _ = sender.try_send(request_uri(request));
+                        Ok(response)
+                    })
+                    .await
+                    .unwrap();
+                ws.send(Message::Text(text.to_string())).await.unwrap();
+                if hold {
+                    open.push(ws);
+                } else {
+                    ws.close(None).await.unwrap();
+                }
+            }
+            std::future::pending::<()>().await;
+        });
+        (url, receiver)
+    }
+
+    fn request_uri(request: &tungstenite::handshake::server::Request) -> String {
+        request.uri().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_reconnect() {
+        let (url, mut uris) = serve(vec!["a", "b"], false).await;
+        let streams = vec!["btcusdt@trade".to_string(), "ethusdt@trade".to_string()];
+        let mut connection = ReconnectingConnection::connect(url, streams).await.unwrap();
+        connection
+            .set_reconnect_policy(RetryPolicy::none().backoff(Duration::ZERO, Duration::ZERO));
+
+        assert!(
+            matches!(connection.next().await, ReconnectMessage::Message(Message::Text(text)) if text == "a")
+        );
+        let first_id = connection.connection_id();
+        let mut next = connection.next().await;
+        if let ReconnectMessage::Message(Message::Close(_)) = next {
+            next = connection.next().await;
+        }
+        assert!(matches!(next, ReconnectMessage::Disconnected(_)));
+        assert!(matches!(
+            connection.next().await,
+            ReconnectMessage::Reconnected
+        ));
+        assert!(
+            matches!(connection.next().await, ReconnectMessage::Message(Message::Text(text)) if text == "b")
+        );
+        assert_ne!(connection.connection_id(), first_id);
+
+        for _ in 0..2 {
+            assert_eq!(
+                uris.recv().await.unwrap(),
+                "/stream?streams=btcusdt@trade/ethusdt@trade"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_confirmed() {
+        // Reject requests for bogus streams.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            while let Some(Ok(Message::Text(text))) = ws.next().await {
+                let request: Value = serde_json::from_str(&text).unwrap();
+                let reply = if text.contains("bogus") {
+                    json!({"error": {"code": 2, "msg": "Invalid request"}, "id": request["id"]})
+                } else {
+                    json!({"result": null, "id": request["id"]})
+                };
+                ws.send(Message::Text(reply.to_string())).await.unwrap();
+            }
+        });
+        let mut connection =
+            ReconnectingConnection::connect(url, vec!["btcusdt@trade".to_string()])
+                .await
+                .unwrap();
+
+        assert!(connection.subscribe(&["btcusdt@bogus"]).await.is_err());
+        assert_eq!(
+            connection.subscribe(&["ethusdt@trade"]).await.unwrap(),
+            Outcome::Applied
+        );
+        assert!(connection.unsubscribe(&["ethusdt@bogus"]).await.is_err());
+        assert_eq!(connection.streams(), ["btcusdt@trade", "ethusdt@trade"]);
+
+        // Through the writer the streams are recorded as the replies are
+        // read.
+        let writer = connection.writer();
+        let reader = tokio::spawn(async move {
+            tokio::time::timeout(Duration::from_millis(200), connection.next())
+                .await
+                .ok();
+            connection
+        });
+        writer.unsubscribe(&["btcusdt@trade"]).await.unwrap();
+        assert!(writer.subscribe(&["bnbusdt@bogus"]).await.is_err());
+        assert_eq!(reader.await.unwrap().streams(), ["ethusdt@trade"]);
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_deferred() {
+        // The server closes the only connection it accepts, so the
+        // connection stays disconnected.
+        let (url, _uris) = serve(vec!["a"], false).await;
+        let mut connection =
+            ReconnectingConnection::connect(url, vec!["btcusdt@trade".to_string()])
+                .await
+                .unwrap();
+        loop {
+            if let ReconnectMessage::Disconnected(_) = connection.next().await {
+                break;
+            }
+        }
+
+        assert_eq!(
+            connection.subscribe(&["ethusdt@trade"]).await.unwrap(),
+            Outcome::Deferred
+        );
+        assert_eq!(
+            connection.unsubscribe(&["btcusdt@trade"]).await.unwrap(),
+            Outcome::Deferred
+        );
+        assert!(connection.list_subscriptions().await.is_err());
+        assert_eq!(connection.streams(), ["ethusdt@trade"]);
+    }
+
+    #[tokio::test]
+    async fn test_rollover() {
+        // The first connection sends "a", then "b" and "c" while the
+        // replacement connects. The replacement sends "c", which it also
+        // has, and then "d".
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut first = tokio_tungstenite::accept_async(stream).await.unwrap();
+            first.send(Message::Text("a".to_string())).await.unwrap();
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut second = tokio_tungstenite::accept_async(stream).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            first.send(Message::Text("b".to_string())).await.unwrap();
+            first.send(Message::Text("c".to_string())).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            second.send(Message::Text("c".to_string())).await.unwrap();
+            second.send(Message::Text("d".to_string())).await.unwrap();
+            std::future::pending::<()>().await;
+        });
+        let mut connection =
+            ReconnectingConnection::connect(url, vec!["btcusdt@trade".to_string()])
+                .await
+                .unwrap();
+        connection.set_rollover(Duration::from_millis(100));
+
+        let mut texts = vec![];
+        let read = async {
+            while texts.len() < 4 {
+                if let ReconnectMessage::Message(Message::Text(text)) = connection.next().await {
+                    texts.push(text);
+                }
+            }
+        };
+        let _ = tokio::time::timeout(Duration::from_secs(5), read).await;
+        assert_eq!(texts, ["a", "b", "c", "d"]);
+        assert!(connection.old.is_none());
+    }
+}
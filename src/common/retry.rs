@@ -0,0 +1,179 @@
+// SPDX-License-Identifier: MIT
+
+//! Retrying of requests that failed for transient reasons.
+
+use std::time::Duration;
+
+use rand::Rng;
+
+use crate::common::transport::HttpResponse;
+use crate::error::{ApiError, ApiErrorCode, Error};
+
+/// The classes of failure a request may be retried on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryClass {
+    /// The connection to the server could not be established.
+    Connect,
+    /// The request timed out, or the server responded with error -1007.
+    Timeout,
+    /// The server responded with a 5xx status.
+    ServerError,
+    /// The server responded with error -1001 (disconnected).
+    Disconnected,
+}
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Maximum number of attempts including the first, 1 disables retries.
+    pub max_attempts: u32,
+    /// Backoff before the first retry.
+    pub initial_backoff: Duration,
+    /// Upper bound on the backoff between attempts.
+    pub max_backoff: Duration,
+    /// Factor the backoff grows by after each attempt.
+    pub multiplier: f64,
+    /// Randomise each backoff between half and all of its value.
+    pub jitter: bool,
+    /// The failures that are retried.
+    pub retry_on: Vec<RetryClass>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(5),
+            multiplier: 2.0,
+            jitter: true,
+            retry_on: vec![
+                RetryClass::Connect,
+                RetryClass::Timeout,
+                RetryClass::ServerError,
+                RetryClass::Disconnected,
+            ],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that never retries. This is what clients use unless
+    /// configured otherwise.
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    pub fn backoff(mut self, initial_backoff: Duration, max_backoff: Duration) -> Self {
+        self.initial_backoff = initial_backoff;
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    pub fn retry_on(mut self, retry_on: &[RetryClass]) -> Self {
+        self.retry_on = retry_on.to_vec();
+        self
+    }
+
+    /// The class of failure of a request, `None` if it succeeded or failed
+    /// for a reason that is not transient.
+    pub fn classify(result: &Result<HttpResponse, Error>) -> Option<RetryClass> {
+        match result {
+            Err(Error::Request(err)) if err.is_connect() => Some(RetryClass::Connect),
+            Err(Error::Request(err)) if err.is_timeout() => Some(RetryClass::Timeout),
+            Ok(response) if !response.status.is_success() => {
+                let code = serde_json::from_str::<ApiError>(&response.body)
+                    .map(|e| e.error_code())
+                    .ok();
+                if code == Some(ApiErrorCode::Disconnected) {
+                    Some(RetryClass::Disconnected)
+                } else if code == Some(ApiErrorCode::Timeout) {
+                    Some(RetryClass::Timeout)
+                } else if response.status.is_server_error() {
+                    Some(RetryClass::ServerError)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// True if the result is a failure this policy retries.
+    pub fn should_retry(&self, result: &Result<HttpResponse, Error>) -> bool {
+        match Self::classify(result) {
+            Some(class) => self.retry_on.contains(&class),
+            None => false,
+        }
+    }
+
+    /// The backoff to wait after the given attempt, counting from 1.
+    pub fn backoff_for(&self, attempt: u32) -> Duration {
+        let factor = self.multiplier.powi(attempt.saturating_sub(1) as i32);
+        let backoff = self.initial_backoff.mul_f64(factor).min(self.max_backoff);
+        if self.jitter {
+            backoff.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+        } else {
+            backoff
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use reqwest::StatusCode;
+
+    #[test]
+    fn test_classify() {
+        let disconnected = r#"{"code":-1001,"msg":"Internal error; unable to process your request. Please try again."}"#;
+        let result = Ok(HttpResponse::new(StatusCode::BAD_REQUEST, disconnected));
+        assert_eq!(
+            RetryPolicy::classify(&result),
+            Some(RetryClass::Disconnected)
+        );
+
+        let result = Ok(HttpResponse::new(StatusCode::SERVICE_UNAVAILABLE, ""));
+        assert_eq!(
+            RetryPolicy::classify(&result),
+            Some(RetryClass::ServerError)
+        );
+
+        let rejected =
+            r#"{"code":-2010,"msg":"Account has insufficient balance for requested action."}"#;
+        let result = Ok(HttpResponse::new(StatusCode::BAD_REQUEST, rejected));
+        assert_eq!(RetryPolicy::classify(&result), None);
+        assert!(!RetryPolicy::default().should_retry(&result));
+
+        let result = Ok(HttpResponse::new(StatusCode::OK, "{}"));
+        assert_eq!(RetryPolicy::classify(&result), None);
+
+        let result = Ok(HttpResponse::new(StatusCode::BAD_GATEWAY, ""));
+        let policy = RetryPolicy::default().retry_on(&[RetryClass::Connect]);
+        assert!(!policy.should_retry(&result));
+        assert!(RetryPolicy::default().should_retry(&result));
+    }
+
+    #[test]
+    fn test_backoff() {
+        let mut policy =
+            RetryPolicy::default().backoff(Duration::from_millis(100), Duration::from_millis(1000));
+        policy.jitter = false;
+        assert_eq!(policy.backoff_for(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff_for(3), Duration::from_millis(400));
+        assert_eq!(policy.backoff_for(10), Duration::from_millis(1000));
+
+        policy.jitter = true;
+        for _ in 0..100 {
+            let backoff = policy.backoff_for(2);
+            assert!(backoff >= Duration::from_millis(100) && backoff <= Duration::from_millis(200));
+        }
+    }
+}
@@ -0,0 +1,370 @@
+// SPDX-License-Identifier: MIT
+
+//! Client side request scheduling to stay under the Binance rate limits.
+//!
+//! Each advertised limit window gets a token bucket holding up to `limit`
+//! tokens which refills at `limit` tokens per window. Requests take their
+//! documented weight from the REQUEST_WEIGHT buckets, order placement also
+//! takes from the ORDERS buckets, and a request that would overdraw a bucket
+//! waits until enough tokens have refilled. Requests are served in the order
+//! they arrive.
+//!
+//! When Binance responds with 429 (rate limited) or 418 (IP banned) all
+//! traffic is held until the time given by the `Retry-After` header.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use reqwest::header::HeaderMap;
+use reqwest::{Method, StatusCode};
+use tracing::warn;
+
+use crate::common::ratelimit::{RateLimit, RateLimitKey, RateLimitState, RateLimitType};
+use crate::common::time::now_millis;
+
+/// How long to hold traffic after a 429 or 418 that has no usable
+/// `Retry-After` header.
+pub const DEFAULT_BACKOFF: Duration = Duration::from_secs(60);
+
+/// The cost of a request against the rate limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestCost {
+    /// Request weight.
+    pub weight: u32,
+    /// Number of orders placed.
+    pub orders: u32,
+}
+
+impl RequestCost {
+    fn required(&self, rate_limit_type: &RateLimitType) -> f64 {
+        match rate_limit_type {
+            RateLimitType::RequestWeight => self.weight as f64,
+            RateLimitType::Orders => self.orders as f64,
+            RateLimitType::RawRequests => 1.0,
+            RateLimitType::Unknown => 0.0,
+        }
+    }
+}
+
+/// The documented weight of an endpoint. Endpoints that are not listed have
+/// a weight of 1.
+pub fn endpoint_cost(method: &Method, endpoint: &str, query_string: &str) -> RequestCost {
+    let has_symbol = query_string
+        .split('&')
+        .any(|param| param.starts_with("symbol="));
+    let limit: Option<u32> = query_string
+        .split('&')
+        .find_map(|param| param.strip_prefix("limit="))
+        .and_then(|limit| limit.parse().ok());
+    let weight = match endpoint {
+        // Spot.
+        "/api/v3/account" => 20,
+        "/api/v3/exchangeInfo" => 20,
+        "/api/v3/ticker/price" if has_symbol => 2,
+        "/api/v3/ticker/price" => 4,
+        "/api/v3/openOrders" if has_symbol => 6,
+        "/api/v3/openOrders" => 80,
+        "/api/v3/order" if method == Method::GET => 4,
+        "/api/v3/userDataStream" => 2,
+
+        // Futures.
+        "/fapi/v1/openOrders" if has_symbol => 1,
+        "/fapi/v1/openOrders" => 40,
+        "/fapi/v1/klines" => match limit {
+            Some(limit) if limit < 100 => 1,
+            Some(limit) if limit < 500 => 2,
+            Some(limit) if limit <= 1000 => 5,
+            Some(_) => 10,
+            None => 5,
+        },
+        "/fapi/v2/positionRisk" => 5,
+        "/fapi/v2/account" => 5,
+        "/fapi/v1/ticker/bookTicker" if has_symbol => 2,
+        "/fapi/v1/ticker/bookTicker" => 5,
+        "/fapi/v1/positionSide/dual" => 30,
+        _ => 1,
+    };
+    let orders = match endpoint {
+        "/api/v3/order" | "/fapi/v1/order" if method == Method::POST => 1,
+        _ => 0,
+    };
+    RequestCost { weight, orders }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    key: RateLimitKey,
+    capacity: f64,
+    tokens: f64,
+    // Tokens refilled per millisecond.
+    refill: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(limit: &RateLimit, now: Instant) -> Self {
+        let key = limit.key();
+        let capacity = limit.limit as f64;
+        let window = key.window_millis().max(1) as f64;
+        Self {
+            key,
+            capacity,
+            tokens: capacity,
+            refill: capacity / window,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_millis() as f64;
+        self.tokens = (self.tokens + elapsed * self.refill).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// How long until `required` tokens are available.
+    fn wait_time(&self, required: f64) -> Duration {
+        if required <= self.tokens {
+            return Duration::from_millis(0);
+        }
+        // A request larger than the bucket can never be satisfied, wait for
+        // a full bucket instead.
+        let required = required.min(self.capacity);
+        let millis = ((required - self.tokens) / self.refill).ceil();
+        Duration::from_millis(millis as u64)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    // Held by the request at the head of the queue while it waits, so
+    // requests are served in order.
+    queue: tokio::sync::Mutex<()>,
+    buckets: Mutex<Vec<Bucket>>,
+    paused_until: Mutex<Option<Instant>>,
+}
+
+impl Scheduler {
+    pub fn new(limits: &[RateLimit]) -> Self {
+        let scheduler = Self::default();
+        scheduler.set_limits(limits);
+        scheduler
+    }
+
+    /// Replace the limits being scheduled against. Buckets for limits that
+    /// already existed keep their current tokens.
+    pub fn set_limits(&self, limits: &[RateLimit]) {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let mut new_buckets = Vec::new();
+        for limit in limits {
+            let mut bucket = Bucket::new(limit, now);
+            if let Some(old) = buckets.iter_mut().find(|b| b.key == bucket.key) {
+                old.refill(now);
+                bucket.tokens = old.tokens.min(bucket.capacity);
+            }
+            new_buckets.push(bucket);
+        }
+        *buckets = new_buckets;
+    }
+
+    /// Wait until the request can be sent without breaching a limit, then
+    /// take its cost from the buckets.
+    pub async fn acquire(&self, cost: RequestCost) {
+        let _queue = self.queue.lock().await;
+        loop {
+            let wait = self.try_acquire(cost, Instant::now());
+            if wait.as_millis() == 0 {
+                return;
+            }
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Take the cost from the buckets if available, otherwise return how
+    /// long to wait before trying again.
+    fn try_acquire(&self, cost: RequestCost, now: Instant) -> Duration {
+        if let Some(until) = self.paused_until(now) {
+            return until - now;
+        }
+        let mut buckets = self.buckets.lock().unwrap();
+        let mut wait = Duration::from_millis(0);
+        for bucket in buckets.iter_mut() {
+            bucket.refill(now);
+            wait = wait.max(bucket.wait_time(cost.required(&bucket.key.rate_limit_type)));
+        }
+        if wait.as_millis() == 0 {
+            for bucket in buckets.iter_mut() {
+                bucket.tokens -= cost.required(&bucket.key.rate_limit_type);
+            }
+        }
+        wait
+    }
+
+    /// Bring the buckets in line with the usage reported by the server, which
+    /// also counts requests made by other processes from the same IP.
+    pub fn reconcile(&self, rate_limits: &RateLimitState) {
+        let mut buckets = self.buckets.lock().unwrap();
+        for bucket in buckets.iter_mut() {
+            if let Some(used) = rate_limits.used(&bucket.key) {
+                let available = (bucket.capacity - used as f64).max(0.0);
+                bucket.tokens = bucket.tokens.min(available);
+            }
+        }
+    }
+
+    /// Handle the response status, holding all traffic on a 429 or 418.
+    pub fn on_response(&self, status: StatusCode, headers: &HeaderMap, body: &str) {
+        if status != StatusCode::TOO_MANY_REQUESTS && status.as_u16() != 418 {
+            return;
+        }
+        let backoff = retry_after(headers, body).unwrap_or(DEFAULT_BACKOFF);
+        warn!(
+            "Rate limited with status {}, holding requests for {:?}",
+            status, backoff
+        );
+        self.pause(backoff);
+    }
+
+    /// Hold all requests for the given duration.
+    pub fn pause(&self, duration: Duration) {
+        let until = Instant::now() + duration;
+        let mut paused_until = self.paused_until.lock().unwrap();
+        if paused_until.map(|u| u < until).unwrap_or(true) {
+            *paused_until = Some(until);
+        }
+    }
+
+    /// The time requests are held until, if paused.
+    pub fn paused_until(&self, now: Instant) -> Option<Instant> {
+        self.paused_until
+            .lock()
+            .unwrap()
+            .filter(|until| *until > now)
+    }
+}
+
+/// How long to back off for from the `Retry-After` header, or for a ban from
+/// the "banned until" timestamp in the message.
+pub fn retry_after(headers: &HeaderMap, body: &str) -> Option<Duration> {
+    if let Some(seconds) = headers
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok())
+    {
+        return Some(Duration::from_secs(seconds));
+    }
+    let until: i64 = body
+        .split("until ")
+        .nth(1)?
+        .split(|c: char| !c.is_ascii_digit())
+        .next()?
+        .parse()
+        .ok()?;
+    Some(Duration::from_millis((until - now_millis()).max(0) as u64))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::ratelimit::RateLimitInterval;
+
+    #[test]
+    fn test_endpoint_cost() {
+        let cost = endpoint_cost(&Method::GET, "/fapi/v1/openOrders", "");
+        assert_eq!(
+            cost,
+            RequestCost {
+                weight: 40,
+                orders: 0
+            }
+        );
+        let cost = endpoint_cost(&Method::GET, "/fapi/v1/openOrders", "symbol=BTCUSDT");
+        assert_eq!(cost.weight, 1);
+        let cost = endpoint_cost(&Method::GET, "/fapi/v1/klines", "symbol=BTCUSDT&limit=1500");
+        assert_eq!(cost.weight, 10);
+        let cost = endpoint_cost(&Method::POST, "/fapi/v1/order", "symbol=BTCUSDT");
+        assert_eq!(
+            cost,
+            RequestCost {
+                weight: 1,
+                orders: 1
+            }
+        );
+        let cost = endpoint_cost(&Method::DELETE, "/fapi/v1/order", "symbol=BTCUSDT");
+        assert_eq!(cost.orders, 0);
+    }
+
+    #[test]
+    fn test_bucket() {
+        let now = Instant::now();
+        let limit = RateLimit {
+            rate_limit_type: RateLimitType::RequestWeight,
+            interval: RateLimitInterval::Second,
+            interval_num: 1,
+            limit: 10,
+        };
+        let mut bucket = Bucket::new(&limit, now);
+        assert_eq!(bucket.wait_time(10.0), Duration::from_millis(0));
+        bucket.tokens = 0.0;
+        assert_eq!(bucket.wait_time(5.0), Duration::from_millis(500));
+        assert_eq!(bucket.wait_time(50.0), Duration::from_millis(1000));
+        bucket.refill(now + Duration::from_millis(200));
+        assert!((bucket.tokens - 2.0).abs() < 0.001);
+        bucket.refill(now + Duration::from_secs(60));
+        assert!((bucket.tokens - 10.0).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_try_acquire() {
+        let now = Instant::now();
+        let limits = vec![
+            RateLimit {
+                rate_limit_type: RateLimitType::RequestWeight,
+                interval: RateLimitInterval::Minute,
+                interval_num: 1,
+                limit: 60,
+            },
+            RateLimit {
+                rate_limit_type: RateLimitType::Orders,
+                interval: RateLimitInterval::Second,
+                interval_num: 10,
+                limit: 1,
+            },
+        ];
+        let scheduler = Scheduler::new(&limits);
+        let order = RequestCost {
+            weight: 1,
+            orders: 1,
+        };
+        assert_eq!(scheduler.try_acquire(order, now), Duration::from_millis(0));
+
+        // The order bucket is empty, a second order has to wait for it.
+        assert_eq!(scheduler.try_acquire(order, now), Duration::from_secs(10));
+
+        // But a request that places no order does not.
+        let query = RequestCost {
+            weight: 1,
+            orders: 0,
+        };
+        assert_eq!(scheduler.try_acquire(query, now), Duration::from_millis(0));
+
+        scheduler.pause(Duration::from_secs(30));
+        assert!(scheduler.try_acquire(query, Instant::now()) > Duration::from_secs(29));
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let mut headers = HeaderMap::new();
+        headers.insert("Retry-After", "7".parse().unwrap());
+        assert_eq!(retry_after(&headers, ""), Some(Duration::from_secs(7)));
+
+        let until = now_millis() + 120_000;
+        let body = format!(
+            "{{\"code\":-1003,\"msg\":\"Way too many requests; IP banned until {}.\"}}",
+            until
+        );
+        let backoff = retry_after(&HeaderMap::new(), &body).unwrap();
+        assert!(backoff > Duration::from_secs(100) && backoff <= Duration::from_secs(120));
+        assert_eq!(retry_after(&HeaderMap::new(), "{}"), None);
+    }
+}
@@ -20,6 +20,9 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
 // DEALINGS IN THE SOFTWARE.
 
+use std::fmt;
+
+use serde::de::{self, Deserializer, SeqAccess, Visitor};
 use serde::Deserialize;
 
 use crate::parsers::*;
@@ -47,3 +50,124 @@ pub struct AggTrade {
     #[serde(rename = "m")]
     pub buyer_maker: bool,
 }
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct KlineEvent {
+    #[serde(rename = "e")]
+    pub event_type: String,
+    #[serde(rename = "E")]
+    pub event_time: f64,
+    #[serde(rename = "s")]
+    pub symbol: String,
+    #[serde(rename = "k")]
+    pub kline: Kline,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Kline {
+    #[serde(rename = "t")]
+    pub open_time: f64,
+    #[serde(rename = "T")]
+    pub close_time: f64,
+    /// Empty for continuous klines, which have no symbol.
+    #[serde(default, rename = "s")]
+    pub symbol: String,
+    #[serde(rename = "i")]
+    pub interval: String,
+    #[serde(rename = "o", deserialize_with = "parse_f64_string")]
+    pub open: f64,
+    #[serde(rename = "c", deserialize_with = "parse_f64_string")]
+    pub close: f64,
+    #[serde(rename = "h", deserialize_with = "parse_f64_string")]
+    pub high: f64,
+    #[serde(rename = "l", deserialize_with = "parse_f64_string")]
+    pub low: f64,
+
+    /// Base asset volume.
+    #[serde(rename = "v", deserialize_with = "parse_f64_string")]
+    pub volume: f64,
+
+    // Number of trades.
+    #[serde(rename = "n")]
+    pub trade_count: u64,
+
+    // Quote asset volume.
+    #[serde(rename = "q", deserialize_with = "parse_f64_string")]
+    pub quote_volume: f64,
+
+    // Taker buy base asset volume.
+    #[serde(rename = "V", deserialize_with = "parse_f64_string")]
+    pub taker_base_volume: f64,
+
+    // Taker buy quote asset volume.
+    #[serde(rename = "Q", deserialize_with = "parse_f64_string")]
+    pub taker_buy_quote_volume: f64,
+
+    #[serde(rename = "x")]
+    pub closed: bool,
+}
+
+/// A price level of an order book.
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct PriceLevel {
+    pub price: f64,
+    pub quantity: f64,
+}
+
+/// Parses the `["price", "quantity"]` pair from the borrowed strings, as
+/// depth updates carry many levels.
+impl<'de> Deserialize<'de> for PriceLevel {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        struct F64String(f64);
+
+        impl<'de> Deserialize<'de> for F64String {
+            fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+                parse_f64_string(d).map(F64String)
+            }
+        }
+
+        struct PriceLevelVisitor;
+
+        impl<'de> Visitor<'de> for PriceLevelVisitor {
+            type Value = PriceLevel;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a price and a quantity")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<PriceLevel, A::Error> {
+                let F64String(price) = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+                let F64String(quantity) = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+                Ok(PriceLevel { price, quantity })
+            }
+        }
+
+        d.deserialize_tuple(2, PriceLevelVisitor)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Default)]
+pub struct MiniTicker {
+    #[serde(rename = "e")]
+    pub event_type: String,
+    #[serde(rename = "E")]
+    pub event_time: u64,
+    #[serde(rename = "s")]
+    pub symbol: String,
+    #[serde(rename = "c", deserialize_with = "parse_f64_string")]
+    pub close_price: f64,
+    #[serde(rename = "o", deserialize_with = "parse_f64_string")]
+    pub open_price: f64,
+    #[serde(rename = "h", deserialize_with = "parse_f64_string")]
+    pub high_price: f64,
+    #[serde(rename = "l", deserialize_with = "parse_f64_string")]
+    pub low_price: f64,
+    #[serde(rename = "v", deserialize_with = "parse_f64_string")]
+    pub base_asset_volume: f64,
+    #[serde(rename = "q", deserialize_with = "parse_f64_string")]
+    pub quote_asset_volume: f64,
+}
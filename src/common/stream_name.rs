@@ -0,0 +1,607 @@
+// SPDX-License-Identifier: MIT
+
+//! Typed names of the market data streams.
+//!
+//! A `StreamName` renders to the name used in the URL and in SUBSCRIBE
+//! messages with `Display`, and parses back from the `stream` field of a
+//! combined stream envelope with `FromStr`. Spot and futures serve different
+//! sets of streams, so names are checked against a `Market` before use.
+
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use crate::error::Error;
+use crate::types::Interval;
+
+/// The market a stream is subscribed on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Market {
+    Spot,
+    /// USD-M futures.
+    Futures,
+}
+
+/// Update speed of a depth stream. Spot only offers 100ms besides its
+/// default of 1000ms, futures defaults to 250ms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DepthSpeed {
+    Ms100,
+    Ms250,
+    Ms500,
+}
+
+impl DepthSpeed {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Ms100 => "100ms",
+            Self::Ms250 => "250ms",
+            Self::Ms500 => "500ms",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "100ms" => Some(Self::Ms100),
+            "250ms" => Some(Self::Ms250),
+            "500ms" => Some(Self::Ms500),
+            _ => None,
+        }
+    }
+}
+
+/// Window of a rolling window ticker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RollingWindow {
+    OneHour,
+    FourHour,
+    OneDay,
+}
+
+impl RollingWindow {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::OneHour => "1h",
+            Self::FourHour => "4h",
+            Self::OneDay => "1d",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "1h" => Some(Self::OneHour),
+            "4h" => Some(Self::FourHour),
+            "1d" => Some(Self::OneDay),
+            _ => None,
+        }
+    }
+}
+
+/// Contract of a continuous kline stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ContractType {
+    Perpetual,
+    CurrentQuarter,
+    NextQuarter,
+}
+
+impl ContractType {
+    const ALL: [Self; 3] = [Self::Perpetual, Self::CurrentQuarter, Self::NextQuarter];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Perpetual => "perpetual",
+            Self::CurrentQuarter => "current_quarter",
+            Self::NextQuarter => "next_quarter",
+        }
+    }
+}
+
+/// A market data stream. Symbols are kept in lower case, as they appear in
+/// stream names; the constructors lower case them.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum StreamName {
+    /// `<symbol>@trade`, spot only.
+    Trade { symbol: String },
+    /// `<symbol>@aggTrade`
+    AggTrade { symbol: String },
+    /// `<symbol>@kline_<interval>`. One second klines are spot only.
+    Kline { symbol: String, interval: Interval },
+    /// `<pair>_<contract>@continuousKline_<interval>`, futures only.
+    ContinuousKline {
+        pair: String,
+        contract_type: ContractType,
+        interval: Interval,
+    },
+    /// `<symbol>@miniTicker`
+    MiniTicker { symbol: String },
+    /// `!miniTicker@arr`
+    AllMiniTickers,
+    /// `<symbol>@ticker`
+    Ticker { symbol: String },
+    /// `!ticker@arr`
+    AllTickers,
+    /// `<symbol>@ticker_<window>`, spot only.
+    RollingWindowTicker {
+        symbol: String,
+        window: RollingWindow,
+    },
+    /// `!ticker_<window>@arr`, spot only.
+    AllRollingWindowTickers { window: RollingWindow },
+    /// `<symbol>@bookTicker`
+    BookTicker { symbol: String },
+    /// `!bookTicker`, futures only.
+    AllBookTickers,
+    /// `<symbol>@depth[@<speed>]`, diff depth updates.
+    Depth {
+        symbol: String,
+        speed: Option<DepthSpeed>,
+    },
+    /// `<symbol>@depth<levels>[@<speed>]`, the top 5, 10 or 20 levels.
+    PartialDepth {
+        symbol: String,
+        levels: u8,
+        speed: Option<DepthSpeed>,
+    },
+    /// `<symbol>@markPrice[@1s]`, futures only. Every 3 seconds unless
+    /// `every_second`.
+    MarkPrice { symbol: String, every_second: bool },
+    /// `!markPrice@arr[@1s]`, futures only.
+    AllMarkPrices { every_second: bool },
+    /// `<symbol>@forceOrder`, futures only.
+    Liquidation { symbol: String },
+    /// `!forceOrder@arr`, futures only.
+    AllLiquidations,
+    /// `!contractInfo`, futures only.
+    ContractInfo,
+    /// `<symbol>@compositeIndex`, futures only.
+    CompositeIndex { symbol: String },
+    /// `<asset symbol>@assetIndex`, futures only.
+    AssetIndex { symbol: String },
+    /// `!assetIndex@arr`, futures only.
+    AllAssetIndexes,
+}
+
+fn lower<S: AsRef<str>>(symbol: S) -> String {
+    symbol.as_ref().to_lowercase()
+}
+
+impl StreamName {
+    pub fn trade<S: AsRef<str>>(symbol: S) -> Self {
+        Self::Trade {
+            symbol: lower(symbol),
+        }
+    }
+
+    pub fn agg_trade<S: AsRef<str>>(symbol: S) -> Self {
+        Self::AggTrade {
+            symbol: lower(symbol),
+        }
+    }
+
+    pub fn kline<S: AsRef<str>>(symbol: S, interval: Interval) -> Self {
+        Self::Kline {
+            symbol: lower(symbol),
+            interval,
+        }
+    }
+
+    pub fn continuous_kline<S: AsRef<str>>(
+        pair: S,
+        contract_type: ContractType,
+        interval: Interval,
+    ) -> Self {
+        Self::ContinuousKline {
+            pair: lower(pair),
+            contract_type,
+            interval,
+        }
+    }
+
+    pub fn mini_ticker<S: AsRef<str>>(symbol: S) -> Self {
+        Self::MiniTicker {
+            symbol: lower(symbol),
+        }
+    }
+
+    pub fn ticker<S: AsRef<str>>(symbol: S) -> Self {
+        Self::Ticker {
+            symbol: lower(symbol),
+        }
+    }
+
+    pub fn rolling_window_ticker<S: AsRef<str>>(symbol: S, window: RollingWindow) -> Self {
+        Self::RollingWindowTicker {
+            symbol: lower(symbol),
+            window,
+        }
+    }
+
+    pub fn book_ticker<S: AsRef<str>>(symbol: S) -> Self {
+        Self::BookTicker {
+            symbol: lower(symbol),
+        }
+    }
+
+    pub fn depth<S: AsRef<str>>(symbol: S, speed: Option<DepthSpeed>) -> Self {
+        Self::Depth {
+            symbol: lower(symbol),
+            speed,
+        }
+    }
+
+    pub fn partial_depth<S: AsRef<str>>(symbol: S, levels: u8, speed: Option<DepthSpeed>) -> Self {
+        Self::PartialDepth {
+            symbol: lower(symbol),
+            levels,
+            speed,
+        }
+    }
+
+    pub fn mark_price<S: AsRef<str>>(symbol: S, every_second: bool) -> Self {
+        Self::MarkPrice {
+            symbol: lower(symbol),
+            every_second,
+        }
+    }
+
+    pub fn liquidation<S: AsRef<str>>(symbol: S) -> Self {
+        Self::Liquidation {
+            symbol: lower(symbol),
+        }
+    }
+
+    pub fn composite_index<S: AsRef<str>>(symbol: S) -> Self {
+        Self::CompositeIndex {
+            symbol: lower(symbol),
+        }
+    }
+
+    pub fn asset_index<S: AsRef<str>>(symbol: S) -> Self {
+        Self::AssetIndex {
+            symbol: lower(symbol),
+        }
+    }
+
+    /// The symbol or pair of the stream, `None` for all market streams.
+    pub fn symbol(&self) -> Option<&str> {
+        match self {
+            Self::Trade { symbol }
+            | Self::AggTrade { symbol }
+            | Self::Kline { symbol, .. }
+            | Self::MiniTicker { symbol }
+            | Self::Ticker { symbol }
+            | Self::RollingWindowTicker { symbol, .. }
+            | Self::BookTicker { symbol }
+            | Self::Depth { symbol, .. }
+            | Self::PartialDepth { symbol, .. }
+            | Self::MarkPrice { symbol, .. }
+            | Self::Liquidation { symbol }
+            | Self::CompositeIndex { symbol }
+            | Self::AssetIndex { symbol } => Some(symbol),
+            Self::ContinuousKline { pair, .. } => Some(pair),
+            _ => None,
+        }
+    }
+
+    /// Check that the market serves this stream.
+    pub fn validate(&self, market: Market) -> Result<(), Error> {
+        let unsupported = || {
+            Err(Error::StreamName(format!(
+                "{} not served by {:?}",
+                self, market
+            )))
+        };
+        match self {
+            Self::PartialDepth { levels, .. } if ![5, 10, 20].contains(levels) => Err(
+                Error::StreamName(format!("{}: depth levels must be 5, 10 or 20", self)),
+            ),
+            Self::Kline {
+                interval: Interval::Other(_),
+                ..
+            }
+            | Self::ContinuousKline {
+                interval: Interval::Other(_),
+                ..
+            } => Err(Error::StreamName(format!("{}: unknown interval", self))),
+            Self::Trade { .. }
+            | Self::RollingWindowTicker { .. }
+            | Self::AllRollingWindowTickers { .. }
+            | Self::Kline {
+                interval: Interval::OneSecond,
+                ..
+            } if market != Market::Spot => unsupported(),
+            Self::Depth { speed, .. } | Self::PartialDepth { speed, .. }
+                if market == Market::Spot
+                    && speed.is_some_and(|speed| speed != DepthSpeed::Ms100) =>
+            {
+                unsupported()
+            }
+            Self::ContinuousKline { .. }
+            | Self::AllBookTickers
+            | Self::MarkPrice { .. }
+            | Self::AllMarkPrices { .. }
+            | Self::Liquidation { .. }
+            | Self::AllLiquidations
+            | Self::ContractInfo
+            | Self::CompositeIndex { .. }
+            | Self::AssetIndex { .. }
+            | Self::AllAssetIndexes
+                if market != Market::Futures =>
+            {
+                unsupported()
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Validate a list of streams for a market and render their names.
+    pub fn render_all(streams: &[StreamName], market: Market) -> Result<Vec<String>, Error> {
+        streams
+            .iter()
+            .map(|stream| {
+                stream.validate(market)?;
+                Ok(stream.to_string())
+            })
+            .collect()
+    }
+}
+
+impl Display for StreamName {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let speed = |speed: &Option<DepthSpeed>| match speed {
+            Some(speed) => format!("@{}", speed.as_str()),
+            None => String::new(),
+        };
+        let every_second = |every_second: &bool| if *every_second { "@1s" } else { "" };
+        match self {
+            Self::Trade { symbol } => write!(f, "{}@trade", symbol),
+            Self::AggTrade { symbol } => write!(f, "{}@aggTrade", symbol),
+            Self::Kline { symbol, interval } => write!(f, "{}@kline_{}", symbol, interval),
+            Self::ContinuousKline {
+                pair,
+                contract_type,
+                interval,
+            } => write!(
+                f,
+                "{}_{}@continuousKline_{}",
+                pair,
+                contract_type.as_str(),
+                interval
+            ),
+            Self::MiniTicker { symbol } => write!(f, "{}@miniTicker", symbol),
+            Self::AllMiniTickers => write!(f, "!miniTicker@arr"),
+            Self::Ticker { symbol } => write!(f, "{}@ticker", symbol),
+            Self::AllTickers => write!(f, "!ticker@arr"),
+            Self::RollingWindowTicker { symbol, window } => {
+                write!(f, "{}@ticker_{}", symbol, window.as_str())
+            }
+            Self::AllRollingWindowTickers { window } => {
+                write!(f, "!ticker_{}@arr", window.as_str())
+            }
+            Self::BookTicker { symbol } => write!(f, "{}@bookTicker", symbol),
+            Self::AllBookTickers => write!(f, "!bookTicker"),
+            Self::Depth { symbol, speed: s } => write!(f, "{}@depth{}", symbol, speed(s)),
+            Self::PartialDepth {
+                symbol,
+                levels,
+                speed: s,
+            } => write!(f, "{}@depth{}{}", symbol, levels, speed(s)),
+            Self::MarkPrice {
+                symbol,
+                every_second: e,
+            } => write!(f, "{}@markPrice{}", symbol, every_second(e)),
+            Self::AllMarkPrices { every_second: e } => {
+                write!(f, "!markPrice@arr{}", every_second(e))
+            }
+            Self::Liquidation { symbol } => write!(f, "{}@forceOrder", symbol),
+            Self::AllLiquidations => write!(f, "!forceOrder@arr"),
+            Self::ContractInfo => write!(f, "!contractInfo"),
+            Self::CompositeIndex { symbol } => write!(f, "{}@compositeIndex", symbol),
+            Self::AssetIndex { symbol } => write!(f, "{}@assetIndex", symbol),
+            Self::AllAssetIndexes => write!(f, "!assetIndex@arr"),
+        }
+    }
+}
+
+impl FromStr for StreamName {
+    type Err = Error;
+
+    /// Parse a stream name, for example the `stream` field of a combined
+    /// stream envelope. Upper case symbols are lower cased.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse(s).ok_or_else(|| Error::StreamName(format!("unknown stream {}", s)))
+    }
+}
+
+fn parse(s: &str) -> Option<StreamName> {
+    if let Some(name) = s.strip_prefix('!') {
+        return match name {
+            "miniTicker@arr" => Some(StreamName::AllMiniTickers),
+            "ticker@arr" => Some(StreamName::AllTickers),
+            "bookTicker" => Some(StreamName::AllBookTickers),
+            "markPrice@arr" => Some(StreamName::AllMarkPrices {
+                every_second: false,
+            }),
+            "markPrice@arr@1s" => Some(StreamName::AllMarkPrices { every_second: true }),
+            "forceOrder@arr" => Some(StreamName::AllLiquidations),
+            "contractInfo" => Some(StreamName::ContractInfo),
+            "assetIndex@arr" => Some(StreamName::AllAssetIndexes),
+            _ => name
+                .strip_prefix("ticker_")
+                .and_then(|name| name.strip_suffix("@arr"))
+                .and_then(RollingWindow::parse)
+                .map(|window| StreamName::AllRollingWindowTickers { window }),
+        };
+    }
+
+    let mut parts = s.split('@');
+    let symbol = parts.next().filter(|symbol| !symbol.is_empty())?;
+    let kind = parts.next()?;
+    let suffix = parts.next();
+    if parts.next().is_some() {
+        return None;
+    }
+
+    let speed = || match suffix {
+        None => Some(None),
+        Some(speed) => DepthSpeed::parse(speed).map(Some),
+    };
+    let stream = match (kind, suffix) {
+        ("trade", None) => StreamName::trade(symbol),
+        ("aggTrade", None) => StreamName::agg_trade(symbol),
+        ("miniTicker", None) => StreamName::mini_ticker(symbol),
+        ("ticker", None) => StreamName::ticker(symbol),
+        ("bookTicker", None) => StreamName::book_ticker(symbol),
+        ("markPrice", None) => StreamName::mark_price(symbol, false),
+        ("markPrice", Some("1s")) => StreamName::mark_price(symbol, true),
+        ("forceOrder", None) => StreamName::liquidation(symbol),
+        ("compositeIndex", None) => StreamName::composite_index(symbol),
+        ("assetIndex", None) => StreamName::asset_index(symbol),
+        ("depth", _) => StreamName::depth(symbol, speed()?),
+        (kind, None) if kind.starts_with("kline_") => {
+            StreamName::kline(symbol, parse_interval(&kind[6..])?)
+        }
+        (kind, None) if kind.starts_with("continuousKline_") => {
+            let interval = parse_interval(&kind[16..])?;
+            let (pair, contract_type) = ContractType::ALL.iter().find_map(|contract_type| {
+                symbol
+                    .strip_suffix(contract_type.as_str())
+                    .and_then(|pair| pair.strip_suffix('_'))
+                    .map(|pair| (pair, *contract_type))
+            })?;
+            StreamName::continuous_kline(pair, contract_type, interval)
+        }
+        (kind, None) if kind.starts_with("ticker_") => {
+            StreamName::rolling_window_ticker(symbol, RollingWindow::parse(&kind[7..])?)
+        }
+        (kind, _) if kind.starts_with("depth") => {
+            let levels = kind[5..].parse().ok()?;
+            StreamName::partial_depth(symbol, levels, speed()?)
+        }
+        _ => return None,
+    };
+    Some(stream)
+}
+
+fn parse_interval(s: &str) -> Option<Interval> {
+    match Interval::from_str_non_strict(s) {
+        Interval::Other(_) => None,
+        interval => Some(interval),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let streams = [
+            (StreamName::trade("BTCUSDT"), "btcusdt@trade"),
+            (StreamName::agg_trade("btcusdt"), "btcusdt@aggTrade"),
+            (
+                StreamName::kline("btcusdt", Interval::OneSecond),
+                "btcusdt@kline_1s",
+            ),
+            (
+                StreamName::continuous_kline(
+                    "btcusdt",
+                    ContractType::CurrentQuarter,
+                    Interval::OneMonth,
+                ),
+                "btcusdt_current_quarter@continuousKline_1M",
+            ),
+            (StreamName::AllMiniTickers, "!miniTicker@arr"),
+            (StreamName::AllTickers, "!ticker@arr"),
+            (
+                StreamName::rolling_window_ticker("bnbbtc", RollingWindow::FourHour),
+                "bnbbtc@ticker_4h",
+            ),
+            (
+                StreamName::AllRollingWindowTickers {
+                    window: RollingWindow::OneDay,
+                },
+                "!ticker_1d@arr",
+            ),
+            (StreamName::book_ticker("bnbbtc"), "bnbbtc@bookTicker"),
+            (StreamName::AllBookTickers, "!bookTicker"),
+            (StreamName::depth("bnbbtc", None), "bnbbtc@depth"),
+            (
+                StreamName::depth("bnbbtc", Some(DepthSpeed::Ms500)),
+                "bnbbtc@depth@500ms",
+            ),
+            (
+                StreamName::partial_depth("bnbbtc", 5, Some(DepthSpeed::Ms100)),
+                "bnbbtc@depth5@100ms",
+            ),
+            (
+                StreamName::partial_depth("bnbbtc", 20, None),
+                "bnbbtc@depth20",
+            ),
+            (
+                StreamName::mark_price("btcusdt", true),
+                "btcusdt@markPrice@1s",
+            ),
+            (
+                StreamName::AllMarkPrices {
+                    every_second: false,
+                },
+                "!markPrice@arr",
+            ),
+            (StreamName::liquidation("btcusdt"), "btcusdt@forceOrder"),
+            (StreamName::AllLiquidations, "!forceOrder@arr"),
+            (StreamName::ContractInfo, "!contractInfo"),
+            (StreamName::asset_index("adausd"), "adausd@assetIndex"),
+        ];
+        for (stream, name) in streams {
+            assert_eq!(stream.to_string(), name);
+            assert_eq!(name.parse::<StreamName>().unwrap(), stream);
+        }
+
+        for name in [
+            "",
+            "btcusdt",
+            "btcusdt@depth@1s",
+            "!ticker_2h@arr",
+            "btcusdt@trade@1s",
+            "btcusdt@kline_bogus",
+            "btcusdt_perpetual@continuousKline_2x",
+        ] {
+            assert!(name.parse::<StreamName>().is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let spot_only = [
+            StreamName::trade("btcusdt"),
+            StreamName::kline("btcusdt", Interval::OneSecond),
+            StreamName::rolling_window_ticker("btcusdt", RollingWindow::OneHour),
+        ];
+        for stream in &spot_only {
+            assert!(stream.validate(Market::Spot).is_ok());
+            assert!(stream.validate(Market::Futures).is_err());
+        }
+
+        let futures_only = [
+            StreamName::mark_price("btcusdt", false),
+            StreamName::AllLiquidations,
+            StreamName::depth("btcusdt", Some(DepthSpeed::Ms500)),
+        ];
+        for stream in &futures_only {
+            assert!(stream.validate(Market::Spot).is_err());
+            assert!(stream.validate(Market::Futures).is_ok());
+        }
+
+        assert!(StreamName::partial_depth("btcusdt", 7, None)
+            .validate(Market::Futures)
+            .is_err());
+        let bogus = StreamName::kline("btcusdt", Interval::Other("bogus".to_string()));
+        assert!(bogus.validate(Market::Spot).is_err());
+        assert!(bogus.validate(Market::Futures).is_err());
+        assert_eq!(
+            StreamName::render_all(&[StreamName::AllTickers], Market::Spot).unwrap(),
+            ["!ticker@arr"]
+        );
+    }
+}
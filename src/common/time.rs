@@ -0,0 +1,120 @@
+// SPDX-License-Identifier: MIT
+
+//! Server time synchronisation.
+//!
+//! Binance rejects signed requests whose timestamp is outside of the
+//! recvWindow with error -1021. Rather than trusting the local clock we keep
+//! an estimate of the offset between the server clock and the local clock,
+//! compensating for the round trip time of the request used to measure it.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+
+/// How long a measured offset is trusted before it is refreshed.
+pub const DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_secs(300);
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ServerTimeResponse {
+    #[serde(rename = "serverTime")]
+    pub server_time: i64,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct State {
+    offset: i64,
+    rtt: u64,
+    last_sync: Option<Instant>,
+}
+
+#[derive(Debug)]
+pub struct TimeSync {
+    state: Mutex<State>,
+    refresh_interval: Duration,
+}
+
+impl Default for TimeSync {
+    fn default() -> Self {
+        Self::new(DEFAULT_REFRESH_INTERVAL)
+    }
+}
+
+impl TimeSync {
+    pub fn new(refresh_interval: Duration) -> Self {
+        Self {
+            state: Mutex::new(State::default()),
+            refresh_interval,
+        }
+    }
+
+    /// Estimated server time minus local time in milliseconds.
+    pub fn offset(&self) -> i64 {
+        self.state.lock().unwrap().offset
+    }
+
+    /// Round trip time of the last synchronisation in milliseconds.
+    pub fn rtt(&self) -> u64 {
+        self.state.lock().unwrap().rtt
+    }
+
+    pub fn last_sync(&self) -> Option<Instant> {
+        self.state.lock().unwrap().last_sync
+    }
+
+    /// True if the offset has been measured before and is now older than the
+    /// refresh interval. A client that has never synced is assumed to have a
+    /// good clock and is not refreshed until the first sync.
+    pub fn needs_refresh(&self) -> bool {
+        match self.last_sync() {
+            Some(last_sync) => last_sync.elapsed() >= self.refresh_interval,
+            None => false,
+        }
+    }
+
+    /// The current time in milliseconds as estimated for the server.
+    pub fn timestamp(&self) -> i64 {
+        now_millis() + self.offset()
+    }
+
+    /// Record a server time measurement. `sent` and `received` are the local
+    /// times in milliseconds the request was sent and the response received.
+    pub fn update(&self, sent: i64, server_time: i64, received: i64) {
+        let rtt = (received - sent).max(0);
+        let offset = server_time - (sent + rtt / 2);
+        let mut state = self.state.lock().unwrap();
+        state.offset = offset;
+        state.rtt = rtt as u64;
+        state.last_sync = Some(Instant::now());
+    }
+}
+
+pub fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_update_compensates_for_rtt() {
+        let sync = TimeSync::default();
+        assert!(!sync.needs_refresh());
+
+        // Request sent at 1000, response at 1100, server says 2050. The server
+        // time was taken half way through the round trip at local time 1050.
+        sync.update(1000, 2050, 1100);
+        assert_eq!(sync.offset(), 1000);
+        assert_eq!(sync.rtt(), 100);
+        assert!(sync.last_sync().is_some());
+        assert!(!sync.needs_refresh());
+
+        let stale = TimeSync::new(Duration::from_secs(0));
+        stale.update(0, 0, 0);
+        assert!(stale.needs_refresh());
+    }
+}
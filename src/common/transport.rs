@@ -0,0 +1,185 @@
+// SPDX-License-Identifier: MIT
+
+//! The HTTP transport used by the REST clients.
+//!
+//! Requests go out through an `HttpTransport`, by default `ReqwestTransport`.
+//! `MockTransport` returns scripted responses and records the requests sent
+//! so clients can be tested without the network.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use futures_util::future::BoxFuture;
+use reqwest::header::HeaderMap;
+use reqwest::{Method, StatusCode};
+
+use crate::error::Error;
+
+#[derive(Debug, Clone)]
+pub struct HttpRequest {
+    pub method: Method,
+    pub url: reqwest::Url,
+    pub headers: HeaderMap,
+    pub body: Option<String>,
+}
+
+impl HttpRequest {
+    /// The query string of the URL, or an empty string.
+    pub fn query(&self) -> &str {
+        self.url.query().unwrap_or("")
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: String,
+}
+
+impl HttpResponse {
+    pub fn new<S: Into<String>>(status: StatusCode, body: S) -> Self {
+        Self {
+            status,
+            headers: HeaderMap::new(),
+            body: body.into(),
+        }
+    }
+
+    pub fn header(mut self, name: &'static str, value: &str) -> Self {
+        if let Ok(value) = value.parse() {
+            self.headers.insert(name, value);
+        }
+        self
+    }
+}
+
+pub trait HttpTransport: Send + Sync {
+    fn send(&self, request: HttpRequest) -> BoxFuture<'_, Result<HttpResponse, Error>>;
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ReqwestTransport {
+    client: reqwest::Client,
+}
+
+impl ReqwestTransport {
+    pub fn new(client: reqwest::Client) -> Self {
+        Self { client }
+    }
+}
+
+impl HttpTransport for ReqwestTransport {
+    fn send(&self, request: HttpRequest) -> BoxFuture<'_, Result<HttpResponse, Error>> {
+        Box::pin(async move {
+            let mut builder = self
+                .client
+                .request(request.method, request.url)
+                .headers(request.headers);
+            if let Some(body) = request.body {
+                builder = builder.body(body);
+            }
+            let response = builder.send().await?;
+            let status = response.status();
+            let headers = response.headers().clone();
+            let body = response.text().await?;
+            Ok(HttpResponse {
+                status,
+                headers,
+                body,
+            })
+        })
+    }
+}
+
+/// An in-memory transport returning scripted responses in order.
+#[derive(Debug, Default)]
+pub struct MockTransport {
+    responses: Mutex<VecDeque<HttpResponse>>,
+    requests: Mutex<Vec<HttpRequest>>,
+}
+
+impl MockTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue a response.
+    pub fn push_response(&self, response: HttpResponse) {
+        self.responses.lock().unwrap().push_back(response);
+    }
+
+    /// Queue a response with the given status and body.
+    pub fn push<S: Into<String>>(&self, status: StatusCode, body: S) {
+        self.push_response(HttpResponse::new(status, body));
+    }
+
+    /// The requests sent so far.
+    pub fn requests(&self) -> Vec<HttpRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Number of scripted responses not yet returned.
+    pub fn remaining(&self) -> usize {
+        self.responses.lock().unwrap().len()
+    }
+}
+
+impl HttpTransport for MockTransport {
+    fn send(&self, request: HttpRequest) -> BoxFuture<'_, Result<HttpResponse, Error>> {
+        let response = self.responses.lock().unwrap().pop_front();
+        self.requests.lock().unwrap().push(request.clone());
+        Box::pin(async move {
+            response.ok_or_else(|| {
+                Error::Anyhow(anyhow::anyhow!(
+                    "no scripted response for {} {}",
+                    request.method,
+                    request.url
+                ))
+            })
+        })
+    }
+}
+
+/// Helpers shared by the tests of the spot and futures clients.
+#[cfg(test)]
+pub(crate) mod test_support {
+    use std::sync::Arc;
+
+    use super::{HttpTransport, MockTransport};
+    use crate::common::client::Authentication;
+    use crate::common::signer::{HmacSigner, Signer};
+
+    pub(crate) const API_KEY: &str =
+        "vmPUZE6mv9SD5VNHk4HlWFsOr6aKE2zvsw0MuIgwCIPy6utIco14y7Ju91duEh8A";
+    pub(crate) const API_SECRET: &str =
+        "NhqPtmdSJYdKjVHjA7PZj4Mge3R5YNiP1e3UZjInClVN65XAbvqqM6A7H5fATj0j";
+
+    /// A client created by `new` with the test credentials, sending its
+    /// requests to the returned transport.
+    pub(crate) fn mock_client<C>(
+        new: fn(Option<Authentication>) -> C,
+        set_transport: fn(&mut C, Arc<dyn HttpTransport>),
+    ) -> (C, Arc<MockTransport>) {
+        let transport = Arc::new(MockTransport::new());
+        let mut client = new(Some(Authentication::hmac(API_KEY, API_SECRET)));
+        set_transport(&mut client, transport.clone());
+        (client, transport)
+    }
+
+    /// Assert a signed form is the params followed by the recvWindow,
+    /// timestamp and a valid signature.
+    pub(crate) fn assert_signed(form: &str, params: &str) {
+        let prefix = match params {
+            "" => "recvWindow=1000&timestamp=".to_string(),
+            _ => format!("{}&recvWindow=1000&timestamp=", params),
+        };
+        let rest = form
+            .strip_prefix(&prefix)
+            .unwrap_or_else(|| panic!("unexpected form {}", form));
+        let timestamp = rest.split('&').next().unwrap();
+        let payload = format!("{}{}", prefix, timestamp);
+        let signature = HmacSigner::new(API_SECRET).sign(&payload).unwrap();
+        assert_eq!(form, format!("{}&signature={}", payload, signature));
+    }
+}
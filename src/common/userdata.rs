@@ -0,0 +1,160 @@
+// SPDX-License-Identifier: MIT
+
+//! The connection of a user data stream.
+//!
+//! `UserDataConnection` creates the listen key, keeps it alive, and keeps a
+//! connection open on the current key: reconnecting with backoff when the
+//! connection drops and moving to the new key when it is replaced. It reads
+//! raw frames, the spot and futures user data streams decode them into their
+//! events.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::warn;
+
+use crate::common::latency::Receipt;
+use crate::common::listenkey::ListenKeyManager;
+use crate::common::retry::RetryPolicy;
+use crate::common::websocket::{Keepalive, LiveConnection};
+use crate::error::Error;
+
+pub(crate) enum UserDataMessage {
+    /// A connection was opened. Events sent while there was none are lost.
+    Connected(Receipt),
+
+    /// A ping or text frame.
+    Message(Message, Receipt),
+}
+
+pub(crate) struct UserDataConnection {
+    /// The WebSocket root the listen key is appended to.
+    base_url: String,
+    manager: Arc<ListenKeyManager>,
+    manager_task: JoinHandle<()>,
+    listen_key: watch::Receiver<Option<String>>,
+    /// The connection and the listen key it was opened with.
+    live: Option<(LiveConnection, String)>,
+    keepalive: Keepalive,
+    pub(crate) reconnect_policy: RetryPolicy,
+    attempt: u32,
+}
+
+impl UserDataConnection {
+    /// Create a listen key and start keeping it alive. The connection opens
+    /// on the first call to `next`.
+    pub(crate) async fn connect(manager: ListenKeyManager, base_url: &str) -> Result<Self, Error> {
+        let manager = Arc::new(manager);
+        manager.create().await?;
+        Ok(Self {
+            base_url: base_url.to_string(),
+            listen_key: manager.subscribe(),
+            manager_task: manager.spawn(),
+            manager,
+            live: None,
+            keepalive: Keepalive::default(),
+            reconnect_policy: RetryPolicy::default()
+                .backoff(Duration::from_secs(1), Duration::from_secs(60)),
+            attempt: 0,
+        })
+    }
+
+    pub(crate) fn set_keepalive(&mut self, keepalive: Keepalive) {
+        self.keepalive = keepalive;
+    }
+
+    /// The listen key of the open connection, the stream its frames are
+    /// received on.
+    pub(crate) fn stream(&self) -> Option<&str> {
+        self.live
+            .as_ref()
+            .map(|(_, listen_key)| listen_key.as_str())
+    }
+
+    /// The next ping or text frame, or `Connected` after opening a
+    /// connection. `listenKeyExpired` events are passed to the manager
+    /// rather than returned.
+    pub(crate) async fn next(&mut self) -> UserDataMessage {
+        loop {
+            let live = match self.live.as_mut() {
+                Some((live, _)) => live,
+                None => {
+                    let id = self.reconnect().await;
+                    return UserDataMessage::Connected(Receipt::now(id));
+                }
+            };
+            tokio::select! {
+                _ = self.listen_key.changed() => {
+                    // A new listen key, the old stream will receive nothing.
+                    self.live = None;
+                }
+                next = live.next() => match next {
+                    Some(Ok((message @ (Message::Ping(_) | Message::Text(_)), receipt)))
+                        if !self.manager.check_message(&message) =>
+                    {
+                        return UserDataMessage::Message(message, receipt);
+                    }
+                    Some(Ok(_)) => {
+                        // Close frames and expired listen keys, the manager
+                        // replaces the key.
+                    }
+                    Some(Err(err)) => {
+                        warn!("User data stream failed, reconnecting: {}", err);
+                        self.live = None;
+                    }
+                    None => {
+                        warn!("User data stream closed, reconnecting");
+                        self.live = None;
+                    }
+                },
+            }
+        }
+    }
+
+    /// Connect with the current listen key, retrying with backoff. Returns
+    /// the id of the new connection.
+    async fn reconnect(&mut self) -> u64 {
+        loop {
+            if self.attempt > 0 {
+                tokio::time::sleep(self.reconnect_policy.backoff_for(self.attempt)).await;
+            }
+            self.attempt += 1;
+            let listen_key = self.listen_key.borrow_and_update().clone();
+            let listen_key = match listen_key {
+                Some(listen_key) => listen_key,
+                None => {
+                    // Closed or being renewed, wait for the next key.
+                    let _ = self.listen_key.changed().await;
+                    continue;
+                }
+            };
+            let url = format!("{}/ws/{}", self.base_url, listen_key);
+            match connect_async(url).await {
+                Ok((ws, _response)) => {
+                    let live = LiveConnection::new(ws, self.keepalive.clone());
+                    let id = live.id();
+                    self.live = Some((live, listen_key));
+                    self.attempt = 0;
+                    return id;
+                }
+                Err(err) => warn!("Failed to connect user data stream: {}", err),
+            }
+        }
+    }
+
+    /// Stop keeping the listen key alive and close the connection.
+    pub(crate) async fn close(mut self) -> Result<(), Error> {
+        self.live = None;
+        self.manager.close().await
+    }
+}
+
+impl Drop for UserDataConnection {
+    fn drop(&mut self) {
+        self.manager_task.abort();
+    }
+}
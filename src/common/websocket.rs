@@ -2,14 +2,40 @@
 //
 // SPDX-License-Identifier: MIT
 
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_util::future::poll_fn;
+use futures_util::{ready, SinkExt, StreamExt};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{Instant, Sleep};
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::MaybeTlsStream;
+use tracing::warn;
+
+use crate::common::cassette::Cassette;
+use crate::common::latency::{next_connection_id, Receipt};
+use crate::common::stream_name::{Market, StreamName};
+use crate::error::{ApiError, Error};
+
+#[deprecated(note = "use StreamName")]
 pub fn stream_name_trade(symbol: &str) -> String {
     format!("{}@trade", symbol.to_lowercase())
 }
 
+#[deprecated(note = "use StreamName")]
 pub fn stream_name_aggtrade<S: AsRef<str>>(symbol: S) -> String {
     format!("{}@aggTrade", symbol.as_ref().to_lowercase())
 }
 
+#[deprecated(note = "use StreamName")]
 pub fn stream_name_kline<S: AsRef<str>, I: AsRef<str>>(symbol: S, interval: I) -> String {
     format!(
         "{}@kline_{}",
@@ -18,10 +44,748 @@ pub fn stream_name_kline<S: AsRef<str>, I: AsRef<str>>(symbol: S, interval: I) -
     )
 }
 
+#[deprecated(note = "use StreamName")]
 pub fn stream_name_liquidation<S: AsRef<str>>(symbol: S) -> String {
     format!("{}@forceOrder", symbol.as_ref().to_lowercase())
 }
 
+#[deprecated(note = "use StreamName")]
 pub fn stream_name_ticker<S: AsRef<str>>(symbol: S) -> String {
     format!("{}@ticker", symbol.as_ref().to_lowercase())
 }
+
+pub(crate) type WebSocketStream = tokio_tungstenite::WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Keepalive settings for a WebSocket connection. Pings from the server are
+/// always answered with a pong carrying the same payload.
+#[derive(Debug, Clone, Default)]
+pub struct Keepalive {
+    /// Send a ping at this interval.
+    pub ping_interval: Option<Duration>,
+    /// Send an unsolicited pong at this interval.
+    pub pong_interval: Option<Duration>,
+    /// Treat the connection as dead when no frame has been received for
+    /// this long.
+    pub timeout: Option<Duration>,
+}
+
+impl Keepalive {
+    pub fn ping_interval(mut self, interval: Duration) -> Self {
+        self.ping_interval = Some(interval);
+        self
+    }
+
+    pub fn pong_interval(mut self, interval: Duration) -> Self {
+        self.pong_interval = Some(interval);
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+}
+
+/// A live connection that answers pings, applies the keepalive settings and
+/// matches replies to the requests sent on it.
+pub(crate) struct LiveConnection {
+    id: u64,
+    ws: WebSocketStream,
+    keepalive: Keepalive,
+    last_frame: Instant,
+    next_ping: Option<Instant>,
+    next_pong: Option<Instant>,
+    timer: Pin<Box<Sleep>>,
+    outgoing: VecDeque<Message>,
+    unflushed: bool,
+    next_id: u64,
+    waiting: HashMap<u64, Waiting>,
+    dead: bool,
+}
+
+/// A request sent and waiting for its reply.
+enum Waiting {
+    /// Gets the result or error of the reply.
+    Request(oneshot::Sender<Result<Option<Value>, Error>>),
+    /// Gets the whole reply.
+    Api(oneshot::Sender<Result<Value, Error>>),
+}
+
+impl LiveConnection {
+    pub(crate) fn new(ws: WebSocketStream, keepalive: Keepalive) -> Self {
+        let mut connection = Self {
+            id: next_connection_id(),
+            ws,
+            keepalive: Keepalive::default(),
+            last_frame: Instant::now(),
+            next_ping: None,
+            next_pong: None,
+            timer: Box::pin(tokio::time::sleep(Duration::ZERO)),
+            outgoing: VecDeque::new(),
+            unflushed: false,
+            next_id: 1,
+            waiting: HashMap::new(),
+            dead: false,
+        };
+        connection.set_keepalive(keepalive);
+        connection
+    }
+
+    /// See `Receipt::connection_id`.
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub(crate) fn set_keepalive(&mut self, keepalive: Keepalive) {
+        let now = Instant::now();
+        self.next_ping = keepalive.ping_interval.map(|interval| now + interval);
+        self.next_pong = keepalive.pong_interval.map(|interval| now + interval);
+        self.keepalive = keepalive;
+    }
+
+    /// The next time a timer fires.
+    fn deadline(&self) -> Option<Instant> {
+        let watchdog = self
+            .keepalive
+            .timeout
+            .map(|timeout| self.last_frame + timeout);
+        [self.next_ping, self.next_pong, watchdog]
+            .iter()
+            .flatten()
+            .min()
+            .copied()
+    }
+
+    pub(crate) async fn next(&mut self) -> Option<Result<(Message, Receipt), tungstenite::Error>> {
+        poll_fn(|cx| self.poll_next(cx)).await
+    }
+
+    /// Read the next message with its receipt, taken as the frame is read.
+    /// Replies to requests are passed to the requester rather than
+    /// returned, and queued frames are written as the socket accepts them.
+    pub(crate) fn poll_next(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<(Message, Receipt), tungstenite::Error>>> {
+        if self.dead {
+            return Poll::Ready(None);
+        }
+        loop {
+            if let Poll::Ready(Err(err)) = self.poll_write(cx) {
+                return Poll::Ready(Some(Err(err)));
+            }
+            match self.ws.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(message))) => {
+                    let receipt = Receipt::now(self.id);
+                    self.last_frame = Instant::now();
+                    if let Message::Ping(data) = &message {
+                        self.outgoing.push_back(Message::Pong(data.clone()));
+                        if let Poll::Ready(Err(err)) = self.poll_write(cx) {
+                            return Poll::Ready(Some(Err(err)));
+                        }
+                    }
+                    if !self.take_reply(&message) {
+                        return Poll::Ready(Some(Ok((message, receipt))));
+                    }
+                    continue;
+                }
+                Poll::Ready(Some(Err(err))) => return Poll::Ready(Some(Err(err))),
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => {}
+            }
+            match self.deadline() {
+                Some(deadline) => {
+                    self.timer.as_mut().reset(deadline);
+                    ready!(self.timer.as_mut().poll(cx));
+                    if let Err(err) = self.on_timer() {
+                        return Poll::Ready(Some(Err(tungstenite::Error::Io(err))));
+                    }
+                }
+                None => return Poll::Pending,
+            }
+        }
+    }
+
+    fn on_timer(&mut self) -> Result<(), std::io::Error> {
+        let now = Instant::now();
+        if let Some(timeout) = self.keepalive.timeout {
+            if now >= self.last_frame + timeout {
+                self.dead = true;
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    format!("no frames received for {:?}", timeout),
+                ));
+            }
+        }
+        if let (Some(next), Some(interval)) = (self.next_ping, self.keepalive.ping_interval) {
+            if now >= next {
+                self.next_ping = Some(now + interval);
+                self.outgoing.push_back(Message::Ping(vec![]));
+            }
+        }
+        if let (Some(next), Some(interval)) = (self.next_pong, self.keepalive.pong_interval) {
+            if now >= next {
+                self.next_pong = Some(now + interval);
+                self.outgoing.push_back(Message::Pong(vec![]));
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the queued frames.
+    fn poll_write(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), tungstenite::Error>> {
+        while !self.outgoing.is_empty() {
+            ready!(self.ws.poll_ready_unpin(cx))?;
+            if let Some(message) = self.outgoing.pop_front() {
+                self.ws.start_send_unpin(message)?;
+                self.unflushed = true;
+            }
+        }
+        if self.unflushed {
+            ready!(self.ws.poll_flush_unpin(cx))?;
+            self.unflushed = false;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Pass a reply to its requester, returning false if the message is not
+    /// a reply to a request of this connection.
+    fn take_reply(&mut self, message: &Message) -> bool {
+        if self.waiting.is_empty() {
+            return false;
+        }
+        let reply = match message {
+            Message::Text(text) => match serde_json::from_str::<Reply>(text) {
+                Ok(reply) => reply,
+                Err(_) => return false,
+            },
+            _ => return false,
+        };
+        let waiting = match reply.id.and_then(|id| self.waiting.remove(&id)) {
+            Some(waiting) => waiting,
+            None => return false,
+        };
+        match waiting {
+            Waiting::Request(sender) => {
+                let _ = sender.send(match reply.error {
+                    Some(error) => Err(Error::ApiError(error)),
+                    None => Ok(Some(reply.result)),
+                });
+            }
+            Waiting::Api(sender) => {
+                let _ = sender.send(match message {
+                    Message::Text(text) => {
+                        serde_json::from_str(text).map_err(|error| Error::Decode {
+                            error,
+                            text: text.to_string(),
+                        })
+                    }
+                    _ => unreachable!(),
+                });
+            }
+        }
+        true
+    }
+
+    /// Queue a request. The result is sent to `reply` when the reply is
+    /// read, or the sender dropped if the connection closes first.
+    pub(crate) fn queue_request(&mut self, request: Request) {
+        self.queue(
+            request.method,
+            request.params,
+            Waiting::Request(request.reply),
+        );
+    }
+
+    /// Queue a WebSocket API request. The whole reply, with its status and
+    /// rate limits, is sent to `reply`, whether it succeeded or not.
+    pub(crate) fn queue_api_request(
+        &mut self,
+        method: &str,
+        params: Value,
+        reply: oneshot::Sender<Result<Value, Error>>,
+    ) {
+        self.queue(method, params, Waiting::Api(reply));
+    }
+
+    fn queue(&mut self, method: &str, params: Value, waiting: Waiting) {
+        let id = self.next_id;
+        self.next_id += 1;
+        let text = json!({"method": method, "params": params, "id": id});
+        self.outgoing.push_back(Message::Text(text.to_string()));
+        self.waiting.insert(id, waiting);
+    }
+
+    pub(crate) async fn close(&mut self) -> Result<(), tungstenite::Error> {
+        poll_fn(|cx| self.poll_write(cx)).await?;
+        self.ws.close(None).await
+    }
+}
+
+/// A SUBSCRIBE, UNSUBSCRIBE or LIST_SUBSCRIPTIONS request waiting to be sent.
+pub(crate) struct Request {
+    pub(crate) method: &'static str,
+    pub(crate) params: Value,
+    /// Gets the result, or `None` if the request was remembered for the
+    /// next connection rather than sent.
+    pub(crate) reply: oneshot::Sender<Result<Option<Value>, Error>>,
+}
+
+/// What became of a subscribe or unsubscribe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// The server accepted it.
+    Applied,
+
+    /// A reconnecting connection was disconnected. Nothing was sent, the
+    /// streams are used when it reconnects.
+    Deferred,
+}
+
+impl Outcome {
+    pub(crate) fn of(result: Option<Value>) -> Self {
+        match result {
+            Some(_) => Outcome::Applied,
+            None => Outcome::Deferred,
+        }
+    }
+}
+
+/// The queue of requests of a connection, made inline or by its `Writer`.
+pub(crate) struct Requests {
+    sender: mpsc::UnboundedSender<Request>,
+    receiver: mpsc::UnboundedReceiver<Request>,
+}
+
+impl Requests {
+    pub(crate) fn new() -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        Self { sender, receiver }
+    }
+
+    pub(crate) fn writer(&self) -> Writer {
+        Writer {
+            sender: self.sender.clone(),
+            timeout: DEFAULT_REQUEST_TIMEOUT,
+        }
+    }
+
+    /// Queue a request, returning the receiver of its result.
+    pub(crate) fn push(
+        &self,
+        method: &'static str,
+        params: Value,
+    ) -> oneshot::Receiver<Result<Option<Value>, Error>> {
+        let (reply, result) = oneshot::channel();
+        // The receiver is owned alongside the sender so this cannot fail.
+        let _ = self.sender.send(Request {
+            method,
+            params,
+            reply,
+        });
+        result
+    }
+
+    /// The next queued request. Never returns `Ready(None)` as the queue
+    /// holds a sender itself.
+    pub(crate) fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<Request>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+/// How long a `Writer` waits for the reply to a request.
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The write half of a WebSocket, for subscribing and unsubscribing while
+/// another task reads. Replies are only received while the WebSocket is
+/// being read.
+#[derive(Clone)]
+pub struct Writer {
+    sender: mpsc::UnboundedSender<Request>,
+    timeout: Duration,
+}
+
+impl Writer {
+    /// Fail requests not answered within `timeout` with `Error::Timeout`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Subscribe to streams. On a reconnecting connection that is
+    /// disconnected the outcome is `Deferred`.
+    pub async fn subscribe<T: AsRef<str>>(&self, streams: &[T]) -> Result<Outcome, Error> {
+        self.request("SUBSCRIBE", stream_params(streams))
+            .await
+            .map(Outcome::of)
+    }
+
+    /// Subscribe to typed streams, failing on any the market does not serve
+    /// before anything is sent.
+    pub async fn subscribe_streams(
+        &self,
+        streams: &[StreamName],
+        market: Market,
+    ) -> Result<Outcome, Error> {
+        self.subscribe(&StreamName::render_all(streams, market)?)
+            .await
+    }
+
+    /// Unsubscribe from streams, `Deferred` like `subscribe`.
+    pub async fn unsubscribe<T: AsRef<str>>(&self, streams: &[T]) -> Result<Outcome, Error> {
+        self.request("UNSUBSCRIBE", stream_params(streams))
+            .await
+            .map(Outcome::of)
+    }
+
+    /// The streams the connection is subscribed to, as reported by the
+    /// server.
+    pub async fn list_subscriptions(&self) -> Result<Vec<String>, Error> {
+        let result = self.request("LIST_SUBSCRIPTIONS", json!([])).await?;
+        decode_subscriptions(result.unwrap_or_default())
+    }
+
+    async fn request(&self, method: &'static str, params: Value) -> Result<Option<Value>, Error> {
+        let (reply, result) = oneshot::channel();
+        self.sender
+            .send(Request {
+                method,
+                params,
+                reply,
+            })
+            .map_err(|_| closed())?;
+        match tokio::time::timeout(self.timeout, result).await {
+            Ok(reply) => reply.map_err(|_| closed())?,
+            Err(_) => Err(Error::Timeout(ApiError::timeout(format!(
+                "no reply to {} within {:?}",
+                method, self.timeout
+            )))),
+        }
+    }
+}
+
+/// The error of a request whose connection closed before the reply.
+pub(crate) fn closed() -> Error {
+    tungstenite::Error::ConnectionClosed.into()
+}
+
+/// Where a WebSocket reads its frames from: a live connection or the
+/// frames of a cassette. Frames read while waiting for the reply to a
+/// request are queued with their receipts.
+pub(crate) struct Connection {
+    live: Option<LiveConnection>,
+    frames: VecDeque<(Message, Receipt)>,
+    replay: VecDeque<Message>,
+    requests: Requests,
+}
+
+impl Connection {
+    pub(crate) fn live(ws: WebSocketStream) -> Self {
+        Self {
+            live: Some(LiveConnection::new(ws, Keepalive::default())),
+            frames: VecDeque::new(),
+            replay: VecDeque::new(),
+            requests: Requests::new(),
+        }
+    }
+
+    pub(crate) fn replay(frames: VecDeque<Message>) -> Self {
+        Self {
+            live: None,
+            frames: VecDeque::new(),
+            replay: frames,
+            requests: Requests::new(),
+        }
+    }
+
+    /// The next frame with its receipt. Replayed frames are stamped as they
+    /// are read, with connection id 0.
+    pub(crate) fn poll_next(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<(Message, Receipt), tungstenite::Error>>> {
+        if let Some(frame) = self.frames.pop_front() {
+            return Poll::Ready(Some(Ok(frame)));
+        }
+        if let Some(message) = self.replay.pop_front() {
+            return Poll::Ready(Some(Ok((message, Receipt::now(0)))));
+        }
+        self.poll_live(cx)
+    }
+
+    /// Send the queued requests and read the live connection.
+    fn poll_live(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<(Message, Receipt), tungstenite::Error>>> {
+        while let Poll::Ready(Some(request)) = self.requests.poll_next(cx) {
+            match self.live.as_mut() {
+                Some(live) => live.queue_request(request),
+                None => {
+                    let _ = request.reply.send(Err(Error::UrlError(
+                        "requests are not supported on replay".to_string(),
+                    )));
+                }
+            }
+        }
+        match self.live.as_mut() {
+            Some(live) => live.poll_next(cx),
+            None => Poll::Ready(None),
+        }
+    }
+
+    pub(crate) fn set_keepalive(&mut self, keepalive: Keepalive) {
+        if let Some(live) = self.live.as_mut() {
+            live.set_keepalive(keepalive);
+        }
+    }
+
+    pub(crate) fn writer(&self) -> Writer {
+        self.requests.writer()
+    }
+
+    /// Send a request and wait for the reply with the same id, returning
+    /// its result. Messages read in the meantime are queued.
+    pub(crate) async fn request(
+        &mut self,
+        method: &'static str,
+        params: Value,
+    ) -> Result<Option<Value>, Error> {
+        let mut result = self.requests.push(method, params);
+        loop {
+            tokio::select! {
+                biased;
+                reply = &mut result => return reply.unwrap_or_else(|_| Err(closed())),
+                next = poll_fn(|cx| self.poll_live(cx)) => match next {
+                    Some(Ok(frame)) => self.frames.push_back(frame),
+                    // The reply may have been read just before the failure.
+                    Some(Err(err)) => return result.try_recv().unwrap_or(Err(err.into())),
+                    None => return result.try_recv().unwrap_or_else(|_| Err(closed())),
+                },
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Reply {
+    id: Option<u64>,
+    #[serde(default)]
+    result: Value,
+    error: Option<ApiError>,
+}
+
+/// The params of a SUBSCRIBE or UNSUBSCRIBE request.
+pub(crate) fn stream_params<T: AsRef<str>>(streams: &[T]) -> Value {
+    Value::from(
+        streams
+            .iter()
+            .map(|stream| stream.as_ref())
+            .collect::<Vec<&str>>(),
+    )
+}
+
+/// The stream names of a LIST_SUBSCRIPTIONS result.
+pub(crate) fn decode_subscriptions(result: Value) -> Result<Vec<String>, Error> {
+    Ok(serde_json::from_value(result)?)
+}
+
+/// Record a frame read from a WebSocket. Failing to record is logged rather
+/// than interrupting the stream.
+pub(crate) fn record_frame(cassette: &Option<Arc<Cassette>>, message: &Message) {
+    if let Some(cassette) = cassette {
+        if let Err(err) = cassette.record_frame(message) {
+            warn!("Failed to record WebSocket frame: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::{accept_async, connect_async};
+
+    async fn connect(keepalive: Keepalive) -> (LiveConnection, WebSocketServer) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            accept_async(stream).await.unwrap()
+        });
+        let (ws, _response) = connect_async(url).await.unwrap();
+        (LiveConnection::new(ws, keepalive), server.await.unwrap())
+    }
+
+    type WebSocketServer = tokio_tungstenite::WebSocketStream<TcpStream>;
+
+    #[tokio::test]
+    async fn test_reply_to_ping() {
+        let (mut connection, mut server) = connect(Keepalive::default()).await;
+        server.send(Message::Ping(vec![1, 2, 3])).await.unwrap();
+        let (message, receipt) = connection.next().await.unwrap().unwrap();
+        assert_eq!(message, Message::Ping(vec![1, 2, 3]));
+        assert_eq!(receipt.connection_id, connection.id());
+        assert_eq!(
+            server.next().await.unwrap().unwrap(),
+            Message::Pong(vec![1, 2, 3])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_keepalive_and_timeout() {
+        let keepalive = Keepalive::default()
+            .ping_interval(Duration::from_millis(20))
+            .timeout(Duration::from_millis(100));
+        let (mut connection, mut server) = connect(keepalive).await;
+        let client = tokio::spawn(async move {
+            let result = connection.next().await;
+            (result, connection.next().await)
+        });
+
+        // The server does not read again, so never answers the pings.
+        assert_eq!(server.next().await.unwrap().unwrap(), Message::Ping(vec![]));
+        let (result, next) = client.await.unwrap();
+        match result {
+            Some(Err(tungstenite::Error::Io(err))) => {
+                assert_eq!(err.kind(), std::io::ErrorKind::TimedOut)
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert!(next.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_requests() {
+        let (live, mut server) = connect(Keepalive::default()).await;
+        let mut connection = Connection {
+            live: Some(live),
+            frames: VecDeque::new(),
+            replay: VecDeque::new(),
+            requests: Requests::new(),
+        };
+        let server = tokio::spawn(async move {
+            let mut requests = vec![];
+            for reply in [
+                r#"{"result":null,"id":1}"#,
+                r#"{"error":{"code":2,"msg":"Invalid request: unknown variant"},"id":2}"#,
+                r#"{"result":["btcusdt@aggTrade"],"id":3}"#,
+            ] {
+                match server.next().await.unwrap().unwrap() {
+                    Message::Text(text) => requests.push(text),
+                    message => panic!("unexpected message {:?}", message),
+                }
+                let event = r#"{"e":"aggTrade"}"#.to_string();
+                server.send(Message::Text(event)).await.unwrap();
+                server.send(Message::Text(reply.to_string())).await.unwrap();
+            }
+            requests
+        });
+
+        connection
+            .request("SUBSCRIBE", stream_params(&["btcusdt@aggTrade"]))
+            .await
+            .unwrap();
+        let err = connection
+            .request("SUBSCRIBE", json!(["BTCUSDT@bogus"]))
+            .await
+            .unwrap_err();
+        assert_eq!(err.api_error().unwrap().code, 2);
+        let result = connection
+            .request("LIST_SUBSCRIPTIONS", json!([]))
+            .await
+            .unwrap();
+        assert_eq!(
+            decode_subscriptions(result.unwrap()).unwrap(),
+            ["btcusdt@aggTrade"]
+        );
+
+        // The events sent before each reply are still delivered, stamped
+        // when they were read rather than when they are returned.
+        let replied = std::time::Instant::now();
+        for _ in 0..3 {
+            let (message, receipt) = poll_fn(|cx| connection.poll_next(cx))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(message, Message::Text(r#"{"e":"aggTrade"}"#.to_string()));
+            assert!(receipt.instant <= replied);
+        }
+        assert_eq!(
+            server.await.unwrap(),
+            [
+                r#"{"id":1,"method":"SUBSCRIBE","params":["btcusdt@aggTrade"]}"#,
+                r#"{"id":2,"method":"SUBSCRIBE","params":["BTCUSDT@bogus"]}"#,
+                r#"{"id":3,"method":"LIST_SUBSCRIPTIONS","params":[]}"#,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_writer() {
+        let (live, mut server) = connect(Keepalive::default()).await;
+        let mut connection = Connection {
+            live: Some(live),
+            frames: VecDeque::new(),
+            replay: VecDeque::new(),
+            requests: Requests::new(),
+        };
+        let writer = connection.writer();
+        let reader = tokio::spawn(async move {
+            let mut messages = vec![];
+            while let Some(Ok((message, _))) = poll_fn(|cx| connection.poll_next(cx)).await {
+                messages.push(message);
+            }
+            messages
+        });
+
+        let server = tokio::spawn(async move {
+            let request = server.next().await.unwrap().unwrap();
+            let event = Message::Text(r#"{"e":"aggTrade"}"#.to_string());
+            server.send(event.clone()).await.unwrap();
+            let reply = r#"{"result":null,"id":1}"#.to_string();
+            server.send(Message::Text(reply)).await.unwrap();
+            server.send(event).await.unwrap();
+            server.close(None).await.unwrap();
+            request
+        });
+
+        writer.subscribe(&["btcusdt@aggTrade"]).await.unwrap();
+        assert_eq!(
+            server.await.unwrap(),
+            Message::Text(
+                r#"{"id":1,"method":"SUBSCRIBE","params":["btcusdt@aggTrade"]}"#.to_string()
+            )
+        );
+        // The reply is not passed to the reader.
+        let messages = reader.await.unwrap();
+        assert_eq!(
+            messages[..2],
+            [
+                Message::Text(r#"{"e":"aggTrade"}"#.to_string()),
+                Message::Text(r#"{"e":"aggTrade"}"#.to_string())
+            ]
+        );
+        assert!(writer.subscribe(&["ethusdt@aggTrade"]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_writer_timeout() {
+        let (live, mut server) = connect(Keepalive::default()).await;
+        let mut connection = Connection {
+            live: Some(live),
+            frames: VecDeque::new(),
+            replay: VecDeque::new(),
+            requests: Requests::new(),
+        };
+        let writer = connection.writer().with_timeout(Duration::from_millis(50));
+        tokio::spawn(
+            async move { while poll_fn(|cx| connection.poll_next(cx)).await.is_some() {} },
+        );
+
+        // The server reads the request but never replies.
+        let err = writer.subscribe(&["btcusdt@aggTrade"]).await.unwrap_err();
+        assert!(err.is_timeout());
+        assert!(matches!(server.next().await, Some(Ok(Message::Text(_)))));
+    }
+}
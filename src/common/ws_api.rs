@@ -0,0 +1,385 @@
+// SPDX-License-Identifier: MIT
+
+//! The WebSocket API, for placing and querying orders over a persistent
+//! connection with lower latency than REST.
+//!
+//! A request is a JSON object with an `id`, which its response is matched
+//! on, a `method` such as `order.place` and `params`. Signed requests are
+//! signed as REST requests are, over their params sorted by name and joined
+//! as a query string. After `session.logon`, which Binance only accepts for
+//! Ed25519 keys, the session authenticates the requests of the connection
+//! and they carry only a timestamp.
+//!
+//! A connection that drops is not reopened, as its session and user data
+//! subscription would be lost with it.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::{mpsc, oneshot};
+use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::warn;
+
+use crate::common::client::{Authentication, DEFAULT_RECV_WINDOW};
+use crate::common::ratelimit::{RateLimitCount, RateLimitState};
+use crate::common::time::{now_millis, ServerTimeResponse, TimeSync};
+use crate::common::websocket::{closed, Keepalive, LiveConnection, WebSocketStream};
+use crate::error::Error;
+
+/// A successful response.
+#[derive(Debug, Clone)]
+pub struct WsApiResponse {
+    pub status: u16,
+    pub result: Value,
+    /// The rate limits counting this request, with their usage after it.
+    pub rate_limits: Vec<RateLimitCount>,
+}
+
+impl WsApiResponse {
+    pub fn decode<T: DeserializeOwned>(self) -> Result<T, Error> {
+        let text = self.result.to_string();
+        serde_json::from_value(self.result).map_err(|error| Error::Decode { error, text })
+    }
+}
+
+#[derive(Deserialize)]
+struct Response {
+    status: u16,
+    #[serde(default)]
+    result: Value,
+    error: Option<Value>,
+    #[serde(default, rename = "rateLimits")]
+    rate_limits: Vec<RateLimitCount>,
+}
+
+struct Command {
+    method: String,
+    params: Value,
+    reply: oneshot::Sender<Result<Value, Error>>,
+}
+
+/// A WebSocket API connection. Requests may be made concurrently, their
+/// responses are read by a task that owns the socket.
+pub struct WsApiConnection {
+    commands: mpsc::UnboundedSender<Command>,
+    events: mpsc::UnboundedReceiver<Value>,
+    auth: Option<Authentication>,
+    time_sync: Arc<TimeSync>,
+    recv_window: u64,
+    logged_on: AtomicBool,
+    rate_limits: Arc<RateLimitState>,
+}
+
+impl WsApiConnection {
+    /// Connect to a WebSocket API endpoint. Must be called within a tokio
+    /// runtime.
+    pub async fn connect(url: &str, authentication: Option<Authentication>) -> Result<Self, Error> {
+        let (ws, _response) = connect_async(url).await?;
+        Ok(Self::new(ws, authentication))
+    }
+
+    fn new(ws: WebSocketStream, authentication: Option<Authentication>) -> Self {
+        let (commands, receiver) = mpsc::unbounded_channel();
+        let (sender, events) = mpsc::unbounded_channel();
+        tokio::spawn(run(
+            LiveConnection::new(ws, Keepalive::default()),
+            receiver,
+            sender,
+        ));
+        Self {
+            commands,
+            events,
+            auth: authentication,
+            time_sync: Arc::new(TimeSync::default()),
+            recv_window: DEFAULT_RECV_WINDOW,
+            logged_on: AtomicBool::new(false),
+            rate_limits: Arc::new(RateLimitState::default()),
+        }
+    }
+
+    pub fn time_sync(&self) -> Arc<TimeSync> {
+        self.time_sync.clone()
+    }
+
+    pub fn set_recv_window(&mut self, recv_window: u64) {
+        self.recv_window = recv_window;
+    }
+
+    /// Rate limit usage as reported by the responses on this connection.
+    pub fn rate_limits(&self) -> Arc<RateLimitState> {
+        self.rate_limits.clone()
+    }
+
+    /// True once `logon` succeeded, and until `logout`.
+    pub fn is_logged_on(&self) -> bool {
+        self.logged_on.load(Ordering::Relaxed)
+    }
+
+    /// Send a request and wait for its response. Error responses are
+    /// returned as the same errors as REST ones.
+    pub async fn request(&self, method: &str, params: Value) -> Result<WsApiResponse, Error> {
+        let (reply, result) = oneshot::channel();
+        self.commands
+            .send(Command {
+                method: method.to_string(),
+                params,
+                reply,
+            })
+            .map_err(|_| closed())?;
+        let value = result.await.map_err(|_| closed())??;
+        let text = value.to_string();
+        let response: Response =
+            serde_json::from_value(value).map_err(|error| Error::Decode { error, text })?;
+        self.rate_limits.record_counts(&response.rate_limits);
+        if let Some(error) = response.error {
+            let status = StatusCode::from_u16(response.status).unwrap_or(StatusCode::BAD_REQUEST);
+            return Err(Error::from_response(
+                status,
+                &HeaderMap::new(),
+                &error.to_string(),
+            ));
+        }
+        Ok(WsApiResponse {
+            status: response.status,
+            result: response.result,
+            rate_limits: response.rate_limits,
+        })
+    }
+
+    /// Send a signed request. `form` holds the params URL encoded, as a
+    /// request type serializes for REST.
+    pub async fn signed_request(&self, method: &str, form: &str) -> Result<WsApiResponse, Error> {
+        let params = self.sign(form, self.is_logged_on())?;
+        self.request(method, params).await
+    }
+
+    /// Add the timestamp and recvWindow to the params of a form and, unless
+    /// the session is logged on, the API key and signature.
+    fn sign(&self, form: &str, logged_on: bool) -> Result<Value, Error> {
+        let pairs: Vec<(String, String)> =
+            serde_urlencoded::from_str(form).map_err(|err| anyhow!("form: {}", err))?;
+        let mut params: BTreeMap<String, Value> = pairs
+            .into_iter()
+            .map(|(name, value)| (name, Value::String(value)))
+            .collect();
+        params.insert("timestamp".into(), self.time_sync.timestamp().into());
+        params.insert("recvWindow".into(), self.recv_window.into());
+        if !logged_on {
+            let auth = self
+                .auth
+                .as_ref()
+                .ok_or_else(|| anyhow!("signed request without authentication"))?;
+            params.insert("apiKey".into(), auth.api_key.clone().into());
+            let signature = auth.signer.sign(&signature_payload(&params))?;
+            params.insert("signature".into(), signature.into());
+        }
+        Ok(Value::Object(params.into_iter().collect()))
+    }
+
+    /// Authenticate the connection with `session.logon`. Requests that
+    /// follow are not signed.
+    pub async fn logon(&self) -> Result<WsApiResponse, Error> {
+        let params = self.sign("", false)?;
+        let response = self.request("session.logon", params).await?;
+        self.logged_on.store(true, Ordering::Relaxed);
+        Ok(response)
+    }
+
+    pub async fn logout(&self) -> Result<WsApiResponse, Error> {
+        let response = self.request("session.logout", json!({})).await?;
+        self.logged_on.store(false, Ordering::Relaxed);
+        Ok(response)
+    }
+
+    pub async fn session_status(&self) -> Result<WsApiResponse, Error> {
+        self.request("session.status", json!({})).await
+    }
+
+    /// Query the server time and update the clock offset used for
+    /// timestamps. Returns the new offset in milliseconds.
+    pub async fn sync_time(&self) -> Result<i64, Error> {
+        let sent = now_millis();
+        let response: ServerTimeResponse = self.request("time", json!({})).await?.decode()?;
+        self.time_sync
+            .update(sent, response.server_time, now_millis());
+        Ok(self.time_sync.offset())
+    }
+
+    /// Receive the user data stream on this connection, see `next_event`.
+    /// The session must be logged on.
+    pub async fn subscribe_user_data(&self) -> Result<WsApiResponse, Error> {
+        self.request("userDataStream.subscribe", json!({})).await
+    }
+
+    pub async fn unsubscribe_user_data(&self) -> Result<WsApiResponse, Error> {
+        self.request("userDataStream.unsubscribe", json!({})).await
+    }
+
+    /// The next user data event, `None` once the connection closed.
+    pub async fn next_event(&mut self) -> Option<Value> {
+        self.events.recv().await
+    }
+}
+
+/// The params, other than the signature, sorted by name and joined as a
+/// query string without URL encoding.
+fn signature_payload(params: &BTreeMap<String, Value>) -> String {
+    params
+        .iter()
+        .map(|(name, value)| match value {
+            Value::String(value) => format!("{}={}", name, value),
+            value => format!("{}={}", name, value),
+        })
+        .collect::<Vec<String>>()
+        .join("&")
+}
+
+/// Write requests and read responses and events until the connection
+/// closes or is dropped. Pending requests fail when it does.
+async fn run(
+    mut live: LiveConnection,
+    mut commands: mpsc::UnboundedReceiver<Command>,
+    events: mpsc::UnboundedSender<Value>,
+) {
+    loop {
+        tokio::select! {
+            command = commands.recv() => match command {
+                Some(command) => live.queue_api_request(&command.method, command.params, command.reply),
+                None => {
+                    let _ = live.close().await;
+                    return;
+                }
+            },
+            message = live.next() => match message {
+                Some(Ok((Message::Text(text), _))) => match serde_json::from_str::<Value>(&text) {
+                    Ok(mut value) => {
+                        if value.get("event").is_some() {
+                            let _ = events.send(value["event"].take());
+                        }
+                    }
+                    Err(err) => warn!("Failed to parse message: {}: {}", err, text),
+                },
+                Some(Ok(_)) => {}
+                Some(Err(err)) => {
+                    warn!("WebSocket API connection failed: {}", err);
+                    return;
+                }
+                None => return,
+            }
+        }
+    }
+}
+
+/// A local WebSocket API server for the tests of the spot and futures APIs.
+#[cfg(test)]
+pub(crate) mod test_support {
+    use std::collections::BTreeMap;
+
+    use futures_util::{SinkExt, StreamExt};
+    use serde_json::{json, Value};
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::Message;
+
+    use super::signature_payload;
+    use crate::common::signer::{HmacSigner, Signer};
+
+    /// Accept a connection, answering requests with the result `respond`
+    /// makes of their method and params, or an error if the signature is
+    /// not by "secret", and pushing `event` on `userDataStream.subscribe`.
+    #[allow(clippy::result_large_err)]
+    pub(crate) async fn serve(respond: fn(&str, &Value) -> Value, event: Value) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            while let Some(Ok(Message::Text(text))) = ws.next().await {
+                let request: Value = serde_json::from_str(&text).unwrap();
+                let method = request["method"].as_str().unwrap_or_default();
+                let mut params: BTreeMap<String, Value> =
+                    serde_json::from_value(request["params"].clone()).unwrap();
+                let rate_limits = json!([{"rateLimitType": "REQUEST_WEIGHT", "interval": "MINUTE",
+                    "intervalNum": 1, "limit": 6000, "count": 4}]);
+                let valid = match params.remove("signature") {
+                    Some(signature) => {
+                        HmacSigner::new("secret")
+                            .sign(&signature_payload(&params))
+                            .unwrap()
+                            == signature
+                    }
+                    None => true,
+                };
+                let response = if valid {
+                    json!({"id": request["id"], "status": 200,
+                        "result": respond(method, &request["params"]),
+                        "rateLimits": rate_limits})
+                } else {
+                    json!({"id": request["id"], "status": 401,
+                        "error": {"code": -1022, "msg": "Signature for this request is not valid."},
+                        "rateLimits": rate_limits})
+                };
+                ws.send(Message::Text(response.to_string())).await.unwrap();
+                if method == "userDataStream.subscribe" {
+                    let event = json!({"subscriptionId": 0, "event": event});
+                    ws.send(Message::Text(event.to_string())).await.unwrap();
+                }
+            }
+        });
+        url
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::test_support::serve;
+    use super::*;
+
+    #[tokio::test]
+    async fn test_requests() {
+        // Answer every request with its params.
+        let url = serve(|_, params| params.clone(), json!({"e": "balanceUpdate"})).await;
+        let auth = Authentication::hmac("key", "secret");
+        let mut connection = WsApiConnection::connect(&url, Some(auth)).await.unwrap();
+
+        let response = connection
+            .signed_request("order.place", "symbol=BTCUSDT&side=BUY&quantity=0.01")
+            .await
+            .unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.result["apiKey"], "key");
+        assert_eq!(response.result["quantity"], "0.01");
+        assert!(response.result["timestamp"].is_i64());
+        assert_eq!(response.rate_limits[0].count, 4);
+        let usage = connection.rate_limits().all_used();
+        assert_eq!(usage.values().copied().collect::<Vec<u64>>(), [4]);
+
+        connection.logon().await.unwrap();
+        assert!(connection.is_logged_on());
+        let response = connection
+            .signed_request("order.status", "symbol=BTCUSDT&orderId=1")
+            .await
+            .unwrap();
+        assert!(response.result.get("signature").is_none());
+        assert!(response.result.get("apiKey").is_none());
+
+        connection.subscribe_user_data().await.unwrap();
+        assert_eq!(connection.next_event().await.unwrap()["e"], "balanceUpdate");
+
+        let params = connection.sign("symbol=BTCUSDT", false).unwrap();
+        let mut params = params.as_object().unwrap().clone();
+        params.insert("signature".into(), "wrong".into());
+        let err = connection
+            .request("order.place", Value::Object(params))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Auth(error) if error.code == -1022));
+    }
+}
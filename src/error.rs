@@ -1,8 +1,13 @@
 // SPDX-License-Identifier: MIT
 
-use thiserror::Error;
+use std::collections::HashMap;
+use std::fmt::Formatter;
+use std::time::Duration;
 
-use crate::futures::client::ApiError;
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -35,4 +40,456 @@ pub enum Error {
 
     #[error("url: {0}")]
     UrlError(String),
+
+    /// A stream name that could not be parsed, or that is not served by the
+    /// market it was used with.
+    #[error("stream name: {0}")]
+    StreamName(String),
+
+    /// A non-success HTTP status without an API error in the body, for
+    /// example from a proxy or load balancer.
+    #[error("http: status {status}: {body}")]
+    Http { status: StatusCode, body: String },
+
+    /// Rate limited (429) or IP banned (418). `retry_after` is how long to
+    /// back off for if the server said.
+    #[error("rate limited: status {status}")]
+    RateLimited {
+        status: StatusCode,
+        retry_after: Option<Duration>,
+        error: Option<ApiError>,
+    },
+
+    /// The request was rejected due to the API key or signature.
+    #[error("auth: {0}")]
+    Auth(ApiError),
+
+    /// The server timed out waiting for the backend.
+    #[error("timeout: {0}")]
+    Timeout(ApiError),
+
+    /// WebSocket connection error, boxed as it is large.
+    #[error("websocket: {0}")]
+    WebSocket(Box<tokio_tungstenite::tungstenite::Error>),
+
+    /// Reading or writing a cassette failed.
+    #[error("io: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+impl From<tokio_tungstenite::tungstenite::Error> for Error {
+    fn from(err: tokio_tungstenite::tungstenite::Error) -> Self {
+        Self::WebSocket(Box::new(err))
+    }
+}
+
+impl Error {
+    /// The API error, if any, carried by this error.
+    pub fn api_error(&self) -> Option<&ApiError> {
+        match self {
+            Self::ApiError(error) | Self::Auth(error) | Self::Timeout(error) => Some(error),
+            Self::RateLimited { error, .. } => error.as_ref(),
+            _ => None,
+        }
+    }
+
+    pub fn api_error_code(&self) -> Option<ApiErrorCode> {
+        self.api_error().map(|error| error.error_code())
+    }
+
+    /// True for 429 and 418 responses.
+    pub fn is_rate_limited(&self) -> bool {
+        matches!(self, Self::RateLimited { .. })
+    }
+
+    /// True if the IP has been banned (418) for ignoring rate limits.
+    pub fn is_banned(&self) -> bool {
+        match self {
+            Self::RateLimited { status, .. } => status.as_u16() == 418,
+            _ => false,
+        }
+    }
+
+    /// True if either the HTTP request or the server timed out.
+    pub fn is_timeout(&self) -> bool {
+        match self {
+            Self::Request(error) => error.is_timeout(),
+            Self::Timeout(_) => true,
+            _ => false,
+        }
+    }
+
+    /// True if the failure is transient and the same request may succeed if
+    /// sent again. Note that the state of an order is unknown after a
+    /// retryable failure. Rate limiting is not considered retryable, see
+    /// `is_rate_limited`.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Request(error) => error.is_connect() || error.is_timeout(),
+            Self::Http { status, .. } => status.is_server_error(),
+            Self::Timeout(_) => true,
+            Self::ApiError(error) => error.error_code().is_retryable(),
+            _ => false,
+        }
+    }
+
+    /// Create an error from a non-success response. The headers give the
+    /// `Retry-After` of a rate limited response.
+    pub fn from_response(status: StatusCode, headers: &HeaderMap, body: &str) -> Self {
+        let api_error = serde_json::from_str::<ApiError>(body).ok();
+        if status == StatusCode::TOO_MANY_REQUESTS || status.as_u16() == 418 {
+            return Self::RateLimited {
+                status,
+                retry_after: crate::common::scheduler::retry_after(headers, body),
+                error: api_error,
+            };
+        }
+        match api_error {
+            Some(error) => match error.error_code() {
+                code if code.is_auth() => Self::Auth(error),
+                ApiErrorCode::Timeout => Self::Timeout(error),
+                _ => Self::ApiError(error),
+            },
+            None => Self::Http {
+                status,
+                body: body.to_string(),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiError {
+    pub code: i64,
+    pub msg: String,
+    #[serde(flatten)]
+    pub other: HashMap<String, serde_json::Value>,
+}
+
+impl ApiError {
+    pub fn error_code(&self) -> ApiErrorCode {
+        ApiErrorCode::from_code(self.code)
+    }
+
+    /// A -1007 timeout for a request the server did not answer in time.
+    pub(crate) fn timeout<S: Into<String>>(msg: S) -> Self {
+        Self {
+            code: -1007,
+            msg: msg.into(),
+            other: HashMap::new(),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "code: {}, msg: {}", self.code, self.msg)
+    }
+}
+
+/// Documented Binance error codes, for spot and futures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ApiErrorCode {
+    /// -1000: An unknown error occurred while processing the request.
+    Unknown,
+    /// -1001: Internal error; unable to process your request.
+    Disconnected,
+    /// -1002: You are not authorized to execute this request.
+    Unauthorized,
+    /// -1003: Too many requests.
+    TooManyRequests,
+    /// -1006: An unexpected response was received from the message bus.
+    UnexpectedResponse,
+    /// -1007: Timeout waiting for response from backend server.
+    Timeout,
+    /// -1008: Server is currently overloaded with other requests.
+    ServerBusy,
+    /// -1014: Unsupported order combination.
+    UnknownOrderComposition,
+    /// -1015: Too many new orders.
+    TooManyOrders,
+    /// -1016: This service is no longer available.
+    ServiceShuttingDown,
+    /// -1020: This operation is not supported.
+    UnsupportedOperation,
+    /// -1021: Timestamp for this request is outside of the recvWindow.
+    InvalidTimestamp,
+    /// -1022: Signature for this request is not valid.
+    InvalidSignature,
+    /// -1100: Illegal characters found in a parameter.
+    IllegalChars,
+    /// -1101: Too many parameters sent for this endpoint.
+    TooManyParameters,
+    /// -1102: A mandatory parameter was not sent, was empty/null, or malformed.
+    MandatoryParamEmptyOrMalformed,
+    /// -1103: An unknown parameter was sent.
+    UnknownParam,
+    /// -1104: Not all sent parameters were read.
+    UnreadParameters,
+    /// -1105: A parameter was empty.
+    ParamEmpty,
+    /// -1106: A parameter was sent when not required.
+    ParamNotRequired,
+    /// -1111: Precision is over the maximum defined for this asset.
+    BadPrecision,
+    /// -1115: Invalid timeInForce.
+    InvalidTimeInForce,
+    /// -1116: Invalid orderType.
+    InvalidOrderType,
+    /// -1117: Invalid side.
+    InvalidSide,
+    /// -1121: Invalid symbol.
+    BadSymbol,
+    /// -1125: This listenKey does not exist.
+    InvalidListenKey,
+    /// -2010: New order rejected.
+    NewOrderRejected,
+    /// -2011: Cancel rejected.
+    CancelRejected,
+    /// -2013: Order does not exist.
+    NoSuchOrder,
+    /// -2014: API-key format invalid.
+    BadApiKeyFormat,
+    /// -2015: Invalid API-key, IP, or permissions for action.
+    RejectedApiKey,
+    /// -2018: Balance is insufficient.
+    BalanceNotSufficient,
+    /// -2019: Margin is insufficient.
+    MarginNotSufficient,
+    /// -2021: Order would immediately trigger.
+    OrderWouldImmediatelyTrigger,
+    /// -2022: ReduceOnly order is rejected.
+    ReduceOnlyRejected,
+    /// -4003: Quantity less than zero.
+    QuantityLessThanZero,
+    /// -4004: Quantity less than min quantity.
+    QuantityBelowMin,
+    /// -4005: Quantity greater than max quantity.
+    QuantityAboveMax,
+    /// -4014: Price not increased by tick size.
+    PriceNotMultipleOfTickSize,
+    /// -4015: Client order id is not valid.
+    InvalidClientOrderId,
+    /// -4016: Price is higher than mark price multiplier cap.
+    PriceAboveMultiplierCap,
+    /// -4023: Qty not increased by step size.
+    QuantityNotMultipleOfStepSize,
+    /// -4024: Price is lower than mark price multiplier floor.
+    PriceBelowMultiplierFloor,
+    /// -4046: No need to change margin type.
+    NoNeedToChangeMarginType,
+    /// -4059: No need to change position side.
+    NoNeedToChangePositionSide,
+    /// -4061: Order's position side does not match user's setting.
+    PositionSideMismatch,
+    /// -4131: The counterparty's best price does not meet the PERCENT_PRICE filter limit.
+    PercentPriceRejected,
+    /// -4164: Order's notional must be no smaller than the minimum.
+    MinNotional,
+    /// -5022: Post only order will be rejected as it would immediately match.
+    PostOnlyRejected,
+    /// Any other code.
+    Other(i64),
+}
+
+impl ApiErrorCode {
+    pub fn from_code(code: i64) -> Self {
+        match code {
+            -1000 => Self::Unknown,
+            -1001 => Self::Disconnected,
+            -1002 => Self::Unauthorized,
+            -1003 => Self::TooManyRequests,
+            -1006 => Self::UnexpectedResponse,
+            -1007 => Self::Timeout,
+            -1008 => Self::ServerBusy,
+            -1014 => Self::UnknownOrderComposition,
+            -1015 => Self::TooManyOrders,
+            -1016 => Self::ServiceShuttingDown,
+            -1020 => Self::UnsupportedOperation,
+            -1021 => Self::InvalidTimestamp,
+            -1022 => Self::InvalidSignature,
+            -1100 => Self::IllegalChars,
+            -1101 => Self::TooManyParameters,
+            -1102 => Self::MandatoryParamEmptyOrMalformed,
+            -1103 => Self::UnknownParam,
+            -1104 => Self::UnreadParameters,
+            -1105 => Self::ParamEmpty,
+            -1106 => Self::ParamNotRequired,
+            -1111 => Self::BadPrecision,
+            -1115 => Self::InvalidTimeInForce,
+            -1116 => Self::InvalidOrderType,
+            -1117 => Self::InvalidSide,
+            -1121 => Self::BadSymbol,
+            -1125 => Self::InvalidListenKey,
+            -2010 => Self::NewOrderRejected,
+            -2011 => Self::CancelRejected,
+            -2013 => Self::NoSuchOrder,
+            -2014 => Self::BadApiKeyFormat,
+            -2015 => Self::RejectedApiKey,
+            -2018 => Self::BalanceNotSufficient,
+            -2019 => Self::MarginNotSufficient,
+            -2021 => Self::OrderWouldImmediatelyTrigger,
+            -2022 => Self::ReduceOnlyRejected,
+            -4003 => Self::QuantityLessThanZero,
+            -4004 => Self::QuantityBelowMin,
+            -4005 => Self::QuantityAboveMax,
+            -4014 => Self::PriceNotMultipleOfTickSize,
+            -4015 => Self::InvalidClientOrderId,
+            -4016 => Self::PriceAboveMultiplierCap,
+            -4023 => Self::QuantityNotMultipleOfStepSize,
+            -4024 => Self::PriceBelowMultiplierFloor,
+            -4046 => Self::NoNeedToChangeMarginType,
+            -4059 => Self::NoNeedToChangePositionSide,
+            -4061 => Self::PositionSideMismatch,
+            -4131 => Self::PercentPriceRejected,
+            -4164 => Self::MinNotional,
+            -5022 => Self::PostOnlyRejected,
+            code => Self::Other(code),
+        }
+    }
+
+    pub fn code(&self) -> i64 {
+        match self {
+            Self::Unknown => -1000,
+            Self::Disconnected => -1001,
+            Self::Unauthorized => -1002,
+            Self::TooManyRequests => -1003,
+            Self::UnexpectedResponse => -1006,
+            Self::Timeout => -1007,
+            Self::ServerBusy => -1008,
+            Self::UnknownOrderComposition => -1014,
+            Self::TooManyOrders => -1015,
+            Self::ServiceShuttingDown => -1016,
+            Self::UnsupportedOperation => -1020,
+            Self::InvalidTimestamp => -1021,
+            Self::InvalidSignature => -1022,
+            Self::IllegalChars => -1100,
+            Self::TooManyParameters => -1101,
+            Self::MandatoryParamEmptyOrMalformed => -1102,
+            Self::UnknownParam => -1103,
+            Self::UnreadParameters => -1104,
+            Self::ParamEmpty => -1105,
+            Self::ParamNotRequired => -1106,
+            Self::BadPrecision => -1111,
+            Self::InvalidTimeInForce => -1115,
+            Self::InvalidOrderType => -1116,
+            Self::InvalidSide => -1117,
+            Self::BadSymbol => -1121,
+            Self::InvalidListenKey => -1125,
+            Self::NewOrderRejected => -2010,
+            Self::CancelRejected => -2011,
+            Self::NoSuchOrder => -2013,
+            Self::BadApiKeyFormat => -2014,
+            Self::RejectedApiKey => -2015,
+            Self::BalanceNotSufficient => -2018,
+            Self::MarginNotSufficient => -2019,
+            Self::OrderWouldImmediatelyTrigger => -2021,
+            Self::ReduceOnlyRejected => -2022,
+            Self::QuantityLessThanZero => -4003,
+            Self::QuantityBelowMin => -4004,
+            Self::QuantityAboveMax => -4005,
+            Self::PriceNotMultipleOfTickSize => -4014,
+            Self::InvalidClientOrderId => -4015,
+            Self::PriceAboveMultiplierCap => -4016,
+            Self::QuantityNotMultipleOfStepSize => -4023,
+            Self::PriceBelowMultiplierFloor => -4024,
+            Self::NoNeedToChangeMarginType => -4046,
+            Self::NoNeedToChangePositionSide => -4059,
+            Self::PositionSideMismatch => -4061,
+            Self::PercentPriceRejected => -4131,
+            Self::MinNotional => -4164,
+            Self::PostOnlyRejected => -5022,
+            Self::Other(code) => *code,
+        }
+    }
+
+    /// Errors caused by the API key or request signature.
+    pub fn is_auth(&self) -> bool {
+        matches!(
+            self,
+            Self::Unauthorized
+                | Self::InvalidSignature
+                | Self::BadApiKeyFormat
+                | Self::RejectedApiKey
+        )
+    }
+
+    /// Errors where the server failed to process the request, and sending it
+    /// again may succeed.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            Self::Unknown
+                | Self::Disconnected
+                | Self::UnexpectedResponse
+                | Self::Timeout
+                | Self::ServerBusy
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_error_from_response() {
+        let body =
+            r#"{"code":-2010,"msg":"Account has insufficient balance for requested action."}"#;
+        let error = Error::from_response(StatusCode::BAD_REQUEST, &HeaderMap::new(), body);
+        assert_eq!(error.api_error_code(), Some(ApiErrorCode::NewOrderRejected));
+        assert!(!error.is_retryable());
+
+        let body = r#"{"code":-1022,"msg":"Signature for this request is not valid."}"#;
+        let error = Error::from_response(StatusCode::BAD_REQUEST, &HeaderMap::new(), body);
+        assert!(matches!(error, Error::Auth(_)));
+
+        let body = r#"{"code":-1007,"msg":"Timeout waiting for response from backend server."}"#;
+        let error = Error::from_response(StatusCode::REQUEST_TIMEOUT, &HeaderMap::new(), body);
+        assert!(matches!(error, Error::Timeout(_)));
+        assert!(error.is_retryable());
+
+        let error = Error::from_response(
+            StatusCode::BAD_GATEWAY,
+            &HeaderMap::new(),
+            "<html>Bad Gateway</html>",
+        );
+        assert!(matches!(error, Error::Http { .. }));
+        assert!(error.is_retryable());
+        assert_eq!(error.api_error_code(), None);
+
+        let body =
+            r#"{"code":-1003,"msg":"Way too many requests; IP banned until 99999999999999."}"#;
+        let error =
+            Error::from_response(StatusCode::from_u16(418).unwrap(), &HeaderMap::new(), body);
+        assert!(error.is_rate_limited());
+        assert!(error.is_banned());
+        assert!(!error.is_retryable());
+        assert_eq!(error.api_error_code(), Some(ApiErrorCode::TooManyRequests));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(reqwest::header::RETRY_AFTER, "7".parse().unwrap());
+        let body = r#"{"code":-1003,"msg":"Too many requests."}"#;
+        match Error::from_response(StatusCode::TOO_MANY_REQUESTS, &headers, body) {
+            Error::RateLimited { retry_after, .. } => {
+                assert_eq!(retry_after, Some(std::time::Duration::from_secs(7)))
+            }
+            error => panic!("unexpected error {:?}", error),
+        }
+    }
+
+    #[test]
+    fn test_api_error_code() {
+        assert_eq!(ApiErrorCode::from_code(-4164), ApiErrorCode::MinNotional);
+        assert_eq!(
+            ApiErrorCode::from_code(-4014),
+            ApiErrorCode::PriceNotMultipleOfTickSize
+        );
+        assert_eq!(ApiErrorCode::PercentPriceRejected.code(), -4131);
+        assert_eq!(ApiErrorCode::from_code(-1021).code(), -1021);
+        assert_eq!(ApiErrorCode::from_code(-9999), ApiErrorCode::Other(-9999));
+        assert_eq!(ApiErrorCode::Other(-9999).code(), -9999);
+    }
 }
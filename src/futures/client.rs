@@ -3,65 +3,108 @@
 // Copyright (C) 2021-2022 Cranky Kernel
 
 use std::collections::HashMap;
-use std::fmt::Formatter;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::Arc;
 
-use hmac::{Mac, NewMac};
-use reqwest::StatusCode;
+use reqwest::Method;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
+use crate::common::cassette::Cassette;
 use crate::common::client::{Authentication, ListenKeyResponse};
+use crate::common::environment::Environment;
+use crate::common::listenkey::ListenKeyManager;
+use crate::common::ratelimit::RateLimitState;
+use crate::common::retry::RetryPolicy;
+use crate::common::time::{ServerTimeResponse, TimeSync};
+use crate::common::transport::{HttpResponse, HttpTransport};
+pub use crate::error::ApiError;
+use crate::error::ApiErrorCode;
 use crate::parsers::*;
 use crate::spot::client::{ExchangeInfoResponse, OrderSide, OrderType};
-use crate::types::{BookTickerResponse, CancelOrder, TimeInForce};
+use crate::types::{BookTickerResponse, CancelOrder, QueryOrder, TimeInForce};
 use crate::Error;
 
 pub const API_ROOT: &str = "https://fapi.binance.com";
+pub const TESTNET_API_ROOT: &str = "https://testnet.binancefuture.com";
+
+/// The endpoint that creates, keeps alive and closes listen keys.
+const LISTEN_KEY_ENDPOINT: &str = "/fapi/v1/listenKey";
 
 #[derive(Clone)]
 pub struct Client {
-    auth: Option<Authentication>,
     client: crate::common::client::Client,
+    environment: Environment,
 }
 
 impl Client {
     pub fn new(authentication: Option<Authentication>) -> Self {
         Self {
-            auth: authentication.clone(),
-            client: crate::common::client::Client::new(API_ROOT, authentication),
+            client: crate::common::client::Client::new(API_ROOT, authentication)
+                .with_time_endpoint("/fapi/v1/time"),
+            environment: Environment::Mainnet,
         }
     }
 
+    /// Create a client for the given environment. Fails if the environment
+    /// does not have a futures market (Binance.US).
+    pub fn with_environment(
+        environment: &Environment,
+        authentication: Option<Authentication>,
+    ) -> Result<Self, Error> {
+        let base_url = environment.futures_rest_url().ok_or_else(|| {
+            Error::UrlError(format!("no futures api for environment {:?}", environment))
+        })?;
+        Ok(Self {
+            client: crate::common::client::Client::new(base_url, authentication)
+                .with_time_endpoint("/fapi/v1/time"),
+            environment: environment.clone(),
+        })
+    }
+
+    /// The environment this client talks to.
+    pub fn environment(&self) -> &Environment {
+        &self.environment
+    }
+
+    /// Set the recvWindow in milliseconds used for all signed requests.
+    pub fn set_recv_window(&mut self, recv_window: u64) {
+        self.client.set_recv_window(recv_window);
+    }
+
+    /// A copy of this client using a different recvWindow, for use on a
+    /// single request. The clock offset is shared with this client.
+    pub fn with_recv_window(&self, recv_window: u64) -> Self {
+        let mut client = self.clone();
+        client.set_recv_window(recv_window);
+        client
+    }
+
+    /// Synchronise with the server clock, returning the offset in
+    /// milliseconds. Once synced the offset is periodically refreshed.
+    pub async fn sync_time(&self) -> Result<i64, Error> {
+        self.client.sync_time().await
+    }
+
+    /// The clock offset kept by `sync_time`, for example for a
+    /// `LatencyTracker`.
+    pub fn time_sync(&self) -> Arc<TimeSync> {
+        self.client.time_sync()
+    }
+
+    pub async fn get_server_time(&self) -> Result<ServerTimeResponse, Error> {
+        self.get("/fapi/v1/time", ()).await
+    }
+
     pub fn compute_signature(&self, request_body: &str) -> anyhow::Result<String> {
-        let mut macr = hmac::Hmac::<sha2::Sha256>::new_varkey(
-            self.auth.as_ref().unwrap().api_secret.as_bytes(),
-        )
-        .map_err(|err| anyhow::anyhow!("hmac: {:?}", err))?;
-        macr.update(request_body.as_bytes());
-        let signature = macr.finalize();
-        Ok(format!("{:x}", signature.into_bytes()))
+        self.client.compute_signature(request_body)
     }
 
     pub fn sign_form(&self, form: Option<&str>) -> anyhow::Result<String> {
-        let form = form.unwrap_or("");
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_millis();
-        let form = format!("{}&recvWindow=1000&timestamp={}", form, timestamp);
-        let signature = self.compute_signature(&form)?;
-        let form = format!("{}&signature={}", &form, &signature);
-        Ok(form)
+        self.client.sign_form(form)
     }
 
     pub fn headers(&self) -> anyhow::Result<reqwest::header::HeaderMap> {
-        let mut headers = reqwest::header::HeaderMap::new();
-        if let Some(auth) = &self.auth {
-            headers.insert("X-MBX-APIKEY", auth.api_key.parse()?);
-        }
-        headers.insert("Content-Type", "application/x-www-form-urlencoded".parse()?);
-        Ok(headers)
+        self.client.headers()
     }
 
     /// Public (unauthenticated) get.
@@ -70,17 +113,12 @@ impl Client {
         F: Serialize,
         T: DeserializeOwned,
     {
-        let url = format!("{}{}", API_ROOT, endpoint);
+        let query_string = serde_urlencoded::to_string(query_string)?;
         let response = self
             .client
-            .client
-            .get(url)
-            .query(&query_string)
-            .send()
+            .send(Method::GET, endpoint, Some(&query_string))
             .await?;
-        let code = response.status();
-        let body = response.text().await?;
-        self.decode_response(code, &body)
+        self.decode_response(&response)
     }
 
     /// Private/user (authenticated) get.
@@ -90,37 +128,18 @@ impl Client {
         form: F,
     ) -> Result<T, Error> {
         let form = serde_urlencoded::to_string(form)?;
-        let form = self.sign_form(Some(&form))?;
-        let url = format!("{}{}?{}", API_ROOT, endpoint, &form);
-        let request = self.client.client.get(url).headers(self.headers()?);
-        let response = request.send().await?;
-        let code = response.status();
-        let body = response.text().await?;
-        self.decode_response(code, &body)
+        let response = self
+            .client
+            .send_signed(Method::GET, endpoint, &form)
+            .await?;
+        self.decode_response(&response)
     }
 
-    pub fn decode_response<T>(&self, status: StatusCode, body: &str) -> Result<T, Error>
+    pub fn decode_response<T>(&self, response: &HttpResponse) -> Result<T, Error>
     where
         T: DeserializeOwned,
     {
-        match status {
-            StatusCode::OK => serde_json::from_str(body).map_err(|error| Error::Decode {
-                error,
-                text: body.to_string(),
-            }),
-            _ => {
-                // Error, attempt to decode the response, if that fails fake it out.
-                if let Ok(error) = serde_json::from_str::<ApiError>(body) {
-                    Err(Error::ApiError(error))
-                } else {
-                    Err(Error::ApiError(ApiError {
-                        code: status.as_u16() as i64,
-                        msg: body.to_string(),
-                        other: HashMap::new(),
-                    }))
-                }
-            }
-        }
+        crate::common::client::decode_response(response)
     }
 
     pub async fn get_open_orders<
@@ -137,34 +156,22 @@ impl Client {
 
     pub async fn cancel_order(&self, request: &CancelOrder) -> Result<CancelOrderResponse, Error> {
         let endpoint = "/fapi/v1/order";
-        let _form = serde_urlencoded::to_string(request)?;
-        let _form = self.client.sign_form(Some(&_form))?;
-        let url = self.client.url2(endpoint, Some(&_form))?;
-        let request = self
-            .client
+        let form = serde_urlencoded::to_string(request)?;
+        let response = self
             .client
-            .delete(url)
-            .headers(self.client.headers()?);
-        let response = request.send().await?;
-        let code = response.status();
-        let body = response.text().await?;
-        self.decode_response(code, &body)
+            .send_signed(Method::DELETE, endpoint, &form)
+            .await?;
+        self.decode_response(&response)
     }
 
     pub async fn cancel_all_open_orders(&self, symbol: &str) -> Result<serde_json::Value, Error> {
         let endpoint = "/fapi/v1/allOpenOrders";
-        let form = serde_urlencoded::to_string(&[("symbol", symbol)])?;
-        let form = self.client.sign_form(Some(&form))?;
-        let url = self.client.url2(endpoint, Some(&form))?;
-        let request = self
-            .client
+        let form = serde_urlencoded::to_string([("symbol", symbol)])?;
+        let response = self
             .client
-            .delete(url)
-            .headers(self.client.headers()?);
-        let response = request.send().await?;
-        let code = response.status();
-        let body = response.text().await?;
-        self.decode_response(code, &body)
+            .send_signed(Method::DELETE, endpoint, &form)
+            .await?;
+        self.decode_response(&response)
     }
 
     pub async fn get_klines<S: AsRef<str>, I: AsRef<str>>(
@@ -184,36 +191,108 @@ impl Client {
         self.get(endpoint, form).await
     }
 
+    /// Get the exchange info. The advertised rate limits are recorded so
+    /// they can be compared with the usage reported by `rate_limits`.
     pub async fn get_exchange_info(&self) -> Result<ExchangeInfoResponse, Error> {
         let endpoint = "/fapi/v1/exchangeInfo";
-        self.get(endpoint, ()).await
+        let info: ExchangeInfoResponse = self.get(endpoint, ()).await?;
+        self.client.set_rate_limits(info.rateLimits.clone());
+        Ok(info)
+    }
+
+    /// Throttle requests to stay under the rate limits, waiting rather than
+    /// sending a request that would breach them. The limits are loaded by
+    /// `get_exchange_info`.
+    pub fn enable_scheduler(&mut self) {
+        self.client.enable_scheduler();
+    }
+
+    /// Set the policy for retrying requests that failed for transient
+    /// reasons. GET requests are retried, see `post_new_order` for orders.
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.client.set_retry_policy(retry_policy);
+    }
+
+    /// Send requests through another transport, for example a
+    /// `MockTransport` to test without the network.
+    pub fn set_transport(&mut self, transport: Arc<dyn HttpTransport>) {
+        self.client.set_transport(transport);
+    }
+
+    /// Record all requests and responses to a cassette. Pass a
+    /// `ReplayTransport` to `set_transport` to replay them.
+    pub fn record(&mut self, cassette: Arc<Cassette>) {
+        self.client.record(cassette);
+    }
+
+    /// Rate limit usage reported by responses to this client.
+    pub fn rate_limits(&self) -> Arc<RateLimitState> {
+        self.client.rate_limits()
     }
 
     pub async fn post_listenkey(&self) -> Result<ListenKeyResponse, Error> {
-        let endpoint = "/fapi/v1/listenKey";
-        self.client.post_listenkey(endpoint).await
+        self.client.post_listenkey(LISTEN_KEY_ENDPOINT).await
+    }
+
+    pub async fn put_listenkey(&self, listen_key: &str) -> Result<(), Error> {
+        self.client
+            .put_listenkey(LISTEN_KEY_ENDPOINT, listen_key)
+            .await
+    }
+
+    pub async fn delete_listenkey(&self, listen_key: &str) -> Result<(), Error> {
+        self.client
+            .delete_listenkey(LISTEN_KEY_ENDPOINT, listen_key)
+            .await
     }
 
-    pub async fn put_listenkey(&self) -> Result<ListenKeyResponse, Error> {
-        let endpoint = "/fapi/v1/listenKey";
-        self.client.put_listenkey(endpoint).await
+    /// A manager that keeps the listen key of the user data stream alive.
+    pub fn listen_key_manager(&self) -> ListenKeyManager {
+        ListenKeyManager::new(self.client.clone(), LISTEN_KEY_ENDPOINT)
     }
 
+    /// Place a new order.
+    ///
+    /// Orders with a client order ID are retried under the retry policy, but
+    /// only after looking the order up to make sure the failed attempt did
+    /// not place it. Orders without a client order ID are never retried.
     pub async fn post_new_order(&self, request: &NewOrder) -> Result<OrderResponse, Error> {
         let endpoint = "/fapi/v1/order";
-        let form = serde_urlencoded::to_string(&request)?;
-        let form = self.client.sign_form(Some(&form))?;
-        let response = self
-            .client
-            .client
-            .post(&format!("{}{}", API_ROOT, endpoint))
-            .headers(self.client.headers()?)
-            .body(form)
-            .send()
-            .await?;
-        let code = response.status();
-        let body = response.text().await?;
-        self.decode_response(code, &body)
+        let form = serde_urlencoded::to_string(request)?;
+        let policy = self.client.retry_policy();
+        let mut attempt = 1;
+        loop {
+            let result = self.client.send_signed(Method::POST, endpoint, &form).await;
+            let query = match (&request.symbol, &request.client_order_id) {
+                (Some(symbol), Some(client_order_id))
+                    if attempt < policy.max_attempts && policy.should_retry(&result) =>
+                {
+                    QueryOrder::by_client_order_id(symbol, client_order_id)
+                }
+                _ => {
+                    let response = result?;
+                    return self.decode_response(&response);
+                }
+            };
+            tokio::time::sleep(policy.backoff_for(attempt)).await;
+            match self.get_order(&query).await {
+                Ok(order) => return Ok(order.into()),
+                Err(err) if err.api_error_code() == Some(ApiErrorCode::NoSuchOrder) => {}
+                Err(_) => {
+                    // The state of the order is unknown, don't risk placing
+                    // it twice.
+                    let response = result?;
+                    return self.decode_response(&response);
+                }
+            }
+            attempt += 1;
+        }
+    }
+
+    /// Query an order by order ID or client order ID.
+    pub async fn get_order(&self, request: &QueryOrder) -> Result<OpenOrder, Error> {
+        let endpoint = "/fapi/v1/order";
+        self.authenticated_get(endpoint, request).await
     }
 
     pub async fn get_positions(&self, symbol: Option<&str>) -> Result<Vec<PositionEntry>, Error> {
@@ -229,13 +308,7 @@ impl Client {
 
     pub async fn book_ticker(&self, symbol: &str) -> Result<BookTickerResponse, Error> {
         let endpoint = "/fapi/v1/ticker/bookTicker";
-        let url = self
-            .client
-            .url2(endpoint, Some(&format!("symbol={}", symbol)))?;
-        let response = self.client.client.get(url).send().await?;
-        let status = response.status();
-        let body = response.text().await?;
-        self.decode_response(status, &body)
+        self.get(endpoint, [("symbol", symbol)]).await
     }
 
     pub async fn get_position_mode(&self) -> Result<PositionModeResponse, Error> {
@@ -352,22 +425,6 @@ where
     Ok(Some(val))
 }
 
-#[derive(Debug, Deserialize)]
-pub struct ApiError {
-    pub code: i64,
-    pub msg: String,
-    #[serde(flatten)]
-    pub other: HashMap<String, serde_json::Value>,
-}
-
-impl std::error::Error for ApiError {}
-
-impl std::fmt::Display for ApiError {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "code: {}, msg: {}", self.code, self.msg)
-    }
-}
-
 #[derive(Debug, Deserialize)]
 pub struct CancelOrderResponse {
     #[serde(rename = "orderId")]
@@ -585,6 +642,37 @@ pub struct OrderResponse {
     pub other: HashMap<String, serde_json::Value>,
 }
 
+impl From<OpenOrder> for OrderResponse {
+    fn from(order: OpenOrder) -> Self {
+        Self {
+            client_order_id: order.client_order_id,
+            cum_qty: order.executed_qty,
+            cum_quote: order.cum_quote,
+            executed_qty: order.executed_qty,
+            order_id: order.order_id,
+            avg_price: order.avg_price,
+            orig_qty: order.orig_qty,
+            price: order.price,
+            reduce_only: order.reduce_only,
+            side: order.side,
+            position_side: order.position_side,
+            status: order.status,
+            stop_price: order.stop_price,
+            close_position: order.close_position,
+            symbol: order.symbol,
+            time_in_force: order.time_in_force,
+            order_type: order.xtype,
+            orig_type: order.orig_type,
+            activate_price: order.active_price,
+            price_rate: order.price_rate,
+            update_time: order.update_time,
+            working_type: order.working_type,
+            price_protect: order.price_protect,
+            other: order.other,
+        }
+    }
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct Account {
     #[serde(rename = "totalMarginBalance", deserialize_with = "parse_f64_string")]
@@ -594,6 +682,177 @@ pub struct Account {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::common::transport::test_support::{assert_signed, mock_client, API_KEY};
+    use crate::common::transport::MockTransport;
+    use reqwest::StatusCode;
+
+    #[tokio::test]
+    async fn test_post_new_order_request() {
+        let (client, transport) = mock_client(Client::new, Client::set_transport);
+        transport.push(
+            StatusCode::OK,
+            r#"{"orderId":1544589222,"symbol":"BTCUSDT","status":"NEW",
+                "clientOrderId":"myOrder1","price":"30000","avgPrice":"0.0000",
+                "origQty":"0.01","executedQty":"0","cumQty":"0","cumQuote":"0",
+                "timeInForce":"GTC","type":"LIMIT","reduceOnly":false,
+                "closePosition":false,"side":"BUY","positionSide":"BOTH",
+                "stopPrice":"0","workingType":"CONTRACT_PRICE","priceProtect":false,
+                "origType":"LIMIT","updateTime":1629929626599}"#,
+        );
+        let order = NewOrder::new_limit_buy("btcusdt", 30000.0, 0.01)
+            .client_order_id("myOrder1".to_string());
+        let response = client.post_new_order(&order).await.unwrap();
+        assert_eq!(response.order_id, 1544589222);
+
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, Method::POST);
+        assert_eq!(
+            requests[0].url.as_str(),
+            "https://fapi.binance.com/fapi/v1/order"
+        );
+        assert_eq!(requests[0].headers["X-MBX-APIKEY"], API_KEY);
+        assert_signed(
+            requests[0].body.as_deref().unwrap(),
+            "symbol=BTCUSDT&side=BUY&type=LIMIT&quantity=000000.01&price=000030000&timeInForce=GTC&newClientOrderId=myOrder1",
+        );
+    }
+
+    /// A client retrying at once.
+    fn retrying_client() -> (Client, Arc<MockTransport>) {
+        let (mut client, transport) = mock_client(Client::new, Client::set_transport);
+        client.set_retry_policy(
+            RetryPolicy::default().backoff(std::time::Duration::ZERO, std::time::Duration::ZERO),
+        );
+        (client, transport)
+    }
+
+    #[tokio::test]
+    async fn test_post_new_order_found_after_server_error() {
+        let (client, transport) = retrying_client();
+        transport.push(StatusCode::SERVICE_UNAVAILABLE, "");
+        transport.push(
+            StatusCode::OK,
+            r#"{"avgPrice":"0.00000","clientOrderId":"myOrder1","cumQuote":"0",
+                "executedQty":"0","orderId":1544589222,"origQty":"0.01","origType":"LIMIT",
+                "price":"30000","reduceOnly":false,"side":"BUY","positionSide":"BOTH",
+                "status":"NEW","stopPrice":"0","closePosition":false,"symbol":"BTCUSDT",
+                "time":1629929626599,"timeInForce":"GTC","type":"LIMIT",
+                "updateTime":1629929626599,"workingType":"CONTRACT_PRICE","priceProtect":false}"#,
+        );
+        let order = NewOrder::new_limit_buy("btcusdt", 30000.0, 0.01)
+            .client_order_id("myOrder1".to_string());
+        let response = client.post_new_order(&order).await.unwrap();
+        assert_eq!(response.order_id, 1544589222);
+
+        // The order was placed, so it is not sent again.
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].method, Method::GET);
+        assert_signed(
+            requests[1].query(),
+            "symbol=BTCUSDT&origClientOrderId=myOrder1",
+        );
+    }
+
+    #[tokio::test]
+    async fn test_post_new_order_resent_when_not_found() {
+        let (client, transport) = retrying_client();
+        transport.push(StatusCode::SERVICE_UNAVAILABLE, "");
+        transport.push(
+            StatusCode::BAD_REQUEST,
+            r#"{"code":-2013,"msg":"Order does not exist."}"#,
+        );
+        transport.push(
+            StatusCode::OK,
+            r#"{"orderId":1544589222,"symbol":"BTCUSDT","status":"NEW",
+                "clientOrderId":"myOrder1","price":"30000","avgPrice":"0.0000",
+                "origQty":"0.01","executedQty":"0","cumQty":"0","cumQuote":"0",
+                "timeInForce":"GTC","type":"LIMIT","reduceOnly":false,
+                "closePosition":false,"side":"BUY","positionSide":"BOTH",
+                "stopPrice":"0","workingType":"CONTRACT_PRICE","priceProtect":false,
+                "origType":"LIMIT","updateTime":1629929626599}"#,
+        );
+        let order = NewOrder::new_limit_buy("btcusdt", 30000.0, 0.01)
+            .client_order_id("myOrder1".to_string());
+        let response = client.post_new_order(&order).await.unwrap();
+        assert_eq!(response.order_id, 1544589222);
+
+        let methods: Vec<Method> = transport
+            .requests()
+            .into_iter()
+            .map(|request| request.method)
+            .collect();
+        assert_eq!(methods, [Method::POST, Method::GET, Method::POST]);
+        assert_eq!(transport.remaining(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_post_new_order_without_client_order_id_not_retried() {
+        let (client, transport) = retrying_client();
+        transport.push(StatusCode::SERVICE_UNAVAILABLE, "");
+        let order = NewOrder::new_limit_buy("btcusdt", 30000.0, 0.01);
+        assert!(client.post_new_order(&order).await.is_err());
+        assert_eq!(transport.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_order_request() {
+        let (client, transport) = mock_client(Client::new, Client::set_transport);
+        transport.push(
+            StatusCode::BAD_REQUEST,
+            r#"{"code":-2011,"msg":"Unknown order sent."}"#,
+        );
+        let err = client
+            .cancel_order(&CancelOrder::by_order_id("BTCUSDT", 42))
+            .await
+            .unwrap_err();
+        assert_eq!(err.api_error_code(), Some(ApiErrorCode::CancelRejected));
+
+        let requests = transport.requests();
+        assert_eq!(requests[0].method, Method::DELETE);
+        assert_eq!(requests[0].url.path(), "/fapi/v1/order");
+        assert_signed(requests[0].query(), "symbol=BTCUSDT&orderId=42");
+    }
+
+    #[tokio::test]
+    async fn test_resync_on_invalid_timestamp() {
+        let (client, transport) = mock_client(Client::new, Client::set_transport);
+        transport.push(
+            StatusCode::BAD_REQUEST,
+            r#"{"code":-1021,"msg":"Timestamp for this request is outside of the recvWindow."}"#,
+        );
+        transport.push(StatusCode::OK, r#"{"serverTime":1499827319559}"#);
+        transport.push(StatusCode::OK, r#"{"dualSidePosition":true}"#);
+        assert!(client.is_hedge_mode().await.unwrap());
+
+        let requests = transport.requests();
+        let paths: Vec<&str> = requests.iter().map(|r| r.url.path()).collect();
+        assert_eq!(
+            paths,
+            [
+                "/fapi/v1/positionSide/dual",
+                "/fapi/v1/time",
+                "/fapi/v1/positionSide/dual"
+            ]
+        );
+        assert_signed(requests[2].query(), "");
+        assert_eq!(transport.remaining(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_timestamp_kept_when_resync_fails() {
+        let (client, transport) = mock_client(Client::new, Client::set_transport);
+        transport.push(
+            StatusCode::BAD_REQUEST,
+            r#"{"code":-1021,"msg":"Timestamp for this request is outside of the recvWindow."}"#,
+        );
+        transport.push(StatusCode::OK, "{}");
+        let err = client.is_hedge_mode().await.unwrap_err();
+        assert_eq!(err.api_error_code(), Some(ApiErrorCode::InvalidTimestamp));
+        assert_eq!(transport.requests().len(), 2);
+        assert_eq!(transport.remaining(), 0);
+    }
 
     #[test]
     fn test_decode_cancel_order_response() {
@@ -0,0 +1,298 @@
+// SPDX-License-Identifier: MIT
+
+//! Single pass decoding of futures events.
+//!
+//! `Event::decode_value` parses a frame into a `Value`, looks up the event
+//! type and then deserializes the `Value` again into the event. Binance
+//! sends the stream name first in combined stream envelopes and the event
+//! type first in events, so here the event type is read as the first field
+//! and the rest of the event is deserialized straight from the text.
+//! Frames laid out otherwise, unknown events and frames that fail to decode
+//! fall back to `decode_value`, so both paths give the same result.
+
+use std::fmt;
+
+use serde::de::value::{BorrowedStrDeserializer, MapAccessDeserializer};
+use serde::de::{DeserializeSeed, Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde::Deserialize;
+
+use crate::futures::websocket::{is_partial_depth, Event, LiquidationEvent};
+
+pub(crate) fn decode_text(text: &str) -> Result<Option<Event>, serde_json::Error> {
+    match serde_json::from_str(text) {
+        Ok(Fast::Event(event)) => Ok(Some(event)),
+        Ok(Fast::Fallback) | Err(_) => Event::decode_value(serde_json::from_str(text)?),
+    }
+}
+
+#[allow(clippy::large_enum_variant)]
+enum Fast {
+    Event(Event),
+    /// Not decoded here, use `Event::decode_value`.
+    Fallback,
+}
+
+impl<'de> Deserialize<'de> for Fast {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        d.deserialize_any(FrameVisitor)
+    }
+}
+
+/// A whole frame: an event, an array of events or a combined stream
+/// envelope.
+struct FrameVisitor;
+
+impl<'de> Visitor<'de> for FrameVisitor {
+    type Value = Fast;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an event")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Fast, A::Error> {
+        match map.next_key::<&str>()? {
+            Some("e") => event(map, None),
+            Some("stream") => {
+                let stream: &str = map.next_value()?;
+                let fast = match map.next_key::<&str>()? {
+                    Some("data") => map.next_value_seed(DataSeed {
+                        stream: Some(stream),
+                    })?,
+                    Some(_) => {
+                        map.next_value::<IgnoredAny>()?;
+                        Fast::Fallback
+                    }
+                    None => Fast::Fallback,
+                };
+                skip_map(map)?;
+                Ok(fast)
+            }
+            Some(_) => {
+                map.next_value::<IgnoredAny>()?;
+                skip_map(map)?;
+                Ok(Fast::Fallback)
+            }
+            None => Ok(Fast::Fallback),
+        }
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Fast, A::Error> {
+        events(seq)
+    }
+}
+
+/// The `data` of a combined stream envelope.
+struct DataSeed<'a> {
+    stream: Option<&'a str>,
+}
+
+impl<'de, 'a> DeserializeSeed<'de> for DataSeed<'a> {
+    type Value = Fast;
+
+    fn deserialize<D: Deserializer<'de>>(self, d: D) -> Result<Fast, D::Error> {
+        d.deserialize_any(self)
+    }
+}
+
+impl<'de, 'a> Visitor<'de> for DataSeed<'a> {
+    type Value = Fast;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an event")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Fast, A::Error> {
+        match map.next_key::<&str>()? {
+            Some("e") => event(map, self.stream),
+            Some(_) => {
+                map.next_value::<IgnoredAny>()?;
+                skip_map(map)?;
+                Ok(Fast::Fallback)
+            }
+            None => Ok(Fast::Fallback),
+        }
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Fast, A::Error> {
+        events(seq)
+    }
+}
+
+/// The entries of an event after its `e` key, with the `e` entry put back
+/// in front for the event's own `Deserialize`.
+struct Replay<'de, A> {
+    key: bool,
+    value: Option<&'de str>,
+    map: A,
+}
+
+impl<'de, A: MapAccess<'de>> MapAccess<'de> for Replay<'de, A> {
+    type Error = A::Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, A::Error> {
+        if self.key {
+            self.key = false;
+            return seed
+                .deserialize(BorrowedStrDeserializer::new("e"))
+                .map(Some);
+        }
+        self.map.next_key_seed(seed)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, A::Error> {
+        match self.value.take() {
+            Some(value) => seed.deserialize(BorrowedStrDeserializer::new(value)),
+            None => self.map.next_value_seed(seed),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        self.map.size_hint().map(|size| size + self.key as usize)
+    }
+}
+
+/// A `forceOrder` event, of which only the order is kept.
+#[derive(Deserialize)]
+struct ForceOrder {
+    o: LiquidationEvent,
+}
+
+/// Decode an event whose `e` key has just been read.
+fn event<'de, A: MapAccess<'de>>(mut map: A, stream: Option<&str>) -> Result<Fast, A::Error> {
+    let e: &'de str = map.next_value()?;
+    let rest = Replay {
+        key: true,
+        value: Some(e),
+        map,
+    };
+    fn de<'de, T: Deserialize<'de>, A: MapAccess<'de>>(
+        rest: Replay<'de, A>,
+    ) -> Result<T, A::Error> {
+        T::deserialize(MapAccessDeserializer::new(rest))
+    }
+    let event = match e {
+        "aggTrade" => Event::AggTrade(de(rest)?),
+        "markPriceUpdate" => Event::MarkPrice(de(rest)?),
+        "depthUpdate" if stream.is_some_and(is_partial_depth) => Event::PartialDepth(de(rest)?),
+        "depthUpdate" => Event::DepthUpdate(de(rest)?),
+        "bookTicker" => Event::BookTicker(de(rest)?),
+        "kline" => Event::Kline(de(rest)?),
+        "continuous_kline" => Event::ContinuousKline(de(rest)?),
+        "24hrTicker" => Event::Ticker(de(rest)?),
+        "24hrMiniTicker" => Event::MiniTicker(de(rest)?),
+        "forceOrder" => Event::LiquidationEvent(de::<ForceOrder, A>(rest)?.o),
+        "indexPriceKline" => Event::IndexPriceKline(de(rest)?),
+        "markPriceKline" => Event::MarkPriceKline(de(rest)?),
+        "contractInfo" => Event::ContractInfo(de(rest)?),
+        "compositeIndex" => Event::CompositeIndex(de(rest)?),
+        "assetIndexUpdate" => Event::AssetIndex(de(rest)?),
+        "ORDER_TRADE_UPDATE" => Event::OrderTradeUpdate(de(rest)?),
+        "TRADE_LITE" => Event::TradeLite(de(rest)?),
+        "ACCOUNT_UPDATE" => Event::AccountUpdate(de(rest)?),
+        _ => {
+            skip_map(rest.map)?;
+            return Ok(Fast::Fallback);
+        }
+    };
+    Ok(Fast::Event(event))
+}
+
+/// An array of events, all of the type of the first.
+fn events<'de, A: SeqAccess<'de>>(mut seq: A) -> Result<Fast, A::Error> {
+    let first = match seq.next_element_seed(DataSeed { stream: None })? {
+        Some(Fast::Event(first)) => first,
+        Some(Fast::Fallback) => {
+            while seq.next_element::<IgnoredAny>()?.is_some() {}
+            return Ok(Fast::Fallback);
+        }
+        None => return Ok(Fast::Fallback),
+    };
+    let events = match first {
+        Event::Ticker(first) => Event::Tickers(rest(first, seq)?),
+        Event::MiniTicker(first) => Event::MiniTickers(rest(first, seq)?),
+        Event::MarkPrice(first) => Event::MarkPrices(rest(first, seq)?),
+        Event::AssetIndex(first) => Event::AssetIndexes(rest(first, seq)?),
+        _ => {
+            while seq.next_element::<IgnoredAny>()?.is_some() {}
+            return Ok(Fast::Fallback);
+        }
+    };
+    Ok(Fast::Event(events))
+}
+
+fn rest<'de, T: Deserialize<'de>, A: SeqAccess<'de>>(
+    first: T,
+    mut seq: A,
+) -> Result<Vec<T>, A::Error> {
+    let mut items = Vec::with_capacity(seq.size_hint().unwrap_or(0) + 1);
+    items.push(first);
+    while let Some(item) = seq.next_element()? {
+        items.push(item);
+    }
+    Ok(items)
+}
+
+fn skip_map<'de, A: MapAccess<'de>>(mut map: A) -> Result<(), A::Error> {
+    while map.next_entry::<IgnoredAny, IgnoredAny>()?.is_some() {}
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Decode through both paths and compare the debug output, as events do
+    /// not all implement `PartialEq`.
+    fn assert_same(text: &str) {
+        let fast = format!("{:?}", decode_text(text).unwrap());
+        let slow = format!(
+            "{:?}",
+            Event::decode_value(serde_json::from_str(text).unwrap()).unwrap()
+        );
+        assert_eq!(fast, slow, "{}", text);
+    }
+
+    #[test]
+    fn test_same_as_decode_value() {
+        let agg_trade = r#"{"e":"aggTrade","E":1672515782136,"s":"BTCUSDT","a":5933014,"p":"0.001","q":"100","f":100,"l":105,"T":1672515782136,"m":true}"#;
+        let mark_price = r#"{"e":"markPriceUpdate","E":1562305380000,"s":"BTCUSDT","p":"11794.15000000","i":"11784.62659091","P":"11784.25641265","r":"0.00038167","T":1562306400000}"#;
+        let depth = r#"{"e":"depthUpdate","E":123456789,"T":123456788,"s":"BTCUSDT","U":157,"u":160,"pu":149,"b":[["0.0024","10"]],"a":[["0.0026","100"]]}"#;
+        let force_order = r#"{"e":"forceOrder","E":1568014460893,"o":{"s":"BTCUSDT","S":"SELL","o":"LIMIT","f":"IOC","q":"0.014","p":"9910","ap":"9910","X":"FILLED","l":"0.014","z":"0.014","T":1568014460893}}"#;
+        for text in [
+            agg_trade.to_string(),
+            mark_price.to_string(),
+            depth.to_string(),
+            force_order.to_string(),
+            format!(r#"{{"stream":"btcusdt@depth5@100ms","data":{}}}"#, depth),
+            format!(r#"{{"stream":"btcusdt@depth@100ms","data":{}}}"#, depth),
+            format!(
+                r#"{{"stream":"!markPrice@arr","data":[{},{}]}}"#,
+                mark_price, mark_price
+            ),
+            format!("[{}]", mark_price),
+            r#"{"stream":"btcusdt@aggTrade","data":{"e":"unknown"}}"#.to_string(),
+            r#"{"result":null,"id":1}"#.to_string(),
+            r#"{"u":400900217,"s":"BNBUSDT","b":"25.35190000","B":"31.21000000","a":"25.36520000","A":"40.66000000"}"#.to_string(),
+            "[]".to_string(),
+        ] {
+            assert_same(&text);
+        }
+
+        for frame in include_str!("../../benches/frames.txt").lines() {
+            assert_same(frame);
+            assert!(matches!(serde_json::from_str(frame), Ok(Fast::Event(_))));
+        }
+
+        // Errors come from the fallback, with the same message.
+        let bad = r#"{"e":"aggTrade","E":1,"s":"BTCUSDT","a":1,"p":"x","q":"1","f":1,"l":1,"T":1,"m":true}"#;
+        assert_eq!(
+            decode_text(bad).unwrap_err().to_string(),
+            Event::decode_value(serde_json::from_str(bad).unwrap())
+                .unwrap_err()
+                .to_string()
+        );
+    }
+}
@@ -0,0 +1,526 @@
+// SPDX-License-Identifier: MIT
+
+//! Sharing futures market data streams between tasks.
+//!
+//! A `Hub` owns the connections of a `ConnectionPool`. Tasks subscribe to a
+//! `StreamName` and receive its events on their own bounded channel. The
+//! stream is subscribed on the socket when its first `Subscription` is
+//! created and unsubscribed when its last one is dropped. A consumer that
+//! falls behind has events dropped, and is told how many with
+//! `RecvError::Lagged`, rather than stalling the reader for everyone.
+
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use futures_util::future::poll_fn;
+use futures_util::{ready, Stream};
+use serde_json::Value;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::{self, Message};
+use tracing::warn;
+
+use crate::common::environment::Environment;
+use crate::common::pool::{ConnectionPool, PoolConfig, PoolWriter};
+use crate::common::reconnect::ReconnectMessage;
+use crate::common::stream_name::{Market, StreamName};
+use crate::common::websocket::closed;
+use crate::error::Error;
+use crate::futures::websocket::{default_pool_config, ws_base_url, Event, BASE_URL};
+
+/// Events buffered for a subscription before it is considered lagging.
+pub const SUBSCRIPTION_BUFFER: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum RecvError {
+    /// The subscription fell behind and this many events were dropped. The
+    /// events after the gap follow.
+    #[error("lagged: {0} events dropped")]
+    Lagged(u64),
+
+    /// The hub was dropped.
+    #[error("hub closed")]
+    Closed,
+}
+
+struct Subscriber {
+    id: u64,
+    /// Events paired with the number dropped just before them.
+    sender: mpsc::Sender<(u64, Event)>,
+    dropped: u64,
+}
+
+#[derive(Default)]
+struct Routes {
+    /// Subscribers by the name of their stream.
+    subscribers: HashMap<String, Vec<Subscriber>>,
+    next_id: u64,
+}
+
+enum Command {
+    Subscribe {
+        stream: String,
+        subscriber: Subscriber,
+        reply: oneshot::Sender<Result<(), Error>>,
+    },
+    /// The last subscriber of a stream went away.
+    Release(String),
+}
+
+pub struct Hub {
+    routes: Arc<Mutex<Routes>>,
+    writer: PoolWriter,
+    commands: mpsc::UnboundedSender<Command>,
+    reader: JoinHandle<()>,
+    control: JoinHandle<()>,
+}
+
+impl Hub {
+    /// A hub on mainnet. Must be called within a tokio runtime.
+    pub fn new() -> Self {
+        Self::with_pool(ConnectionPool::new(BASE_URL, default_pool_config()))
+    }
+
+    pub fn with_environment(environment: &Environment) -> Result<Self, Error> {
+        Self::with_config(environment, default_pool_config())
+    }
+
+    pub fn with_config(environment: &Environment, config: PoolConfig) -> Result<Self, Error> {
+        let base_url = ws_base_url(environment).map_err(tungstenite::Error::Url)?;
+        Ok(Self::with_pool(ConnectionPool::new(base_url, config)))
+    }
+
+    fn with_pool(pool: ConnectionPool) -> Self {
+        let routes = Arc::new(Mutex::new(Routes::default()));
+        let writer = pool.writer();
+        let (commands, receiver) = mpsc::unbounded_channel();
+        let reader = tokio::spawn(read(pool, routes.clone(), commands.clone()));
+        let control = tokio::spawn(control(writer.clone(), routes.clone(), receiver));
+        Self {
+            routes,
+            writer,
+            commands,
+            reader,
+            control,
+        }
+    }
+
+    /// See `subscribe_with_buffer`, with a buffer of `SUBSCRIPTION_BUFFER`.
+    pub async fn subscribe(&self, stream: &StreamName) -> Result<Subscription, Error> {
+        self.subscribe_with_buffer(stream, SUBSCRIPTION_BUFFER)
+            .await
+    }
+
+    /// Receive the events of a stream, subscribing to it on the socket if no
+    /// other subscription has. Up to `buffer` events are held for the
+    /// subscription before it lags.
+    pub async fn subscribe_with_buffer(
+        &self,
+        stream: &StreamName,
+        buffer: usize,
+    ) -> Result<Subscription, Error> {
+        stream.validate(Market::Futures)?;
+        let name = stream.to_string();
+        let (sender, receiver) = mpsc::channel(buffer.max(1));
+        let id = {
+            let mut routes = self.routes.lock().unwrap();
+            routes.next_id += 1;
+            routes.next_id
+        };
+        let (reply, result) = oneshot::channel();
+        self.commands
+            .send(Command::Subscribe {
+                stream: name.clone(),
+                subscriber: Subscriber {
+                    id,
+                    sender,
+                    dropped: 0,
+                },
+                reply,
+            })
+            .map_err(|_| closed())?;
+        result.await.map_err(|_| closed())??;
+        Ok(Subscription {
+            stream: stream.clone(),
+            name,
+            id,
+            receiver,
+            pending: None,
+            routes: self.routes.clone(),
+            commands: self.commands.clone(),
+        })
+    }
+
+    /// The number of live subscriptions to a stream.
+    pub fn subscribers(&self, stream: &StreamName) -> usize {
+        let routes = self.routes.lock().unwrap();
+        routes
+            .subscribers
+            .get(&stream.to_string())
+            .map_or(0, |subscribers| subscribers.len())
+    }
+
+    /// The streams subscribed on the sockets.
+    pub async fn streams(&self) -> Vec<String> {
+        self.writer.streams().await
+    }
+
+    /// The number of connections opened.
+    pub async fn connections(&self) -> usize {
+        self.writer.connections().await
+    }
+}
+
+impl Default for Hub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Hub {
+    fn drop(&mut self) {
+        self.reader.abort();
+        self.control.abort();
+        // Close the channels of the subscriptions that outlive the hub.
+        self.routes.lock().unwrap().subscribers.clear();
+    }
+}
+
+/// The events of one stream from a `Hub`. Dropping the last subscription
+/// to a stream unsubscribes from it.
+pub struct Subscription {
+    stream: StreamName,
+    name: String,
+    id: u64,
+    receiver: mpsc::Receiver<(u64, Event)>,
+    /// The event that followed a gap, returned after `RecvError::Lagged`.
+    pending: Option<Event>,
+    routes: Arc<Mutex<Routes>>,
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+impl Subscription {
+    pub fn stream(&self) -> &StreamName {
+        &self.stream
+    }
+
+    /// The next event. `Disconnected` and `Reconnected` are sent to every
+    /// subscription, as the hub does not track which connection carries
+    /// which stream.
+    pub async fn recv(&mut self) -> Result<Event, RecvError> {
+        poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Result<Event, RecvError>> {
+        if let Some(event) = self.pending.take() {
+            return Poll::Ready(Ok(event));
+        }
+        Poll::Ready(match ready!(self.receiver.poll_recv(cx)) {
+            Some((0, event)) => Ok(event),
+            Some((dropped, event)) => {
+                self.pending = Some(event);
+                Err(RecvError::Lagged(dropped))
+            }
+            None => Err(RecvError::Closed),
+        })
+    }
+}
+
+impl Stream for Subscription {
+    type Item = Result<Event, RecvError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Poll::Ready(match ready!(self.poll_recv(cx)) {
+            Err(RecvError::Closed) => None,
+            result => Some(result),
+        })
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let mut routes = self.routes.lock().unwrap();
+        if let Some(subscribers) = routes.subscribers.get_mut(&self.name) {
+            subscribers.retain(|subscriber| subscriber.id != self.id);
+            if subscribers.is_empty() {
+                routes.subscribers.remove(&self.name);
+                let _ = self.commands.send(Command::Release(self.name.clone()));
+            }
+        }
+    }
+}
+
+/// Apply subscribe and release commands in order, so a stream released
+/// while being subscribed again stays subscribed.
+async fn control(
+    writer: PoolWriter,
+    routes: Arc<Mutex<Routes>>,
+    mut commands: mpsc::UnboundedReceiver<Command>,
+) {
+    while let Some(command) = commands.recv().await {
+        match command {
+            Command::Subscribe {
+                stream,
+                subscriber,
+                reply,
+            } => {
+                // Routed before subscribing, so the first events are kept.
+                let id = subscriber.id;
+                let subscribed = {
+                    let mut routes = routes.lock().unwrap();
+                    let subscribed = routes.subscribers.contains_key(&stream);
+                    routes
+                        .subscribers
+                        .entry(stream.clone())
+                        .or_default()
+                        .push(subscriber);
+                    subscribed
+                };
+                let result = if subscribed {
+                    Ok(())
+                } else {
+                    writer.subscribe(&[&stream]).await
+                };
+                if result.is_err() {
+                    let mut routes = routes.lock().unwrap();
+                    if let Some(subscribers) = routes.subscribers.get_mut(&stream) {
+                        subscribers.retain(|subscriber| subscriber.id != id);
+                        if subscribers.is_empty() {
+                            routes.subscribers.remove(&stream);
+                        }
+                    }
+                }
+                let _ = reply.send(result);
+            }
+            Command::Release(stream) => {
+                let released = !routes.lock().unwrap().subscribers.contains_key(&stream);
+                if released {
+                    if let Err(err) = writer.unsubscribe(&[&stream]).await {
+                        warn!("Failed to unsubscribe from {}: {}", stream, err);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Read the pool, sending each event to the subscribers of its stream.
+async fn read(
+    mut pool: ConnectionPool,
+    routes: Arc<Mutex<Routes>>,
+    commands: mpsc::UnboundedSender<Command>,
+) {
+    while let Some(message) = pool.next().await {
+        match message.message {
+            ReconnectMessage::Message(Message::Text(text)) => {
+                let value: Value = match serde_json::from_str(&text) {
+                    Ok(value) => value,
+                    Err(err) => {
+                        warn!("Failed to parse message: {}: {}", err, text);
+                        continue;
+                    }
+                };
+                let stream = match value["stream"].as_str() {
+                    Some(stream) => stream.to_string(),
+                    None => continue,
+                };
+                let event = Event::decode_value(value)
+                    .unwrap_or_else(|err| Some(Event::ParseError(err.to_string(), text.clone())))
+                    .unwrap_or(Event::Message(Message::Text(text)));
+                dispatch(&routes, &commands, Some(&stream), event);
+            }
+            ReconnectMessage::Disconnected(reason) => {
+                dispatch(&routes, &commands, None, Event::Disconnected(reason))
+            }
+            ReconnectMessage::Reconnected => dispatch(&routes, &commands, None, Event::Reconnected),
+            ReconnectMessage::Message(_) => {}
+        }
+    }
+}
+
+/// Send an event to the subscribers of a stream, or to all subscribers if
+/// `stream` is `None`, without waiting on full channels.
+fn dispatch(
+    routes: &Mutex<Routes>,
+    commands: &mpsc::UnboundedSender<Command>,
+    stream: Option<&str>,
+    event: Event,
+) {
+    let mut routes = routes.lock().unwrap();
+    let mut released = vec![];
+    match stream {
+        Some(stream) => {
+            if let Some(subscribers) = routes.subscribers.get_mut(stream) {
+                if !deliver(stream, subscribers, &event) {
+                    released.push(stream.to_string());
+                }
+            }
+        }
+        None => {
+            for (stream, subscribers) in routes.subscribers.iter_mut() {
+                if !deliver(stream, subscribers, &event) {
+                    released.push(stream.clone());
+                }
+            }
+        }
+    }
+    for stream in released {
+        routes.subscribers.remove(&stream);
+        let _ = commands.send(Command::Release(stream));
+    }
+}
+
+/// Returns false once no subscriber is left, those whose receiver was
+/// dropped being removed.
+fn deliver(stream: &str, subscribers: &mut Vec<Subscriber>, event: &Event) -> bool {
+    subscribers.retain_mut(|subscriber| {
+        match subscriber
+            .sender
+            .try_send((subscriber.dropped, event.clone()))
+        {
+            Ok(()) => {
+                subscriber.dropped = 0;
+                true
+            }
+            Err(TrySendError::Full(_)) => {
+                if subscriber.dropped == 0 {
+                    warn!("Subscriber {} to {} is lagging", subscriber.id, stream);
+                }
+                subscriber.dropped += 1;
+                true
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    });
+    !subscribers.is_empty()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures_util::{SinkExt, StreamExt};
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    /// Accept connections, sending an event for each stream in the request
+    /// URI, and for each stream of a SUBSCRIBE after replying to it.
+    #[allow(clippy::result_large_err)]
+    async fn serve() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut uri = String::new();
+                    let mut ws = tokio_tungstenite::accept_hdr_async(
+                        stream,
+                        |request: &tungstenite::handshake::server::Request, response| {
+                            uri = request.uri().to_string();
+                            Ok(response)
+                        },
+                    )
+                    .await
+                    .unwrap();
+                    let event = |stream: &str| {
+                        let event = serde_json::json!({"stream": stream, "data": {"e": "test"}});
+                        Message::Text(event.to_string())
+                    };
+                    let streams = uri.split_once("streams=").unwrap().1.to_string();
+                    for stream in streams.split('/') {
+                        ws.send(event(stream)).await.unwrap();
+                    }
+                    while let Some(Ok(Message::Text(text))) = ws.next().await {
+                        let request: Value = serde_json::from_str(&text).unwrap();
+                        let reply = serde_json::json!({"result": null, "id": request["id"]});
+                        ws.send(Message::Text(reply.to_string())).await.unwrap();
+                        if request["method"] == "SUBSCRIBE" {
+                            for stream in request["params"].as_array().unwrap() {
+                                ws.send(event(stream.as_str().unwrap())).await.unwrap();
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        url
+    }
+
+    async fn next_text(subscription: &mut Subscription) -> String {
+        match subscription.recv().await.unwrap() {
+            Event::Message(Message::Text(text)) => text,
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_refcount() {
+        let url = serve().await;
+        let hub = Hub::with_environment(&Environment::custom("http://localhost", url)).unwrap();
+        let btc = StreamName::agg_trade("BTCUSDT");
+        let eth = StreamName::agg_trade("ETHUSDT");
+
+        let mut a = hub.subscribe(&btc).await.unwrap();
+        assert!(next_text(&mut a).await.contains("btcusdt@aggTrade"));
+        let b = hub.subscribe(&btc).await.unwrap();
+        assert_eq!(hub.subscribers(&btc), 2);
+
+        let mut c = hub.subscribe(&eth).await.unwrap();
+        assert!(next_text(&mut c).await.contains("ethusdt@aggTrade"));
+        assert_eq!(
+            hub.streams().await,
+            ["btcusdt@aggTrade", "ethusdt@aggTrade"]
+        );
+        assert_eq!(hub.connections().await, 1);
+
+        drop(a);
+        assert_eq!(hub.subscribers(&btc), 1);
+        drop(b);
+        assert_eq!(hub.subscribers(&btc), 0);
+        let mut streams = hub.streams().await;
+        for _ in 0..100 {
+            if streams.len() == 1 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            streams = hub.streams().await;
+        }
+        assert_eq!(streams, ["ethusdt@aggTrade"]);
+
+        assert!(hub.subscribe(&StreamName::trade("btcusdt")).await.is_err());
+        drop(hub);
+        assert_eq!(c.recv().await.unwrap_err(), RecvError::Closed);
+    }
+
+    #[test]
+    fn test_lagged() {
+        let routes = Mutex::new(Routes::default());
+        let (commands, mut released) = mpsc::unbounded_channel();
+        let (sender, mut receiver) = mpsc::channel(1);
+        routes.lock().unwrap().subscribers.insert(
+            "btcusdt@aggTrade".to_string(),
+            vec![Subscriber {
+                id: 1,
+                sender,
+                dropped: 0,
+            }],
+        );
+        let event = |n: u8| Event::Ping(vec![n]);
+        for n in 0..3 {
+            dispatch(&routes, &commands, Some("btcusdt@aggTrade"), event(n));
+        }
+        assert!(matches!(receiver.try_recv(), Ok((0, Event::Ping(data))) if data == [0]));
+        dispatch(&routes, &commands, None, event(3));
+        assert!(matches!(receiver.try_recv(), Ok((2, Event::Ping(data))) if data == [3]));
+
+        drop(receiver);
+        dispatch(&routes, &commands, None, event(4));
+        assert!(routes.lock().unwrap().subscribers.is_empty());
+        assert!(
+            matches!(released.try_recv(), Ok(Command::Release(stream)) if stream == "btcusdt@aggTrade")
+        );
+    }
+}
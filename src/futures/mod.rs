@@ -21,4 +21,8 @@
 // DEALINGS IN THE SOFTWARE.
 
 pub mod client;
+mod decode;
+pub mod hub;
+pub mod userdata;
 pub mod websocket;
+pub mod ws_api;
@@ -0,0 +1,242 @@
+// SPDX-License-Identifier: MIT
+
+//! A futures user data stream that survives disconnects and expired listen
+//! keys.
+
+use futures_util::Stream;
+use tracing::warn;
+
+use crate::common::latency::{EventTime, Receipt, Received};
+use crate::common::userdata::{UserDataConnection, UserDataMessage};
+use crate::common::websocket::Keepalive;
+use crate::futures::client::{Client, OpenOrder, PositionEntry};
+use crate::futures::websocket::{self, AccountUpdate, Event, OrderTradeUpdateEvent};
+use crate::Error;
+
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
+pub enum UserDataEvent {
+    OrderTradeUpdate(OrderTradeUpdateEvent),
+    AccountUpdate(AccountUpdate),
+
+    /// The stream (re)connected. Events sent while it was disconnected are
+    /// lost, the snapshot, if enabled, is the state to reconcile against.
+    Resynced(Resync),
+
+    /// Any other event received on the stream.
+    Other(Event),
+}
+
+impl EventTime for UserDataEvent {
+    fn event_time(&self) -> Option<i64> {
+        match self {
+            UserDataEvent::OrderTradeUpdate(event) => Some(event.event_time as u64 as i64),
+            UserDataEvent::AccountUpdate(event) => Some(event.event_time as i64),
+            UserDataEvent::Resynced(_) => None,
+            UserDataEvent::Other(event) => event.event_time(),
+        }
+    }
+}
+
+/// The account state fetched over REST after the stream (re)connected. Each
+/// field is `None` if snapshots are disabled or the request failed.
+#[derive(Debug, Default)]
+pub struct Resync {
+    pub open_orders: Option<Vec<OpenOrder>>,
+    pub positions: Option<Vec<PositionEntry>>,
+}
+
+pub struct UserDataStream {
+    client: Client,
+    connection: UserDataConnection,
+    snapshots: bool,
+}
+
+impl UserDataStream {
+    /// Create a listen key and start keeping it alive. The stream connects
+    /// to the environment of the client on the first call to `next`.
+    pub async fn connect(client: &Client) -> Result<Self, Error> {
+        let base_url = websocket::ws_base_url(client.environment())
+            .map_err(|err| Error::UrlError(err.to_string()))?;
+        Ok(Self {
+            client: client.clone(),
+            connection: UserDataConnection::connect(client.listen_key_manager(), base_url).await?,
+            snapshots: false,
+        })
+    }
+
+    /// Fetch the open orders and positions after every (re)connect.
+    pub fn with_snapshots(mut self, snapshots: bool) -> Self {
+        self.snapshots = snapshots;
+        self
+    }
+
+    /// Set the keepalive of the stream connections. A connection that times
+    /// out is reconnected.
+    pub fn with_keepalive(mut self, keepalive: Keepalive) -> Self {
+        self.connection.set_keepalive(keepalive);
+        self
+    }
+
+    /// The next event. The stream reconnects as needed so this only returns
+    /// once an event is available.
+    pub async fn next(&mut self) -> UserDataEvent {
+        self.next_received().await.event
+    }
+
+    /// The next event with the time and connection it was received on.
+    /// `Resynced` is stamped once the snapshot is fetched.
+    pub async fn next_received(&mut self) -> Received<UserDataEvent> {
+        loop {
+            let (message, receipt) = match self.connection.next().await {
+                UserDataMessage::Connected(receipt) => {
+                    let event = UserDataEvent::Resynced(self.resync().await);
+                    return Received {
+                        event,
+                        stream: None,
+                        receipt: Receipt::now(receipt.connection_id),
+                    };
+                }
+                UserDataMessage::Message(message, receipt) => (message, receipt),
+            };
+            let Received {
+                event,
+                stream,
+                receipt,
+            } = websocket::receive_message(message, receipt, self.connection.stream());
+            let event = match event {
+                Event::OrderTradeUpdate(event) => UserDataEvent::OrderTradeUpdate(event),
+                Event::AccountUpdate(event) => UserDataEvent::AccountUpdate(event),
+                Event::Ping(_) => continue,
+                event => UserDataEvent::Other(event),
+            };
+            return Received {
+                event,
+                stream,
+                receipt,
+            };
+        }
+    }
+
+    async fn resync(&self) -> Resync {
+        if !self.snapshots {
+            return Resync::default();
+        }
+        let open_orders = self
+            .client
+            .get_open_orders::<&str>(None)
+            .await
+            .map_err(|err| warn!("Failed to fetch open orders: {}", err))
+            .ok();
+        let positions = self
+            .client
+            .get_positions(None)
+            .await
+            .map_err(|err| warn!("Failed to fetch positions: {}", err))
+            .ok();
+        Resync {
+            open_orders,
+            positions,
+        }
+    }
+
+    /// The events as a `Stream`, which never ends.
+    pub fn into_stream(self) -> impl Stream<Item = UserDataEvent> {
+        futures_util::stream::unfold(self, |mut stream| async move {
+            let event = stream.next().await;
+            Some((event, stream))
+        })
+    }
+
+    /// Stop keeping the listen key alive and close the stream.
+    pub async fn close(self) -> Result<(), Error> {
+        self.connection.close().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::client::Authentication;
+    use crate::common::environment::Environment;
+    use crate::common::retry::RetryPolicy;
+    use crate::common::transport::MockTransport;
+    use futures_util::SinkExt;
+    use reqwest::StatusCode;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::Message;
+
+    #[tokio::test]
+    #[allow(clippy::result_large_err)]
+    async fn test_reconnect() {
+        // Serve two connections, each sending one order update and closing.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let mut paths = vec![];
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut ws = tokio_tungstenite::accept_hdr_async(
+                    stream,
+                    |request: &tokio_tungstenite::tungstenite::handshake::server::Request,
+                     response| {
+                        paths.push(request.uri().path().to_string());
+                        Ok(response)
+                    },
+                )
+                .await
+                .unwrap();
+                let text = r#"{"e":"ORDER_TRADE_UPDATE","T":1612418801174,"E":1612418801179,"o":{"s":"BTCUSDT","c":"test","S":"SELL","o":"LIMIT","f":"GTC","q":"0.100","p":"40000","ap":"0","sp":"0","x":"NEW","X":"NEW","i":13584185467,"l":"0","z":"0","L":"0","T":1612418801174,"t":0,"b":"0","a":"4000","m":false,"R":false,"wt":"CONTRACT_PRICE","ot":"LIMIT","ps":"SHORT","cp":false,"rp":"0","pP":false,"si":0,"ss":0}}"#;
+                ws.send(Message::Text(text.to_string())).await.unwrap();
+                ws.close(None).await.unwrap();
+            }
+            paths
+        });
+
+        let transport = Arc::new(MockTransport::new());
+        transport.push(StatusCode::OK, r#"{"listenKey":"key1"}"#);
+        transport.push(StatusCode::OK, "[]");
+        transport.push(StatusCode::OK, "[]");
+        transport.push(StatusCode::OK, "[]");
+        transport.push(StatusCode::OK, "[]");
+        transport.push(StatusCode::OK, "{}");
+        let environment =
+            Environment::custom("https://fapi.binance.com", format!("ws://{}", address));
+        let mut client =
+            Client::with_environment(&environment, Some(Authentication::hmac("key", "secret")))
+                .unwrap();
+        client.set_transport(transport.clone());
+
+        let mut stream = UserDataStream::connect(&client)
+            .await
+            .unwrap()
+            .with_snapshots(true);
+        stream.connection.reconnect_policy =
+            RetryPolicy::none().backoff(Duration::ZERO, Duration::ZERO);
+        let mut connection_ids = vec![];
+        for _ in 0..2 {
+            let resynced = stream.next_received().await;
+            match resynced.event {
+                UserDataEvent::Resynced(resync) => {
+                    assert_eq!(resync.open_orders.unwrap().len(), 0);
+                    assert_eq!(resync.positions.unwrap().len(), 0);
+                }
+                event => panic!("unexpected event {:?}", event),
+            }
+            let received = stream.next_received().await;
+            assert!(matches!(received.event, UserDataEvent::OrderTradeUpdate(_)));
+            assert_eq!(received.event.event_time(), Some(1612418801179));
+            assert_eq!(
+                received.receipt.connection_id,
+                resynced.receipt.connection_id
+            );
+            connection_ids.push(received.receipt.connection_id);
+        }
+        assert_ne!(connection_ids[0], connection_ids[1]);
+        assert_eq!(server.await.unwrap(), ["/ws/key1", "/ws/key1"]);
+        stream.close().await.unwrap();
+        assert_eq!(transport.remaining(), 0);
+    }
+}
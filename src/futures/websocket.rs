@@ -2,47 +2,427 @@
 //
 // SPDX-License-Identifier: MIT
 
-use futures_util::StreamExt;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_util::future::poll_fn;
+use futures_util::{ready, Stream, StreamExt};
+
 use serde::Deserialize;
-use serde_json::Value;
+use serde_json::{json, Value};
 use tokio::net::TcpStream;
 use tokio_tungstenite::tungstenite::Message;
 use tokio_tungstenite::{connect_async, tungstenite, MaybeTlsStream, WebSocketStream};
 
-use crate::common::stream::AggTrade;
+use crate::common::cassette::{self, Cassette, CassetteEntry};
+use crate::common::environment::Environment;
+use crate::common::latency::{combined_stream, EventTime, Receipt, Received};
+use crate::common::listenkey::ListenKeyExpired;
+use crate::common::pool::{ConnectionPool, PoolConfig, PoolWriter};
+use crate::common::reconnect::{ReconnectMessage, ReconnectingConnection};
+use crate::common::retry::RetryPolicy;
+use crate::common::stream::{AggTrade, MiniTicker, PriceLevel};
+pub use crate::common::stream::{Kline, KlineEvent};
+use crate::common::stream_name::{Market, StreamName};
+use crate::common::websocket::{
+    decode_subscriptions, record_frame, stream_params, Connection, Keepalive, Outcome, Writer,
+};
+use crate::futures::decode;
 use crate::parsers::*;
 
 pub const BASE_URL: &str = "wss://fstream.binance.com";
+pub const TESTNET_BASE_URL: &str = "wss://stream.binancefuture.com";
 
 pub struct WebSocket {
-    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    ws: Connection,
+    cassette: Option<Arc<Cassette>>,
+    /// The stream of a single stream connection.
+    stream: Option<String>,
 }
 
 impl WebSocket {
     pub fn new(ws: WebSocketStream<MaybeTlsStream<TcpStream>>) -> Self {
-        Self { ws }
+        Self {
+            ws: Connection::live(ws),
+            cassette: None,
+            stream: None,
+        }
+    }
+
+    /// A WebSocket that reads the frames recorded in a cassette.
+    pub fn replay(entries: &[CassetteEntry]) -> Self {
+        Self {
+            ws: Connection::replay(cassette::frames(entries)),
+            cassette: None,
+            stream: None,
+        }
+    }
+
+    /// Set the keepalive. Pings from the server are answered regardless.
+    pub fn set_keepalive(&mut self, keepalive: Keepalive) {
+        self.ws.set_keepalive(keepalive);
+    }
+
+    /// Record every frame read to a cassette.
+    pub fn record(&mut self, cassette: Arc<Cassette>) {
+        self.cassette = Some(cassette);
+    }
+
+    /// Subscribe to more streams on this connection.
+    pub async fn subscribe<T: AsRef<str>>(&mut self, streams: &[T]) -> Result<(), crate::Error> {
+        self.ws
+            .request("SUBSCRIBE", stream_params(streams))
+            .await
+            .map(|_| ())
+    }
+
+    /// Subscribe to typed streams, failing on any the market does not serve.
+    pub async fn subscribe_streams(&mut self, streams: &[StreamName]) -> Result<(), crate::Error> {
+        self.subscribe(&stream_names(streams)?).await
+    }
+
+    /// Unsubscribe from streams on this connection.
+    pub async fn unsubscribe<T: AsRef<str>>(&mut self, streams: &[T]) -> Result<(), crate::Error> {
+        self.ws
+            .request("UNSUBSCRIBE", stream_params(streams))
+            .await
+            .map(|_| ())
     }
 
-    pub async fn next(&mut self) -> Option<Result<Event, tokio_tungstenite::tungstenite::Error>> {
+    /// The streams this connection is subscribed to.
+    pub async fn list_subscriptions(&mut self) -> Result<Vec<String>, crate::Error> {
+        let result = self.ws.request("LIST_SUBSCRIPTIONS", json!([])).await?;
+        decode_subscriptions(result.unwrap_or_default())
+    }
+
+    /// The write half, for subscribing and unsubscribing while another
+    /// task reads this WebSocket.
+    pub fn writer(&self) -> Writer {
+        self.ws.writer()
+    }
+
+    pub async fn next(&mut self) -> Option<Result<Event, tungstenite::Error>> {
+        StreamExt::next(self).await
+    }
+
+    /// The next event with the time and connection it was received on.
+    pub async fn next_received(&mut self) -> Option<Result<Received<Event>, tungstenite::Error>> {
+        match poll_fn(|cx| self.poll_frame(cx)).await? {
+            Ok((message, receipt)) => Some(Ok(receive_message(
+                message,
+                receipt,
+                self.stream.as_deref(),
+            ))),
+            Err(err) => Some(Err(err)),
+        }
+    }
+
+    /// The next ping or text frame, with its receipt.
+    fn poll_frame(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<(Message, Receipt), tungstenite::Error>>> {
         loop {
-            let next = self.ws.next().await;
-            match next {
-                Some(Ok(message)) => match message {
-                    Message::Ping(_) | Message::Text(_) => {
-                        return Some(Ok(Event::decode_message(message)));
-                    }
-                    _ => {
-                        // Ignore, move onto the next incoming message.
+            match ready!(self.ws.poll_next(cx)) {
+                Some(Ok((message, receipt))) => {
+                    record_frame(&self.cassette, &message);
+                    match message {
+                        Message::Ping(_) | Message::Text(_) => {
+                            return Poll::Ready(Some(Ok((message, receipt))));
+                        }
+                        _ => {
+                            // Ignore, move onto the next incoming message.
+                        }
                     }
-                },
-                Some(Err(err)) => {
-                    return Some(Err(err));
-                }
-                None => {
-                    return None;
                 }
+                Some(Err(err)) => return Poll::Ready(Some(Err(err))),
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+impl Stream for WebSocket {
+    type Item = Result<Event, tungstenite::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Poll::Ready(match ready!(self.poll_frame(cx)) {
+            Some(Ok((message, _))) => Some(Ok(match self.stream.as_deref() {
+                Some(stream) => Event::decode_stream_message(message, stream),
+                None => Event::decode_message(message),
+            })),
+            Some(Err(err)) => Some(Err(err)),
+            None => None,
+        })
+    }
+}
+
+/// A combined stream that reconnects when the connection drops and
+/// replaces the connection ahead of the 24 hour disconnect.
+pub struct ReconnectingWebSocket {
+    connection: ReconnectingConnection,
+}
+
+impl ReconnectingWebSocket {
+    pub async fn connect_combined<T: AsRef<str>>(
+        streams: &[T],
+    ) -> Result<Self, tungstenite::Error> {
+        Self::connect_combined_with_environment(&Environment::Mainnet, streams).await
+    }
+
+    pub async fn connect_combined_with_environment<T: AsRef<str>>(
+        environment: &Environment,
+        streams: &[T],
+    ) -> Result<Self, tungstenite::Error> {
+        let streams = streams.iter().map(|e| e.as_ref().to_string()).collect();
+        Ok(Self {
+            connection: ReconnectingConnection::connect(ws_base_url(environment)?, streams).await?,
+        })
+    }
+
+    /// Connect to typed streams, failing on any the market does not serve.
+    pub async fn connect_combined_streams(streams: &[StreamName]) -> Result<Self, crate::Error> {
+        Self::connect_combined_streams_with_environment(&Environment::Mainnet, streams).await
+    }
+
+    pub async fn connect_combined_streams_with_environment(
+        environment: &Environment,
+        streams: &[StreamName],
+    ) -> Result<Self, crate::Error> {
+        let streams = stream_names(streams)?;
+        Ok(Self::connect_combined_with_environment(environment, &streams).await?)
+    }
+
+    pub fn set_rollover(&mut self, rollover: Duration) {
+        self.connection.set_rollover(rollover);
+    }
+
+    pub fn set_reconnect_policy(&mut self, reconnect_policy: RetryPolicy) {
+        self.connection.set_reconnect_policy(reconnect_policy);
+    }
+
+    /// Set the keepalive of this and future connections. A connection that
+    /// times out is reconnected.
+    pub fn set_keepalive(&mut self, keepalive: Keepalive) {
+        self.connection.set_keepalive(keepalive);
+    }
+
+    /// Subscribe to more streams. The streams are also subscribed to on
+    /// reconnect, and only then while disconnected, when the outcome is
+    /// `Deferred`.
+    pub async fn subscribe<T: AsRef<str>>(
+        &mut self,
+        streams: &[T],
+    ) -> Result<Outcome, crate::Error> {
+        self.connection.subscribe(streams).await
+    }
+
+    /// Subscribe to typed streams, failing on any the market does not serve.
+    pub async fn subscribe_streams(
+        &mut self,
+        streams: &[StreamName],
+    ) -> Result<Outcome, crate::Error> {
+        self.subscribe(&stream_names(streams)?).await
+    }
+
+    /// Unsubscribe from streams, including on reconnect.
+    pub async fn unsubscribe<T: AsRef<str>>(
+        &mut self,
+        streams: &[T],
+    ) -> Result<Outcome, crate::Error> {
+        self.connection.unsubscribe(streams).await
+    }
+
+    /// The streams the current connection is subscribed to.
+    pub async fn list_subscriptions(&mut self) -> Result<Vec<String>, crate::Error> {
+        self.connection.list_subscriptions().await
+    }
+
+    /// The write half, for subscribing and unsubscribing while another
+    /// task reads. Streams subscribed to through it are also subscribed to
+    /// on reconnect.
+    pub fn writer(&self) -> Writer {
+        self.connection.writer()
+    }
+
+    /// The next event. Connection failures are reported as `Disconnected`
+    /// and `Reconnected` events rather than errors.
+    pub async fn next(&mut self) -> Option<Result<Event, tungstenite::Error>> {
+        StreamExt::next(self).await
+    }
+
+    /// The next event with the time and connection it was received on.
+    pub async fn next_received(&mut self) -> Option<Result<Received<Event>, tungstenite::Error>> {
+        loop {
+            let message = self.connection.next().await;
+            if let Some(received) = receive_reconnect_message(message, self.connection.receipt()) {
+                return Some(Ok(received));
+            }
+        }
+    }
+}
+
+impl Stream for ReconnectingWebSocket {
+    type Item = Result<Event, tungstenite::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        while let Some(message) = ready!(self.connection.poll_next_unpin(cx)) {
+            if let Some(event) = decode_reconnect_message(message) {
+                return Poll::Ready(Some(Ok(event)));
             }
         }
+        Poll::Ready(None)
+    }
+}
+
+/// Any number of streams spread over as many reconnecting connections as
+/// needed, read as one stream of events.
+pub struct StreamPool {
+    pool: ConnectionPool,
+}
+
+impl StreamPool {
+    pub fn new() -> Self {
+        Self {
+            pool: ConnectionPool::new(BASE_URL, default_pool_config()),
+        }
+    }
+
+    pub fn with_environment(environment: &Environment) -> Result<Self, crate::Error> {
+        Self::with_config(environment, default_pool_config())
+    }
+
+    pub fn with_config(
+        environment: &Environment,
+        config: PoolConfig,
+    ) -> Result<Self, crate::Error> {
+        let base_url = ws_base_url(environment).map_err(tungstenite::Error::Url)?;
+        Ok(Self {
+            pool: ConnectionPool::new(base_url, config),
+        })
+    }
+
+    /// Subscribe to streams, opening connections as the open ones fill up.
+    pub async fn subscribe<T: AsRef<str>>(&mut self, streams: &[T]) -> Result<(), crate::Error> {
+        self.pool.subscribe(streams).await
+    }
+
+    /// Subscribe to typed streams, failing on any the market does not serve.
+    pub async fn subscribe_streams(&mut self, streams: &[StreamName]) -> Result<(), crate::Error> {
+        self.subscribe(&stream_names(streams)?).await
+    }
+
+    pub async fn unsubscribe<T: AsRef<str>>(&mut self, streams: &[T]) -> Result<(), crate::Error> {
+        self.pool.unsubscribe(streams).await
+    }
+
+    pub async fn streams(&self) -> Vec<String> {
+        self.pool.streams().await
+    }
+
+    pub async fn connections(&self) -> usize {
+        self.pool.connections().await
+    }
+
+    /// The write half, for subscribing and unsubscribing while another task
+    /// reads the pool.
+    pub fn writer(&self) -> PoolWriter {
+        self.pool.writer()
+    }
+
+    /// The next event from any connection. Each connection reports its own
+    /// `Disconnected` and `Reconnected` events.
+    pub async fn next(&mut self) -> Option<Result<Event, tungstenite::Error>> {
+        StreamExt::next(self).await
+    }
+
+    /// The next event with the time and connection it was received on.
+    pub async fn next_received(&mut self) -> Option<Result<Received<Event>, tungstenite::Error>> {
+        while let Some(message) = self.pool.next().await {
+            if let Some(received) = receive_reconnect_message(message.message, message.receipt) {
+                return Some(Ok(received));
+            }
+        }
+        None
+    }
+}
+
+impl Stream for StreamPool {
+    type Item = Result<Event, tungstenite::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        while let Some(message) = ready!(self.pool.poll_next_unpin(cx)) {
+            if let Some(event) = decode_reconnect_message(message.message) {
+                return Poll::Ready(Some(Ok(event)));
+            }
+        }
+        Poll::Ready(None)
+    }
+}
+
+impl Default for StreamPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Futures connections accept 10 control messages per second.
+pub(crate) fn default_pool_config() -> PoolConfig {
+    PoolConfig {
+        messages_per_second: 10,
+        ..Default::default()
+    }
+}
+
+fn decode_reconnect_message(message: ReconnectMessage) -> Option<Event> {
+    match message {
+        ReconnectMessage::Message(message) => match message {
+            Message::Ping(_) | Message::Text(_) => Some(Event::decode_message(message)),
+            _ => None,
+        },
+        ReconnectMessage::Disconnected(reason) => Some(Event::Disconnected(reason)),
+        ReconnectMessage::Reconnected => Some(Event::Reconnected),
+    }
+}
+
+/// Decode a frame keeping its receipt. `stream` is the stream of a single
+/// stream connection, combined stream frames name their own.
+pub(crate) fn receive_message(
+    message: Message,
+    receipt: Receipt,
+    stream: Option<&str>,
+) -> Received<Event> {
+    let combined = match &message {
+        Message::Text(text) => combined_stream(text).map(String::from),
+        _ => None,
+    };
+    // Combined stream frames are decoded by the name they carry.
+    let event = match stream {
+        Some(stream) if combined.is_none() => Event::decode_stream_message(message, stream),
+        _ => Event::decode_message(message),
+    };
+    Received {
+        stream: combined.or_else(|| stream.map(String::from)),
+        event,
+        receipt,
+    }
+}
+
+fn receive_reconnect_message(
+    message: ReconnectMessage,
+    receipt: Receipt,
+) -> Option<Received<Event>> {
+    match message {
+        ReconnectMessage::Message(message @ (Message::Ping(_) | Message::Text(_))) => {
+            Some(receive_message(message, receipt, None))
+        }
+        message => decode_reconnect_message(message).map(|event| Received {
+            event,
+            stream: None,
+            receipt,
+        }),
     }
 }
 
@@ -52,18 +432,66 @@ pub async fn connect<T: AsRef<str>>(url: T) -> Result<WebSocket, tungstenite::Er
 }
 
 pub async fn connect_stream<T: AsRef<str>>(name: T) -> Result<WebSocket, tungstenite::Error> {
-    let url = format!("{}/ws/{}", BASE_URL, name.as_ref());
-    connect(&url).await
+    connect_stream_with_environment(&Environment::Mainnet, name).await
+}
+
+pub async fn connect_stream_with_environment<T: AsRef<str>>(
+    environment: &Environment,
+    name: T,
+) -> Result<WebSocket, tungstenite::Error> {
+    let url = format!("{}/ws/{}", ws_base_url(environment)?, name.as_ref());
+    let mut ws = connect(&url).await?;
+    ws.stream = Some(name.as_ref().to_string());
+    Ok(ws)
+}
+
+/// Render typed stream names for the futures endpoint, failing on any stream
+/// it does not serve.
+pub fn stream_names(streams: &[StreamName]) -> Result<Vec<String>, crate::Error> {
+    StreamName::render_all(streams, Market::Futures)
+}
+
+/// Connect to typed streams, failing on any the market does not serve.
+pub async fn connect_combined_streams(streams: &[StreamName]) -> Result<WebSocket, crate::Error> {
+    connect_combined_streams_with_environment(&Environment::Mainnet, streams).await
+}
+
+pub async fn connect_combined_streams_with_environment(
+    environment: &Environment,
+    streams: &[StreamName],
+) -> Result<WebSocket, crate::Error> {
+    let streams = stream_names(streams)?;
+    Ok(connect_combined_with_environment(environment, &streams).await?)
 }
 
 pub async fn connect_combined<T: AsRef<str>>(
     streams: &[T],
+) -> Result<WebSocket, tungstenite::Error> {
+    connect_combined_with_environment(&Environment::Mainnet, streams).await
+}
+
+pub async fn connect_combined_with_environment<T: AsRef<str>>(
+    environment: &Environment,
+    streams: &[T],
 ) -> Result<WebSocket, tungstenite::Error> {
     let streams: Vec<&str> = streams.iter().map(|e| e.as_ref()).collect();
-    let url = format!("{}/stream?streams={}", BASE_URL, streams.join("/"));
+    let url = format!(
+        "{}/stream?streams={}",
+        ws_base_url(environment)?,
+        streams.join("/")
+    );
     connect(&url).await
 }
 
+pub(crate) fn ws_base_url(environment: &Environment) -> Result<&str, tungstenite::error::UrlError> {
+    environment.futures_ws_url().ok_or_else(|| {
+        tungstenite::error::UrlError::UnableToConnect(format!(
+            "no futures streams for environment {:?}",
+            environment
+        ))
+    })
+}
+
 #[derive(Debug, Clone)]
 #[allow(clippy::large_enum_variant)]
 pub enum Event {
@@ -82,41 +510,132 @@ pub enum Event {
     /// Account update event (user stream).
     AccountUpdate(AccountUpdate),
 
+    /// Positions close to liquidation (user stream).
+    MarginCall(MarginCall),
+
+    /// Leverage or multi-assets mode changed (user stream).
+    AccountConfigUpdate(AccountConfigUpdate),
+
+    /// The listen key of the user stream expired, no more events follow.
+    ListenKeyExpired(ListenKeyExpired),
+
+    /// Strategy status changed (user stream).
+    StrategyUpdate(StrategyUpdate),
+
+    /// Grid strategy update (user stream).
+    GridUpdate(GridUpdate),
+
+    /// A triggered conditional order was rejected (user stream).
+    ConditionalOrderTriggerReject(ConditionalOrderTriggerReject),
+
+    /// A trade, sent ahead of the full `ORDER_TRADE_UPDATE` (user stream).
+    TradeLite(TradeLite),
+
     /// Public liquidation event.
     LiquidationEvent(LiquidationEvent),
 
     Ticker(Ticker),
 
+    /// The tickers of all symbols that changed, from `!ticker@arr`.
+    Tickers(Vec<Ticker>),
+
+    MiniTicker(MiniTicker),
+
+    /// From `!miniTicker@arr`.
+    MiniTickers(Vec<MiniTicker>),
+
+    MarkPrice(MarkPrice),
+
+    /// The mark prices of all symbols, from `!markPrice@arr`.
+    MarkPrices(Vec<MarkPrice>),
+
+    /// Diff depth event.
+    DepthUpdate(DepthUpdate),
+
+    /// Top levels of the order book, from a `<symbol>@depth<levels>` stream.
+    /// The frames are the same as a diff depth event, told apart by the name
+    /// of a combined stream or of a single stream connection's stream.
+    PartialDepth(DepthUpdate),
+
+    BookTicker(BookTicker),
+    ContinuousKline(ContinuousKlineEvent),
+    IndexPriceKline(PriceKlineEvent),
+    MarkPriceKline(PriceKlineEvent),
+    ContractInfo(ContractInfo),
+    CompositeIndex(CompositeIndex),
+    AssetIndex(AssetIndex),
+
+    /// From `!assetIndex@arr`.
+    AssetIndexes(Vec<AssetIndex>),
+
     /// A serde deserialize error. We use a string for the serde error so we can implement clone.
     /// The second string is the input that failed to parse.
     ParseError(String, String),
 
-    /// WebSocket ping.
+    /// WebSocket ping, already answered with a pong.
     Ping(Vec<u8>),
+
+    /// The connection of a `ReconnectingWebSocket` dropped, with the reason.
+    Disconnected(String),
+
+    /// A `ReconnectingWebSocket` reconnected after `Disconnected`.
+    Reconnected,
 }
 
 impl Event {
     pub fn decode_message(message: Message) -> Event {
         match message {
-            Message::Text(text) => serde_json::from_str::<Value>(&text)
-                .and_then(Self::decode_value)
+            Message::Text(text) => Self::decode_text(&text)
                 .unwrap_or_else(|err| Some(Self::ParseError(err.to_string(), text.to_string())))
-                .unwrap_or_else(|| Self::Message(Message::Text(text))),
+                .unwrap_or(Self::Message(Message::Text(text))),
             Message::Ping(data) => Event::Ping(data),
             _ => unreachable!(),
         }
     }
 
+    /// Decode a frame received on `stream`, the stream of a single stream
+    /// connection, whose frames don't name their stream.
+    pub fn decode_stream_message(message: Message, stream: &str) -> Event {
+        match Self::decode_message(message) {
+            Event::DepthUpdate(depth) if is_partial_depth(stream) => Event::PartialDepth(depth),
+            event => event,
+        }
+    }
+
+    /// Decode a text frame in a single pass over the text, without
+    /// building a `Value` first. Gives the same result as `decode_value`.
+    pub fn decode_text(text: &str) -> Result<Option<Event>, serde_json::Error> {
+        decode::decode_text(text)
+    }
+
     pub fn decode_value(mut value: Value) -> Result<Option<Event>, serde_json::Error> {
-        if value["stream"].is_string() && value["data"]["e"].is_string() {
-            return Self::decode_data(value["data"].take());
-        } else if value["e"].is_string() {
-            return Self::decode_data(value);
+        if let Some(stream) = value["stream"].as_str() {
+            let partial_depth = is_partial_depth(stream);
+            let data = value["data"].take();
+            if partial_depth && data["e"] == "depthUpdate" {
+                return Ok(Some(Event::PartialDepth(serde_json::from_value(data)?)));
+            }
+            return Self::decode_data(data);
         }
-        Ok(None)
+        Self::decode_data(value)
     }
 
     pub fn decode_data(mut value: Value) -> Result<Option<Event>, serde_json::Error> {
+        if let Value::Array(values) = &value {
+            return match values.first().and_then(|first| first["e"].as_str()) {
+                Some("24hrTicker") => Ok(Some(Event::Tickers(serde_json::from_value(value)?))),
+                Some("24hrMiniTicker") => {
+                    Ok(Some(Event::MiniTickers(serde_json::from_value(value)?)))
+                }
+                Some("markPriceUpdate") => {
+                    Ok(Some(Event::MarkPrices(serde_json::from_value(value)?)))
+                }
+                Some("assetIndexUpdate") => {
+                    Ok(Some(Event::AssetIndexes(serde_json::from_value(value)?)))
+                }
+                _ => Ok(None),
+            };
+        }
         if let Some(e) = value["e"].as_str() {
             match e {
                 "kline" => {
@@ -133,6 +652,33 @@ impl Event {
                 "ACCOUNT_UPDATE" => {
                     return Ok(Some(Event::AccountUpdate(serde_json::from_value(value)?)));
                 }
+                "MARGIN_CALL" => {
+                    return Ok(Some(Event::MarginCall(serde_json::from_value(value)?)));
+                }
+                "ACCOUNT_CONFIG_UPDATE" => {
+                    return Ok(Some(Event::AccountConfigUpdate(serde_json::from_value(
+                        value,
+                    )?)));
+                }
+                "listenKeyExpired" => {
+                    return Ok(Some(Event::ListenKeyExpired(serde_json::from_value(
+                        value,
+                    )?)));
+                }
+                "STRATEGY_UPDATE" => {
+                    return Ok(Some(Event::StrategyUpdate(serde_json::from_value(value)?)));
+                }
+                "GRID_UPDATE" => {
+                    return Ok(Some(Event::GridUpdate(serde_json::from_value(value)?)));
+                }
+                "CONDITIONAL_ORDER_TRIGGER_REJECT" => {
+                    return Ok(Some(Event::ConditionalOrderTriggerReject(
+                        serde_json::from_value(value)?,
+                    )));
+                }
+                "TRADE_LITE" => {
+                    return Ok(Some(Event::TradeLite(serde_json::from_value(value)?)));
+                }
                 "forceOrder" => {
                     return Ok(Some(Event::LiquidationEvent(serde_json::from_value(
                         value["o"].take(),
@@ -141,81 +687,93 @@ impl Event {
                 "24hrTicker" => {
                     return Ok(Some(Event::Ticker(serde_json::from_value(value)?)));
                 }
+                "24hrMiniTicker" => {
+                    return Ok(Some(Event::MiniTicker(serde_json::from_value(value)?)));
+                }
+                "markPriceUpdate" => {
+                    return Ok(Some(Event::MarkPrice(serde_json::from_value(value)?)));
+                }
+                "depthUpdate" => {
+                    return Ok(Some(Event::DepthUpdate(serde_json::from_value(value)?)));
+                }
+                "bookTicker" => {
+                    return Ok(Some(Event::BookTicker(serde_json::from_value(value)?)));
+                }
+                "continuous_kline" => {
+                    return Ok(Some(Event::ContinuousKline(serde_json::from_value(value)?)));
+                }
+                "indexPriceKline" => {
+                    return Ok(Some(Event::IndexPriceKline(serde_json::from_value(value)?)));
+                }
+                "markPriceKline" => {
+                    return Ok(Some(Event::MarkPriceKline(serde_json::from_value(value)?)));
+                }
+                "contractInfo" => {
+                    return Ok(Some(Event::ContractInfo(serde_json::from_value(value)?)));
+                }
+                "compositeIndex" => {
+                    return Ok(Some(Event::CompositeIndex(serde_json::from_value(value)?)));
+                }
+                "assetIndexUpdate" => {
+                    return Ok(Some(Event::AssetIndex(serde_json::from_value(value)?)));
+                }
                 _ => {}
             }
+        } else if value.get("u").is_some() && value.get("b").is_some() {
+            // A book ticker without an event type.
+            return Ok(Some(Event::BookTicker(serde_json::from_value(value)?)));
         }
         Ok(None)
     }
 
     pub fn is_liquidation_event(&self) -> bool {
-        match self {
-            Self::LiquidationEvent(_) => true,
-            _ => false,
-        }
+        matches!(self, Self::LiquidationEvent(_))
     }
 
     pub fn is_ping(&self) -> bool {
-        if let Event::Ping(_) = self {
-            true
-        } else {
-            false
-        }
+        matches!(self, Event::Ping(_))
     }
 }
 
-#[derive(Clone, Debug, Deserialize, PartialEq)]
-pub struct KlineEvent {
-    #[serde(rename = "e")]
-    pub event_type: String,
-    #[serde(rename = "E")]
-    pub event_time: f64,
-    #[serde(rename = "s")]
-    pub symbol: String,
-    #[serde(rename = "k")]
-    pub kline: Kline,
-}
-
-#[derive(Clone, Debug, Deserialize, PartialEq)]
-pub struct Kline {
-    #[serde(rename = "t")]
-    pub open_time: f64,
-    #[serde(rename = "T")]
-    pub close_time: f64,
-    #[serde(rename = "s")]
-    pub symbol: String,
-    #[serde(rename = "i")]
-    pub interval: String,
-    #[serde(rename = "o", deserialize_with = "parse_f64_string")]
-    pub open: f64,
-    #[serde(rename = "c", deserialize_with = "parse_f64_string")]
-    pub close: f64,
-    #[serde(rename = "h", deserialize_with = "parse_f64_string")]
-    pub high: f64,
-    #[serde(rename = "l", deserialize_with = "parse_f64_string")]
-    pub low: f64,
-
-    /// Base asset volume.
-    #[serde(rename = "v", deserialize_with = "parse_f64_string")]
-    pub volume: f64,
-
-    // Number of trades.
-    #[serde(rename = "n")]
-    pub trade_count: u64,
-
-    // Quote asset volume.
-    #[serde(rename = "q", deserialize_with = "parse_f64_string")]
-    pub quote_volume: f64,
-
-    // Taker buy base asset volume.
-    #[serde(rename = "V", deserialize_with = "parse_f64_string")]
-    pub taker_base_volume: f64,
-
-    // Taker buy quote asset volume.
-    #[serde(rename = "Q", deserialize_with = "parse_f64_string")]
-    pub taker_buy_quote_volume: f64,
-
-    #[serde(rename = "x")]
-    pub closed: bool,
+impl EventTime for Event {
+    /// The `E` time of the event, the first of an array. Liquidations have
+    /// the trade time of the order.
+    fn event_time(&self) -> Option<i64> {
+        let event_time = match self {
+            Event::Kline(event) => event.event_time as u64,
+            Event::AggTrade(event) => event.event_time as u64,
+            Event::OrderTradeUpdate(event) => event.event_time as u64,
+            Event::AccountUpdate(event) => event.event_time,
+            Event::MarginCall(event) => event.event_time,
+            Event::AccountConfigUpdate(event) => event.event_time,
+            Event::ListenKeyExpired(event) => event.event_time,
+            Event::StrategyUpdate(event) => event.event_time,
+            Event::GridUpdate(event) => event.event_time,
+            Event::ConditionalOrderTriggerReject(event) => event.event_time,
+            Event::TradeLite(event) => event.event_time,
+            Event::LiquidationEvent(event) => event.trade_time,
+            Event::Ticker(event) => event.event_time,
+            Event::Tickers(events) => events.first()?.event_time,
+            Event::MiniTicker(event) => event.event_time,
+            Event::MiniTickers(events) => events.first()?.event_time,
+            Event::MarkPrice(event) => event.event_time,
+            Event::MarkPrices(events) => events.first()?.event_time,
+            Event::DepthUpdate(event) | Event::PartialDepth(event) => event.event_time,
+            Event::BookTicker(event) => event.event_time?,
+            Event::ContinuousKline(event) => event.event_time,
+            Event::IndexPriceKline(event) | Event::MarkPriceKline(event) => event.event_time,
+            Event::ContractInfo(event) => event.event_time,
+            Event::CompositeIndex(event) => event.event_time,
+            Event::AssetIndex(event) => event.event_time,
+            Event::AssetIndexes(events) => events.first()?.event_time,
+            Event::Message(_)
+            | Event::ParseError(..)
+            | Event::Ping(_)
+            | Event::Disconnected(_)
+            | Event::Reconnected => return None,
+        };
+        Some(event_time as i64)
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, PartialEq)]
@@ -348,6 +906,391 @@ pub struct AccountUpdatePosition {
     pub position_side: String,
 }
 
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct MarginCall {
+    #[serde(rename = "e")]
+    pub event_type: String,
+    #[serde(rename = "E")]
+    pub event_time: u64,
+    /// Only sent for crossed positions.
+    #[serde(default, rename = "cw", deserialize_with = "parse_opt_f64_string")]
+    pub cross_wallet_balance: Option<f64>,
+    #[serde(rename = "p")]
+    pub positions: Vec<MarginCallPosition>,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct MarginCallPosition {
+    #[serde(rename = "s")]
+    pub symbol: String,
+    #[serde(rename = "ps")]
+    pub position_side: String,
+    #[serde(rename = "pa", deserialize_with = "parse_f64_string")]
+    pub position_amount: f64,
+    #[serde(rename = "mt")]
+    pub margin_type: String,
+    #[serde(rename = "iw", deserialize_with = "parse_f64_string")]
+    pub isolated_wallet: f64,
+    #[serde(rename = "mp", deserialize_with = "parse_f64_string")]
+    pub mark_price: f64,
+    #[serde(rename = "up", deserialize_with = "parse_f64_string")]
+    pub unrealized_profit: f64,
+    #[serde(rename = "mm", deserialize_with = "parse_f64_string")]
+    pub maint_margin: f64,
+}
+
+/// Either `leverage` or `multi_assets` is set, depending on what changed.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct AccountConfigUpdate {
+    #[serde(rename = "e")]
+    pub event_type: String,
+    #[serde(rename = "E")]
+    pub event_time: u64,
+    #[serde(rename = "T")]
+    pub transaction_time: u64,
+    #[serde(default, rename = "ac")]
+    pub leverage: Option<LeverageUpdate>,
+    #[serde(default, rename = "ai")]
+    pub multi_assets: Option<MultiAssetsUpdate>,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct LeverageUpdate {
+    #[serde(rename = "s")]
+    pub symbol: String,
+    #[serde(rename = "l")]
+    pub leverage: u32,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct MultiAssetsUpdate {
+    #[serde(rename = "j")]
+    pub multi_assets_margin: bool,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct StrategyUpdate {
+    #[serde(rename = "e")]
+    pub event_type: String,
+    #[serde(rename = "E")]
+    pub event_time: u64,
+    #[serde(rename = "T")]
+    pub transaction_time: u64,
+    #[serde(rename = "su")]
+    pub update: StrategyStatus,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct StrategyStatus {
+    #[serde(rename = "si")]
+    pub strategy_id: u64,
+    #[serde(rename = "st")]
+    pub strategy_type: String,
+    #[serde(rename = "ss")]
+    pub strategy_status: String,
+    #[serde(rename = "s")]
+    pub symbol: String,
+    #[serde(rename = "ut")]
+    pub update_time: u64,
+    /// The reason for the update, see the opcode table in the API docs.
+    #[serde(rename = "c")]
+    pub op_code: u32,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct GridUpdate {
+    #[serde(rename = "e")]
+    pub event_type: String,
+    #[serde(rename = "E")]
+    pub event_time: u64,
+    #[serde(rename = "T")]
+    pub transaction_time: u64,
+    #[serde(rename = "gu")]
+    pub update: GridStatus,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct GridStatus {
+    #[serde(rename = "si")]
+    pub strategy_id: u64,
+    #[serde(rename = "st")]
+    pub strategy_type: String,
+    #[serde(rename = "ss")]
+    pub strategy_status: String,
+    #[serde(rename = "s")]
+    pub symbol: String,
+    #[serde(rename = "r", deserialize_with = "parse_f64_string")]
+    pub realized_pnl: f64,
+    #[serde(rename = "up", deserialize_with = "parse_f64_string")]
+    pub unmatched_average_price: f64,
+    #[serde(rename = "uq", deserialize_with = "parse_f64_string")]
+    pub unmatched_quantity: f64,
+    #[serde(rename = "uf", deserialize_with = "parse_f64_string")]
+    pub unmatched_fee: f64,
+    #[serde(rename = "mp", deserialize_with = "parse_f64_string")]
+    pub matched_pnl: f64,
+    #[serde(rename = "ut")]
+    pub update_time: u64,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct ConditionalOrderTriggerReject {
+    #[serde(rename = "e")]
+    pub event_type: String,
+    #[serde(rename = "E")]
+    pub event_time: u64,
+    #[serde(rename = "T")]
+    pub transaction_time: u64,
+    #[serde(rename = "or")]
+    pub order: RejectedOrder,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct RejectedOrder {
+    #[serde(rename = "s")]
+    pub symbol: String,
+    #[serde(rename = "i")]
+    pub order_id: u64,
+    #[serde(rename = "r")]
+    pub reason: String,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct TradeLite {
+    #[serde(rename = "e")]
+    pub event_type: String,
+    #[serde(rename = "E")]
+    pub event_time: u64,
+    #[serde(rename = "T")]
+    pub transaction_time: u64,
+    #[serde(rename = "s")]
+    pub symbol: String,
+    #[serde(rename = "q", deserialize_with = "parse_f64_string")]
+    pub orig_qty: f64,
+    #[serde(rename = "p", deserialize_with = "parse_f64_string")]
+    pub orig_price: f64,
+    #[serde(rename = "m")]
+    pub is_maker: bool,
+    #[serde(rename = "c")]
+    pub client_order_id: String,
+    #[serde(rename = "S")]
+    pub order_side: String,
+    #[serde(rename = "L", deserialize_with = "parse_f64_string")]
+    pub last_fill_price: f64,
+    #[serde(rename = "l", deserialize_with = "parse_f64_string")]
+    pub last_fill_amount: f64,
+    #[serde(rename = "t")]
+    pub trade_id: u64,
+    #[serde(rename = "i")]
+    pub order_id: u64,
+}
+
+/// True for the name of a partial depth stream such as `btcusdt@depth5@100ms`,
+/// checked without parsing the name as it is done per frame.
+pub(crate) fn is_partial_depth(stream: &str) -> bool {
+    let kind = stream.split('@').nth(1).unwrap_or_default();
+    match kind.strip_prefix("depth") {
+        Some(levels) => !levels.is_empty() && levels.bytes().all(|b| b.is_ascii_digit()),
+        None => false,
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct MarkPrice {
+    #[serde(rename = "e")]
+    pub event_type: String,
+    #[serde(rename = "E")]
+    pub event_time: u64,
+    #[serde(rename = "s")]
+    pub symbol: String,
+    #[serde(rename = "p", deserialize_with = "parse_f64_string")]
+    pub mark_price: f64,
+    #[serde(rename = "i", deserialize_with = "parse_f64_string")]
+    pub index_price: f64,
+    /// Only useful in the last hour before settlement.
+    #[serde(rename = "P", deserialize_with = "parse_f64_string")]
+    pub estimated_settle_price: f64,
+    #[serde(rename = "r", deserialize_with = "parse_f64_string")]
+    pub funding_rate: f64,
+    #[serde(rename = "T")]
+    pub next_funding_time: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct DepthUpdate {
+    #[serde(rename = "e")]
+    pub event_type: String,
+    #[serde(rename = "E")]
+    pub event_time: u64,
+    #[serde(rename = "T")]
+    pub transaction_time: u64,
+    #[serde(rename = "s")]
+    pub symbol: String,
+    #[serde(rename = "U")]
+    pub first_update_id: u64,
+    #[serde(rename = "u")]
+    pub final_update_id: u64,
+    /// The final update id of the previous event.
+    #[serde(rename = "pu")]
+    pub previous_final_update_id: u64,
+    #[serde(rename = "b")]
+    pub bids: Vec<PriceLevel>,
+    #[serde(rename = "a")]
+    pub asks: Vec<PriceLevel>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct BookTicker {
+    #[serde(rename = "u")]
+    pub update_id: u64,
+    /// Not sent by every stream.
+    #[serde(default, rename = "E")]
+    pub event_time: Option<u64>,
+    #[serde(default, rename = "T")]
+    pub transaction_time: Option<u64>,
+    #[serde(rename = "s")]
+    pub symbol: String,
+    #[serde(rename = "b", deserialize_with = "parse_f64_string")]
+    pub bid_price: f64,
+    #[serde(rename = "B", deserialize_with = "parse_f64_string")]
+    pub bid_quantity: f64,
+    #[serde(rename = "a", deserialize_with = "parse_f64_string")]
+    pub ask_price: f64,
+    #[serde(rename = "A", deserialize_with = "parse_f64_string")]
+    pub ask_quantity: f64,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ContinuousKlineEvent {
+    #[serde(rename = "e")]
+    pub event_type: String,
+    #[serde(rename = "E")]
+    pub event_time: u64,
+    #[serde(rename = "ps")]
+    pub pair: String,
+    /// `PERPETUAL`, `CURRENT_QUARTER` or `NEXT_QUARTER`.
+    #[serde(rename = "ct")]
+    pub contract_type: String,
+    /// The symbol of the kline is empty.
+    #[serde(rename = "k")]
+    pub kline: Kline,
+}
+
+/// An index price or mark price kline. Volumes and trade counts are zero or
+/// count price updates rather than trades.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct PriceKlineEvent {
+    #[serde(rename = "e")]
+    pub event_type: String,
+    #[serde(rename = "E")]
+    pub event_time: u64,
+    #[serde(rename = "ps")]
+    pub pair: String,
+    #[serde(rename = "k")]
+    pub kline: Kline,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ContractInfo {
+    #[serde(rename = "e")]
+    pub event_type: String,
+    #[serde(rename = "E")]
+    pub event_time: u64,
+    #[serde(rename = "s")]
+    pub symbol: String,
+    #[serde(rename = "ps")]
+    pub pair: String,
+    #[serde(rename = "ct")]
+    pub contract_type: String,
+    #[serde(rename = "dt")]
+    pub delivery_time: u64,
+    #[serde(rename = "ot")]
+    pub onboard_time: u64,
+    #[serde(rename = "cs")]
+    pub contract_status: String,
+    /// Only sent when the brackets changed.
+    #[serde(default, rename = "bks")]
+    pub brackets: Vec<ContractBracket>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ContractBracket {
+    #[serde(rename = "bs")]
+    pub bracket: u32,
+    #[serde(rename = "bnf")]
+    pub notional_floor: f64,
+    #[serde(rename = "bnc")]
+    pub notional_cap: f64,
+    #[serde(rename = "mmr")]
+    pub maint_margin_ratio: f64,
+    #[serde(rename = "cf")]
+    pub cum: f64,
+    #[serde(rename = "mi")]
+    pub min_leverage: u32,
+    #[serde(rename = "ma")]
+    pub max_leverage: u32,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct CompositeIndex {
+    #[serde(rename = "e")]
+    pub event_type: String,
+    #[serde(rename = "E")]
+    pub event_time: u64,
+    #[serde(rename = "s")]
+    pub symbol: String,
+    #[serde(rename = "p", deserialize_with = "parse_f64_string")]
+    pub price: f64,
+    /// The asset the weights are in, `baseAsset` or `quoteAsset`.
+    #[serde(rename = "C")]
+    pub weight_asset: String,
+    #[serde(rename = "c")]
+    pub composition: Vec<IndexComponent>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct IndexComponent {
+    #[serde(rename = "b")]
+    pub base_asset: String,
+    #[serde(rename = "q")]
+    pub quote_asset: String,
+    #[serde(rename = "w", deserialize_with = "parse_f64_string")]
+    pub weight_in_quantity: f64,
+    #[serde(rename = "W", deserialize_with = "parse_f64_string")]
+    pub weight_in_percentage: f64,
+    #[serde(rename = "i", deserialize_with = "parse_f64_string")]
+    pub index_price: f64,
+}
+
+/// The index of a multi-assets mode margin asset.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct AssetIndex {
+    #[serde(rename = "e")]
+    pub event_type: String,
+    #[serde(rename = "E")]
+    pub event_time: u64,
+    #[serde(rename = "s")]
+    pub symbol: String,
+    #[serde(rename = "i", deserialize_with = "parse_f64_string")]
+    pub index_price: f64,
+    #[serde(rename = "b", deserialize_with = "parse_f64_string")]
+    pub bid_buffer: f64,
+    #[serde(rename = "a", deserialize_with = "parse_f64_string")]
+    pub ask_buffer: f64,
+    #[serde(rename = "B", deserialize_with = "parse_f64_string")]
+    pub bid_rate: f64,
+    #[serde(rename = "A", deserialize_with = "parse_f64_string")]
+    pub ask_rate: f64,
+    #[serde(rename = "q", deserialize_with = "parse_f64_string")]
+    pub auto_exchange_bid_buffer: f64,
+    #[serde(rename = "g", deserialize_with = "parse_f64_string")]
+    pub auto_exchange_ask_buffer: f64,
+    #[serde(rename = "Q", deserialize_with = "parse_f64_string")]
+    pub auto_exchange_bid_rate: f64,
+    #[serde(rename = "D", deserialize_with = "parse_f64_string")]
+    pub auto_exchange_ask_rate: f64,
+}
+
 #[derive(Deserialize, Clone, Debug, PartialEq)]
 pub struct LiquidationEvent {
     #[serde(rename = "s")]
@@ -418,6 +1361,113 @@ pub struct Ticker {
 mod test {
     use super::*;
 
+    #[tokio::test]
+    async fn test_replay() {
+        let text = r#"
+            {"type":"frame","time":1612418801180,"kind":"text","data":"{\"e\":\"aggTrade\",\"E\":123456789,\"s\":\"BTCUSDT\",\"a\":5933014,\"p\":\"0.001\",\"q\":\"100\",\"f\":100,\"l\":105,\"T\":123456785,\"m\":true}"}
+            {"type":"frame","time":1612418801190,"kind":"ping","data":"AQI="}
+        "#;
+        let entries = cassette::parse(text.as_bytes()).unwrap();
+        let mut ws = WebSocket::replay(&entries);
+        // Checked before the request is sent, which would fail on replay.
+        assert!(matches!(
+            ws.subscribe_streams(&[StreamName::trade("btcusdt")]).await,
+            Err(crate::Error::StreamName(_))
+        ));
+        assert!(matches!(ws.next().await, Some(Ok(Event::AggTrade(_)))));
+        match ws.next().await {
+            Some(Ok(Event::Ping(data))) => assert_eq!(data, [1, 2]),
+            event => panic!("unexpected event {:?}", event),
+        }
+        assert!(ws.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_stream() {
+        let text = r#"
+            {"type":"frame","time":1612418801180,"kind":"text","data":"{\"e\":\"aggTrade\",\"E\":123456789,\"s\":\"BTCUSDT\",\"a\":5933014,\"p\":\"0.001\",\"q\":\"100\",\"f\":100,\"l\":105,\"T\":123456785,\"m\":true}"}
+            {"type":"frame","time":1612418801185,"kind":"pong","data":""}
+            {"type":"frame","time":1612418801190,"kind":"ping","data":"AQI="}
+        "#;
+        let entries = cassette::parse(text.as_bytes()).unwrap();
+        let events: Vec<Event> = WebSocket::replay(&entries)
+            .filter_map(|event| async move { event.ok() })
+            .collect()
+            .await;
+        assert_eq!(events.len(), 2);
+        assert!(matches!(events[0], Event::AggTrade(_)));
+        assert!(matches!(events[1], Event::Ping(_)));
+
+        // Requests fail on replay rather than waiting for a reply.
+        let ws = WebSocket::replay(&entries);
+        let writer = ws.writer();
+        let reader = tokio::spawn(ws.count());
+        assert!(writer.subscribe(&["btcusdt@aggTrade"]).await.is_err());
+        assert!(matches!(
+            writer
+                .subscribe_streams(&[StreamName::trade("btcusdt")], Market::Futures)
+                .await,
+            Err(crate::Error::StreamName(_))
+        ));
+        assert_eq!(reader.await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_next_received() {
+        let text = r#"
+            {"type":"frame","time":1612418801180,"kind":"text","data":"{\"stream\":\"btcusdt@aggTrade\",\"data\":{\"e\":\"aggTrade\",\"E\":123456789,\"s\":\"BTCUSDT\",\"a\":5933014,\"p\":\"0.001\",\"q\":\"100\",\"f\":100,\"l\":105,\"T\":123456785,\"m\":true}}"}
+            {"type":"frame","time":1612418801190,"kind":"ping","data":"AQI="}
+        "#;
+        let entries = cassette::parse(text.as_bytes()).unwrap();
+        let mut ws = WebSocket::replay(&entries);
+
+        let received = ws.next_received().await.unwrap().unwrap();
+        assert!(matches!(received.event, Event::AggTrade(_)));
+        assert_eq!(received.stream.as_deref(), Some("btcusdt@aggTrade"));
+        assert_eq!(received.receipt.connection_id, 0);
+        assert_eq!(received.event.event_time(), Some(123456789));
+        assert_eq!(
+            received.latency(-received.receipt.wall_clock),
+            Some(-123456789)
+        );
+
+        let received = ws.next_received().await.unwrap().unwrap();
+        assert!(received.event.is_ping());
+        assert_eq!(received.stream, None);
+        assert_eq!(received.event.event_time(), None);
+        assert!(ws.next_received().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_single_stream_partial_depth() {
+        // Serve a single stream connection sending the same frame twice.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let text = r#"{"e":"depthUpdate","E":1571889248277,"T":1571889248276,"s":"BTCUSDT","U":390497796,"u":390497878,"pu":390497794,"b":[["7403.89","0.002"]],"a":[["7405.96","3.340"]]}"#;
+            for _ in 0..2 {
+                futures_util::SinkExt::send(&mut ws, Message::Text(text.to_string()))
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let environment = Environment::custom("http://127.0.0.1", format!("ws://{}", address));
+        let mut ws = connect_stream_with_environment(&environment, "btcusdt@depth5@100ms")
+            .await
+            .unwrap();
+        assert!(matches!(
+            ws.next().await,
+            Some(Ok(Event::PartialDepth(depth))) if depth.symbol == "BTCUSDT"
+        ));
+        let received = ws.next_received().await.unwrap().unwrap();
+        assert!(matches!(received.event, Event::PartialDepth(_)));
+        assert_eq!(received.stream.as_deref(), Some("btcusdt@depth5@100ms"));
+        server.await.unwrap();
+    }
+
     #[test]
     fn test_decode_order_trade_update() {
         let _text = r#"{
@@ -432,7 +1482,7 @@ mod test {
                 "f":"GTC",
                 "q":"0.100",
                 "p":"40000","ap":"0","sp":"0","x":"NEW","X":"NEW","i":13584185467,"l":"0","z":"0","L":"0","T":1612418801174,"t":0,"b":"0","a":"4000","m":false,"R":false,"wt":"CONTRACT_PRICE","ot":"LIMIT","ps":"SHORT","cp":false,"rp":"0","pP":false,"si":0,"ss":0}}"#;
-        let _order_trade_update: OrderTradeUpdateEvent = serde_json::from_str(&_text).unwrap();
+        let _order_trade_update: OrderTradeUpdateEvent = serde_json::from_str(_text).unwrap();
     }
 
     #[test]
@@ -490,5 +1540,223 @@ mod test {
                 \"F\":1888682750,\
                 \"L\":1891841228,\
                 \"n\":3158308}}";
+        let event = Event::decode_message(Message::Text(text.to_string()));
+        if let Event::Ticker(ticker) = event {
+            assert_eq!(ticker.symbol, "BTCUSDT");
+        } else {
+            unreachable!();
+        }
+    }
+
+    fn decode(text: &str) -> Event {
+        Event::decode_message(Message::Text(text.to_string()))
+    }
+
+    #[test]
+    fn test_decode_mark_price() {
+        let text = r#"{"e":"markPriceUpdate","E":1562305380000,"s":"BTCUSDT","p":"11794.15000000","i":"11784.62659091","P":"11784.25641265","r":"0.00038167","T":1562306400000}"#;
+        match decode(text) {
+            Event::MarkPrice(mark_price) => {
+                assert_eq!(mark_price.mark_price, 11794.15);
+                assert_eq!(mark_price.funding_rate, 0.00038167);
+                assert_eq!(mark_price.next_funding_time, 1562306400000);
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+        let text = format!(
+            r#"{{"stream":"!markPrice@arr@1s","data":[{},{}]}}"#,
+            text, text
+        );
+        assert!(matches!(decode(&text), Event::MarkPrices(prices) if prices.len() == 2));
+    }
+
+    #[test]
+    fn test_decode_depth() {
+        let text = r#"{"e":"depthUpdate","E":1571889248277,"T":1571889248276,"s":"BTCUSDT","U":390497796,"u":390497878,"pu":390497794,"b":[["7403.89","0.002"],["7403.90","3.906"]],"a":[["7405.96","3.340"]]}"#;
+        match decode(text) {
+            Event::DepthUpdate(depth) => {
+                assert_eq!(depth.previous_final_update_id, 390497794);
+                assert_eq!(depth.bids.len(), 2);
+                assert_eq!(depth.asks[0].price, 7405.96);
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+        let diff = format!(r#"{{"stream":"btcusdt@depth@100ms","data":{}}}"#, text);
+        assert!(matches!(decode(&diff), Event::DepthUpdate(_)));
+        let partial = format!(r#"{{"stream":"btcusdt@depth5@100ms","data":{}}}"#, text);
+        assert!(
+            matches!(decode(&partial), Event::PartialDepth(depth) if depth.symbol == "BTCUSDT")
+        );
+
+        // A single stream connection's frames don't name the stream.
+        let message = Message::Text(text.to_string());
+        assert!(matches!(
+            Event::decode_stream_message(message, "btcusdt@depth5@100ms"),
+            Event::PartialDepth(_)
+        ));
+        let message = Message::Text(text.to_string());
+        assert!(matches!(
+            Event::decode_stream_message(message, "btcusdt@depth@100ms"),
+            Event::DepthUpdate(_)
+        ));
+
+        assert!(is_partial_depth("btcusdt@depth20"));
+        assert!(!is_partial_depth("btcusdt@depth@500ms"));
+        assert!(!is_partial_depth("btcusdt@depthx@100ms"));
+        assert!(!is_partial_depth("depth5"));
+    }
+
+    #[test]
+    fn test_decode_tickers() {
+        let text = r#"{"e":"bookTicker","u":400900217,"E":1568014460893,"T":1568014460891,"s":"BNBUSDT","b":"25.35190000","B":"31.21000000","a":"25.36520000","A":"40.66000000"}"#;
+        match decode(text) {
+            Event::BookTicker(ticker) => {
+                assert_eq!(ticker.event_time, Some(1568014460893));
+                assert_eq!(ticker.bid_quantity, 31.21);
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+        let text = r#"{"u":400900217,"s":"BNBUSDT","b":"25.35190000","B":"31.21000000","a":"25.36520000","A":"40.66000000"}"#;
+        assert!(
+            matches!(decode(text), Event::BookTicker(ticker) if ticker.event_time.is_none() && ticker.ask_quantity == 40.66)
+        );
+
+        let text = r#"{"e":"24hrMiniTicker","E":123456789,"s":"BTCUSDT","c":"0.0025","o":"0.0010","h":"0.0025","l":"0.0010","v":"10000","q":"18"}"#;
+        assert!(matches!(decode(text), Event::MiniTicker(ticker) if ticker.open_price == 0.001));
+        let text = format!(r#"{{"stream":"!miniTicker@arr","data":[{}]}}"#, text);
+        assert!(matches!(decode(&text), Event::MiniTickers(tickers) if tickers.len() == 1));
+    }
+
+    #[test]
+    fn test_decode_klines() {
+        let text = r#"{"e":"continuous_kline","E":1607443058651,"ps":"BTCUSDT","ct":"PERPETUAL","k":{"t":1607443020000,"T":1607443079999,"i":"1m","f":116467658886,"L":116468012423,"o":"18787.00","c":"18804.04","h":"18804.04","l":"18786.54","v":"197.664","n":543,"x":false,"q":"3715253.19494","V":"184.769","Q":"3472925.84746","B":"0"}}"#;
+        match decode(text) {
+            Event::ContinuousKline(event) => {
+                assert_eq!(event.pair, "BTCUSDT");
+                assert_eq!(event.contract_type, "PERPETUAL");
+                assert_eq!(event.kline.close, 18804.04);
+                assert_eq!(event.kline.symbol, "");
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+
+        let kline = r#"{"t":1591267020000,"T":1591267079999,"s":"0","i":"1m","f":1591267020000,"L":1591267070000,"o":"9542.21900000","c":"9542.50440000","h":"9542.71640000","l":"9542.21040000","v":"0","n":52,"x":false,"q":"0","V":"0","Q":"0","B":"0"}"#;
+        let text = format!(
+            r#"{{"e":"indexPriceKline","E":1591267070033,"ps":"BTCUSD","k":{}}}"#,
+            kline
+        );
+        assert!(matches!(decode(&text), Event::IndexPriceKline(event) if event.pair == "BTCUSD"));
+        let text = format!(
+            r#"{{"e":"markPriceKline","E":1591267070033,"ps":"BTCUSD","k":{}}}"#,
+            kline
+        );
+        assert!(
+            matches!(decode(&text), Event::MarkPriceKline(event) if event.kline.trade_count == 52)
+        );
+    }
+
+    #[test]
+    fn test_decode_contract_and_indexes() {
+        let text = r#"{"e":"contractInfo","E":1669356423908,"s":"IOTAUSDT","ps":"IOTAUSDT","ct":"PERPETUAL","dt":4133404800000,"ot":1569398400000,"cs":"TRADING","bks":[{"bs":1,"bnf":0,"bnc":5000,"mmr":0.01,"cf":0,"mi":21,"ma":50},{"bs":2,"bnf":5000,"bnc":25000,"mmr":0.025,"cf":75,"mi":11,"ma":20}]}"#;
+        match decode(text) {
+            Event::ContractInfo(info) => {
+                assert_eq!(info.contract_status, "TRADING");
+                assert_eq!(info.brackets.len(), 2);
+                assert_eq!(info.brackets[1].maint_margin_ratio, 0.025);
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+        let text = r#"{"e":"contractInfo","E":1669356423908,"s":"IOTAUSDT","ps":"IOTAUSDT","ct":"PERPETUAL","dt":4133404800000,"ot":1569398400000,"cs":"TRADING"}"#;
+        assert!(matches!(decode(text), Event::ContractInfo(info) if info.brackets.is_empty()));
+
+        let text = r#"{"e":"compositeIndex","E":1602310596000,"s":"DEFIUSDT","p":"554.41604065","C":"baseAsset","c":[{"b":"BAL","q":"USDT","w":"1.04884844","W":"0.01457800","i":"24.33521021"},{"b":"BAND","q":"USDT","w":"3.53782729","W":"0.03935200","i":"7.26420084"}]}"#;
+        match decode(text) {
+            Event::CompositeIndex(index) => {
+                assert_eq!(index.price, 554.41604065);
+                assert_eq!(index.composition[1].base_asset, "BAND");
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+
+        let text = r#"{"e":"assetIndexUpdate","E":1686749230000,"s":"ADAUSD","i":"0.27462452","b":"0.10000000","a":"0.10000000","B":"0.24716207","A":"0.30208698","q":"0.05000000","g":"0.05000000","Q":"0.26089330","D":"0.28835575"}"#;
+        assert!(
+            matches!(decode(text), Event::AssetIndex(index) if index.index_price == 0.27462452)
+        );
+        let text = format!(r#"{{"stream":"!assetIndex@arr","data":[{}]}}"#, text);
+        assert!(matches!(decode(&text), Event::AssetIndexes(indexes) if indexes.len() == 1));
+    }
+
+    #[test]
+    fn test_decode_margin_call_and_config() {
+        let text = r#"{"e":"MARGIN_CALL","E":1587727187525,"cw":"3.16812045","p":[{"s":"ETHUSDT","ps":"LONG","pa":"1.327","mt":"CROSSED","iw":"0","mp":"187.17127","up":"-1.166074","mm":"1.614445"}]}"#;
+        match decode(text) {
+            Event::MarginCall(call) => {
+                assert_eq!(call.cross_wallet_balance, Some(3.16812045));
+                assert_eq!(call.positions[0].maint_margin, 1.614445);
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+
+        let text = r#"{"e":"ACCOUNT_CONFIG_UPDATE","E":1611646737479,"T":1611646737476,"ac":{"s":"BTCUSDT","l":25}}"#;
+        match decode(text) {
+            Event::AccountConfigUpdate(update) => {
+                assert_eq!(
+                    update.leverage,
+                    Some(LeverageUpdate {
+                        symbol: "BTCUSDT".to_string(),
+                        leverage: 25
+                    })
+                );
+                assert_eq!(update.multi_assets, None);
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+        let text =
+            r#"{"e":"ACCOUNT_CONFIG_UPDATE","E":1611646737479,"T":1611646737476,"ai":{"j":true}}"#;
+        assert!(matches!(
+            decode(text),
+            Event::AccountConfigUpdate(AccountConfigUpdate {
+                multi_assets: Some(MultiAssetsUpdate {
+                    multi_assets_margin: true
+                }),
+                ..
+            })
+        ));
+
+        let text = r#"{"e":"listenKeyExpired","E":"1736996475556","listenKey":"WsCMN0a4KHUPTQuX6IUnqEZfB1inxmv1qR4kbf1LuEjur5VdbzqvyxqG9TSjVVxv"}"#;
+        assert!(
+            matches!(decode(text), Event::ListenKeyExpired(event) if event.event_time == 1736996475556)
+        );
+    }
+
+    #[test]
+    fn test_decode_strategy_events() {
+        let text = r#"{"e":"STRATEGY_UPDATE","T":1669262908216,"E":1669262908218,"su":{"si":176054594,"st":"GRID","ss":"NEW","s":"BTCUSDT","ut":1669262908197,"c":9}}"#;
+        match decode(text) {
+            Event::StrategyUpdate(update) => {
+                assert_eq!(update.update.strategy_status, "NEW");
+                assert_eq!(update.update.op_code, 9);
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+
+        let text = r#"{"e":"GRID_UPDATE","T":1669262908216,"E":1669262908218,"gu":{"si":176057039,"st":"GRID","ss":"WORKING","s":"BTCUSDT","r":"-0.00300716","up":"16720","uq":"-0.001","uf":"-0.00300716","mp":"0.0","ut":1669262908197}}"#;
+        assert!(
+            matches!(decode(text), Event::GridUpdate(update) if update.update.unmatched_quantity == -0.001)
+        );
+
+        let text = r#"{"e":"CONDITIONAL_ORDER_TRIGGER_REJECT","E":1685517224945,"T":1685517224955,"or":{"s":"ETHUSDT","i":155618472834,"r":"Due to the order could not be filled immediately, the FOK order has been rejected. The order will not be recorded in the order history"}}"#;
+        assert!(
+            matches!(decode(text), Event::ConditionalOrderTriggerReject(reject) if reject.order.order_id == 155618472834)
+        );
+
+        let text = r#"{"e":"TRADE_LITE","E":1721895408092,"T":1721895408214,"s":"BTCUSDT","q":"0.001","p":"0","m":false,"c":"z8hcUoOsqEdKMeKPSABslD","S":"BUY","L":"64089.20","l":"0.040","t":109100866,"i":8886774}"#;
+        match decode(text) {
+            Event::TradeLite(trade) => {
+                assert_eq!(trade.last_fill_price, 64089.2);
+                assert_eq!(trade.order_id, 8886774);
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
     }
 }
@@ -0,0 +1,82 @@
+// SPDX-License-Identifier: MIT
+
+//! The USD-M futures WebSocket API, see `common::ws_api`. Unlike spot, the
+//! futures user data stream is not pushed on this connection; it is still
+//! read from a listen key stream.
+
+use std::sync::Arc;
+
+use crate::common::client::Authentication;
+use crate::common::environment::Environment;
+use crate::common::ratelimit::RateLimitState;
+use crate::common::ws_api::WsApiConnection;
+use crate::error::Error;
+use crate::futures::client::{CancelOrderResponse, NewOrder, OpenOrder, OrderResponse};
+use crate::types::{CancelOrder, QueryOrder};
+
+pub const WS_API_URL: &str = "wss://ws-fapi.binance.com/ws-fapi/v1";
+pub const TESTNET_WS_API_URL: &str = "wss://testnet.binancefuture.com/ws-fapi/v1";
+
+pub struct WsApi {
+    connection: WsApiConnection,
+}
+
+impl WsApi {
+    pub async fn connect(authentication: Authentication) -> Result<Self, Error> {
+        Self::connect_with_environment(&Environment::Mainnet, authentication).await
+    }
+
+    pub async fn connect_with_environment(
+        environment: &Environment,
+        authentication: Authentication,
+    ) -> Result<Self, Error> {
+        let url = environment.futures_ws_api_url().ok_or_else(|| {
+            Error::UrlError(format!(
+                "no futures WebSocket API for environment {:?}",
+                environment
+            ))
+        })?;
+        let connection = WsApiConnection::connect(url, Some(authentication)).await?;
+        Ok(Self { connection })
+    }
+
+    /// The underlying connection, for requests without a method here.
+    pub fn connection(&self) -> &WsApiConnection {
+        &self.connection
+    }
+
+    pub fn rate_limits(&self) -> Arc<RateLimitState> {
+        self.connection.rate_limits()
+    }
+
+    /// Authenticate the session, needs an Ed25519 key.
+    pub async fn logon(&self) -> Result<(), Error> {
+        self.connection.logon().await?;
+        Ok(())
+    }
+
+    pub async fn place_order(&self, order: &NewOrder) -> Result<OrderResponse, Error> {
+        let form = serde_urlencoded::to_string(order)?;
+        self.connection
+            .signed_request("order.place", &form)
+            .await?
+            .decode()
+    }
+
+    pub async fn cancel_order(&self, request: &CancelOrder) -> Result<CancelOrderResponse, Error> {
+        let form = serde_urlencoded::to_string(request)?;
+        self.connection
+            .signed_request("order.cancel", &form)
+            .await?
+            .decode()
+    }
+
+    /// Query an order by order ID or client order ID.
+    pub async fn get_order(&self, request: &QueryOrder) -> Result<OpenOrder, Error> {
+        let form = serde_urlencoded::to_string(request)?;
+        self.connection
+            .signed_request("order.status", &form)
+            .await?
+            .decode()
+    }
+}
@@ -22,32 +22,78 @@
 
 //! Helper parser/deserializes for decoding some Binance messages.
 
-use serde::de::Error;
-use serde::{Deserialize, Serializer};
+use std::fmt;
+use std::marker::PhantomData;
+use std::str::FromStr;
+
+use serde::de::{Error, Visitor};
+use serde::Serializer;
+
+/// Parses a value sent as a string from the `&str`, borrowed from the input
+/// when the deserializer allows, so no `String` is allocated.
+struct FromStrVisitor<T>(PhantomData<T>);
+
+impl<'de, T> Visitor<'de> for FromStrVisitor<T>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    type Value = T;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a string")
+    }
+
+    fn visit_str<E: Error>(self, v: &str) -> Result<T, E> {
+        v.parse().map_err(E::custom)
+    }
+}
 
 pub fn parse_f64_string<'de, D>(d: D) -> Result<f64, D::Error>
 where
     D: serde::de::Deserializer<'de>,
 {
-    let s: String = serde::de::Deserialize::deserialize(d)?;
-    s.parse::<f64>().map_err(D::Error::custom)
+    d.deserialize_str(FromStrVisitor(PhantomData))
 }
 
 pub fn parse_opt_f64_string<'de, D>(d: D) -> Result<Option<f64>, D::Error>
 where
     D: serde::de::Deserializer<'de>,
 {
-    let s: String = Deserialize::deserialize(d)?;
-    let val = s.parse::<f64>().map_err(D::Error::custom)?;
-    Ok(Some(val))
+    parse_f64_string(d).map(Some)
 }
 
 pub fn parse_bool_string<'de, D>(d: D) -> Result<bool, D::Error>
 where
     D: serde::de::Deserializer<'de>,
 {
-    let s: String = Deserialize::deserialize(d)?;
-    s.parse::<bool>().map_err(D::Error::custom)
+    d.deserialize_str(FromStrVisitor(PhantomData))
+}
+
+/// A u64 sent as either a number or a string.
+pub fn parse_u64_or_string<'de, D>(d: D) -> Result<u64, D::Error>
+where
+    D: serde::de::Deserializer<'de>,
+{
+    struct U64OrString;
+
+    impl<'de> Visitor<'de> for U64OrString {
+        type Value = u64;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a u64 or a string")
+        }
+
+        fn visit_u64<E: Error>(self, v: u64) -> Result<u64, E> {
+            Ok(v)
+        }
+
+        fn visit_str<E: Error>(self, v: &str) -> Result<u64, E> {
+            v.parse().map_err(E::custom)
+        }
+    }
+
+    d.deserialize_any(U64OrString)
 }
 
 pub(crate) fn serialize_opt_f64<S>(v: &Option<f64>, s: S) -> Result<S::Ok, S::Error>
@@ -1,31 +1,88 @@
 // SPDX-License-Identifier: MIT
 
 use std::collections::HashMap;
+use std::sync::Arc;
 
-use reqwest::StatusCode;
+use reqwest::Method;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
 use crate::common;
+use crate::common::cassette::Cassette;
 use crate::common::client::{Authentication, ListenKeyResponse};
-use crate::error::Error;
-use crate::futures::client::ApiError;
+use crate::common::environment::Environment;
+use crate::common::listenkey::ListenKeyManager;
+use crate::common::ratelimit::{RateLimit, RateLimitState};
+use crate::common::retry::RetryPolicy;
+use crate::common::time::{ServerTimeResponse, TimeSync};
+use crate::common::transport::{HttpResponse, HttpTransport};
+use crate::error::{ApiErrorCode, Error};
 use crate::parsers::*;
+use crate::types::QueryOrder;
 
 pub const API_ROOT: &str = "https://api.binance.com";
+pub const TESTNET_API_ROOT: &str = "https://testnet.binance.vision";
+pub const US_API_ROOT: &str = "https://api.binance.us";
+
+/// The endpoint that creates, keeps alive and closes listen keys.
+const LISTEN_KEY_ENDPOINT: &str = "/api/v3/userDataStream";
 
 #[derive(Clone)]
 pub struct Client {
     client: crate::common::client::Client,
+    environment: Environment,
 }
 
 impl Client {
     pub fn new(authentication: Option<Authentication>) -> Self {
+        Self::with_environment(&Environment::Mainnet, authentication)
+    }
+
+    pub fn with_environment(
+        environment: &Environment,
+        authentication: Option<Authentication>,
+    ) -> Self {
         Self {
-            client: common::client::Client::new(API_ROOT, authentication),
+            client: common::client::Client::new(environment.spot_rest_url(), authentication)
+                .with_time_endpoint("/api/v3/time"),
+            environment: environment.clone(),
         }
     }
 
+    /// The environment this client talks to.
+    pub fn environment(&self) -> &Environment {
+        &self.environment
+    }
+
+    /// Set the recvWindow in milliseconds used for all signed requests.
+    pub fn set_recv_window(&mut self, recv_window: u64) {
+        self.client.set_recv_window(recv_window);
+    }
+
+    /// A copy of this client using a different recvWindow, for use on a
+    /// single request. The clock offset is shared with this client.
+    pub fn with_recv_window(&self, recv_window: u64) -> Self {
+        let mut client = self.clone();
+        client.set_recv_window(recv_window);
+        client
+    }
+
+    /// Synchronise with the server clock, returning the offset in
+    /// milliseconds. Once synced the offset is periodically refreshed.
+    pub async fn sync_time(&self) -> Result<i64, Error> {
+        self.client.sync_time().await
+    }
+
+    /// The clock offset kept by `sync_time`, for example for a
+    /// `LatencyTracker`.
+    pub fn time_sync(&self) -> Arc<TimeSync> {
+        self.client.time_sync()
+    }
+
+    pub async fn get_server_time(&self) -> Result<ServerTimeResponse, Error> {
+        self.get("/api/v3/time", None).await
+    }
+
     pub async fn get_account(&self) -> anyhow::Result<AccountResponse> {
         let endpoint = "/api/v3/account";
         let form: Vec<(&str, &str)> = vec![];
@@ -34,21 +91,56 @@ impl Client {
         Ok(response)
     }
 
+    /// Place a new order.
+    ///
+    /// Orders with a client order ID are retried under the retry policy, but
+    /// only after looking the order up to make sure the failed attempt did
+    /// not place it. Orders without a client order ID are never retried.
     pub async fn post_order(&self, order: &OrderRequest) -> Result<OrderResponse, Error> {
         let endpoint = "/api/v3/order";
-        let form = serde_urlencoded::to_string(&order)?;
-        let form = self.client.sign_form(Some(&form))?;
-        let url = self.client.url2(endpoint, None)?;
-        let request = self
-            .client
-            .client
-            .post(url)
-            .headers(self.client.headers()?)
-            .body(form);
-        let response = request.send().await?;
-        let code = response.status();
-        let body = response.text().await?;
-        self.decode_response(code, &body)
+        let form = serde_urlencoded::to_string(order)?;
+        let policy = self.client.retry_policy();
+        let mut attempt = 1;
+        loop {
+            let result = self.client.send_signed(Method::POST, endpoint, &form).await;
+            let query = match &order.client_order_id {
+                Some(client_order_id)
+                    if attempt < policy.max_attempts && policy.should_retry(&result) =>
+                {
+                    QueryOrder::by_client_order_id(&order.symbol, client_order_id)
+                }
+                _ => {
+                    let response = result?;
+                    return self.decode_response(&response);
+                }
+            };
+            tokio::time::sleep(policy.backoff_for(attempt)).await;
+            match self.get_order(&query).await {
+                Ok(order) => return Ok(order.into()),
+                Err(err) if err.api_error_code() == Some(ApiErrorCode::NoSuchOrder) => {}
+                Err(_) => {
+                    // The state of the order is unknown, don't risk placing
+                    // it twice.
+                    let response = result?;
+                    return self.decode_response(&response);
+                }
+            }
+            attempt += 1;
+        }
+    }
+
+    /// Query an order by order ID or client order ID.
+    pub async fn get_order(&self, request: &QueryOrder) -> Result<Order, Error> {
+        let endpoint = "/api/v3/order";
+        let form = serde_urlencoded::to_string(request)?;
+        self.authenticated_get(endpoint, &form).await
+    }
+
+    /// Get the open orders of a symbol, or of all symbols.
+    pub async fn get_open_orders(&self, symbol: Option<&str>) -> Result<Vec<Order>, Error> {
+        let endpoint = "/api/v3/openOrders";
+        let form = serde_urlencoded::to_string([("symbol", symbol)])?;
+        self.authenticated_get(endpoint, &form).await
     }
 
     pub async fn authenticated_get<T: DeserializeOwned>(
@@ -56,15 +148,8 @@ impl Client {
         endpoint: &str,
         form: &str,
     ) -> Result<T, Error> {
-        let form = self.client.sign_form(Some(form))?;
-        let request = self
-            .client
-            .get2(endpoint, Some(&form))?
-            .headers(self.client.headers()?);
-        let response = request.send().await?;
-        let code = response.status();
-        let body = response.text().await?;
-        self.decode_response(code, &body)
+        let response = self.client.send_signed(Method::GET, endpoint, form).await?;
+        self.decode_response(&response)
     }
 
     pub async fn get<T: DeserializeOwned>(
@@ -72,11 +157,11 @@ impl Client {
         endpoint: &str,
         query_string: Option<&str>,
     ) -> Result<T, Error> {
-        let url = self.client.url2(endpoint, query_string)?;
-        let response = self.client.client.get(url).send().await?;
-        let code = response.status();
-        let body = response.text().await?;
-        self.decode_response(code, &body)
+        let response = self
+            .client
+            .send(Method::GET, endpoint, query_string)
+            .await?;
+        self.decode_response(&response)
     }
 
     pub async fn get_ticker_price(&self) -> Result<Vec<TickerPriceEntry>, Error> {
@@ -84,43 +169,71 @@ impl Client {
         self.get(endpoint, None).await
     }
 
+    /// Get the exchange info. The advertised rate limits are recorded so
+    /// they can be compared with the usage reported by `rate_limits`.
     pub async fn get_exchange_info(&self) -> Result<ExchangeInfoResponse, Error> {
         let endpoint = "/api/v3/exchangeInfo";
-        self.get(endpoint, None).await
+        let info: ExchangeInfoResponse = self.get(endpoint, None).await?;
+        self.client.set_rate_limits(info.rateLimits.clone());
+        Ok(info)
+    }
+
+    /// Throttle requests to stay under the rate limits, waiting rather than
+    /// sending a request that would breach them. The limits are loaded by
+    /// `get_exchange_info`.
+    pub fn enable_scheduler(&mut self) {
+        self.client.enable_scheduler();
     }
 
-    pub fn decode_response<T>(&self, status: StatusCode, body: &str) -> Result<T, Error>
+    /// Set the policy for retrying requests that failed for transient
+    /// reasons. GET requests are retried, see `post_order` for orders.
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.client.set_retry_policy(retry_policy);
+    }
+
+    /// Send requests through another transport, for example a
+    /// `MockTransport` to test without the network.
+    pub fn set_transport(&mut self, transport: Arc<dyn HttpTransport>) {
+        self.client.set_transport(transport);
+    }
+
+    /// Record all requests and responses to a cassette. Pass a
+    /// `ReplayTransport` to `set_transport` to replay them.
+    pub fn record(&mut self, cassette: Arc<Cassette>) {
+        self.client.record(cassette);
+    }
+
+    /// Rate limit usage reported by responses to this client.
+    pub fn rate_limits(&self) -> Arc<RateLimitState> {
+        self.client.rate_limits()
+    }
+
+    pub fn decode_response<T>(&self, response: &HttpResponse) -> Result<T, Error>
     where
         T: DeserializeOwned,
     {
-        match status {
-            StatusCode::OK => serde_json::from_str(body).map_err(|error| Error::Decode {
-                error,
-                text: body.to_string(),
-            }),
-            _ => {
-                // Error, attempt to decode the response, if that fails fake it out.
-                if let Ok(error) = serde_json::from_str::<ApiError>(body) {
-                    Err(Error::ApiError(error))
-                } else {
-                    Err(Error::ApiError(ApiError {
-                        code: status.as_u16() as i64,
-                        msg: body.to_string(),
-                        other: HashMap::new(),
-                    }))
-                }
-            }
-        }
+        common::client::decode_response(response)
     }
 
     pub async fn post_listenkey(&self) -> Result<ListenKeyResponse, Error> {
-        let endpoint = "/api/v3/userDataStream";
-        self.client.post_listenkey(endpoint).await
+        self.client.post_listenkey(LISTEN_KEY_ENDPOINT).await
+    }
+
+    pub async fn put_listenkey(&self, listen_key: &str) -> Result<(), Error> {
+        self.client
+            .put_listenkey(LISTEN_KEY_ENDPOINT, listen_key)
+            .await
+    }
+
+    pub async fn delete_listenkey(&self, listen_key: &str) -> Result<(), Error> {
+        self.client
+            .delete_listenkey(LISTEN_KEY_ENDPOINT, listen_key)
+            .await
     }
 
-    pub async fn put_listenkey(&self) -> Result<ListenKeyResponse, Error> {
-        let endpoint = "/api/v1/listenKey";
-        self.client.put_listenkey(endpoint).await
+    /// A manager that keeps the listen key of the user data stream alive.
+    pub fn listen_key_manager(&self) -> ListenKeyManager {
+        ListenKeyManager::new(self.client.clone(), LISTEN_KEY_ENDPOINT)
     }
 }
 
@@ -140,7 +253,7 @@ fn build_form<S: AsRef<str> + std::fmt::Display + std::fmt::Debug>(vals: &[(&str
 #[derive(Deserialize, Debug, Clone)]
 #[allow(non_snake_case)]
 pub struct ExchangeInfoResponse {
-    pub rateLimits: Vec<serde_json::Value>,
+    pub rateLimits: Vec<RateLimit>,
     pub exchangeFilters: Vec<serde_json::Value>,
     pub symbols: Vec<SymbolInfo>,
     #[serde(flatten)]
@@ -225,6 +338,8 @@ pub struct OrderRequest {
     pub order_type: OrderType,
     #[serde(rename = "quoteOrderQty")]
     pub quote_order_qty: Option<f64>,
+    #[serde(rename = "newClientOrderId", skip_serializing_if = "Option::is_none")]
+    pub client_order_id: Option<String>,
 }
 
 #[derive(Debug, Serialize, Clone)]
@@ -301,9 +416,275 @@ pub struct OrderResponse {
     pub fills: Vec<serde_json::Value>,
 }
 
+/// A canceled order.
+#[derive(Deserialize, Debug)]
+pub struct CancelOrderResponse {
+    pub symbol: String,
+    #[serde(rename = "orderId")]
+    pub order_id: u64,
+    #[serde(rename = "orderListId")]
+    pub order_list_id: i64,
+    #[serde(rename = "clientOrderId")]
+    pub client_order_id: String,
+    #[serde(rename = "origClientOrderId")]
+    pub orig_client_order_id: String,
+    #[serde(deserialize_with = "parse_f64_string")]
+    pub price: f64,
+    #[serde(rename = "origQty", deserialize_with = "parse_f64_string")]
+    pub orig_qty: f64,
+    #[serde(rename = "executedQty", deserialize_with = "parse_f64_string")]
+    pub executed_qty: f64,
+    #[serde(rename = "cummulativeQuoteQty", deserialize_with = "parse_f64_string")]
+    pub cummulative_quote_qty: f64,
+    pub status: String,
+    #[serde(rename = "timeInForce")]
+    pub time_in_force: String,
+    #[serde(rename = "type")]
+    pub order_type: String,
+    pub side: String,
+    #[serde(flatten)]
+    pub other: HashMap<String, serde_json::Value>,
+}
+
+/// An order as returned by a query.
+#[derive(Deserialize, Debug)]
+pub struct Order {
+    pub symbol: String,
+    #[serde(rename = "orderId")]
+    pub order_id: u64,
+    #[serde(rename = "orderListId")]
+    pub order_list_id: i64,
+    #[serde(rename = "clientOrderId")]
+    pub client_order_id: String,
+    #[serde(deserialize_with = "parse_f64_string")]
+    pub price: f64,
+    #[serde(rename = "origQty", deserialize_with = "parse_f64_string")]
+    pub orig_qty: f64,
+    #[serde(rename = "executedQty", deserialize_with = "parse_f64_string")]
+    pub executed_qty: f64,
+    #[serde(rename = "cummulativeQuoteQty", deserialize_with = "parse_f64_string")]
+    pub cummulative_quote_qty: f64,
+    pub status: String,
+    #[serde(rename = "timeInForce")]
+    pub time_in_force: String,
+    #[serde(rename = "type")]
+    pub order_type: String,
+    pub side: String,
+    #[serde(rename = "stopPrice", deserialize_with = "parse_f64_string")]
+    pub stop_price: f64,
+    pub time: u64,
+    #[serde(rename = "updateTime")]
+    pub update_time: u64,
+    #[serde(rename = "isWorking")]
+    pub is_working: bool,
+    #[serde(flatten)]
+    pub other: HashMap<String, serde_json::Value>,
+}
+
+impl From<Order> for OrderResponse {
+    fn from(order: Order) -> Self {
+        Self {
+            symbol: order.symbol,
+            order_id: order.order_id,
+            order_list_id: order.order_list_id,
+            client_order_id: order.client_order_id,
+            transact_time: order.time,
+            price: order.price,
+            orig_qty: order.orig_qty,
+            executed_qty: order.executed_qty,
+            cummulative_quote_qty: order.cummulative_quote_qty,
+            status: order.status,
+            time_in_force: order.time_in_force,
+            order_type: order.order_type,
+            side: order.side,
+            fills: Vec::new(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::common::transport::test_support::{assert_signed, mock_client, API_KEY};
+    use crate::common::transport::MockTransport;
+    use reqwest::StatusCode;
+
+    #[tokio::test]
+    async fn test_post_order_request() {
+        let (client, transport) = mock_client(Client::new, Client::set_transport);
+        transport.push(
+            StatusCode::OK,
+            r#"{"symbol":"BNBUSDT","orderId":2946045072,"orderListId":-1,
+                "clientOrderId":"myOrder1","transactTime":1630366113477,
+                "price":"0.00000000","origQty":"0.03200000","executedQty":"0.03200000",
+                "cummulativeQuoteQty":"14.86080000","status":"FILLED","timeInForce":"GTC",
+                "type":"MARKET","side":"BUY","fills":[]}"#,
+        );
+        let order = OrderRequest {
+            symbol: "BNBUSDT".to_string(),
+            side: OrderSide::Buy,
+            order_type: OrderType::Market,
+            quote_order_qty: Some(15.0),
+            client_order_id: Some("myOrder1".to_string()),
+        };
+        let response = client.post_order(&order).await.unwrap();
+        assert_eq!(response.order_id, 2946045072);
+
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, Method::POST);
+        assert_eq!(
+            requests[0].url.as_str(),
+            "https://api.binance.com/api/v3/order"
+        );
+        assert_eq!(requests[0].headers["X-MBX-APIKEY"], API_KEY);
+        assert_signed(
+            requests[0].body.as_deref().unwrap(),
+            "symbol=BNBUSDT&side=BUY&type=MARKET&quoteOrderQty=15.0&newClientOrderId=myOrder1",
+        );
+    }
+
+    /// A client retrying at once and a market order.
+    fn retrying_client(
+        client_order_id: Option<&str>,
+    ) -> (Client, Arc<MockTransport>, OrderRequest) {
+        let (mut client, transport) = mock_client(Client::new, Client::set_transport);
+        client.set_retry_policy(
+            RetryPolicy::default().backoff(std::time::Duration::ZERO, std::time::Duration::ZERO),
+        );
+        let order = OrderRequest {
+            symbol: "BNBUSDT".to_string(),
+            side: OrderSide::Buy,
+            order_type: OrderType::Market,
+            quote_order_qty: Some(15.0),
+            client_order_id: client_order_id.map(String::from),
+        };
+        (client, transport, order)
+    }
+
+    #[tokio::test]
+    async fn test_post_order_found_after_server_error() {
+        let (client, transport, order) = retrying_client(Some("myOrder1"));
+        transport.push(StatusCode::BAD_GATEWAY, "");
+        transport.push(
+            StatusCode::OK,
+            r#"{"symbol":"BNBUSDT","orderId":2946045072,"orderListId":-1,"clientOrderId":"myOrder1",
+                "price":"0.0","origQty":"0.032","executedQty":"0.032","cummulativeQuoteQty":"14.8608",
+                "status":"FILLED","timeInForce":"GTC","type":"MARKET","side":"BUY",
+                "stopPrice":"0.0","time":1630366113477,"updateTime":1630366113477,"isWorking":true}"#,
+        );
+        let response = client.post_order(&order).await.unwrap();
+        assert_eq!(response.order_id, 2946045072);
+
+        // The order was placed, so it is not sent again.
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].method, Method::GET);
+        assert_signed(
+            requests[1].query(),
+            "symbol=BNBUSDT&origClientOrderId=myOrder1",
+        );
+    }
+
+    #[tokio::test]
+    async fn test_post_order_resent_when_not_found() {
+        let (client, transport, order) = retrying_client(Some("myOrder1"));
+        transport.push(StatusCode::BAD_GATEWAY, "");
+        transport.push(
+            StatusCode::BAD_REQUEST,
+            r#"{"code":-2013,"msg":"Order does not exist."}"#,
+        );
+        transport.push(
+            StatusCode::OK,
+            r#"{"symbol":"BNBUSDT","orderId":2946045072,"orderListId":-1,
+                "clientOrderId":"myOrder1","transactTime":1630366113477,
+                "price":"0.00000000","origQty":"0.03200000","executedQty":"0.03200000",
+                "cummulativeQuoteQty":"14.86080000","status":"FILLED","timeInForce":"GTC",
+                "type":"MARKET","side":"BUY","fills":[]}"#,
+        );
+        let response = client.post_order(&order).await.unwrap();
+        assert_eq!(response.order_id, 2946045072);
+
+        let methods: Vec<Method> = transport
+            .requests()
+            .into_iter()
+            .map(|request| request.method)
+            .collect();
+        assert_eq!(methods, [Method::POST, Method::GET, Method::POST]);
+        assert_eq!(transport.remaining(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_post_order_without_client_order_id_not_retried() {
+        let (client, transport, order) = retrying_client(None);
+        transport.push(StatusCode::BAD_GATEWAY, "");
+        assert!(client.post_order(&order).await.is_err());
+        assert_eq!(transport.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_get_order_request() {
+        let (client, transport) = mock_client(Client::new, Client::set_transport);
+        transport.push(
+            StatusCode::OK,
+            r#"{"symbol":"LTCBTC","orderId":1,"orderListId":-1,"clientOrderId":"myOrder1",
+                "price":"0.1","origQty":"1.0","executedQty":"0.0","cummulativeQuoteQty":"0.0",
+                "status":"NEW","timeInForce":"GTC","type":"LIMIT","side":"BUY",
+                "stopPrice":"0.0","icebergQty":"0.0","time":1499827319559,
+                "updateTime":1499827319559,"isWorking":true,"origQuoteOrderQty":"0.000000"}"#,
+        );
+        let query = QueryOrder::by_client_order_id("LTCBTC", "myOrder1");
+        let order = client.get_order(&query).await.unwrap();
+        assert_eq!(order.client_order_id, "myOrder1");
+
+        let requests = transport.requests();
+        assert_eq!(requests[0].method, Method::GET);
+        assert_eq!(requests[0].url.path(), "/api/v3/order");
+        assert!(requests[0].body.is_none());
+        assert_signed(
+            requests[0].query(),
+            "symbol=LTCBTC&origClientOrderId=myOrder1",
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_retry_after() {
+        let (client, transport) = mock_client(Client::new, Client::set_transport);
+        transport.push_response(
+            HttpResponse::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                r#"{"code":-1003,"msg":"Too many requests."}"#,
+            )
+            .header("retry-after", "3"),
+        );
+        match client.get_ticker_price().await {
+            Err(Error::RateLimited { retry_after, .. }) => {
+                assert_eq!(retry_after, Some(std::time::Duration::from_secs(3)))
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_scheduler_uses_exchange_info_limits() {
+        let (mut client, transport) = mock_client(Client::new, Client::set_transport);
+        client.enable_scheduler();
+        transport.push(
+            StatusCode::OK,
+            r#"{"rateLimits":[{"rateLimitType":"REQUEST_WEIGHT","interval":"SECOND",
+                "intervalNum":1,"limit":8}],"exchangeFilters":[],"symbols":[]}"#,
+        );
+        client.get_exchange_info().await.unwrap();
+
+        // The ticker price weighs 4, the third request waits for the bucket
+        // to refill at 8 per second.
+        let start = std::time::Instant::now();
+        for _ in 0..3 {
+            transport.push(StatusCode::OK, "[]");
+            client.get_ticker_price().await.unwrap();
+        }
+        assert!(start.elapsed() >= std::time::Duration::from_millis(400));
+    }
 
     #[test]
     fn test_order_response_success() {
@@ -329,4 +710,31 @@ mod test {
                 \"tradeId\":398890750}]}";
         let _response: OrderResponse = serde_json::from_str(_response_text).unwrap();
     }
+
+    #[test]
+    fn test_query_order_response() {
+        let text = r#"{
+            "symbol":"LTCBTC",
+            "orderId":1,
+            "orderListId":-1,
+            "clientOrderId":"myOrder1",
+            "price":"0.1",
+            "origQty":"1.0",
+            "executedQty":"0.0",
+            "cummulativeQuoteQty":"0.0",
+            "status":"NEW",
+            "timeInForce":"GTC",
+            "type":"LIMIT",
+            "side":"BUY",
+            "stopPrice":"0.0",
+            "icebergQty":"0.0",
+            "time":1499827319559,
+            "updateTime":1499827319559,
+            "isWorking":true,
+            "origQuoteOrderQty":"0.000000"}"#;
+        let order: Order = serde_json::from_str(text).unwrap();
+        let response: OrderResponse = order.into();
+        assert_eq!(response.client_order_id, "myOrder1");
+        assert_eq!(response.transact_time, 1499827319559);
+    }
 }
@@ -1,2 +1,4 @@
 pub mod client;
+pub mod userdata;
 pub mod websocket;
+pub mod ws_api;
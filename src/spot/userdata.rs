@@ -0,0 +1,240 @@
+// SPDX-License-Identifier: MIT
+
+//! A spot user data stream that survives disconnects and expired listen
+//! keys.
+
+use futures_util::Stream;
+use tracing::warn;
+
+use crate::common::latency::{EventTime, Receipt, Received};
+use crate::common::userdata::{UserDataConnection, UserDataMessage};
+use crate::common::websocket::Keepalive;
+use crate::error::Error;
+use crate::spot::client::{AccountResponse, Client, Order};
+use crate::spot::websocket::{self, AccountUpdate, Event, ExecutionReport};
+
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
+pub enum UserDataEvent {
+    ExecutionReport(ExecutionReport),
+    AccountUpdate(AccountUpdate),
+
+    /// The stream (re)connected. Events sent while it was disconnected are
+    /// lost, the snapshot, if enabled, is the state to reconcile against.
+    Resynced(Resync),
+
+    /// Any other event received on the stream.
+    Other(Event),
+}
+
+impl EventTime for UserDataEvent {
+    fn event_time(&self) -> Option<i64> {
+        match self {
+            UserDataEvent::ExecutionReport(event) => Some(event.event_time as i64),
+            UserDataEvent::AccountUpdate(event) => Some(event.event_time as i64),
+            UserDataEvent::Resynced(_) => None,
+            UserDataEvent::Other(event) => event.event_time(),
+        }
+    }
+}
+
+/// The account state fetched over REST after the stream (re)connected. Each
+/// field is `None` if snapshots are disabled or the request failed.
+#[derive(Debug, Default)]
+pub struct Resync {
+    pub open_orders: Option<Vec<Order>>,
+    pub account: Option<AccountResponse>,
+}
+
+pub struct UserDataStream {
+    client: Client,
+    connection: UserDataConnection,
+    snapshots: bool,
+}
+
+impl UserDataStream {
+    /// Create a listen key and start keeping it alive. The stream connects
+    /// to the environment of the client on the first call to `next`.
+    pub async fn connect(client: &Client) -> Result<Self, Error> {
+        let base_url = client.environment().spot_ws_url();
+        Ok(Self {
+            client: client.clone(),
+            connection: UserDataConnection::connect(client.listen_key_manager(), base_url).await?,
+            snapshots: false,
+        })
+    }
+
+    /// Fetch the open orders and account balances after every (re)connect.
+    pub fn with_snapshots(mut self, snapshots: bool) -> Self {
+        self.snapshots = snapshots;
+        self
+    }
+
+    /// Set the keepalive of the stream connections. A connection that times
+    /// out is reconnected.
+    pub fn with_keepalive(mut self, keepalive: Keepalive) -> Self {
+        self.connection.set_keepalive(keepalive);
+        self
+    }
+
+    /// The next event. The stream reconnects as needed so this only returns
+    /// once an event is available.
+    pub async fn next(&mut self) -> UserDataEvent {
+        self.next_received().await.event
+    }
+
+    /// The next event with the time and connection it was received on.
+    /// `Resynced` is stamped once the snapshot is fetched.
+    pub async fn next_received(&mut self) -> Received<UserDataEvent> {
+        loop {
+            let (message, receipt) = match self.connection.next().await {
+                UserDataMessage::Connected(receipt) => {
+                    let event = UserDataEvent::Resynced(self.resync().await);
+                    return Received {
+                        event,
+                        stream: None,
+                        receipt: Receipt::now(receipt.connection_id),
+                    };
+                }
+                UserDataMessage::Message(message, receipt) => (message, receipt),
+            };
+            let Received {
+                event,
+                stream,
+                receipt,
+            } = websocket::receive_message(message, receipt, self.connection.stream());
+            let event = match event {
+                Event::ExecutionReport(event) => UserDataEvent::ExecutionReport(event),
+                Event::AccountUpdate(event) => UserDataEvent::AccountUpdate(event),
+                Event::Message(message) if message.is_ping() => continue,
+                event => UserDataEvent::Other(event),
+            };
+            return Received {
+                event,
+                stream,
+                receipt,
+            };
+        }
+    }
+
+    async fn resync(&self) -> Resync {
+        if !self.snapshots {
+            return Resync::default();
+        }
+        let open_orders = self
+            .client
+            .get_open_orders(None)
+            .await
+            .map_err(|err| warn!("Failed to fetch open orders: {}", err))
+            .ok();
+        let account = self
+            .client
+            .get_account()
+            .await
+            .map_err(|err| warn!("Failed to fetch account: {}", err))
+            .ok();
+        Resync {
+            open_orders,
+            account,
+        }
+    }
+
+    /// The events as a `Stream`, which never ends.
+    pub fn into_stream(self) -> impl Stream<Item = UserDataEvent> {
+        futures_util::stream::unfold(self, |mut stream| async move {
+            let event = stream.next().await;
+            Some((event, stream))
+        })
+    }
+
+    /// Stop keeping the listen key alive and close the stream.
+    pub async fn close(self) -> Result<(), Error> {
+        self.connection.close().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::client::Authentication;
+    use crate::common::environment::Environment;
+    use crate::common::retry::RetryPolicy;
+    use crate::common::transport::MockTransport;
+    use futures_util::SinkExt;
+    use reqwest::StatusCode;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::Message;
+
+    #[tokio::test]
+    #[allow(clippy::result_large_err)]
+    async fn test_reconnect() {
+        // Serve two connections, each sending one execution report and closing.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let mut paths = vec![];
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut ws = tokio_tungstenite::accept_hdr_async(
+                    stream,
+                    |request: &tokio_tungstenite::tungstenite::handshake::server::Request,
+                     response| {
+                        paths.push(request.uri().path().to_string());
+                        Ok(response)
+                    },
+                )
+                .await
+                .unwrap();
+                let text = r#"{"e":"executionReport","E":1617686659383,"s":"TFUELUSDT","c":"iWoC4jk0fET4rFkrvFFY1y","S":"BUY","o":"MARKET","f":"GTC","q":"30.00000000","p":"0.00000000","P":"0.00000000","F":"0.00000000","g":-1,"C":"","x":"TRADE","X":"FILLED","r":"NONE","i":197554424,"l":"30.00000000","z":"30.00000000","L":"0.36653900","n":"0.00002190","N":"BNB","T":1617686659382,"t":13155802,"I":408139578,"w":false,"m":false,"M":true,"O":1617686659382,"Z":"10.99617000","Y":"10.99617000","Q":"0.00000000"}"#;
+                ws.send(Message::Text(text.to_string())).await.unwrap();
+                ws.close(None).await.unwrap();
+            }
+            paths
+        });
+
+        let transport = Arc::new(MockTransport::new());
+        transport.push(StatusCode::OK, r#"{"listenKey":"key1"}"#);
+        for _ in 0..2 {
+            transport.push(StatusCode::OK, "[]");
+            transport.push(StatusCode::OK, r#"{"canTrade":true,"balances":[]}"#);
+        }
+        transport.push(StatusCode::OK, "{}");
+        let environment =
+            Environment::custom("https://api.binance.com", format!("ws://{}", address));
+        let mut client =
+            Client::with_environment(&environment, Some(Authentication::hmac("key", "secret")));
+        client.set_transport(transport.clone());
+
+        let mut stream = UserDataStream::connect(&client)
+            .await
+            .unwrap()
+            .with_snapshots(true);
+        stream.connection.reconnect_policy =
+            RetryPolicy::none().backoff(Duration::ZERO, Duration::ZERO);
+        let mut connection_ids = vec![];
+        for _ in 0..2 {
+            let resynced = stream.next_received().await;
+            match resynced.event {
+                UserDataEvent::Resynced(resync) => {
+                    assert_eq!(resync.open_orders.unwrap().len(), 0);
+                    assert!(resync.account.unwrap().canTrade);
+                }
+                event => panic!("unexpected event {:?}", event),
+            }
+            let received = stream.next_received().await;
+            assert!(matches!(received.event, UserDataEvent::ExecutionReport(_)));
+            assert_eq!(received.event.event_time(), Some(1617686659383));
+            assert_eq!(
+                received.receipt.connection_id,
+                resynced.receipt.connection_id
+            );
+            connection_ids.push(received.receipt.connection_id);
+        }
+        assert_ne!(connection_ids[0], connection_ids[1]);
+        assert_eq!(server.await.unwrap(), ["/ws/key1", "/ws/key1"]);
+        stream.close().await.unwrap();
+        assert_eq!(transport.remaining(), 0);
+    }
+}
@@ -1,50 +1,413 @@
 // SPDX-License-Identifier: MIT
 
 use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_util::future::poll_fn;
+use futures_util::{ready, Stream, StreamExt};
 
 use anyhow::Result;
-use futures_util::StreamExt;
 use serde::Deserialize;
-use serde_json::Value;
+use serde_json::{json, Value};
 use tokio::net::TcpStream;
 use tokio_tungstenite::tungstenite::error::Error;
 use tokio_tungstenite::tungstenite::Message;
 use tokio_tungstenite::{connect_async, tungstenite, MaybeTlsStream, WebSocketStream};
-use tracing::error;
 
+use crate::common::cassette::{self, Cassette, CassetteEntry};
+use crate::common::environment::Environment;
+use crate::common::latency::{combined_stream, EventTime, Receipt, Received};
+use crate::common::listenkey::ListenKeyExpired;
+use crate::common::pool::{ConnectionPool, PoolConfig, PoolWriter};
+use crate::common::reconnect::{ReconnectMessage, ReconnectingConnection};
+use crate::common::retry::RetryPolicy;
+use crate::common::stream::{AggTrade, KlineEvent, MiniTicker, PriceLevel};
+use crate::common::stream_name::{Market, StreamName};
+use crate::common::websocket::{
+    decode_subscriptions, record_frame, stream_params, Connection, Keepalive, Outcome, Writer,
+};
 use crate::parsers::*;
 
 pub const BASE_URL: &str = "wss://stream.binance.com:9443";
+pub const TESTNET_BASE_URL: &str = "wss://testnet.binance.vision";
+pub const US_BASE_URL: &str = "wss://stream.binance.us:9443";
 
 pub struct WebSocket {
-    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    ws: Connection,
+    cassette: Option<Arc<Cassette>>,
+    /// The stream of a single stream connection.
+    stream: Option<String>,
 }
 
 impl WebSocket {
     pub fn new(ws: WebSocketStream<MaybeTlsStream<TcpStream>>) -> Self {
-        Self { ws }
+        Self {
+            ws: Connection::live(ws),
+            cassette: None,
+            stream: None,
+        }
+    }
+
+    /// A WebSocket that reads the frames recorded in a cassette.
+    pub fn replay(entries: &[CassetteEntry]) -> Self {
+        Self {
+            ws: Connection::replay(cassette::frames(entries)),
+            cassette: None,
+            stream: None,
+        }
+    }
+
+    /// Set the keepalive. Pings from the server are answered regardless.
+    pub fn set_keepalive(&mut self, keepalive: Keepalive) {
+        self.ws.set_keepalive(keepalive);
+    }
+
+    /// Record every frame read to a cassette.
+    pub fn record(&mut self, cassette: Arc<Cassette>) {
+        self.cassette = Some(cassette);
+    }
+
+    /// Subscribe to more streams on this connection.
+    pub async fn subscribe<T: AsRef<str>>(&mut self, streams: &[T]) -> Result<(), crate::Error> {
+        self.ws
+            .request("SUBSCRIBE", stream_params(streams))
+            .await
+            .map(|_| ())
+    }
+
+    /// Subscribe to typed streams, failing on any the market does not serve.
+    pub async fn subscribe_streams(&mut self, streams: &[StreamName]) -> Result<(), crate::Error> {
+        self.subscribe(&stream_names(streams)?).await
+    }
+
+    /// Unsubscribe from streams on this connection.
+    pub async fn unsubscribe<T: AsRef<str>>(&mut self, streams: &[T]) -> Result<(), crate::Error> {
+        self.ws
+            .request("UNSUBSCRIBE", stream_params(streams))
+            .await
+            .map(|_| ())
+    }
+
+    /// The streams this connection is subscribed to.
+    pub async fn list_subscriptions(&mut self) -> Result<Vec<String>, crate::Error> {
+        let result = self.ws.request("LIST_SUBSCRIPTIONS", json!([])).await?;
+        decode_subscriptions(result.unwrap_or_default())
+    }
+
+    /// The write half, for subscribing and unsubscribing while another
+    /// task reads this WebSocket.
+    pub fn writer(&self) -> Writer {
+        self.ws.writer()
     }
 
     pub async fn next(&mut self) -> Option<Result<Event, Error>> {
+        StreamExt::next(self).await
+    }
+
+    /// The next event with the time and connection it was received on.
+    pub async fn next_received(&mut self) -> Option<Result<Received<Event>, Error>> {
+        match poll_fn(|cx| self.poll_frame(cx)).await? {
+            Ok((message, receipt)) => Some(Ok(receive_message(
+                message,
+                receipt,
+                self.stream.as_deref(),
+            ))),
+            Err(err) => Some(Err(err)),
+        }
+    }
+
+    /// The next ping or text frame, with its receipt.
+    fn poll_frame(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<(Message, Receipt), Error>>> {
         loop {
-            let next = self.ws.next().await;
-            match next {
-                Some(Ok(message)) => match message {
-                    Message::Ping(_) | Message::Text(_) => {
-                        return Some(Ok(Decoder {}.decode_event(message)));
-                    }
-                    _ => {
-                        // Ignore, move onto the next incoming message.
+            match ready!(self.ws.poll_next(cx)) {
+                Some(Ok((message, receipt))) => {
+                    record_frame(&self.cassette, &message);
+                    match message {
+                        Message::Ping(_) | Message::Text(_) => {
+                            return Poll::Ready(Some(Ok((message, receipt))));
+                        }
+                        _ => {
+                            // Ignore, move onto the next incoming message.
+                        }
                     }
-                },
-                Some(Err(err)) => {
-                    return Some(Err(err));
-                }
-                None => {
-                    return None;
                 }
+                Some(Err(err)) => return Poll::Ready(Some(Err(err))),
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+impl Stream for WebSocket {
+    type Item = Result<Event, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Poll::Ready(match ready!(self.poll_frame(cx)) {
+            Some(Ok((message, _))) => Some(Ok(Decoder {}.decode_event(message))),
+            Some(Err(err)) => Some(Err(err)),
+            None => None,
+        })
+    }
+}
+
+/// A combined stream that reconnects when the connection drops and
+/// replaces the connection ahead of the 24 hour disconnect.
+pub struct ReconnectingWebSocket {
+    connection: ReconnectingConnection,
+}
+
+impl ReconnectingWebSocket {
+    pub async fn connect_combined<T: AsRef<str>>(
+        streams: &[T],
+    ) -> Result<Self, tungstenite::Error> {
+        Self::connect_combined_with_environment(&Environment::Mainnet, streams).await
+    }
+
+    pub async fn connect_combined_with_environment<T: AsRef<str>>(
+        environment: &Environment,
+        streams: &[T],
+    ) -> Result<Self, tungstenite::Error> {
+        let streams = streams.iter().map(|e| e.as_ref().to_string()).collect();
+        Ok(Self {
+            connection: ReconnectingConnection::connect(environment.spot_ws_url(), streams).await?,
+        })
+    }
+
+    /// Connect to typed streams, failing on any the market does not serve.
+    pub async fn connect_combined_streams(streams: &[StreamName]) -> Result<Self, crate::Error> {
+        Self::connect_combined_streams_with_environment(&Environment::Mainnet, streams).await
+    }
+
+    pub async fn connect_combined_streams_with_environment(
+        environment: &Environment,
+        streams: &[StreamName],
+    ) -> Result<Self, crate::Error> {
+        let streams = stream_names(streams)?;
+        Ok(Self::connect_combined_with_environment(environment, &streams).await?)
+    }
+
+    pub fn set_rollover(&mut self, rollover: Duration) {
+        self.connection.set_rollover(rollover);
+    }
+
+    pub fn set_reconnect_policy(&mut self, reconnect_policy: RetryPolicy) {
+        self.connection.set_reconnect_policy(reconnect_policy);
+    }
+
+    /// Set the keepalive of this and future connections. A connection that
+    /// times out is reconnected.
+    pub fn set_keepalive(&mut self, keepalive: Keepalive) {
+        self.connection.set_keepalive(keepalive);
+    }
+
+    /// Subscribe to more streams. The streams are also subscribed to on
+    /// reconnect, and only then while disconnected, when the outcome is
+    /// `Deferred`.
+    pub async fn subscribe<T: AsRef<str>>(
+        &mut self,
+        streams: &[T],
+    ) -> Result<Outcome, crate::Error> {
+        self.connection.subscribe(streams).await
+    }
+
+    /// Subscribe to typed streams, failing on any the market does not serve.
+    pub async fn subscribe_streams(
+        &mut self,
+        streams: &[StreamName],
+    ) -> Result<Outcome, crate::Error> {
+        self.subscribe(&stream_names(streams)?).await
+    }
+
+    /// Unsubscribe from streams, including on reconnect.
+    pub async fn unsubscribe<T: AsRef<str>>(
+        &mut self,
+        streams: &[T],
+    ) -> Result<Outcome, crate::Error> {
+        self.connection.unsubscribe(streams).await
+    }
+
+    /// The streams the current connection is subscribed to.
+    pub async fn list_subscriptions(&mut self) -> Result<Vec<String>, crate::Error> {
+        self.connection.list_subscriptions().await
+    }
+
+    /// The write half, for subscribing and unsubscribing while another
+    /// task reads. Streams subscribed to through it are also subscribed to
+    /// on reconnect.
+    pub fn writer(&self) -> Writer {
+        self.connection.writer()
+    }
+
+    /// The next event. Connection failures are reported as `Disconnected`
+    /// and `Reconnected` events rather than errors.
+    pub async fn next(&mut self) -> Option<Result<Event, Error>> {
+        StreamExt::next(self).await
+    }
+
+    /// The next event with the time and connection it was received on.
+    pub async fn next_received(&mut self) -> Option<Result<Received<Event>, Error>> {
+        loop {
+            let message = self.connection.next().await;
+            if let Some(received) = receive_reconnect_message(message, self.connection.receipt()) {
+                return Some(Ok(received));
+            }
+        }
+    }
+}
+
+impl Stream for ReconnectingWebSocket {
+    type Item = Result<Event, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        while let Some(message) = ready!(self.connection.poll_next_unpin(cx)) {
+            if let Some(event) = decode_reconnect_message(message) {
+                return Poll::Ready(Some(Ok(event)));
+            }
+        }
+        Poll::Ready(None)
+    }
+}
+
+/// Any number of streams spread over as many reconnecting connections as
+/// needed, read as one stream of events.
+pub struct StreamPool {
+    pool: ConnectionPool,
+}
+
+impl StreamPool {
+    pub fn new() -> Self {
+        Self {
+            pool: ConnectionPool::new(BASE_URL, PoolConfig::default()),
+        }
+    }
+
+    /// Every environment serves spot streams, so this does not fail. It
+    /// returns a `Result` like the futures pool.
+    pub fn with_environment(environment: &Environment) -> Result<Self, crate::Error> {
+        Self::with_config(environment, PoolConfig::default())
+    }
+
+    pub fn with_config(
+        environment: &Environment,
+        config: PoolConfig,
+    ) -> Result<Self, crate::Error> {
+        Ok(Self {
+            pool: ConnectionPool::new(environment.spot_ws_url(), config),
+        })
+    }
+
+    /// Subscribe to streams, opening connections as the open ones fill up.
+    pub async fn subscribe<T: AsRef<str>>(&mut self, streams: &[T]) -> Result<(), crate::Error> {
+        self.pool.subscribe(streams).await
+    }
+
+    /// Subscribe to typed streams, failing on any the market does not serve.
+    pub async fn subscribe_streams(&mut self, streams: &[StreamName]) -> Result<(), crate::Error> {
+        self.subscribe(&stream_names(streams)?).await
+    }
+
+    pub async fn unsubscribe<T: AsRef<str>>(&mut self, streams: &[T]) -> Result<(), crate::Error> {
+        self.pool.unsubscribe(streams).await
+    }
+
+    pub async fn streams(&self) -> Vec<String> {
+        self.pool.streams().await
+    }
+
+    pub async fn connections(&self) -> usize {
+        self.pool.connections().await
+    }
+
+    /// The write half, for subscribing and unsubscribing while another task
+    /// reads the pool.
+    pub fn writer(&self) -> PoolWriter {
+        self.pool.writer()
+    }
+
+    /// The next event from any connection. Each connection reports its own
+    /// `Disconnected` and `Reconnected` events.
+    pub async fn next(&mut self) -> Option<Result<Event, Error>> {
+        StreamExt::next(self).await
+    }
+
+    /// The next event with the time and connection it was received on.
+    pub async fn next_received(&mut self) -> Option<Result<Received<Event>, Error>> {
+        while let Some(message) = self.pool.next().await {
+            if let Some(received) = receive_reconnect_message(message.message, message.receipt) {
+                return Some(Ok(received));
             }
         }
+        None
+    }
+}
+
+impl Stream for StreamPool {
+    type Item = Result<Event, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        while let Some(message) = ready!(self.pool.poll_next_unpin(cx)) {
+            if let Some(event) = decode_reconnect_message(message.message) {
+                return Poll::Ready(Some(Ok(event)));
+            }
+        }
+        Poll::Ready(None)
+    }
+}
+
+impl Default for StreamPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn decode_reconnect_message(message: ReconnectMessage) -> Option<Event> {
+    match message {
+        ReconnectMessage::Message(message) => match message {
+            Message::Ping(_) | Message::Text(_) => Some(Decoder {}.decode_event(message)),
+            _ => None,
+        },
+        ReconnectMessage::Disconnected(reason) => Some(Event::Disconnected(reason)),
+        ReconnectMessage::Reconnected => Some(Event::Reconnected),
+    }
+}
+
+/// Decode a frame keeping its receipt. `stream` is the stream of a single
+/// stream connection, combined stream frames name their own.
+pub(crate) fn receive_message(
+    message: Message,
+    receipt: Receipt,
+    stream: Option<&str>,
+) -> Received<Event> {
+    let stream = match &message {
+        Message::Text(text) => combined_stream(text).or(stream),
+        _ => stream,
+    };
+    Received {
+        stream: stream.map(String::from),
+        event: Decoder {}.decode_event(message),
+        receipt,
+    }
+}
+
+fn receive_reconnect_message(
+    message: ReconnectMessage,
+    receipt: Receipt,
+) -> Option<Received<Event>> {
+    match message {
+        ReconnectMessage::Message(message @ (Message::Ping(_) | Message::Text(_))) => {
+            Some(receive_message(message, receipt, None))
+        }
+        message => decode_reconnect_message(message).map(|event| Received {
+            event,
+            stream: None,
+            receipt,
+        }),
     }
 }
 
@@ -54,15 +417,54 @@ pub async fn connect(url: &str) -> Result<WebSocket, tungstenite::Error> {
 }
 
 pub async fn connect_stream(name: &str) -> Result<WebSocket, tungstenite::Error> {
-    let url = format!("{}/ws/{}", BASE_URL, name);
-    connect(&url).await
+    connect_stream_with_environment(&Environment::Mainnet, name).await
+}
+
+pub async fn connect_stream_with_environment(
+    environment: &Environment,
+    name: &str,
+) -> Result<WebSocket, tungstenite::Error> {
+    let url = format!("{}/ws/{}", environment.spot_ws_url(), name);
+    let mut ws = connect(&url).await?;
+    ws.stream = Some(name.to_string());
+    Ok(ws)
+}
+
+/// Render typed stream names for the spot endpoint, failing on any stream
+/// it does not serve.
+pub fn stream_names(streams: &[StreamName]) -> Result<Vec<String>, crate::Error> {
+    StreamName::render_all(streams, Market::Spot)
+}
+
+/// Connect to typed streams, failing on any the market does not serve.
+pub async fn connect_combined_streams(streams: &[StreamName]) -> Result<WebSocket, crate::Error> {
+    connect_combined_streams_with_environment(&Environment::Mainnet, streams).await
+}
+
+pub async fn connect_combined_streams_with_environment(
+    environment: &Environment,
+    streams: &[StreamName],
+) -> Result<WebSocket, crate::Error> {
+    let streams = stream_names(streams)?;
+    Ok(connect_combined_with_environment(environment, &streams).await?)
 }
 
 pub async fn connect_combined<T: AsRef<str>>(
     streams: &[T],
+) -> Result<WebSocket, tungstenite::Error> {
+    connect_combined_with_environment(&Environment::Mainnet, streams).await
+}
+
+pub async fn connect_combined_with_environment<T: AsRef<str>>(
+    environment: &Environment,
+    streams: &[T],
 ) -> Result<WebSocket, tungstenite::Error> {
     let streams: Vec<&str> = streams.iter().map(|e| e.as_ref()).collect();
-    let url = format!("{}/stream?streams={}", BASE_URL, streams.join("/"));
+    let url = format!(
+        "{}/stream?streams={}",
+        environment.spot_ws_url(),
+        streams.join("/")
+    );
     connect(&url).await
 }
 
@@ -70,25 +472,102 @@ pub struct Decoder {}
 
 impl Decoder {
     fn decode_event(&self, message: Message) -> Event {
-        if let Message::Text(s) = &message {
-            if let Ok(value) = serde_json::from_str::<Value>(s) {
-                if value["e"].as_str().is_some() {
-                    match self.decode_value(value) {
-                        Ok(Some(event)) => {
-                            return event;
-                        }
-                        Err(err) => {
-                            error!("Failed to decode event: {} -- {}", err, message);
-                        }
-                        _ => {}
-                    }
-                }
-            }
+        match message {
+            Message::Text(text) => serde_json::from_str::<Value>(&text)
+                .and_then(Event::decode_value)
+                .unwrap_or_else(|err| Some(Event::ParseError(err.to_string(), text.to_string())))
+                .unwrap_or(Event::Message(Message::Text(text))),
+            message => Event::Message(message),
         }
-        Event::Message(message)
     }
+}
+
+#[derive(Debug, Clone)]
+#[allow(clippy::large_enum_variant)]
+pub enum Event {
+    ExecutionReport(ExecutionReport),
+    AccountUpdate(AccountUpdate),
+
+    /// A deposit, withdrawal or transfer changed a balance (user stream).
+    BalanceUpdate(BalanceUpdate),
+
+    /// An order list such as an OCO changed (user stream).
+    ListStatus(ListStatus),
+
+    /// The listen key of the user stream expired, no more events follow.
+    ListenKeyExpired(ListenKeyExpired),
+
+    Trade(Trade),
+    AggTrade(AggTrade),
+
+    /// Kline/OHLC event.
+    Kline(KlineEvent),
+
+    /// Diff depth event.
+    DepthUpdate(DepthUpdate),
+
+    /// Top levels of the order book, from a `<symbol>@depth<levels>` stream.
+    PartialDepth(PartialDepth),
+
+    BookTicker(BookTicker),
+    Ticker(Ticker),
+    MiniTicker(MiniTicker),
+
+    /// A `<symbol>@ticker_<window>` event.
+    RollingWindowTicker(RollingWindowTicker),
+
+    /// The tickers of all symbols that changed, from `!ticker@arr`.
+    Tickers(Vec<Ticker>),
+
+    /// From `!miniTicker@arr`.
+    MiniTickers(Vec<MiniTicker>),
+
+    /// From `!ticker_<window>@arr`.
+    RollingWindowTickers(Vec<RollingWindowTicker>),
+
+    /// Undecoded WebSocket message.
+    Message(Message),
+
+    /// A serde deserialize error, as a string so the event can be cloned,
+    /// and the text that failed to parse.
+    ParseError(String, String),
 
-    fn decode_value(&self, value: Value) -> Result<Option<Event>> {
+    /// The connection of a `ReconnectingWebSocket` dropped, with the reason.
+    Disconnected(String),
+
+    /// A `ReconnectingWebSocket` reconnected after `Disconnected`.
+    Reconnected,
+}
+
+impl Event {
+    /// Decode an event, unwrapping it from a combined stream envelope.
+    /// Returns `None` for messages that are not a known event.
+    pub fn decode_value(mut value: Value) -> Result<Option<Event>, serde_json::Error> {
+        if let Some(stream) = value["stream"].as_str() {
+            let stream = stream.to_string();
+            return Self::decode_data(Some(&stream), value["data"].take());
+        }
+        Self::decode_data(None, value)
+    }
+
+    /// Decode the payload of an event. The stream name, if known, is used to
+    /// fill in the symbol of partial depth events which do not carry one.
+    pub fn decode_data(
+        stream: Option<&str>,
+        value: Value,
+    ) -> Result<Option<Event>, serde_json::Error> {
+        if let Value::Array(values) = &value {
+            return match values.first().and_then(|first| first["e"].as_str()) {
+                Some("24hrTicker") => Ok(Some(Event::Tickers(serde_json::from_value(value)?))),
+                Some("24hrMiniTicker") => {
+                    Ok(Some(Event::MiniTickers(serde_json::from_value(value)?)))
+                }
+                Some(e) if is_rolling_window_ticker(e) => Ok(Some(Event::RollingWindowTickers(
+                    serde_json::from_value(value)?,
+                ))),
+                _ => Ok(None),
+            };
+        }
         match value["e"].as_str() {
             Some("executionReport") => {
                 Ok(Some(Event::ExecutionReport(serde_json::from_value(value)?)))
@@ -96,18 +575,227 @@ impl Decoder {
             Some("outboundAccountPosition") => {
                 Ok(Some(Event::AccountUpdate(serde_json::from_value(value)?)))
             }
-            _ => Ok(None),
+            Some("balanceUpdate") => Ok(Some(Event::BalanceUpdate(serde_json::from_value(value)?))),
+            Some("listStatus") => Ok(Some(Event::ListStatus(serde_json::from_value(value)?))),
+            Some("listenKeyExpired") => Ok(Some(Event::ListenKeyExpired(serde_json::from_value(
+                value,
+            )?))),
+            Some("trade") => Ok(Some(Event::Trade(serde_json::from_value(value)?))),
+            Some("aggTrade") => Ok(Some(Event::AggTrade(serde_json::from_value(value)?))),
+            Some("kline") => Ok(Some(Event::Kline(serde_json::from_value(value)?))),
+            Some("depthUpdate") => Ok(Some(Event::DepthUpdate(serde_json::from_value(value)?))),
+            Some("24hrTicker") => Ok(Some(Event::Ticker(serde_json::from_value(value)?))),
+            Some("24hrMiniTicker") => Ok(Some(Event::MiniTicker(serde_json::from_value(value)?))),
+            Some(e) if is_rolling_window_ticker(e) => Ok(Some(Event::RollingWindowTicker(
+                serde_json::from_value(value)?,
+            ))),
+            Some(_) => Ok(None),
+            // Partial depth and book ticker events have no event type.
+            None if value.get("lastUpdateId").is_some() => {
+                let mut depth: PartialDepth = serde_json::from_value(value)?;
+                depth.symbol = stream
+                    .and_then(|stream| stream.split('@').next())
+                    .map(|symbol| symbol.to_uppercase());
+                Ok(Some(Event::PartialDepth(depth)))
+            }
+            None if value.get("u").is_some() && value.get("b").is_some() => {
+                Ok(Some(Event::BookTicker(serde_json::from_value(value)?)))
+            }
+            None => Ok(None),
         }
     }
 }
 
-#[derive(Debug, Clone)]
-pub enum Event {
-    ExecutionReport(ExecutionReport),
-    AccountUpdate(AccountUpdate),
+impl EventTime for Event {
+    /// The `E` time of the event, the first of an array. Partial depth and
+    /// book ticker events have none.
+    fn event_time(&self) -> Option<i64> {
+        let event_time = match self {
+            Event::ExecutionReport(event) => event.event_time,
+            Event::AccountUpdate(event) => event.event_time,
+            Event::BalanceUpdate(event) => event.event_time,
+            Event::ListStatus(event) => event.event_time,
+            Event::ListenKeyExpired(event) => event.event_time,
+            Event::Trade(event) => event.event_time,
+            Event::AggTrade(event) => event.event_time as u64,
+            Event::Kline(event) => event.event_time as u64,
+            Event::DepthUpdate(event) => event.event_time,
+            Event::Ticker(event) => event.event_time,
+            Event::MiniTicker(event) => event.event_time,
+            Event::RollingWindowTicker(event) => event.event_time,
+            Event::Tickers(events) => events.first()?.event_time,
+            Event::MiniTickers(events) => events.first()?.event_time,
+            Event::RollingWindowTickers(events) => events.first()?.event_time,
+            Event::PartialDepth(_)
+            | Event::BookTicker(_)
+            | Event::Message(_)
+            | Event::ParseError(..)
+            | Event::Disconnected(_)
+            | Event::Reconnected => return None,
+        };
+        Some(event_time as i64)
+    }
+}
 
-    /// Undecoded WebSocket message.
-    Message(Message),
+/// The event types of the 1h, 4h and 1d rolling window tickers.
+fn is_rolling_window_ticker(event_type: &str) -> bool {
+    matches!(event_type, "1hTicker" | "4hTicker" | "1dTicker")
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Trade {
+    #[serde(rename = "e")]
+    pub event_type: String,
+    #[serde(rename = "E")]
+    pub event_time: u64,
+    #[serde(rename = "s")]
+    pub symbol: String,
+    #[serde(rename = "t")]
+    pub trade_id: u64,
+    #[serde(rename = "p", deserialize_with = "parse_f64_string")]
+    pub price: f64,
+    #[serde(rename = "q", deserialize_with = "parse_f64_string")]
+    pub quantity: f64,
+    #[serde(rename = "T")]
+    pub trade_time: u64,
+    #[serde(rename = "m")]
+    pub buyer_maker: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct DepthUpdate {
+    #[serde(rename = "e")]
+    pub event_type: String,
+    #[serde(rename = "E")]
+    pub event_time: u64,
+    #[serde(rename = "s")]
+    pub symbol: String,
+    #[serde(rename = "U")]
+    pub first_update_id: u64,
+    #[serde(rename = "u")]
+    pub final_update_id: u64,
+    #[serde(rename = "b")]
+    pub bids: Vec<PriceLevel>,
+    #[serde(rename = "a")]
+    pub asks: Vec<PriceLevel>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct PartialDepth {
+    /// From the stream name, `None` on a raw stream.
+    #[serde(skip)]
+    pub symbol: Option<String>,
+    #[serde(rename = "lastUpdateId")]
+    pub last_update_id: u64,
+    pub bids: Vec<PriceLevel>,
+    pub asks: Vec<PriceLevel>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct BookTicker {
+    #[serde(rename = "u")]
+    pub update_id: u64,
+    #[serde(rename = "s")]
+    pub symbol: String,
+    #[serde(rename = "b", deserialize_with = "parse_f64_string")]
+    pub bid_price: f64,
+    #[serde(rename = "B", deserialize_with = "parse_f64_string")]
+    pub bid_quantity: f64,
+    #[serde(rename = "a", deserialize_with = "parse_f64_string")]
+    pub ask_price: f64,
+    #[serde(rename = "A", deserialize_with = "parse_f64_string")]
+    pub ask_quantity: f64,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Ticker {
+    #[serde(rename = "e")]
+    pub event_type: String,
+    #[serde(rename = "E")]
+    pub event_time: u64,
+    #[serde(rename = "s")]
+    pub symbol: String,
+    #[serde(rename = "p", deserialize_with = "parse_f64_string")]
+    pub price_change: f64,
+    #[serde(rename = "P", deserialize_with = "parse_f64_string")]
+    pub price_change_percent: f64,
+    #[serde(rename = "w", deserialize_with = "parse_f64_string")]
+    pub weight_avg_price: f64,
+    /// The last price before the 24 hour window.
+    #[serde(rename = "x", deserialize_with = "parse_f64_string")]
+    pub first_trade_price: f64,
+    #[serde(rename = "c", deserialize_with = "parse_f64_string")]
+    pub last_price: f64,
+    #[serde(rename = "Q", deserialize_with = "parse_f64_string")]
+    pub last_quantity: f64,
+    #[serde(rename = "b", deserialize_with = "parse_f64_string")]
+    pub bid_price: f64,
+    #[serde(rename = "B", deserialize_with = "parse_f64_string")]
+    pub bid_quantity: f64,
+    #[serde(rename = "a", deserialize_with = "parse_f64_string")]
+    pub ask_price: f64,
+    #[serde(rename = "A", deserialize_with = "parse_f64_string")]
+    pub ask_quantity: f64,
+    #[serde(rename = "o", deserialize_with = "parse_f64_string")]
+    pub open_price: f64,
+    #[serde(rename = "h", deserialize_with = "parse_f64_string")]
+    pub high_price: f64,
+    #[serde(rename = "l", deserialize_with = "parse_f64_string")]
+    pub low_price: f64,
+    #[serde(rename = "v", deserialize_with = "parse_f64_string")]
+    pub base_asset_volume: f64,
+    #[serde(rename = "q", deserialize_with = "parse_f64_string")]
+    pub quote_asset_volume: f64,
+    #[serde(rename = "O")]
+    pub stats_open_time: u64,
+    #[serde(rename = "C")]
+    pub stats_close_time: u64,
+    /// -1 if there were no trades.
+    #[serde(rename = "F")]
+    pub first_trade_id: i64,
+    #[serde(rename = "L")]
+    pub last_trade_id: i64,
+    #[serde(rename = "n")]
+    pub trade_count: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct RollingWindowTicker {
+    /// `1hTicker`, `4hTicker` or `1dTicker`.
+    #[serde(rename = "e")]
+    pub event_type: String,
+    #[serde(rename = "E")]
+    pub event_time: u64,
+    #[serde(rename = "s")]
+    pub symbol: String,
+    #[serde(rename = "p", deserialize_with = "parse_f64_string")]
+    pub price_change: f64,
+    #[serde(rename = "P", deserialize_with = "parse_f64_string")]
+    pub price_change_percent: f64,
+    #[serde(rename = "o", deserialize_with = "parse_f64_string")]
+    pub open_price: f64,
+    #[serde(rename = "h", deserialize_with = "parse_f64_string")]
+    pub high_price: f64,
+    #[serde(rename = "l", deserialize_with = "parse_f64_string")]
+    pub low_price: f64,
+    #[serde(rename = "c", deserialize_with = "parse_f64_string")]
+    pub last_price: f64,
+    #[serde(rename = "w", deserialize_with = "parse_f64_string")]
+    pub weight_avg_price: f64,
+    #[serde(rename = "v", deserialize_with = "parse_f64_string")]
+    pub base_asset_volume: f64,
+    #[serde(rename = "q", deserialize_with = "parse_f64_string")]
+    pub quote_asset_volume: f64,
+    #[serde(rename = "O")]
+    pub stats_open_time: u64,
+    #[serde(rename = "C")]
+    pub stats_close_time: u64,
+    #[serde(rename = "F")]
+    pub first_trade_id: i64,
+    #[serde(rename = "L")]
+    pub last_trade_id: i64,
+    #[serde(rename = "n")]
+    pub trade_count: u64,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -200,6 +888,59 @@ pub struct AccountUpdateBalance {
     pub locked: f64,
 }
 
+#[derive(Clone, Debug, Deserialize)]
+pub struct BalanceUpdate {
+    #[serde(rename = "e")]
+    pub event_type: String,
+    #[serde(rename = "E")]
+    pub event_time: u64,
+    #[serde(rename = "a")]
+    pub asset: String,
+    #[serde(rename = "d", deserialize_with = "parse_f64_string")]
+    pub balance_delta: f64,
+    #[serde(rename = "T")]
+    pub clear_time: u64,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ListStatus {
+    #[serde(rename = "e")]
+    pub event_type: String,
+    #[serde(rename = "E")]
+    pub event_time: u64,
+    #[serde(rename = "s")]
+    pub symbol: String,
+    #[serde(rename = "g")]
+    pub order_list_id: i64,
+    /// `OCO` or `OTO`.
+    #[serde(rename = "c")]
+    pub contingency_type: String,
+    /// `RESPONSE`, `EXEC_STARTED` or `ALL_DONE`.
+    #[serde(rename = "l")]
+    pub list_status_type: String,
+    /// `EXECUTING`, `ALL_DONE` or `REJECT`.
+    #[serde(rename = "L")]
+    pub list_order_status: String,
+    #[serde(rename = "r")]
+    pub reject_reason: String,
+    #[serde(rename = "C")]
+    pub list_client_order_id: String,
+    #[serde(rename = "T")]
+    pub transaction_time: u64,
+    #[serde(rename = "O")]
+    pub orders: Vec<ListStatusOrder>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ListStatusOrder {
+    #[serde(rename = "s")]
+    pub symbol: String,
+    #[serde(rename = "i")]
+    pub order_id: u64,
+    #[serde(rename = "c")]
+    pub client_order_id: String,
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -267,4 +1008,166 @@ mod test {
             ]\
         }";
     }
+
+    fn decode(text: &str) -> Event {
+        Event::decode_value(serde_json::from_str(text).unwrap())
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_decode_trades() {
+        let event = decode(
+            r#"{"e":"trade","E":1672515782136,"s":"BNBBTC","t":12345,"p":"0.001","q":"100","T":1672515782136,"m":true,"M":true}"#,
+        );
+        match event {
+            Event::Trade(trade) => {
+                assert_eq!(trade.trade_id, 12345);
+                assert_eq!(trade.price, 0.001);
+                assert!(trade.buyer_maker);
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+
+        let event = decode(
+            r#"{"stream":"bnbbtc@aggTrade","data":{"e":"aggTrade","E":1672515782136,"s":"BNBBTC","a":12345,"p":"0.001","q":"100","f":100,"l":105,"T":1672515782136,"m":true,"M":true}}"#,
+        );
+        assert!(matches!(event, Event::AggTrade(trade) if trade.agg_trade_id == 12345));
+
+        let event = decode(
+            r#"{"e":"kline","E":1672515782136,"s":"BNBBTC","k":{"t":1672515780000,"T":1672515839999,"s":"BNBBTC","i":"1m","f":100,"L":200,"o":"0.0010","c":"0.0020","h":"0.0025","l":"0.0015","v":"1000","n":100,"x":false,"q":"1.0000","V":"500","Q":"0.500","B":"123456"}}"#,
+        );
+        match event {
+            Event::Kline(event) => {
+                assert_eq!(event.kline.interval, "1m");
+                assert_eq!(event.kline.high, 0.0025);
+                assert!(!event.kline.closed);
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+
+    #[test]
+    fn test_decode_depth() {
+        let event = decode(
+            r#"{"e":"depthUpdate","E":1672515782136,"s":"BNBBTC","U":157,"u":160,"b":[["0.0024","10"]],"a":[["0.0026","100"]]}"#,
+        );
+        match event {
+            Event::DepthUpdate(depth) => {
+                assert_eq!(depth.first_update_id, 157);
+                assert_eq!(depth.final_update_id, 160);
+                assert_eq!(
+                    depth.bids,
+                    [PriceLevel {
+                        price: 0.0024,
+                        quantity: 10.0
+                    }]
+                );
+                assert_eq!(depth.asks[0].quantity, 100.0);
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+
+        let text = r#"{"lastUpdateId":160,"bids":[["0.0024","10"]],"asks":[["0.0026","100"]]}"#;
+        match decode(text) {
+            Event::PartialDepth(depth) => {
+                assert_eq!(depth.symbol, None);
+                assert_eq!(depth.last_update_id, 160);
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+        let text = format!(r#"{{"stream":"bnbbtc@depth5@100ms","data":{}}}"#, text);
+        match decode(&text) {
+            Event::PartialDepth(depth) => assert_eq!(depth.symbol.as_deref(), Some("BNBBTC")),
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+
+    #[test]
+    fn test_decode_tickers() {
+        let text = r#"{"u":400900217,"s":"BNBUSDT","b":"25.35190000","B":"31.21000000","a":"25.36520000","A":"40.66000000"}"#;
+        match decode(text) {
+            Event::BookTicker(ticker) => {
+                assert_eq!(ticker.update_id, 400900217);
+                assert_eq!(ticker.ask_price, 25.3652);
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+
+        let ticker = r#"{"e":"24hrTicker","E":1672515782136,"s":"BNBBTC","p":"0.0015","P":"250.00","w":"0.0018","x":"0.0009","c":"0.0025","Q":"10","b":"0.0024","B":"10","a":"0.0026","A":"100","o":"0.0010","h":"0.0025","l":"0.0010","v":"10000","q":"18","O":0,"C":86400000,"F":0,"L":18150,"n":18151}"#;
+        assert!(matches!(decode(ticker), Event::Ticker(ticker) if ticker.trade_count == 18151));
+        let tickers = format!(r#"{{"stream":"!ticker@arr","data":[{}]}}"#, ticker);
+        assert!(matches!(decode(&tickers), Event::Tickers(tickers) if tickers.len() == 1));
+
+        let mini = r#"{"e":"24hrMiniTicker","E":1672515782136,"s":"BNBBTC","c":"0.0025","o":"0.0010","h":"0.0025","l":"0.0010","v":"10000","q":"18"}"#;
+        assert!(matches!(decode(mini), Event::MiniTicker(ticker) if ticker.close_price == 0.0025));
+        let minis = format!(r#"[{},{}]"#, mini, mini);
+        assert!(matches!(decode(&minis), Event::MiniTickers(tickers) if tickers.len() == 2));
+
+        let window = r#"{"e":"1hTicker","E":1672515782136,"s":"BNBBTC","p":"0.0015","P":"250.00","o":"0.0010","h":"0.0025","l":"0.0010","c":"0.0025","w":"0.0018","v":"10000","q":"18","O":0,"C":1675216573749,"F":0,"L":18150,"n":18151}"#;
+        match decode(window) {
+            Event::RollingWindowTicker(ticker) => {
+                assert_eq!(ticker.event_type, "1hTicker");
+                assert_eq!(ticker.price_change_percent, 250.0);
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+        let windows = format!(r#"{{"stream":"!ticker_1h@arr","data":[{}]}}"#, window);
+        assert!(matches!(
+            decode(&windows),
+            Event::RollingWindowTickers(tickers) if tickers.len() == 1
+        ));
+    }
+
+    #[test]
+    fn test_decode_unknown() {
+        let value = serde_json::from_str(r#"{"result":null,"id":1}"#).unwrap();
+        assert!(Event::decode_value(value).unwrap().is_none());
+        let message = Message::Text(r#"{"e":"unknown"}"#.to_string());
+        assert!(matches!(
+            Decoder {}.decode_event(message),
+            Event::Message(_)
+        ));
+    }
+
+    #[test]
+    fn test_decode_user_data() {
+        let text = r#"{"e":"balanceUpdate","E":1573200697110,"a":"BTC","d":"100.00000000","T":1573200697068}"#;
+        match decode(text) {
+            Event::BalanceUpdate(update) => {
+                assert_eq!(update.asset, "BTC");
+                assert_eq!(update.balance_delta, 100.0);
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+
+        let text = r#"{"e":"listStatus","E":1564035303637,"s":"ETHBTC","g":2,"c":"OCO","l":"EXEC_STARTED","L":"EXECUTING","r":"NONE","C":"F4QN4G8DlFATFlIUQ0cjdD","T":1564035303625,"O":[{"s":"ETHBTC","i":17,"c":"AJYsMjErWJesZvqlJCTUgL"},{"s":"ETHBTC","i":18,"c":"bfYPSQdLoqAJeNrOr9adzq"}]}"#;
+        match decode(text) {
+            Event::ListStatus(status) => {
+                assert_eq!(status.contingency_type, "OCO");
+                assert_eq!(status.orders[1].order_id, 18);
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+
+        let text = r#"{"e":"listenKeyExpired","E":1576653824250,"listenKey":"OfYGbUzi3PraNagEkdKuFwUHn48brFsItTdsuiIXrucEvD0rhRXZ7I6URWfE8YE8"}"#;
+        assert!(
+            matches!(decode(text), Event::ListenKeyExpired(event) if event.event_time == 1576653824250)
+        );
+    }
+
+    #[test]
+    fn test_decode_parse_error() {
+        let text =
+            r#"{"e":"balanceUpdate","E":1573200697110,"a":"BTC","d":"bogus","T":1573200697068}"#;
+        let decoder = Decoder {};
+        match decoder.decode_event(Message::Text(text.to_string())) {
+            Event::ParseError(_, input) => assert_eq!(input, text),
+            event => panic!("unexpected event {:?}", event),
+        }
+        match decoder.decode_event(Message::Text("not json".to_string())) {
+            Event::ParseError(_, input) => assert_eq!(input, "not json"),
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
 }
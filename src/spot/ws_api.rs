@@ -0,0 +1,171 @@
+// SPDX-License-Identifier: MIT
+
+//! The spot WebSocket API, see `common::ws_api`.
+
+use std::sync::Arc;
+
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::common::client::Authentication;
+use crate::common::environment::Environment;
+use crate::common::ratelimit::RateLimitState;
+use crate::common::ws_api::WsApiConnection;
+use crate::error::Error;
+use crate::spot::client::{CancelOrderResponse, Order, OrderRequest, OrderResponse};
+use crate::spot::websocket::Event;
+use crate::types::{CancelOrder, QueryOrder};
+
+pub const WS_API_URL: &str = "wss://ws-api.binance.com:443/ws-api/v3";
+pub const TESTNET_WS_API_URL: &str = "wss://ws-api.testnet.binance.vision/ws-api/v3";
+pub const US_WS_API_URL: &str = "wss://ws-api.binance.us:443/ws-api/v3";
+
+pub struct WsApi {
+    connection: WsApiConnection,
+}
+
+impl WsApi {
+    pub async fn connect(authentication: Authentication) -> Result<Self, Error> {
+        Self::connect_with_environment(&Environment::Mainnet, authentication).await
+    }
+
+    pub async fn connect_with_environment(
+        environment: &Environment,
+        authentication: Authentication,
+    ) -> Result<Self, Error> {
+        let connection =
+            WsApiConnection::connect(environment.spot_ws_api_url(), Some(authentication)).await?;
+        Ok(Self { connection })
+    }
+
+    /// The underlying connection, for requests without a method here.
+    pub fn connection(&self) -> &WsApiConnection {
+        &self.connection
+    }
+
+    pub fn rate_limits(&self) -> Arc<RateLimitState> {
+        self.connection.rate_limits()
+    }
+
+    /// Authenticate the session, needs an Ed25519 key.
+    pub async fn logon(&self) -> Result<(), Error> {
+        self.connection.logon().await?;
+        Ok(())
+    }
+
+    pub async fn sync_time(&self) -> Result<i64, Error> {
+        self.connection.sync_time().await
+    }
+
+    pub async fn place_order(&self, order: &OrderRequest) -> Result<OrderResponse, Error> {
+        let form = serde_urlencoded::to_string(order)?;
+        self.connection
+            .signed_request("order.place", &form)
+            .await?
+            .decode()
+    }
+
+    pub async fn cancel_order(&self, request: &CancelOrder) -> Result<CancelOrderResponse, Error> {
+        let form = serde_urlencoded::to_string(request)?;
+        self.connection
+            .signed_request("order.cancel", &form)
+            .await?
+            .decode()
+    }
+
+    /// Query an order by order ID or client order ID.
+    pub async fn get_order(&self, request: &QueryOrder) -> Result<Order, Error> {
+        let form = serde_urlencoded::to_string(request)?;
+        self.connection
+            .signed_request("order.status", &form)
+            .await?
+            .decode()
+    }
+
+    /// Receive the user data stream on this connection, see `next_event`.
+    /// The session must be logged on.
+    pub async fn subscribe_user_data(&self) -> Result<(), Error> {
+        self.connection.subscribe_user_data().await?;
+        Ok(())
+    }
+
+    /// The next user data event, `None` once the connection closed.
+    pub async fn next_event(&mut self) -> Option<Event> {
+        let value = self.connection.next_event().await?;
+        let text = value.to_string();
+        Some(
+            Event::decode_data(None, value)
+                .unwrap_or_else(|err| Some(Event::ParseError(err.to_string(), text.clone())))
+                .unwrap_or(Event::Message(Message::Text(text))),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::ws_api::test_support::serve;
+    use crate::spot::client::{OrderSide, OrderType};
+    use serde_json::{json, Value};
+
+    fn respond(method: &str, params: &Value) -> Value {
+        match method {
+            "order.place" => json!({"symbol": params["symbol"], "orderId": 28, "orderListId": -1,
+                "clientOrderId": params["newClientOrderId"], "transactTime": 1507725176595u64,
+                "price": "0.00000000", "origQty": "0.03200000", "executedQty": "0.03200000",
+                "cummulativeQuoteQty": params["quoteOrderQty"], "status": "FILLED",
+                "timeInForce": "GTC", "type": params["type"], "side": params["side"],
+                "fills": []}),
+            "order.cancel" => json!({"symbol": params["symbol"], "origClientOrderId": "myOrder1",
+                "orderId": params["orderId"].as_str().unwrap().parse::<u64>().unwrap(),
+                "orderListId": -1, "clientOrderId": "cancel1", "transactTime": 1684804350068u64,
+                "price": "23416.10000000", "origQty": "0.00847000", "executedQty": "0.00001000",
+                "cummulativeQuoteQty": "0.23416100", "status": "CANCELED", "timeInForce": "GTC",
+                "type": "LIMIT", "side": "SELL", "selfTradePreventionMode": "NONE"}),
+            _ => params.clone(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let event = json!({"e": "balanceUpdate", "E": 1573200697110u64, "a": "BTC",
+            "d": "100.00000000", "T": 1573200697068u64});
+        let url = serve(respond, event).await;
+        let environment = Environment::custom("http://127.0.0.1", url);
+        let mut api =
+            WsApi::connect_with_environment(&environment, Authentication::hmac("key", "secret"))
+                .await
+                .unwrap();
+
+        let order = OrderRequest {
+            symbol: "BNBUSDT".to_string(),
+            side: OrderSide::Buy,
+            order_type: OrderType::Market,
+            quote_order_qty: Some(15.0),
+            client_order_id: Some("myOrder1".to_string()),
+        };
+        let response = api.place_order(&order).await.unwrap();
+        assert_eq!(response.symbol, "BNBUSDT");
+        assert_eq!(response.client_order_id, "myOrder1");
+        assert_eq!(response.side, "BUY");
+        assert_eq!(response.order_type, "MARKET");
+        assert_eq!(response.cummulative_quote_qty, 15.0);
+
+        let response = api
+            .cancel_order(&CancelOrder::by_order_id("BTCUSDT", 12569099453))
+            .await
+            .unwrap();
+        assert_eq!(response.order_id, 12569099453);
+        assert_eq!(response.status, "CANCELED");
+        assert_eq!(response.orig_client_order_id, "myOrder1");
+        assert_eq!(response.other["selfTradePreventionMode"], "NONE");
+
+        api.subscribe_user_data().await.unwrap();
+        match api.next_event().await.unwrap() {
+            Event::BalanceUpdate(update) => {
+                assert_eq!(update.asset, "BTC");
+                assert_eq!(update.balance_delta, 100.0);
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+}
@@ -24,8 +24,11 @@ use std::fmt::{Display, Formatter};
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub enum Interval {
+    /// Spot klines only.
+    #[serde(rename = "1s")]
+    OneSecond,
     #[serde(rename = "1m")]
     OneMinute,
     #[serde(rename = "3m")]
@@ -34,10 +37,28 @@ pub enum Interval {
     FiveMinute,
     #[serde(rename = "15m")]
     FifteenMinute,
+    #[serde(rename = "30m")]
+    ThirtyMinute,
     #[serde(rename = "1h")]
     OneHour,
+    #[serde(rename = "2h")]
+    TwoHour,
     #[serde(rename = "4h")]
     FourHour,
+    #[serde(rename = "6h")]
+    SixHour,
+    #[serde(rename = "8h")]
+    EightHour,
+    #[serde(rename = "12h")]
+    TwelveHour,
+    #[serde(rename = "1d")]
+    OneDay,
+    #[serde(rename = "3d")]
+    ThreeDay,
+    #[serde(rename = "1w")]
+    OneWeek,
+    #[serde(rename = "1M")]
+    OneMonth,
 
     // For other values...
     Other(String),
@@ -46,24 +67,46 @@ pub enum Interval {
 impl Interval {
     pub fn from_str_non_strict<S: AsRef<str>>(s: S) -> Self {
         match s.as_ref() {
+            "1s" => Self::OneSecond,
             "1m" => Self::OneMinute,
             "3m" => Self::ThreeMinute,
             "5m" => Self::FiveMinute,
             "15m" => Self::FifteenMinute,
+            "30m" => Self::ThirtyMinute,
             "1h" => Self::OneHour,
+            "2h" => Self::TwoHour,
             "4h" => Self::FourHour,
+            "6h" => Self::SixHour,
+            "8h" => Self::EightHour,
+            "12h" => Self::TwelveHour,
+            "1d" => Self::OneDay,
+            "3d" => Self::ThreeDay,
+            "1w" => Self::OneWeek,
+            "1M" => Self::OneMonth,
             _ => Self::Other(s.as_ref().to_string()),
         }
     }
 
+    /// The length of the interval. A month is taken as 30 days, though
+    /// monthly klines follow the calendar.
     pub fn to_seconds(&self) -> u64 {
         match self {
+            Self::OneSecond => 1,
             Self::OneMinute => 60,
             Self::ThreeMinute => 60 * 3,
             Self::FiveMinute => 60 * 5,
             Self::FifteenMinute => 60 * 15,
+            Self::ThirtyMinute => 60 * 30,
             Self::OneHour => 60 * 60,
+            Self::TwoHour => 60 * 60 * 2,
             Self::FourHour => 60 * 60 * 4,
+            Self::SixHour => 60 * 60 * 6,
+            Self::EightHour => 60 * 60 * 8,
+            Self::TwelveHour => 60 * 60 * 12,
+            Self::OneDay => 60 * 60 * 24,
+            Self::ThreeDay => 60 * 60 * 24 * 3,
+            Self::OneWeek => 60 * 60 * 24 * 7,
+            Self::OneMonth => 60 * 60 * 24 * 30,
 
             // Should probably error?
             Self::Other(_) => 0,
@@ -78,12 +121,22 @@ impl Interval {
 impl Display for Interval {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         let v = match self {
+            Interval::OneSecond => "1s",
             Interval::OneMinute => "1m",
             Interval::ThreeMinute => "3m",
             Interval::FiveMinute => "5m",
             Interval::FifteenMinute => "15m",
+            Interval::ThirtyMinute => "30m",
             Interval::OneHour => "1h",
+            Interval::TwoHour => "2h",
             Interval::FourHour => "4h",
+            Interval::SixHour => "6h",
+            Interval::EightHour => "8h",
+            Interval::TwelveHour => "12h",
+            Interval::OneDay => "1d",
+            Interval::ThreeDay => "3d",
+            Interval::OneWeek => "1w",
+            Interval::OneMonth => "1M",
             Interval::Other(s) => s,
         };
         write!(f, "{}", v)
@@ -98,6 +151,8 @@ mod test {
     pub fn test_interval() {
         assert_eq!(Interval::from_str_non_strict("1m"), Interval::OneMinute);
         assert_eq!(format!("{}", Interval::OneMinute), "1m");
+        assert_eq!(Interval::from_str_non_strict("1M"), Interval::OneMonth);
+        assert_eq!(Interval::from_str_non_strict("1d").to_seconds(), 86400);
 
         assert_eq!(format!("{}", Interval::Other("1.5m".to_string())), "1.5m");
     }
@@ -56,6 +56,10 @@ impl CancelOrder {
     }
 }
 
+/// Query order datatype. An order is identified the same way for a query as
+/// for a cancel.
+pub type QueryOrder = CancelOrder;
+
 #[derive(Debug, Serialize, Clone)]
 pub enum TimeInForce {
     /// Good till cancel.